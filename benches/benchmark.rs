@@ -1,4 +1,4 @@
-use bfield::BField;
+use bfield::{BField, Backing};
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 
 fn build_bfield(n_secondaries: u8) -> BField<String> {
@@ -42,5 +42,157 @@ fn bench_querying(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, bench_insertion, bench_querying);
+fn bench_membership(c: &mut Criterion) {
+    let bfield = build_bfield(4);
+    for p in 0..4 {
+        for i in 0..10_000u32 {
+            bfield.insert(&i.to_be_bytes(), i, p);
+        }
+    }
+
+    let mut group = c.benchmark_group("bfield membership");
+    let mut key = 0u32;
+    group.bench_function("get", |b| {
+        b.iter(|| {
+            key = (key + 7_919) % 10_000;
+            black_box(bfield.get(black_box(&key.to_be_bytes())).is_some())
+        })
+    });
+    group.bench_function("contains", |b| {
+        b.iter(|| {
+            key = (key + 7_919) % 10_000;
+            black_box(bfield.contains(black_box(&key.to_be_bytes())))
+        })
+    });
+    group.finish();
+}
+
+fn bench_querying_huge_pages(c: &mut Criterion) {
+    // a 256MB primary array, so random lookups mostly miss the TLB
+    let build = |huge_pages: bool| {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            1 << 31,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        if huge_pages {
+            bfield.use_huge_pages().expect("huge pages to be supported");
+        }
+        for p in 0..2 {
+            for i in 0..100_000u32 {
+                bfield.insert(&i.to_be_bytes(), i, p);
+            }
+        }
+        bfield
+    };
+
+    let mut group = c.benchmark_group("bfield random querying");
+    for (name, huge_pages) in [("default pages", false), ("huge pages", true)] {
+        let bfield = build(huge_pages);
+        let mut key = 0u32;
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                key = (key + 7_919) % 100_000;
+                black_box(bfield.get(black_box(&key.to_be_bytes())))
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_backends(c: &mut Criterion) {
+    let build = |backing: Backing| {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            10_000_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            backing,
+            String::new(),
+        )
+        .expect("to build");
+        bfield
+    };
+
+    let mut group = c.benchmark_group("bfield backends");
+    for (name, backing) in [
+        ("anonymous map", Backing::Memory),
+        ("words", Backing::Words),
+    ] {
+        let bfield = build(backing);
+        let mut key = 0u32;
+        group.bench_function(format!("{} insertion", name), |b| {
+            b.iter(|| {
+                key = (key + 7_919) % 50_000;
+                bfield.insert(black_box(&key.to_be_bytes()), key, 0)
+            })
+        });
+        group.bench_function(format!("{} querying", name), |b| {
+            b.iter(|| {
+                key = (key + 7_919) % 50_000;
+                black_box(bfield.get(black_box(&key.to_be_bytes())))
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_get_many(c: &mut Criterion) {
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let bfield: BField<String> = BField::create(
+        tmp_dir.path(),
+        "bfield",
+        100_000_000,
+        10,
+        39,
+        4,
+        0.1,
+        0.025,
+        1,
+        true,
+        String::new(),
+    )
+    .expect("to build");
+    for key in 0..1_000_000u32 {
+        bfield.insert(&key.to_be_bytes(), key % 1_000, 0);
+    }
+    // scattered keys, so most lookups miss the CPU caches
+    let keys: Vec<[u8; 4]> = (0..10_000u32)
+        .map(|i| (i.wrapping_mul(7_919) % 1_000_000).to_be_bytes())
+        .collect();
+
+    let mut group = c.benchmark_group("bfield get_many");
+    for depth in [0, 4, 8, 16] {
+        group.bench_function(format!("depth {}", depth), |b| {
+            b.iter(|| black_box(bfield.get_many_pipelined(black_box(&keys), depth)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_insertion,
+    bench_querying,
+    bench_membership,
+    bench_querying_huge_pages,
+    bench_backends,
+    bench_get_many
+);
 criterion_main!(benches);
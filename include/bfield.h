@@ -0,0 +1,133 @@
+#ifndef BFIELD_H
+#define BFIELD_H
+
+/* Generated with cbindgen from src/ffi.rs, don't edit by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The result of a `bfield_*` call.
+ */
+typedef enum BFieldStatus {
+  /**
+   * The call succeeded
+   */
+  B_FIELD_STATUS_OK = 0,
+  /**
+   * `bfield_get` didn't find the key
+   */
+  B_FIELD_STATUS_NOT_FOUND = 1,
+  /**
+   * `bfield_insert` didn't insert the key, as an earlier member has it
+   */
+  B_FIELD_STATUS_NOT_INSERTED = 2,
+  /**
+   * A pointer was null, a string wasn't UTF-8 or a value or parameter
+   * was out of range
+   */
+  B_FIELD_STATUS_INVALID_ARGUMENT = -1,
+  /**
+   * A file couldn't be created, read or written
+   */
+  B_FIELD_STATUS_IO = -2,
+  /**
+   * The `BField` was loaded read-only
+   */
+  B_FIELD_STATUS_READ_ONLY = -3,
+  /**
+   * The `BField` files are corrupted or inconsistent
+   */
+  B_FIELD_STATUS_INVALID_DATA = -4,
+  /**
+   * Unexpected internal error
+   */
+  B_FIELD_STATUS_PANIC = -5,
+} BFieldStatus;
+
+/**
+ * An opaque handle on a `BField`. The `other` parameters are kept as raw
+ * bytes, which also reads the `String` parameters of `BField`s built from
+ * Rust.
+ */
+typedef struct BFieldHandle BFieldHandle;
+
+/**
+ * Creates a `BField` stored in `.bfd` files in `directory`, named
+ * `{filename}.{n}.bfd`, and sets `*out` to a handle on it (see
+ * `BField::create` for the parameters).
+ *
+ * # Safety
+ *
+ * `directory` and `filename` must be null-terminated strings and `out` a
+ * valid pointer. The handle must be freed with `bfield_free`.
+ */
+BFieldStatus bfield_create(const char *directory,
+                           const char *filename,
+                           size_t size,
+                           uint8_t n_hashes,
+                           uint8_t marker_width,
+                           uint8_t n_marker_bits,
+                           double secondary_scaledown,
+                           double max_scaledown,
+                           uint8_t n_secondaries,
+                           BFieldHandle **out);
+
+/**
+ * Loads the `BField` whose primary array is at `path` (the file ending in
+ * `0.bfd`) and sets `*out` to a handle on it.
+ *
+ * # Safety
+ *
+ * `path` must be a null-terminated string and `out` a valid pointer. The
+ * handle must be freed with `bfield_free`.
+ */
+BFieldStatus bfield_load(const char *path, bool read_only, BFieldHandle **out);
+
+/**
+ * Looks up the `key_len` bytes at `key`, setting `*value` and returning
+ * `Ok` if they're found and returning `NotFound` otherwise.
+ *
+ * # Safety
+ *
+ * `bfield` must be a handle from `bfield_create` or `bfield_load`, `key`
+ * must point to `key_len` readable bytes and `value` must be a valid
+ * pointer. Handles can be used from several threads at once.
+ */
+BFieldStatus bfield_get(const BFieldHandle *bfield,
+                        const uint8_t *key,
+                        size_t key_len,
+                        uint32_t *value);
+
+/**
+ * Inserts the `key_len` bytes at `key` with `value` for pass `pass` (see
+ * `BField::insert`), returning `NotInserted` if an earlier member already
+ * resolves the key.
+ *
+ * # Safety
+ *
+ * `bfield` must be a handle from `bfield_create` or `bfield_load` and
+ * `key` must point to `key_len` readable bytes. Inserts can run on several
+ * threads at once, as long as each pass is finished before the next one
+ * starts.
+ */
+BFieldStatus bfield_insert(const BFieldHandle *bfield,
+                           const uint8_t *key,
+                           size_t key_len,
+                           uint32_t value,
+                           size_t pass);
+
+/**
+ * Frees a handle. Does nothing if `bfield` is null.
+ *
+ * # Safety
+ *
+ * `bfield` must be null or a handle from `bfield_create` or `bfield_load`
+ * that isn't used again.
+ */
+void bfield_free(BFieldHandle *bfield);
+
+#endif /* BFIELD_H */
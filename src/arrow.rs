@@ -0,0 +1,249 @@
+//! Building a `BField` straight from Arrow `RecordBatch`es (e.g. read from
+//! Parquet or produced by DataFusion), with a binary key column and a uint32
+//! value column. Keys are borrowed from the batches' buffers, so nothing is
+//! allocated per row.
+use std::io;
+
+use arrow_array::{Array, BinaryArray, RecordBatch, UInt32Array};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bfield::{BField, Progress};
+use crate::bfield_member::BFieldValue;
+
+/// The key and value columns of `batch`, checking their types, that they
+/// have no nulls and that every value fits in `V`.
+fn columns<'b, V: BFieldValue>(
+    batch: &'b RecordBatch,
+    key_column: &str,
+    value_column: &str,
+) -> Result<(&'b BinaryArray, &'b UInt32Array), io::Error> {
+    let keys = column::<BinaryArray>(batch, key_column, "Binary")?;
+    let values = column::<UInt32Array>(batch, value_column, "UInt32")?;
+    if let Some(&value) = values
+        .values()
+        .iter()
+        .find(|&&v| V::from_u64(u64::from(v)).is_none())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Value {} of column {:?} doesn't fit in {} bits",
+                value,
+                value_column,
+                V::BITS
+            ),
+        ));
+    }
+    Ok((keys, values))
+}
+
+fn column<'b, A: Array + 'static>(
+    batch: &'b RecordBatch,
+    name: &str,
+    type_name: &str,
+) -> Result<&'b A, io::Error> {
+    let array = batch.column_by_name(name).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("No column {:?} in the record batch", name),
+        )
+    })?;
+    let array = array.as_any().downcast_ref::<A>().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Column {:?} is {}, not {}",
+                name,
+                array.data_type(),
+                type_name
+            ),
+        )
+    })?;
+    if array.null_count() > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Column {:?} has {} nulls", name, array.null_count()),
+        ));
+    }
+    Ok(array)
+}
+
+/// The `(key, value)` pairs of checked columns.
+fn pairs<'b, V: BFieldValue>(
+    keys: &'b BinaryArray,
+    values: &'b UInt32Array,
+) -> impl Iterator<Item = (&'b [u8], V)> + Clone + 'b {
+    (0..keys.len()).map(move |i| {
+        let value = V::from_u64(u64::from(values.value(i))).expect("values are checked");
+        (keys.value(i), value)
+    })
+}
+
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> BField<T, V> {
+    /// Inserts the rows of `batch` at `pass` like [`BField::insert`], with
+    /// the keys of its `key_column` (`Binary`) and the values of its
+    /// `value_column` (`UInt32`). Keys an earlier member already determines
+    /// are skipped. Nothing is inserted if a column is missing, has the
+    /// wrong type or has nulls, or if a value can't be stored.
+    pub fn insert_record_batch(
+        &self,
+        batch: &RecordBatch,
+        key_column: &str,
+        value_column: &str,
+        pass: usize,
+    ) -> Result<(), io::Error> {
+        if self.is_read_only() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Can't insert into read_only bfields",
+            ));
+        }
+        let (keys, values) = columns::<V>(batch, key_column, value_column)?;
+        let batch_size = self.batch_size();
+        let mut pairs = pairs::<V>(keys, values).filter(|(key, _)| self.reaches(key, pass));
+        loop {
+            let (keys, values): (Vec<&[u8]>, Vec<u64>) = pairs
+                .by_ref()
+                .take(batch_size)
+                .map(|(key, value)| (key, value.into_u64()))
+                .unzip();
+            if keys.is_empty() {
+                return Ok(());
+            }
+            self.insert_keys(&keys, &values, pass)?;
+        }
+    }
+
+    /// Inserts the rows of all the `batches` like
+    /// [`BField::build_from_iter`] (calling `progress` the same way), with
+    /// the keys of their `key_column` (`Binary`) and the values of their
+    /// `value_column` (`UInt32`). Every batch is checked before anything is
+    /// inserted, see [`BField::insert_record_batch`].
+    pub fn build_from_batches<F: Fn(Progress)>(
+        &self,
+        batches: &[RecordBatch],
+        key_column: &str,
+        value_column: &str,
+        progress: F,
+    ) -> Result<(), io::Error> {
+        let columns = batches
+            .iter()
+            .map(|batch| columns::<V>(batch, key_column, value_column))
+            .collect::<Result<Vec<_>, _>>()?;
+        let pairs = columns
+            .iter()
+            .flat_map(|&(keys, values)| pairs::<V>(keys, values));
+        self.build_from_iter(pairs, progress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use arrow_array::{ArrayRef, Int64Array};
+
+    fn batch(range: std::ops::Range<u32>) -> RecordBatch {
+        let keys: Vec<Vec<u8>> = range.clone().map(|i| i.to_be_bytes().to_vec()).collect();
+        let keys: ArrayRef = Arc::new(BinaryArray::from_iter_values(keys));
+        let values: ArrayRef = Arc::new(UInt32Array::from_iter_values(range));
+        RecordBatch::try_from_iter(vec![("key", keys), ("value", values)]).unwrap()
+    }
+
+    fn create(dir: &std::path::Path, name: &str) -> BField<String> {
+        let mut bfield = BField::create(
+            dir,
+            name,
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            3,
+            true,
+            String::new(),
+        )
+        .unwrap();
+        // so the digests don't depend on the creation time
+        bfield.set_deterministic(true).unwrap();
+        bfield
+    }
+
+    #[test]
+    fn can_build_from_batches() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let expected = create(tmp_dir.path(), "expected");
+        expected
+            .build_from_iter((0..3_000u32).map(|i| (i.to_be_bytes(), i)), |_| {})
+            .unwrap();
+
+        let batches = vec![batch(0..1_000), batch(1_000..3_000)];
+        let bfield = create(tmp_dir.path(), "bfield");
+        bfield
+            .build_from_batches(&batches, "key", "value", |_| {})
+            .unwrap();
+        assert_eq!(bfield.digest(), expected.digest());
+        assert!((0..3_000u32).all(|i| {
+            let key = i.to_be_bytes();
+            bfield.get(&key) == expected.get(&key)
+        }));
+
+        let single = create(tmp_dir.path(), "single");
+        single
+            .insert_record_batch(&batches[0], "key", "value", 0)
+            .unwrap();
+        assert_eq!(single.members()[0].n_keys(), 1_000);
+    }
+
+    #[test]
+    fn can_reject_bad_batches() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield = create(tmp_dir.path(), "bfield");
+        let good = batch(0..10);
+        let err = |batch: &RecordBatch, key, value| {
+            bfield
+                .insert_record_batch(batch, key, value, 0)
+                .unwrap_err()
+                .kind()
+        };
+        assert_eq!(err(&good, "missing", "value"), io::ErrorKind::InvalidInput);
+        assert_eq!(err(&good, "value", "key"), io::ErrorKind::InvalidInput);
+
+        let keys: ArrayRef = Arc::new(BinaryArray::from(vec![Some(&b"a"[..]), None]));
+        let values: ArrayRef = Arc::new(UInt32Array::from(vec![1, 2]));
+        let nulls = RecordBatch::try_from_iter(vec![("key", keys), ("value", values)]).unwrap();
+        assert_eq!(err(&nulls, "key", "value"), io::ErrorKind::InvalidData);
+        let signed: ArrayRef = Arc::new(Int64Array::from(vec![1, 2]));
+        let keys = good.column(0).slice(0, 2);
+        let signed = RecordBatch::try_from_iter(vec![("key", keys), ("value", signed)]).unwrap();
+        assert_eq!(err(&signed, "key", "value"), io::ErrorKind::InvalidInput);
+        // a bad batch fails the build before any insert
+        let batches = vec![good, nulls];
+        assert!(bfield
+            .build_from_batches(&batches, "key", "value", |_| {})
+            .is_err());
+        assert_eq!(bfield.members()[0].n_keys(), 0);
+
+        let small: BField<String, u8> = BField::create(
+            tmp_dir.path(),
+            "small",
+            1_000,
+            3,
+            10,
+            2,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .unwrap();
+        let err = small
+            .insert_record_batch(&batch(250..260), "key", "value", 0)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
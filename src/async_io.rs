@@ -0,0 +1,82 @@
+//! Async wrappers running the blocking file IO on tokio's blocking pool.
+use std::io;
+use std::path::PathBuf;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bfield::BField;
+use crate::bfield_member::BFieldValue;
+
+/// Runs `f` on tokio's blocking pool.
+async fn run_blocking<R, F>(f: F) -> Result<R, io::Error>
+where
+    F: FnOnce() -> Result<R, io::Error> + Send + 'static,
+    R: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(io::Error::other)?
+}
+
+impl<T, V> BField<T, V>
+where
+    T: Clone + DeserializeOwned + Serialize + Send + 'static,
+    V: BFieldValue + Send + 'static,
+{
+    /// Same as [`BField::load`], without blocking the async executor.
+    pub async fn load_async<P: Into<PathBuf>>(
+        main_db_path: P,
+        read_only: bool,
+    ) -> Result<Self, io::Error> {
+        let main_db_path = main_db_path.into();
+        run_blocking(move || Self::load(main_db_path, read_only)).await
+    }
+
+    /// Same as [`BField::persist_to_disk`], without blocking the async executor.
+    pub async fn persist_to_disk_async(self) -> Result<Self, io::Error> {
+        run_blocking(move || self.persist_to_disk()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_persist_and_load_async() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            3,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        for p in 0..3 {
+            for i in 0..1_000u32 {
+                bfield.insert(&i.to_be_bytes(), i, p);
+            }
+        }
+
+        runtime.block_on(async {
+            drop(bfield.persist_to_disk_async().await.unwrap());
+            let bfield = BField::<String>::load_async(tmp_dir.path().join("bfield.0.bfd"), true)
+                .await
+                .unwrap();
+            for i in 0..1_000u32 {
+                assert_eq!(bfield.get(&i.to_be_bytes()), Some(i));
+            }
+        });
+    }
+}
@@ -1,27 +1,77 @@
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
 use std::io;
+use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use crate::combinatorial::rank;
+use crate::combinatorial::{RankError, MAX_MARKER_BITS};
 use serde::de::DeserializeOwned;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use xxhash_rust::xxh3::Xxh3;
 
-use crate::bfield_member::{BFieldLookup, BFieldMember, BFieldVal};
+use crate::bfield_member::{
+    member_seed, BFieldLookup, BFieldMember, BFieldParams, BFieldVal, BFieldValue, Tristate,
+};
+#[cfg(feature = "cache")]
+use crate::cache::LookupCache;
+use crate::dedup::{duplicate_positions, skip_positions, DedupOptions};
+use crate::journal::{Journal, JournalOp};
+use crate::key::BFieldKey;
+use crate::keyed::HashSecret;
+use crate::manifest::{manifest_path, Manifest};
+#[cfg(feature = "metrics")]
+use crate::metrics::{BoundMetrics, LookupMetrics};
+use crate::params::ParamsUpgrade;
+use crate::prefilter::{prefilter_path, Prefilter};
+use crate::provenance::Provenance;
+use crate::saturation::SaturationMonitor;
+use crate::sidecar::{sidecar_path, Sidecar};
+use crate::stats::indeterminacy_rate_at;
+use crate::storage::{byte_len, replace_file, AccessPattern, Backing, Storage};
+
+/// Number of keys `build_from_iter` encodes at once.
+const BUILD_BATCH_SIZE: usize = 4096;
+
+/// How many keys ahead `get_many` prefetches.
+pub(crate) const DEFAULT_PIPELINE_DEPTH: usize = 8;
 
 /// The `struct` holding the `BField` primary and secondary bit arrays.
-pub struct BField<T> {
+///
+/// `V` is the type of the stored values; it defaults to `u32` but can be
+/// set to `u64` for value spaces larger than 2^32.
+///
+/// A `BField` is `Send` and `Sync` whenever `T` is: the bit arrays are only
+/// ever written to with atomic operations, so it can be shared between
+/// threads (e.g. in an `Arc`) for both inserts and lookups.
+pub struct BField<T, V = BFieldVal> {
     members: Vec<BFieldMember<T>>,
     read_only: bool,
+    saturation: Option<SaturationMonitor>,
+    journal: Option<Journal>,
+    sidecar: Option<Sidecar>,
+    prefilter: Option<Prefilter>,
+    #[cfg(feature = "cache")]
+    cache: Option<LookupCache>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<BoundMetrics>,
+    // number of keys whose writes builds sort together, 0 if they don't
+    sorted_writes: usize,
+    // see `set_deterministic`
+    deterministic: bool,
+    // see `dedup_keys`
+    dedup: Option<DedupOptions>,
+    value_type: PhantomData<V>,
 }
 
-// This is safe in theory, as the mmap is send+sync
-unsafe impl<T> Send for BField<T> {}
-unsafe impl<T> Sync for BField<T> {}
-
-impl<T: Clone + DeserializeOwned + Serialize> BField<T> {
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> BField<T, V> {
     /// A (rather complex) method for creating a `BField`.
     ///
     /// This will create a series of `BField` bit array files in `directory` with the given `filename` and the
-    /// suffixes `(0..n_secondaries).bfd`. If you set `in_memory` to true, remember to call `persist_to_disk` once it's built to
+    /// suffixes `(0..n_secondaries).bfd`. If you store it in memory, remember to call `persist_to_disk` once it's built to
     /// save it.
     ///
     /// The following parameters are required. See the [README.md](https://github.com/onecodex/rust-bfield/)
@@ -31,13 +81,16 @@ impl<T: Clone + DeserializeOwned + Serialize> BField<T> {
     /// - `size` is the primary `BField` size, subsequent `BField` sizes will be determined
     /// by the `secondary_scaledown` and `max_scaledown` parameters
     /// - `n_hashes`. The number of hash functions _k_ to use.
-    /// - `marker_width` or v (nu). The length of the bit-string to use for
+    /// - `marker_width` or v (nu). The length of the bit-string to use for (widths over 128 use
+    /// a slower two-word marker path)
     /// - `n_marker_bits` or κ (kappa). The number of 1s to set in each v-length bit-string (also its Hamming weight).
+    /// Up to 16 bits are supported.
     /// - `secondary_scaledown` or β (beta). The scaling factor to use for each subsequent `BField` size.
     /// - `max_scaledown`. A maximum scaling factor to use for secondary `BField` sizes, since β raised to the power of
     /// `n_secondaries` can be impractically/needlessly small.
     /// - `n_secondaries`. The number of secondary `BField`s to create.
-    /// - `in_memory`. Whether to create the `BField` in memory or on disk.
+    /// - `backing`. Where to store the arrays: on disk (`false` or `Backing::File`), in anonymous
+    /// memory maps (`true` or `Backing::Memory`) or in heap-allocated words (`Backing::Words`).
     #[allow(clippy::too_many_arguments)]
     pub fn create<P>(
         directory: P,
@@ -49,109 +102,551 @@ impl<T: Clone + DeserializeOwned + Serialize> BField<T> {
         secondary_scaledown: f64, // beta
         max_scaledown: f64,
         n_secondaries: u8,
-        in_memory: bool,
+        backing: impl Into<Backing>,
+        other_params: T,
+    ) -> Result<Self, io::Error>
+    where
+        P: AsRef<Path>,
+    {
+        Self::create_with_progress(
+            directory,
+            filename,
+            size,
+            n_hashes,
+            marker_width,
+            n_marker_bits,
+            secondary_scaledown,
+            max_scaledown,
+            n_secondaries,
+            backing,
+            other_params,
+            |_| {},
+        )
+    }
+
+    /// Same as [`BField::create`], calling `progress` after each member is
+    /// created.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_with_progress<P, F>(
+        directory: P,
+        filename: &str,
+        size: usize,
+        n_hashes: u8,             // k
+        marker_width: u8,         // nu
+        n_marker_bits: u8,        // kappa
+        secondary_scaledown: f64, // beta
+        max_scaledown: f64,
+        n_secondaries: u8,
+        backing: impl Into<Backing>,
         other_params: T,
+        progress: F,
     ) -> Result<Self, io::Error>
     where
         P: AsRef<Path>,
+        F: Fn(Progress),
     {
         debug_assert!(!filename.is_empty());
-        let mut cur_size = size;
+        check_marker_params(marker_width, n_marker_bits)?;
+        let backing = backing.into();
         let mut members = Vec::new();
 
-        for n in 0..n_secondaries {
+        let sizes = member_sizes(size, secondary_scaledown, max_scaledown, n_secondaries);
+        let mut report = Progress::new(&sizes);
+        for (n, cur_size) in sizes.into_iter().enumerate() {
             let file = directory.as_ref().join(format!("{filename}.{n}.bfd"));
-            let params = if n == 0 {
-                Some(other_params.clone())
-            } else {
-                None
-            };
-            let member = BFieldMember::create(
-                file,
-                in_memory,
-                cur_size,
-                n_hashes,
-                marker_width,
-                n_marker_bits,
-                params,
-            )?;
+            let mut params =
+                BFieldParams::new(n_hashes, marker_width, n_marker_bits, V::BITS, None);
+            if n == 0 {
+                params.other = Some(other_params.clone());
+                params.provenance = Some(Provenance::new(secondary_scaledown, max_scaledown));
+            }
+            let member = BFieldMember::create_with_params(&file, backing, cur_size, params)?;
             members.push(member);
-            cur_size = f64::max(
-                cur_size as f64 * secondary_scaledown,
-                size as f64 * max_scaledown,
-            ) as usize;
+            report.member_written(cur_size);
+            progress(report);
         }
 
-        // Initialize our marker table, so we don't
-        // have any race conditions across threads
-        let _ = rank(0, n_marker_bits);
-
-        Ok(BField {
+        let bfield = BField {
             members,
             read_only: false,
-        })
+            saturation: None,
+            journal: None,
+            sidecar: None,
+            prefilter: None,
+            #[cfg(feature = "cache")]
+            cache: None,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+            sorted_writes: 0,
+            deterministic: false,
+            dedup: None,
+            value_type: PhantomData,
+        };
+        if backing == Backing::File {
+            bfield.write_manifest(false)?;
+        }
+        Ok(bfield)
+    }
+
+    /// Creates a `BField` with a single array in "bitset" mode: each value
+    /// is a set of up to `marker_width` category bits (at most the width of
+    /// `V`) stored positionally instead of as a κ-bit marker. Inserting a
+    /// key several times ORs its categories together and `get` returns their
+    /// union, which may include some categories of other keys (with the
+    /// false positive rate) but is never indeterminate. Keys can't be
+    /// masked, so `update` and `mask_or_insert` can only add categories and
+    /// `remove` isn't supported.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_bitset<P>(
+        directory: P,
+        filename: &str,
+        size: usize,
+        n_hashes: u8,
+        marker_width: u8,
+        backing: impl Into<Backing>,
+        other_params: T,
+    ) -> Result<Self, io::Error>
+    where
+        P: AsRef<Path>,
+    {
+        debug_assert!(!filename.is_empty());
+        let backing = backing.into();
+        let member = BFieldMember::create_bitset(
+            directory.as_ref().join(format!("{filename}.0.bfd")),
+            backing,
+            size,
+            n_hashes,
+            marker_width,
+            V::BITS,
+            Some(other_params),
+        )?;
+        let bfield = BField {
+            members: vec![member],
+            read_only: false,
+            saturation: None,
+            journal: None,
+            sidecar: None,
+            prefilter: None,
+            #[cfg(feature = "cache")]
+            cache: None,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+            sorted_writes: 0,
+            deterministic: false,
+            dedup: None,
+            value_type: PhantomData,
+        };
+        if backing == Backing::File {
+            bfield.write_manifest(false)?;
+        }
+        Ok(bfield)
     }
 
     /// Loads the `BField` given the path to the primary array data file (eg the one ending with `0.bfd`).
     pub fn load<P: AsRef<Path>>(main_db_path: P, read_only: bool) -> Result<Self, io::Error> {
+        Self::load_with(main_db_path, &LoadOptions::new().read_only(read_only))
+    }
+
+    /// Loads the `BField` like [`BField::load`], but reads the members into
+    /// memory instead of mapping them, so lookups never wait on page faults.
+    pub fn load_in_memory<P: AsRef<Path>>(main_db_path: P) -> Result<Self, io::Error> {
+        Self::load_with(main_db_path, &LoadOptions::new().in_memory(true))
+    }
+
+    /// Same as [`BField::load`], with the extra settings in `options`.
+    pub fn load_with<P: AsRef<Path>>(
+        main_db_path: P,
+        options: &LoadOptions,
+    ) -> Result<Self, io::Error> {
+        let read_only = options.read_only;
         let mut members = Vec::new();
-        let mut n = 0;
 
-        let main_db_filename = match main_db_path.as_ref().file_name() {
-            Some(p) => p.to_string_lossy(),
-            None => {
+        check_primary_path(main_db_path.as_ref())?;
+        if let Some(marker) = rebuild_marker_path(main_db_path.as_ref()).filter(|p| p.exists()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{:?} exists: a rebuild was interrupted, so the arrays may be a mix of old and new ones",
+                    marker
+                ),
+            ));
+        }
+
+        let manifest = match manifest_path(main_db_path.as_ref()) {
+            Some(path) => Manifest::read(&path)?.map(|m| (path, m)),
+            None => None,
+        };
+        if let Some((manifest_path, manifest)) = manifest {
+            let parent = main_db_path.as_ref().parent().unwrap();
+            for entry in &manifest.members {
+                let path = parent.join(&entry.file);
+                if !path.exists() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("Member {:?} listed in {:?} is missing", path, manifest_path),
+                    ));
+                }
+                let member = options.open_member(&path)?;
+                Manifest::check_member(entry, &member)?;
+                members.push(member);
+            }
+            let extra = member_path(main_db_path.as_ref(), members.len());
+            if extra.exists() {
                 return Err(io::Error::new(
-                    io::ErrorKind::NotFound,
-                    format!("Couldn't get filename from {:?}", main_db_path.as_ref()),
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Found member {:?} which isn't listed in {:?}",
+                        extra, manifest_path
+                    ),
                 ));
             }
-        };
-        assert!(main_db_path.as_ref().parent().is_some());
-        assert!(main_db_filename.ends_with("0.bfd"));
-
-        loop {
-            let member_filename =
-                PathBuf::from(&main_db_filename.replace("0.bfd", &format!("{n}.bfd")));
-            let member_path = main_db_path
-                .as_ref()
-                .parent()
-                .unwrap()
-                .join(member_filename);
-            if !member_path.exists() {
-                break;
+        } else {
+            // no manifest (written before v0.4): probe for members until one is missing
+            loop {
+                let path = member_path(main_db_path.as_ref(), members.len());
+                if !path.exists() {
+                    break;
+                }
+                let member = options.open_member(&path)?;
+                members.push(member);
             }
-            let member = BFieldMember::open(&member_path, read_only)?;
-            members.push(member);
-            n += 1;
         }
 
+        let mut bfield = Self::from_members(members, read_only, main_db_path.as_ref())?;
+        if let Some(path) = sidecar_path(main_db_path.as_ref()) {
+            bfield.sidecar = Sidecar::read(&path)?;
+        }
+        if let Some(path) = prefilter_path(main_db_path.as_ref()) {
+            bfield.prefilter = Prefilter::read(&path)?;
+        }
+        if options.verify_checksums {
+            bfield.verify_checksums()?;
+        }
+        if options.populate {
+            bfield.warm_up()?;
+        }
+        Ok(bfield)
+    }
+
+    /// Assembles a `BField` from members loaded from `path`, checking
+    /// they're usable with `V`.
+    pub(crate) fn from_members(
+        members: Vec<BFieldMember<T>>,
+        read_only: bool,
+        path: &Path,
+    ) -> Result<Self, io::Error> {
         if members.is_empty() {
             return Err(io::Error::new(
                 io::ErrorKind::NotFound,
-                format!("No Bfield found at {:?}", main_db_path.as_ref()),
+                format!("No Bfield found at {:?}", path),
+            ));
+        }
+        if let Some(member) = members
+            .iter()
+            .find(|m| m.is_keyed() && m.hash_secret().is_none())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{:?} is hashed with a secret, which has to be given with LoadOptions::hash_secret",
+                    member.filename
+                ),
+            ));
+        }
+        let value_bits = members[0].params.value_bits;
+        if value_bits > V::BITS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Bfield at {:?} stores {}-bit values, which don't fit in a {}-bit value type",
+                    path,
+                    value_bits,
+                    V::BITS
+                ),
             ));
         }
-        Ok(BField { members, read_only })
+        Ok(BField {
+            members,
+            read_only,
+            saturation: None,
+            journal: None,
+            sidecar: None,
+            prefilter: None,
+            #[cfg(feature = "cache")]
+            cache: None,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+            sorted_writes: 0,
+            deterministic: false,
+            dedup: None,
+            value_type: PhantomData,
+        })
+    }
+
+    pub(crate) fn members(&self) -> &[BFieldMember<T>] {
+        &self.members
+    }
+
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Appends a secondary array of `size` bits with the same parameters as
+    /// the others, stored like the primary array: in a new `.bfd` file (and
+    /// the manifest) if it's stored in one, in memory otherwise.
+    pub(crate) fn add_secondary(&mut self, size: usize) -> Result<(), io::Error> {
+        let primary = &self.members[0];
+        let main_db_path = &primary.filename;
+        let base = main_db_path
+            .file_name()
+            .and_then(|f| f.to_str())
+            .and_then(|f| f.strip_suffix("0.bfd"))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Can't add arrays to the BField at {:?}", main_db_path),
+                )
+            })?;
+        let file = main_db_path.with_file_name(format!("{base}{}.bfd", self.members.len()));
+        let backing = primary.storage().backing();
+        let (_, n_hashes, marker_width, n_marker_bits) = primary.info();
+        let blocked = primary.is_blocked();
+        let mut member = if primary.is_bitset() {
+            BFieldMember::create_bitset(
+                file,
+                backing,
+                size,
+                n_hashes,
+                marker_width,
+                primary.params.value_bits,
+                None,
+            )?
+        } else {
+            BFieldMember::create(
+                file,
+                backing,
+                size,
+                n_hashes,
+                marker_width,
+                n_marker_bits,
+                primary.params.value_bits,
+                None,
+            )?
+        };
+        if blocked {
+            member.use_blocked_layout()?;
+        }
+        member.use_hashing_of(primary, self.members.len())?;
+        self.members.push(member);
+        if backing == Backing::File {
+            self.write_manifest(false)?;
+        }
+        Ok(())
+    }
+
+    /// Opens another, read-only, handle on the same `BField`, e.g. to give
+    /// each worker thread its own. Members stored in `.bfd` files are
+    /// reopened and members of a container or byte buffer share its mapping,
+    /// while in-memory members are copied (so the copy won't see later
+    /// inserts into them).
+    pub fn try_clone(&self) -> Result<Self, io::Error> {
+        let members = self
+            .members
+            .iter()
+            .map(|m| m.try_clone())
+            .collect::<Result<_, _>>()?;
+        Ok(BField {
+            members,
+            read_only: true,
+            saturation: None,
+            journal: None,
+            sidecar: self.sidecar.as_ref().map(Sidecar::duplicate),
+            prefilter: self.prefilter.as_ref().map(Prefilter::duplicate),
+            #[cfg(feature = "cache")]
+            cache: None,
+            #[cfg(feature = "metrics")]
+            metrics: self.metrics.clone(),
+            sorted_writes: 0,
+            deterministic: false,
+            dedup: None,
+            value_type: PhantomData,
+        })
     }
 
     /// Write the current `BField` to disk.
     /// Only useful if you are creating a `BField` in memory.
     pub fn persist_to_disk(self) -> Result<Self, io::Error> {
+        self.persist_to_disk_with_progress(|_| {})
+    }
+
+    /// Same as [`BField::persist_to_disk`], calling `progress` as the
+    /// members are written.
+    pub fn persist_to_disk_with_progress<F: Fn(Progress)>(
+        self,
+        progress: F,
+    ) -> Result<Self, io::Error> {
+        let sizes: Vec<usize> = self.members.iter().map(|m| m.storage().size()).collect();
+        let mut report = Progress::new(&sizes);
         let mut members = Vec::with_capacity(self.members.len());
-        for m in self.members {
-            members.push(m.persist_to_disk()?);
+        for (m, size) in self.members.into_iter().zip(sizes) {
+            let written = report.bytes_written;
+            members.push(m.persist_to_disk_reporting(&|n| {
+                progress(Progress {
+                    bytes_written: written + n as u64,
+                    ..report
+                })
+            })?);
+            report.member_written(size);
+            progress(report);
         }
-        Ok(Self {
+        let bfield = Self {
             members,
             read_only: self.read_only,
+            saturation: self.saturation,
+            journal: self.journal,
+            sidecar: self.sidecar,
+            prefilter: self.prefilter,
+            #[cfg(feature = "cache")]
+            cache: self.cache,
+            #[cfg(feature = "metrics")]
+            metrics: self.metrics,
+            sorted_writes: self.sorted_writes,
+            deterministic: self.deterministic,
+            dedup: self.dedup.clone(),
+            value_type: PhantomData,
+        };
+        bfield.write_manifest(true)?;
+        bfield.write_side_files()?;
+        Ok(bfield)
+    }
+
+    /// Writes the manifest listing the member files (and their checksums if
+    /// `checksums` is set), if the members are stored in `.bfd` files.
+    pub(crate) fn write_manifest(&self, checksums: bool) -> Result<(), io::Error> {
+        match manifest_path(&self.members[0].filename) {
+            Some(path) => Manifest::from_members(&self.members, checksums).write(&path),
+            None => Ok(()),
+        }
+    }
+
+    /// The manifest path, or an error if the members aren't stored in `.bfd` files.
+    fn checked_manifest_path(&self) -> Result<PathBuf, io::Error> {
+        manifest_path(&self.members[0].filename).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Checksums are only recorded for BFields stored in .bfd files",
+            )
         })
     }
 
+    /// Computes the checksum of each member and records them in the manifest,
+    /// along with the number of keys in each member's header.
+    ///
+    /// This is done automatically by `persist_to_disk`; `BField`s built
+    /// directly on disk should call this once all the inserts are done.
+    pub fn update_checksums(&self) -> Result<(), io::Error> {
+        let path = self.checked_manifest_path()?;
+        for member in &self.members {
+            member.write_header()?;
+        }
+        self.write_side_files()?;
+        Manifest::from_members(&self.members, true).write(&path)
+    }
+
+    /// Checks the contents of each member against the checksums recorded in
+    /// the manifest, returning an `InvalidData` error if any of them
+    /// doesn't match (e.g. if a file was corrupted while being copied).
+    pub fn verify_checksums(&self) -> Result<(), io::Error> {
+        let path = self.checked_manifest_path()?;
+        let manifest = Manifest::read(&path)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No manifest found at {:?}", path),
+            )
+        })?;
+        if manifest.members.len() != self.members.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{:?} lists {} members, found {}",
+                    path,
+                    manifest.members.len(),
+                    self.members.len()
+                ),
+            ));
+        }
+        for (entry, member) in manifest.members.iter().zip(&self.members) {
+            Manifest::verify_member(entry, member)?;
+        }
+        Ok(())
+    }
+
+    /// Tells the OS how the members will be accessed (with `madvise`), e.g.
+    /// `AccessPattern::Random` for query workloads or
+    /// `AccessPattern::WillNeed` to warm up the page cache after loading.
+    pub fn advise(&self, pattern: AccessPattern) -> Result<(), io::Error> {
+        for member in &self.members {
+            member.advise(pattern)?;
+        }
+        Ok(())
+    }
+
+    /// Pre-faults every page of the members so the first lookups after
+    /// `load` don't each have to wait for a page to be read from disk. This
+    /// reads the whole `BField` so it can take a while for large ones.
+    pub fn warm_up(&self) -> Result<(), io::Error> {
+        for member in &self.members {
+            member.warm_up()?;
+        }
+        Ok(())
+    }
+
+    /// Opts into backing the members with transparent huge pages
+    /// (`madvise(MADV_HUGEPAGE)`), which reduces TLB misses for lookups in
+    /// large `BField`s. Call it right after `create` or `load`, before the
+    /// pages are touched.
+    ///
+    /// Only supported on Linux, and only effective if transparent huge pages
+    /// are enabled (in `madvise` or `always` mode) for the kind of memory the
+    /// members are in: in-memory `BField`s always qualify, while file-backed
+    /// ones need kernel support for file THPs.
+    pub fn use_huge_pages(&self) -> Result<(), io::Error> {
+        for member in &self.members {
+            member.use_huge_pages()?;
+        }
+        Ok(())
+    }
+
     /// Returns `(n_hashes, marker_width, n_marker_bits, Vec<size of each member>)`.
+    #[deprecated(since = "0.4.0", note = "use `build_parameters` instead")]
     pub fn build_params(&self) -> (u8, u8, u8, Vec<usize>) {
+        let params = self.build_parameters();
+        (
+            params.n_hashes,
+            params.marker_width,
+            params.n_marker_bits,
+            params.sizes,
+        )
+    }
+
+    /// Returns the parameters the `BField` was built with.
+    pub fn build_parameters(&self) -> BuildParams {
         let (_, n_hashes, marker_width, n_marker_bits) = self.members[0].info();
-        let sizes = self.members.iter().map(|i| i.info().0).collect();
-        (n_hashes, marker_width, n_marker_bits, sizes)
+        BuildParams {
+            n_hashes,
+            marker_width,
+            n_marker_bits,
+            sizes: self.members.iter().map(|m| m.info().0).collect(),
+        }
+    }
+
+    /// Returns the largest value that can be stored in this `BField`, i.e.
+    /// `choose(marker_width, n_marker_bits) - 1` (or the largest `V` if that's smaller).
+    pub fn max_value(&self) -> V {
+        let max_value = self.members[0]
+            .max_value()
+            .min(u64::MAX >> (64 - u32::from(V::BITS)));
+        V::from_u64(max_value).expect("max value was capped to the range of V")
     }
 
     /// Returns the params given at build time to the `BField` arrays.
@@ -159,8 +654,38 @@ impl<T: Clone + DeserializeOwned + Serialize> BField<T> {
         &self.members[0].params.other
     }
 
+    /// Tags the params with `version` in the headers, so that a later
+    /// version of the application can convert them when loading (see
+    /// [`LoadOptions::upgrade_params`]). The params are stored as JSON, so
+    /// only changes other than adding fields with `#[serde(default)]` or
+    /// removing fields need a new version.
+    pub fn set_params_version(&mut self, version: u32) -> Result<(), io::Error> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Can't change the params of read_only bfields",
+            ));
+        }
+        for member in &mut self.members {
+            member.params.params_version = version;
+            member.write_header()?;
+        }
+        Ok(())
+    }
+
+    /// The version the params are tagged with, see
+    /// [`BField::set_params_version`] (0 for `BField`s written before
+    /// v0.4).
+    pub fn params_version(&self) -> u32 {
+        self.members[0].params.params_version
+    }
+
     /// ⚠️ Method for setting parameters without actually updating any files on disk. **Only useful for supporting legacy file formats
     /// in which these parameters are not saved.**
+    #[deprecated(
+        since = "0.4.0",
+        note = "load legacy files with `legacy::load_v01` or `legacy::load_v02` instead"
+    )]
     pub fn mock_params(&mut self, params: T) {
         self.members[0].params.other = Some(params);
     }
@@ -173,67 +698,2993 @@ impl<T: Clone + DeserializeOwned + Serialize> BField<T> {
     /// set values return an indeterminate result in the primary array,
     /// then causing fallback to the secondary arrays where they were never
     /// inserted (and returning a false negative).
-    pub fn force_insert(&self, key: &[u8], value: BFieldVal) {
+    ///
+    /// Panics if `value` is too large to be encoded with the `BField`'s
+    /// `marker_width` and `n_marker_bits` (i.e. `value >= choose(ν, κ)`).
+    #[deprecated(since = "0.4.0", note = "use `mask_or_insert` instead")]
+    pub fn force_insert(&self, key: &[u8], value: V) {
         debug_assert!(!self.read_only, "Can't insert into read_only bfields");
-        for secondary in &self.members {
+        let value = value.into_u64();
+        for (ix, secondary) in self.members.iter().enumerate() {
             if secondary.mask_or_insert(key, value) {
+                self.add_to_prefilter(key);
+                self.record_insert(ix);
                 break;
             }
         }
     }
 
+    /// Inserts `key` with `value` into a `BField` that's already built,
+    /// guaranteeing `get` returns `value` for it afterwards (unlike
+    /// `force_insert`).
+    ///
+    /// The members are walked in lookup order: wherever `key` resolves to
+    /// another value it's masked (see [`BField::remove`], with the same
+    /// costs), and it's inserted into the first member where it's absent,
+    /// going on to the next ones if its new marker there overlaps other
+    /// keys' bits, and adding a secondary array the size of the last one if
+    /// there's none left. On bitset `BField`s, `value`'s categories are added
+    /// to the key's instead.
+    ///
+    /// Panics if `value` is too large to be encoded with the `BField`'s
+    /// `marker_width` and `n_marker_bits` (i.e. `value >= choose(ν, κ)`).
+    pub fn mask_or_insert<K: BFieldKey>(&mut self, key: K, value: V) -> Result<(), io::Error> {
+        key.with_bytes(|key| {
+            if self.read_only {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Can't insert into read_only bfields",
+                ));
+            }
+            let value = value.into_u64();
+            self.log(JournalOp::Insert, key, value)?;
+            self.place(key, value)
+        })
+    }
+
+    /// Same as [`BField::mask_or_insert`], except that if `key` is already
+    /// found with another value, it's replaced by `merge(old, value)`
+    /// rather than by `value`, e.g. the lowest common ancestor of two taxa in
+    /// a taxonomic index. Returns the value now stored for `key`.
+    ///
+    /// Panics if the value stored is too large to be encoded with the
+    /// `BField`'s `marker_width` and `n_marker_bits`.
+    pub fn insert_with_merge<F>(&mut self, key: &[u8], value: V, merge: F) -> Result<V, io::Error>
+    where
+        F: FnOnce(V, V) -> V,
+    {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Can't insert into read_only bfields",
+            ));
+        }
+        let old = match self.lookup(key) {
+            BFieldLookup::Some(old) => V::from_u64(old),
+            _ => None,
+        };
+        let merged = match old {
+            Some(old) if old.into_u64() == value.into_u64() => return Ok(old),
+            Some(old) => merge(old, value),
+            None => value,
+        };
+        if old.map(V::into_u64) != Some(merged.into_u64()) {
+            self.log(JournalOp::Insert, key, merged.into_u64())?;
+            self.place(key, merged.into_u64())?;
+        }
+        Ok(merged)
+    }
+
+    /// Records a change in the journal, if there's one.
+    fn log(&self, op: JournalOp, key: &[u8], value: u64) -> Result<(), io::Error> {
+        match &self.journal {
+            Some(journal) => journal.append(op, key, value),
+            None => Ok(()),
+        }
+    }
+
+    /// Sets (or with `None`, removes) the journal recording the changes made
+    /// by `mask_or_insert`, `update` and `remove`.
+    pub fn set_journal(&mut self, journal: Option<Journal>) {
+        self.journal = journal;
+    }
+
+    /// Removes `key` if it's currently found with `value`, returning whether
+    /// it was.
+    ///
+    /// The key is masked (made indeterminate by setting an extra bit of its
+    /// marker) in the member it resolves in, and in any later member where it
+    /// then resolves, so lookups fall through to a member where it's absent
+    /// (or to the end) and return `None`. Each mask sets about `n_hashes`
+    /// extra bits in its member, which can make other keys indeterminate
+    /// there: as they weren't necessarily inserted into the next members,
+    /// they may not be found anymore. Removing keys raises the error rates
+    /// about as much as inserting as many new keys would.
+    ///
+    /// Fails on bitset `BField`s (see [`BField::create_bitset`]), whose
+    /// keys can't be masked.
+    pub fn remove<K: BFieldKey>(&self, key: K, value: V) -> Result<bool, io::Error> {
+        key.with_bytes(|key| {
+            if self.read_only {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Can't remove from read_only bfields",
+                ));
+            }
+            if self.members[0].is_bitset() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Can't remove keys from bitset bfields",
+                ));
+            }
+            let value = value.into_u64();
+            if self.lookup(key) != BFieldLookup::Some(value) {
+                return Ok(false);
+            }
+            self.log(JournalOp::Remove, key, value)?;
+            for (ix, member) in self.members.iter().enumerate() {
+                match member.get(key) {
+                    BFieldLookup::None => break,
+                    BFieldLookup::Indeterminate => continue,
+                    BFieldLookup::Some(_) => {
+                        member.mask(key);
+                        self.record_insert(ix);
+                    }
+                }
+            }
+            if let Some(sidecar) = &self.sidecar {
+                sidecar.remove(key, self.secret().as_ref());
+            }
+            Ok(true)
+        })
+    }
+
+    /// Changes the value of `key`, if it's currently found, to `new_value`,
+    /// returning whether it was found.
+    ///
+    /// The key is masked (see [`BField::remove`], with the same costs) in the
+    /// member it resolves in and inserted with `new_value` into the next one
+    /// where it's absent, adding a secondary array the size of the last one
+    /// if there's none left. On bitset `BField`s, `new_value`'s categories
+    /// are added to the key's instead.
+    ///
+    /// Panics if `new_value` is too large to be encoded with the `BField`'s
+    /// `marker_width` and `n_marker_bits` (i.e. `value >= choose(ν, κ)`).
+    pub fn update<K: BFieldKey>(&mut self, key: K, new_value: V) -> Result<bool, io::Error> {
+        key.with_bytes(|key| {
+            if self.read_only {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Can't update read_only bfields",
+                ));
+            }
+            if !matches!(self.lookup(key), BFieldLookup::Some(_)) {
+                return Ok(false);
+            }
+            let value = new_value.into_u64();
+            self.log(JournalOp::Update, key, value)?;
+            self.place(key, value)?;
+            Ok(true)
+        })
+    }
+
+    /// Walks the members, masking `key` wherever it resolves to another value,
+    /// until it resolves to `value` (inserting it in the first member where
+    /// it's absent, and adding members as needed). Bitset `BField`s can't
+    /// mask keys, so `value`'s categories are ORed into their only member.
+    fn place(&mut self, key: &[u8], value: u64) -> Result<(), io::Error> {
+        if let Some(sidecar) = &self.sidecar {
+            sidecar.remove(key, self.secret().as_ref());
+        }
+        self.add_to_prefilter(key);
+        if self.members[0].is_bitset() {
+            self.members[0].insert(key, value);
+            self.record_insert(0);
+            return Ok(());
+        }
+        let mut ix = 0;
+        loop {
+            if ix == self.members.len() {
+                let size = self.members[ix - 1].storage().size();
+                self.add_secondary(size)?;
+            }
+            let member = &self.members[ix];
+            match member.get(key) {
+                BFieldLookup::Some(found) if found == value => return Ok(()),
+                BFieldLookup::Some(_) => {
+                    member.mask(key);
+                    self.record_insert(ix);
+                }
+                BFieldLookup::Indeterminate => {}
+                BFieldLookup::None => {
+                    member.insert(key, value);
+                    self.record_insert(ix);
+                    // the new marker can overlap bits set by other keys
+                    if member.get(key) == BFieldLookup::Some(value) {
+                        return Ok(());
+                    }
+                }
+            }
+            ix += 1;
+        }
+    }
+
     /// Insert the given key/value at the given pass (1-indexed `BField` array/member).
     /// Returns whether the value was inserted during this call, i.e., will return `false` if
-    /// the value was already present.
-    pub fn insert(&self, key: &[u8], value: BFieldVal, pass: usize) -> bool {
+    /// the value was already present. The key can be bytes, a string or an
+    /// integer, see [`BFieldKey`].
+    ///
+    /// Panics if `value` is too large to be encoded with the `BField`'s
+    /// `marker_width` and `n_marker_bits` (i.e. `value >= choose(ν, κ)`),
+    /// see [`BField::try_insert`].
+    ///
+    /// Bits are set with atomic ORs, so several threads can insert into the
+    /// same `BField` at once. All inserts of a pass should still be done
+    /// before starting the next one.
+    pub fn insert<K: BFieldKey>(&self, key: K, value: V, pass: usize) -> bool {
         debug_assert!(!self.read_only, "Can't insert into read_only bfields");
         debug_assert!(
             pass < self.members.len(),
             "Can't have more passes than bfield members"
         );
-        if pass > 0 {
-            for secondary in self.members[..pass].iter() {
-                match secondary.get(key) {
+        key.with_bytes(|key| {
+            if pass > 0 {
+                for secondary in self.members[..pass].iter() {
+                    match secondary.get(key) {
+                        BFieldLookup::Indeterminate => continue,
+                        _ => return false,
+                    }
+                }
+            }
+            self.members[pass].insert(key, value.into_u64());
+            self.add_to_prefilter(key);
+            self.record_insert(pass);
+            true
+        })
+    }
+
+    /// Same as [`BField::insert`], but returns an error instead of panicking
+    /// if `value` can't be encoded with the `BField`'s `marker_width` and
+    /// `n_marker_bits`, e.g. for values coming from untrusted input.
+    pub fn try_insert<K: BFieldKey>(
+        &self,
+        key: K,
+        value: V,
+        pass: usize,
+    ) -> Result<bool, RankError> {
+        self.members[pass].try_check_value(value.into_u64())?;
+        Ok(self.insert(key, value, pass))
+    }
+
+    /// Inserts `key` with `value` without tracking build passes: it goes
+    /// into the first member where it isn't indeterminate, then into the
+    /// next ones for as long as it's indeterminate in the member it was just
+    /// inserted into. Returns the member that determines its value, or
+    /// `None` if it's still indeterminate in the last one.
+    ///
+    /// Unlike the passes of `build_from_iter`, this doesn't go back over the
+    /// keys inserted before: those that later inserts make indeterminate in
+    /// a member were never inserted into the next one, and aren't found
+    /// anymore. Prefer `build_from_iter` when all the keys are known
+    /// upfront.
+    ///
+    /// Panics if `value` is too large to be encoded with the `BField`'s
+    /// `marker_width` and `n_marker_bits` (i.e. `value >= choose(ν, κ)`).
+    pub fn insert_auto<K: BFieldKey>(&self, key: K, value: V) -> Option<usize> {
+        key.with_bytes(|key| {
+            debug_assert!(!self.read_only, "Can't insert into read_only bfields");
+            let value = value.into_u64();
+            self.add_to_prefilter(key);
+            for (pass, member) in self.members.iter().enumerate() {
+                // inserting can't make an indeterminate key determinate
+                if member.get(key) == BFieldLookup::Indeterminate {
+                    continue;
+                }
+                member.insert(key, value);
+                self.record_insert(pass);
+                if member.get(key) != BFieldLookup::Indeterminate {
+                    return Some(pass);
+                }
+            }
+            None
+        })
+    }
+
+    /// Returns the value already stored for `key` if it resolves in the
+    /// members up to `pass`, and otherwise inserts it with `value` at `pass`
+    /// like [`BField::insert`] (returning `None`), hashing the key and
+    /// computing its positions only once, e.g. to deduplicate keys while
+    /// ingesting them.
+    ///
+    /// As with `get`, `None` is also returned for keys an earlier member says
+    /// are absent (which `insert` wouldn't insert either) and for stored
+    /// values that don't fit in `V`.
+    pub fn get_or_insert<K: BFieldKey>(&self, key: K, value: V, pass: usize) -> Option<V> {
+        key.with_bytes(|key| {
+            debug_assert!(!self.read_only, "Can't insert into read_only bfields");
+            debug_assert!(
+                pass < self.members.len(),
+                "Can't have more passes than bfield members"
+            );
+            let hash = self.hash(key);
+            for (ix, member) in self.members[..pass].iter().enumerate() {
+                match member.get_hashed(self.member_hash(ix, key, hash)) {
                     BFieldLookup::Indeterminate => continue,
-                    _ => return false,
+                    BFieldLookup::Some(value) => return V::from_u64(value),
+                    BFieldLookup::None => return None,
                 }
             }
-        }
-        self.members[pass].insert(key, value);
-        true
+            let hash = self.member_hash(pass, key, hash);
+            match self.members[pass].get_or_insert(hash, value.into_u64()) {
+                Some(existing) => V::from_u64(existing),
+                None => {
+                    self.add_to_prefilter(key);
+                    self.record_insert(pass);
+                    None
+                }
+            }
+        })
     }
 
-    /// Returns the value of the given key if found, `None` otherwise.
-    /// The current implementation also returns `None` for indeterminate values.
-    pub fn get(&self, key: &[u8]) -> Option<BFieldVal> {
-        for secondary in self.members.iter() {
-            match secondary.get(key) {
-                BFieldLookup::Indeterminate => continue,
-                BFieldLookup::Some(value) => return Some(value),
-                BFieldLookup::None => return None,
+    /// Inserts all the `(key, value)` pairs of `pairs`, going through them
+    /// once per member: keys that are indeterminate after the first pass go
+    /// into the first secondary array, and so on. `pairs` is cloned for
+    /// each pass, so it should be cheap to clone (e.g. an iterator over a
+    /// slice, or one reading a file from the start).
+    ///
+    /// `progress` is called every 100,000 keys (or every batch of sorted
+    /// writes if larger, see [`BField::sort_writes`]) and at the end of each
+    /// pass, with `members_completed` counting the passes done.
+    pub fn build_from_iter<I, K, F>(&self, pairs: I, progress: F) -> Result<(), io::Error>
+    where
+        I: IntoIterator<Item = (K, V)> + Clone,
+        K: AsRef<[u8]>,
+        F: Fn(Progress),
+    {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Can't insert into read_only bfields",
+            ));
+        }
+        let mut report = Progress {
+            total_members: self.members.len(),
+            ..Progress::default()
+        };
+        let duplicates = match &self.dedup {
+            Some(options) => {
+                duplicate_positions(pairs.clone().into_iter().map(|(key, _)| key), options)?
+            }
+            None => Vec::new(),
+        };
+        let batch_size = self.batch_size();
+        let mut batch = Vec::with_capacity(batch_size);
+        for pass in 0..self.members.len() {
+            let mut pairs = skip_positions(pairs.clone().into_iter(), &duplicates);
+            loop {
+                batch.clear();
+                batch.extend(pairs.by_ref().take(batch_size));
+                if batch.is_empty() {
+                    break;
+                }
+                self.insert_batch(&batch, pass)?;
+                let n_inserted = report.keys_inserted;
+                report.keys_inserted += batch.len() as u64;
+                if report.keys_inserted / 100_000 > n_inserted / 100_000 {
+                    progress(report);
+                }
             }
+            report.members_completed += 1;
+            progress(report);
         }
-        // TODO: better value for totally indeterminate? panic?
-        // or return a Result<Option<BFieldVal>, ...> instead?
-        None
+        self.finish_build(skip_positions(pairs.into_iter(), &duplicates))
     }
 
-    /// Get the info of each secondary array (`BFieldMember`) in the `BField`.
-    /// Returns `Vec<(size, n_hashes, marker_width, n_marker_bits)>`.
-    pub fn info(&self) -> Vec<(usize, u8, u8, u8)> {
-        self.members.iter().map(|m| m.info()).collect()
+    /// Inserts `pairs` at `pass` like [`BField::insert`], encoding their
+    /// values together. Nothing is inserted if any of the values can't be
+    /// encoded.
+    pub(crate) fn insert_batch<K: AsRef<[u8]>>(
+        &self,
+        pairs: &[(K, V)],
+        pass: usize,
+    ) -> Result<(), RankError> {
+        let (keys, values): (Vec<&[u8]>, Vec<u64>) = pairs
+            .iter()
+            .filter(|(key, _)| self.reaches(key.as_ref(), pass))
+            .map(|(key, value)| (key.as_ref(), value.into_u64()))
+            .unzip();
+        self.insert_keys(&keys, &values, pass)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Number of keys builds insert at once.
+    pub(crate) fn batch_size(&self) -> usize {
+        self.sorted_writes.max(BUILD_BATCH_SIZE)
+    }
 
-    #[test]
+    /// Whether `key` is indeterminate in every member before `pass`, i.e.
+    /// gets inserted at `pass`.
+    pub(crate) fn reaches(&self, key: &[u8], pass: usize) -> bool {
+        self.members[..pass]
+            .iter()
+            .all(|member| member.get(key) == BFieldLookup::Indeterminate)
+    }
+
+    /// Inserts `keys` with `values` at `pass`, without checking they're
+    /// indeterminate in the earlier members. Nothing is inserted if any of
+    /// the values can't be encoded.
+    pub(crate) fn insert_keys(
+        &self,
+        keys: &[&[u8]],
+        values: &[u64],
+        pass: usize,
+    ) -> Result<(), RankError> {
+        for &value in values {
+            self.members[pass].try_check_value(value)?;
+        }
+        if self.sorted_writes > 0 {
+            self.members[pass].insert_many_sorted(keys, values);
+        } else {
+            self.members[pass].insert_many(keys, values);
+        }
+        for key in keys {
+            self.add_to_prefilter(key);
+            self.record_insert(pass);
+        }
+        Ok(())
+    }
+
+    /// Makes `build_from_iter` (and the builds based on it) insert each key
+    /// only once, with the value it comes with first, skipping the keys it
+    /// has already seen instead of setting bits again for them (and making
+    /// the keys repeated with another value indeterminate). This goes over
+    /// the keys once more before the first pass, to find the repeated ones
+    /// by their 128-bit hashes, spilling these to disk for large inputs (see
+    /// [`DedupOptions`]). `None` turns it back off.
+    pub fn dedup_keys(&mut self, options: Option<DedupOptions>) {
+        self.dedup = options;
+    }
+
+    /// Makes `build_from_iter` (and the builds based on it) buffer the writes
+    /// of `n_keys` keys at a time and apply them to each array in ascending
+    /// order of position, so disk-backed arrays are written mostly front to
+    /// back rather than at random, which is much faster on spinning disks
+    /// and network storage. Buffering takes about `16 * n_hashes` bytes per
+    /// key; `0` goes back to writing each key as it comes.
+    pub fn sort_writes(&mut self, n_keys: usize) {
+        self.sorted_writes = n_keys;
+    }
+
+    /// Makes builds reproducible: given the same parameters and the same
+    /// `(key, value)` pairs in the same order, two builds produce
+    /// byte-identical `.bfd` files (which [`BField::digest`] can check).
+    ///
+    /// Bits are only ever ORed into the arrays, so the order of `insert`s
+    /// (even from several threads) within a pass never changes them, and the
+    /// headers are encoded field by field without any padding. What this
+    /// mode changes is:
+    /// - `use_random_hash_seed` fails, as the seed would differ every time
+    /// - `build_from_iter` (and the builds based on it) record the number of
+    ///   keys in the headers of `.bfd` files when they finish, instead of
+    ///   leaving that to `update_checksums` or `persist_to_disk`
+    /// - the creation time isn't recorded in the [`Provenance`], which
+    ///   means this has to be called before inserting any key (if it was
+    ///   recorded)
+    ///
+    /// Calls whose result depends on the order keys come in (`mask_or_insert`,
+    /// `insert_with_merge`, `update`, `get_or_insert` and inserting the same
+    /// key twice with different values) still need a fixed order.
+    pub fn set_deterministic(&mut self, deterministic: bool) -> Result<(), io::Error> {
+        if deterministic {
+            self.change_provenance(|provenance| provenance.created_at = None)?;
+        }
+        self.deterministic = deterministic;
+        Ok(())
+    }
+
+    /// Where the `BField` comes from: when and how it was created, and what
+    /// was declared with [`BField::set_build_label`] and
+    /// [`BField::declare_n_elements`]. `None` for `BField`s written before
+    /// v0.4 (or created with `create_bitset`).
+    pub fn provenance(&self) -> Option<&Provenance> {
+        self.members[0].params.provenance.as_ref()
+    }
+
+    /// Records a free-form `label` in the provenance of the `BField`, e.g.
+    /// the name and release of the database. Has to be called before
+    /// inserting any key, as the header of the primary array is written
+    /// again.
+    pub fn set_build_label(&mut self, label: &str) -> Result<(), io::Error> {
+        self.change_provenance(|provenance| provenance.label = Some(label.to_string()))
+    }
+
+    /// Records the number of elements the `BField` is built for in its
+    /// provenance. Has to be called before inserting any key, as the header
+    /// of the primary array is written again.
+    pub fn declare_n_elements(&mut self, n_elements: u64) -> Result<(), io::Error> {
+        self.change_provenance(|provenance| provenance.n_elements = Some(n_elements))
+    }
+
+    /// Applies `change` to the provenance, if there's one and it changes.
+    fn change_provenance<F: FnOnce(&mut Provenance)>(
+        &mut self,
+        change: F,
+    ) -> Result<(), io::Error> {
+        let provenance = match self.provenance() {
+            Some(provenance) => provenance,
+            None => return Ok(()),
+        };
+        let mut changed = provenance.clone();
+        change(&mut changed);
+        if &changed == provenance {
+            return Ok(());
+        }
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Can't change the provenance of read_only bfields",
+            ));
+        }
+        self.members[0].set_provenance(changed)
+    }
+
+    /// A 64-bit xxh3 digest of the contents of every member (header, number
+    /// of keys included, and bit array), e.g. to check that two builds from
+    /// the same inputs were identical without comparing their files (see
+    /// [`BField::set_deterministic`]). The side files (sidecar, prefilter and
+    /// manifest) aren't included.
+    pub fn digest(&self) -> u64 {
+        let mut hasher = Xxh3::new();
+        for member in &self.members {
+            hasher.update(&member.digest().to_be_bytes());
+        }
+        hasher.digest()
+    }
+
+    /// Hashes keys with `secret` (using SipHash) instead of the unkeyed
+    /// MurmurHash3, so clients that don't know it can't construct keys whose
+    /// markers collide to saturate parts of the arrays, e.g. for services
+    /// inserting or looking up untrusted keys. The sidecar, the prefilter
+    /// and the lookup cache hash keys with it too. The secret isn't stored: it
+    /// has to be given again with [`LoadOptions::hash_secret`] to load the
+    /// `BField`, and `BFieldView` can't read it.
+    ///
+    /// Can only be called before any key is inserted.
+    pub fn use_keyed_hashing(&mut self, secret: HashSecret) -> Result<(), io::Error> {
+        self.change_hashing(|_, member| member.use_keyed_hashing(secret))
+    }
+
+    /// The secret the members hash keys with, if any.
+    #[inline]
+    fn secret(&self) -> Option<HashSecret> {
+        self.members[0].hash_secret()
+    }
+
+    /// Hashes keys with `seed` instead of 0, so `BField`s built with
+    /// different seeds don't share their collisions (e.g. to build again
+    /// with another seed after an unlucky build left too many keys
+    /// indeterminate). The seed is recorded in the headers, so it's used
+    /// again on load (and by `BFieldView`); 0 is the default hashing.
+    ///
+    /// The primary array uses `seed` and each secondary one a seed derived
+    /// from it, so the markers of a key are placed independently in each
+    /// array: a key that's indeterminate in one array isn't more likely to
+    /// be in the next one (as it is when they share their hashing).
+    ///
+    /// Can only be called before any key is inserted.
+    pub fn use_hash_seed(&mut self, seed: u64) -> Result<(), io::Error> {
+        self.change_hashing(|ix, member| member.use_hash_seed(member_seed(seed, ix)))
+    }
+
+    /// Same as [`BField::use_hash_seed`] with a random seed, which is
+    /// returned.
+    pub fn use_random_hash_seed(&mut self) -> Result<u64, io::Error> {
+        if self.deterministic {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can't use a random hash seed in deterministic builds",
+            ));
+        }
+        // `HashMap`'s hasher is keyed randomly each time, which saves
+        // depending on a random number generator
+        let seed = RandomState::new().build_hasher().finish();
+        self.use_hash_seed(seed)?;
+        Ok(seed)
+    }
+
+    /// The seed keys are hashed with, see [`BField::use_hash_seed`].
+    pub fn hash_seed(&self) -> u64 {
+        self.members[0].hash_seed()
+    }
+
+    /// Applies `change` to the hashing of every (empty) member, given with
+    /// its index, checking they can all be changed first.
+    fn change_hashing<F>(&mut self, change: F) -> Result<(), io::Error>
+    where
+        F: Fn(usize, &mut BFieldMember<T>) -> Result<(), io::Error>,
+    {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Can't change the hashing of read_only bfields",
+            ));
+        }
+        for member in &self.members {
+            member.check_hashing_change()?;
+        }
+        for (ix, member) in self.members.iter_mut().enumerate() {
+            change(ix, member)?;
+        }
+        Ok(())
+    }
+
+    /// Confines the markers of each key to a single 512-bit block (a cache
+    /// line) of each array, picked by the key's hash, instead of spreading
+    /// them over the whole array. Lookups then touch at most two cache lines
+    /// per array instead of one per hash, at the cost of a slightly higher
+    /// false positive rate (as some blocks fill up faster than others).
+    ///
+    /// Can only be called before any key is inserted; the layout is recorded
+    /// in the arrays' headers, so `load` and `BFieldView` read them correctly.
+    pub fn use_blocked_layout(&mut self) -> Result<(), io::Error> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Can't change the layout of read_only bfields",
+            ));
+        }
+        // so the layout isn't changed for only some of the arrays
+        for member in &self.members {
+            member.check_blocked_layout()?;
+        }
+        for member in &mut self.members {
+            member.use_blocked_layout()?;
+        }
+        Ok(())
+    }
+
+    /// Keeps the keys that are still indeterminate in every member in an
+    /// exact map next to the members (`{filename}.sidecar.bin`), filled at
+    /// the end of `build_from_iter` and `build_scalable`, so `get` still finds
+    /// them. Best enabled before the build, on a `BField` whose last members
+    /// are already sized to leave very few keys out.
+    pub fn enable_sidecar(&mut self) {
+        if self.sidecar.is_none() {
+            self.sidecar = Some(Sidecar::default());
+        }
+    }
+
+    /// The number of keys in the sidecar map, or `None` if it isn't enabled
+    /// (see [`BField::enable_sidecar`]).
+    pub fn n_sidecar_keys(&self) -> Option<usize> {
+        self.sidecar.as_ref().map(Sidecar::len)
+    }
+
+    /// Ends a build from `pairs`: adds the keys that are indeterminate in
+    /// every member to the sidecar (if enabled), and writes it and the
+    /// prefilter out if the members are on disk.
+    pub(crate) fn finish_build<I, K>(&self, pairs: I) -> Result<(), io::Error>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+    {
+        self.fill_sidecar(pairs);
+        self.write_build_files()
+    }
+
+    /// Adds the keys of `pairs` that are indeterminate in every member to
+    /// the sidecar, if enabled.
+    pub(crate) fn fill_sidecar<I, K>(&self, pairs: I)
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+    {
+        if let Some(sidecar) = &self.sidecar {
+            for (key, value) in pairs {
+                if self.lookup(key.as_ref()) == BFieldLookup::Indeterminate {
+                    sidecar.insert(key.as_ref(), self.secret().as_ref(), value.into_u64());
+                }
+            }
+        }
+    }
+
+    /// Writes out what a build changes besides the arrays (deterministic
+    /// headers, sidecar and prefilter) if the members are on disk.
+    pub(crate) fn write_build_files(&self) -> Result<(), io::Error> {
+        if self.members[0].storage().is_file() {
+            if self.deterministic {
+                for member in &self.members {
+                    member.write_header()?;
+                }
+            }
+            self.write_side_files()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the sidecar and prefilter, if enabled, next to the members.
+    fn write_side_files(&self) -> Result<(), io::Error> {
+        let main_db_path = &self.members[0].filename;
+        if let (Some(sidecar), Some(path)) = (&self.sidecar, sidecar_path(main_db_path)) {
+            sidecar.write(&path)?;
+        }
+        if let (Some(prefilter), Some(path)) = (&self.prefilter, prefilter_path(main_db_path)) {
+            prefilter.write(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Caches the results of `get` for the `capacity` most recently looked
+    /// up keys (`0` removes the cache), for query workloads where a few keys
+    /// come up very often. Only read-only `BField`s can cache lookups, as
+    /// inserts would make cached results stale; others get an
+    /// `InvalidInput` error.
+    #[cfg(feature = "cache")]
+    pub fn enable_cache(&mut self, capacity: usize) -> Result<(), io::Error> {
+        if !self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Only read_only bfields can cache lookups",
+            ));
+        }
+        self.cache = if capacity == 0 {
+            None
+        } else {
+            Some(LookupCache::new(capacity))
+        };
+        Ok(())
+    }
+
+    /// Adds a Bloom filter over the inserted keys, checked before the
+    /// members by `get` so most absent keys are rejected without probing
+    /// them, for workloads where most queried keys are absent. It's sized for
+    /// `n_keys` keys with a `false_positive_rate` (e.g. 0.01 takes about 10
+    /// bits per key) and stored as `{filename}.prefilter.bin` by
+    /// `build_from_iter`, `persist_to_disk` and `update_checksums`.
+    ///
+    /// Returns an `InvalidInput` error if keys were already inserted, as they
+    /// wouldn't be found anymore.
+    pub fn enable_prefilter(
+        &mut self,
+        n_keys: usize,
+        false_positive_rate: f64,
+    ) -> Result<(), io::Error> {
+        if self.members.iter().any(|m| m.n_keys() > 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The prefilter must be enabled before inserting any key",
+            ));
+        }
+        self.prefilter = Some(Prefilter::new(n_keys, false_positive_rate));
+        Ok(())
+    }
+
+    #[inline]
+    fn add_to_prefilter(&self, key: &[u8]) {
+        if let Some(prefilter) = &self.prefilter {
+            prefilter.insert(key, self.secret().as_ref());
+        }
+    }
+
+    /// Merges `other`, built with the same parameters (e.g. from another
+    /// batch of keys), into this `BField` by ORing their bit arrays together.
+    ///
+    /// The result holds the keys of both, but with the error rates of a
+    /// `BField` holding all of them: its arrays are about as full as if all
+    /// the keys had been inserted into it. Keys inserted into both with
+    /// different values become indeterminate. As each secondary array only
+    /// holds the keys that were indeterminate in its own `BField`, keys that
+    /// only become indeterminate in the merged primary array may not be
+    /// found anymore: merging works best before the secondary passes (i.e.
+    /// merging primary arrays and then running the secondary passes over all
+    /// the keys), or with generous array sizes.
+    pub fn merge(&mut self, other: &BField<T, V>) -> Result<(), io::Error> {
+        self.check_mergeable(other)?;
+        for (member, other_member) in self.members.iter().zip(&other.members) {
+            member.merge(other_member);
+        }
+        if let (Some(prefilter), Some(other_prefilter)) = (&self.prefilter, &other.prefilter) {
+            prefilter.or_with(other_prefilter);
+        }
+        Ok(())
+    }
+
+    fn check_mergeable(&self, other: &BField<T, V>) -> Result<(), io::Error> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Can't merge into read_only bfields",
+            ));
+        }
+        let (ours, theirs) = (self.member_info(), other.member_info());
+        if ours != theirs {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Can only merge BFields with the same parameters: {:?} vs {:?}",
+                    ours, theirs
+                ),
+            ));
+        }
+        self.check_same_hashing(other)?;
+        let same_prefilter = match (&self.prefilter, &other.prefilter) {
+            (Some(ours), Some(theirs)) => ours.same_shape(theirs),
+            (None, _) => true,
+            (Some(_), None) => false,
+        };
+        if !same_prefilter {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Both BFields need prefilters of the same size to be merged",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the members of `other` hash keys and lay out their markers like
+    /// ours, so their arrays can be combined (or compared) bit by bit.
+    pub(crate) fn check_same_hashing(&self, other: &BField<T, V>) -> Result<(), io::Error> {
+        for (ix, (ours, theirs)) in self.members.iter().zip(&other.members).enumerate() {
+            let mismatch = if ours.hash_seed() != theirs.hash_seed() {
+                Some("hashed with different seeds")
+            } else if ours.params.secret_check != theirs.params.secret_check {
+                Some("hashed with different secrets")
+            } else if ours.is_blocked() != theirs.is_blocked() {
+                Some("laid out differently (blocked or not)")
+            } else if ours.is_bitset() != theirs.is_bitset() {
+                Some("a bitset array in only one of them")
+            } else {
+                None
+            };
+            if let Some(mismatch) = mismatch {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Array {} of both BFields is {}", ix, mismatch),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Same as [`BField::merge`], but first estimates the indeterminacy rate
+    /// of the result (as [`BField::stats`] does) and leaves this `BField`
+    /// unchanged, returning an `InvalidData` error, if it would be over
+    /// `max_indeterminacy`. Returns how many bits of each array were newly
+    /// set by the union, and the estimated indeterminacy rates before and
+    /// after it.
+    pub fn union_with(
+        &mut self,
+        other: &BField<T, V>,
+        max_indeterminacy: Option<f64>,
+    ) -> Result<UnionReport, io::Error> {
+        self.check_mergeable(other)?;
+        let params = self.build_parameters();
+        let mut newly_set = Vec::with_capacity(self.members.len());
+        let mut indeterminacy_before = 1.0;
+        let mut indeterminacy_after = 1.0;
+        for (member, other_member) in self.members.iter().zip(&other.members) {
+            let storage = member.storage();
+            let (n_set, n_new) = storage.count_union(other_member.storage());
+            let size = storage.size().max(1) as f64;
+            indeterminacy_before *= indeterminacy_rate_at(&params, (n_set - n_new) as f64 / size);
+            indeterminacy_after *= indeterminacy_rate_at(&params, n_set as f64 / size);
+            newly_set.push(n_new);
+        }
+        if let Some(max_indeterminacy) = max_indeterminacy {
+            if indeterminacy_after > max_indeterminacy {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "The union would have an estimated indeterminacy rate of {:e}, over {:e}",
+                        indeterminacy_after, max_indeterminacy
+                    ),
+                ));
+            }
+        }
+        self.merge(other)?;
+        Ok(UnionReport {
+            newly_set,
+            indeterminacy_before,
+            indeterminacy_after,
+        })
+    }
+
+    /// Builds a new `BField` with the parameters in `params` (e.g. bigger
+    /// arrays for an over-saturated `BField`) from the `(key, value)` pairs
+    /// of `pairs`, as [`BField::build_from_iter`] does, and returns it.
+    ///
+    /// The new members are built in memory; if this `BField` is stored in
+    /// `.bfd` files, they're then written next to them and renamed over them
+    /// (removing any secondary array the new `BField` doesn't have), so its
+    /// files are only replaced once the rebuild has succeeded. This `BField`
+    /// can still be queried meanwhile, but should be dropped afterwards.
+    ///
+    /// The files are renamed one at a time, so a crash while they are could
+    /// leave a mix of old and new arrays: a `{filename}.rebuilding` file is
+    /// kept next to them meanwhile, and loading a `BField` that has one fails
+    /// with an `InvalidData` error (it then has to be built again).
+    ///
+    /// Windows can't replace files that are mapped, so there a `BField`
+    /// stored in `.bfd` files has to be loaded with
+    /// [`BField::load_in_memory`] to be rebuilt.
+    pub fn rebuild<I, K, F>(
+        &self,
+        params: &BuildParams,
+        pairs: I,
+        progress: F,
+    ) -> Result<Self, io::Error>
+    where
+        I: IntoIterator<Item = (K, V)> + Clone,
+        K: AsRef<[u8]>,
+        F: Fn(Progress),
+    {
+        check_marker_params(params.marker_width, params.n_marker_bits)?;
+        if params.sizes.is_empty() || params.sizes.contains(&0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Can't rebuild into arrays of sizes {:?}", params.sizes),
+            ));
+        }
+        let main_db_path = &self.members[0].filename;
+        let base = main_db_path
+            .file_name()
+            .and_then(|f| f.to_str())
+            .and_then(|f| f.strip_suffix("0.bfd"))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Can only rebuild BFields stored in .bfd files, not {:?}",
+                        main_db_path
+                    ),
+                )
+            })?;
+        let member_path = |n: usize| main_db_path.with_file_name(format!("{base}{n}.bfd"));
+        if cfg!(windows) && self.members.iter().any(|m| m.storage().is_file()) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "Can't replace the mapped files of {:?} on Windows, load it with `load_in_memory` to rebuild it",
+                    main_db_path
+                ),
+            ));
+        }
+
+        // the rebuilt arrays are built in memory, in words if the current
+        // ones are
+        let backing = match self.members[0].storage().backing() {
+            Backing::Words => Backing::Words,
+            _ => Backing::Memory,
+        };
+        let mut members = Vec::with_capacity(params.sizes.len());
+        for (n, &size) in params.sizes.iter().enumerate() {
+            let mut member = BFieldMember::create(
+                member_path(n),
+                backing,
+                size,
+                params.n_hashes,
+                params.marker_width,
+                params.n_marker_bits,
+                V::BITS,
+                if n == 0 { self.params().clone() } else { None },
+            )?;
+            if self.members[0].is_blocked() {
+                member.use_blocked_layout()?;
+            }
+            member.use_hashing_of(&self.members[0], n)?;
+            if n == 0 {
+                member.params.provenance = self.provenance().cloned();
+            }
+            members.push(member);
+        }
+        let mut rebuilt = Self::from_members(members, false, main_db_path)?;
+        if self.sidecar.is_some() {
+            rebuilt.enable_sidecar();
+        }
+        rebuilt.prefilter = self.prefilter.as_ref().map(Prefilter::empty_copy);
+        rebuilt.build_from_iter(pairs, progress)?;
+        // a BField that was never persisted stays in memory
+        if self.members[0].storage().is_anonymous() {
+            return Ok(rebuilt);
+        }
+
+        let tmp_path = |n: usize| main_db_path.with_file_name(format!("{base}{n}.bfd.tmp"));
+        let written = rebuilt
+            .members
+            .iter()
+            .enumerate()
+            .try_for_each(|(n, member)| member.write_file(tmp_path(n)));
+        if let Err(e) = written {
+            for n in 0..rebuilt.members.len() {
+                let _ = fs::remove_file(tmp_path(n));
+            }
+            return Err(e);
+        }
+        let marker = main_db_path.with_file_name(format!("{base}rebuilding"));
+        fs::File::create(&marker)?.sync_all()?;
+        for n in 0..rebuilt.members.len() {
+            replace_file(&tmp_path(n), &member_path(n))?;
+        }
+        let mut n = rebuilt.members.len();
+        while member_path(n).exists() {
+            fs::remove_file(member_path(n))?;
+            n += 1;
+        }
+        rebuilt.write_side_files()?;
+        drop(rebuilt);
+
+        let in_memory = matches!(self.members[0].storage(), Storage::Heap { .. });
+        let mut options = LoadOptions::new()
+            .read_only(self.read_only)
+            .in_memory(in_memory);
+        options.secret = self.members[0].hash_secret();
+        let bfield = Self::from_members(
+            (0..params.sizes.len())
+                .map(|n| options.open_member(&member_path(n)))
+                .collect::<Result<_, _>>()?,
+            self.read_only,
+            main_db_path,
+        )?;
+        bfield.write_manifest(true)?;
+        fs::remove_file(&marker)?;
+        Ok(bfield)
+    }
+
+    /// Sets (or with `None`, removes) a monitor that's called back when the
+    /// member being inserted into gets fuller than expected.
+    pub fn monitor_saturation(&mut self, monitor: Option<SaturationMonitor>) {
+        self.saturation = monitor;
+    }
+
+    /// Sets (or with `None`, removes) the Prometheus metrics that `get`
+    /// records each lookup in. Lookups answered from the cache aren't
+    /// recorded, nor are the probes of arrays added afterwards.
+    #[cfg(feature = "metrics")]
+    pub fn monitor_lookups(&mut self, metrics: Option<&LookupMetrics>) {
+        self.metrics = metrics.map(|m| m.bind(self.members.len()));
+    }
+
+    #[inline]
+    fn record_insert(&self, member: usize) {
+        if let Some(monitor) = &self.saturation {
+            monitor.record_insert(member, self.members[member].storage());
+        }
+    }
+
+    /// Returns the value of the given key if found, `None` otherwise.
+    /// The current implementation also returns `None` for indeterminate values
+    /// (and for decoded values that don't fit in `V`, which can only be false positives).
+    /// The key can be bytes, a string or an integer, see [`BFieldKey`].
+    pub fn get<K: BFieldKey>(&self, key: K) -> Option<V> {
+        key.with_bytes(|key| {
+            #[cfg(feature = "cache")]
+            if let Some(cache) = &self.cache {
+                return cache
+                    .get_or_insert_with(key, self.secret().as_ref(), || {
+                        self.get_uncached(key).map(V::into_u64)
+                    })
+                    .and_then(V::from_u64);
+            }
+            self.get_uncached(key)
+        })
+    }
+
+    /// The values of each of `keys`, as returned by [`BField::get`]. The
+    /// lookups are pipelined, see [`BField::get_many_pipelined`].
+    pub fn get_many<I, K>(&self, keys: I) -> Vec<Option<V>>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        self.get_many_pipelined(keys, DEFAULT_PIPELINE_DEPTH)
+    }
+
+    /// Same as [`BField::get_many`], hashing each key and prefetching its
+    /// markers in the primary array `depth` keys before looking it up, so
+    /// the memory accesses of several lookups overlap instead of waiting on
+    /// each other. The best depth depends on the hardware (the default is
+    /// 8); with a depth of 0, or with a cache or metrics enabled, the keys
+    /// are looked up one at a time with `get`.
+    pub fn get_many_pipelined<I, K>(&self, keys: I, depth: usize) -> Vec<Option<V>>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        #[cfg(feature = "cache")]
+        if self.cache.is_some() {
+            return keys.into_iter().map(|key| self.get(key.as_ref())).collect();
+        }
+        self.get_many_uncached(keys, depth)
+    }
+
+    /// [`BField::get_many_pipelined`] without going through the cache.
+    pub(crate) fn get_many_uncached<I, K>(&self, keys: I, depth: usize) -> Vec<Option<V>>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        #[cfg(feature = "metrics")]
+        let depth = if self.metrics.is_some() { 0 } else { depth };
+        let mut keys = keys.into_iter();
+        if depth == 0 {
+            return keys.map(|key| self.get_uncached(key.as_ref())).collect();
+        }
+        let mut values = Vec::with_capacity(keys.size_hint().0);
+        let mut pending = VecDeque::with_capacity(depth + 1);
+        loop {
+            while pending.len() <= depth {
+                match keys.next() {
+                    Some(key) => {
+                        let hash = self.hash(key.as_ref());
+                        self.members[0].prefetch_hashed(hash);
+                        pending.push_back((key, hash));
+                    }
+                    None => break,
+                }
+            }
+            let (key, hash) = match pending.pop_front() {
+                Some(pending) => pending,
+                None => return values,
+            };
+            let value = match self.resolve_hashed(key.as_ref(), hash).0 {
+                BFieldLookup::Some(value) => V::from_u64(value),
+                BFieldLookup::Indeterminate | BFieldLookup::None => None,
+            };
+            values.push(value);
+        }
+    }
+
+    fn get_uncached(&self, key: &[u8]) -> Option<V> {
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            let start = std::time::Instant::now();
+            let (lookup, _, n_probed) = self.resolve(key);
+            // values that don't fit in `V` are misses, as for `get`
+            let (lookup, value) = match lookup {
+                BFieldLookup::Some(value) => match V::from_u64(value) {
+                    Some(value) => (lookup, Some(value)),
+                    None => (BFieldLookup::None, None),
+                },
+                lookup => (lookup, None),
+            };
+            metrics.record(&lookup, n_probed, start.elapsed());
+            return value;
+        }
+        match self.lookup(key) {
+            BFieldLookup::Some(value) => V::from_u64(value),
+            // TODO: better value for totally indeterminate? panic?
+            // or return a Result<Option<V>, ...> instead?
+            BFieldLookup::Indeterminate | BFieldLookup::None => None,
+        }
+    }
+
+    /// Same as [`BField::get`], also returning the index of the member that
+    /// resolved the key (0 for the primary array, or the number of members
+    /// for keys found in the sidecar), e.g. to check which pass keys ended up
+    /// in or to debug saturated members. Doesn't go through the cache.
+    pub fn get_with_provenance<K: BFieldKey>(&self, key: K) -> Option<(V, usize)> {
+        key.with_bytes(|key| match self.resolve(key) {
+            (BFieldLookup::Some(value), ix, _) => V::from_u64(value).map(|value| (value, ix)),
+            _ => None,
+        })
+    }
+
+    /// Whether `key` is in the `BField`, which is faster than `get` when its
+    /// value isn't needed as it isn't decoded. Keys reported `Present` are
+    /// subject to the same false positive rate as `get`.
+    pub fn contains<K: BFieldKey>(&self, key: K) -> Tristate {
+        key.with_bytes(|key| {
+            if let Some(prefilter) = &self.prefilter {
+                if !prefilter.contains(key, self.secret().as_ref()) {
+                    return Tristate::Absent;
+                }
+            }
+            for member in self.members.iter() {
+                match member.contains(key) {
+                    Tristate::Indeterminate => continue,
+                    found => return found,
+                }
+            }
+            match self
+                .sidecar
+                .as_ref()
+                .and_then(|s| s.get(key, self.secret().as_ref()))
+            {
+                Some(_) => Tristate::Present,
+                None => Tristate::Indeterminate,
+            }
+        })
+    }
+
+    /// Looks `key` up in each member until one of them is determinate.
+    pub(crate) fn lookup(&self, key: &[u8]) -> BFieldLookup {
+        self.resolve(key).0
+    }
+
+    /// Same as `lookup`, also returning the index of the member that
+    /// answered (the number of members if none did) and the number of
+    /// members probed (0 if the prefilter rejected the key).
+    fn resolve(&self, key: &[u8]) -> (BFieldLookup, usize, usize) {
+        self.resolve_hashed(key, self.hash(key))
+    }
+
+    /// The 128-bit hash of `key` the primary array derives its marker
+    /// positions from.
+    #[inline]
+    fn hash(&self, key: &[u8]) -> (u64, u64) {
+        self.members[0].hash(key)
+    }
+
+    /// The hash of `key` in the `ix`th member, reusing `primary_hash` when
+    /// the member hashes keys like the primary array (i.e. unless the
+    /// `BField` has a seed, see `use_hash_seed`).
+    #[inline]
+    fn member_hash(&self, ix: usize, key: &[u8], primary_hash: (u64, u64)) -> (u64, u64) {
+        let member = &self.members[ix];
+        if member.hash_seed() == self.members[0].hash_seed() {
+            primary_hash
+        } else {
+            member.hash(key)
+        }
+    }
+
+    /// Same as `resolve`, given the hash of the key in the primary array.
+    fn resolve_hashed(&self, key: &[u8], hash: (u64, u64)) -> (BFieldLookup, usize, usize) {
+        if let Some(prefilter) = &self.prefilter {
+            if !prefilter.contains(key, self.secret().as_ref()) {
+                return (BFieldLookup::None, 0, 0);
+            }
+        }
+        for (ix, secondary) in self.members.iter().enumerate() {
+            match secondary.get_hashed(self.member_hash(ix, key, hash)) {
+                BFieldLookup::Indeterminate => continue,
+                lookup => return (lookup, ix, ix + 1),
+            }
+        }
+        let lookup = match self
+            .sidecar
+            .as_ref()
+            .and_then(|s| s.get(key, self.secret().as_ref()))
+        {
+            Some(value) => BFieldLookup::Some(value),
+            None => BFieldLookup::Indeterminate,
+        };
+        (lookup, self.members.len(), self.members.len())
+    }
+
+    /// Get the info of each secondary array (`BFieldMember`) in the `BField`.
+    /// Returns `Vec<(size, n_hashes, marker_width, n_marker_bits)>`.
+    #[deprecated(since = "0.4.0", note = "use `member_info` instead")]
+    pub fn info(&self) -> Vec<(usize, u8, u8, u8)> {
+        self.members.iter().map(|m| m.info()).collect()
+    }
+
+    /// Get the info of each array (`BFieldMember`) in the `BField`, starting
+    /// with the primary one.
+    pub fn member_info(&self) -> Vec<MemberInfo> {
+        self.members
+            .iter()
+            .map(|m| {
+                let (size, n_hashes, marker_width, n_marker_bits) = m.info();
+                MemberInfo {
+                    size,
+                    n_hashes,
+                    marker_width,
+                    n_marker_bits,
+                    value_bits: m.params.value_bits,
+                }
+            })
+            .collect()
+    }
+}
+
+/// The parameters a `BField` was built with, see [`BField::create`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildParams {
+    /// The number of hash functions (k)
+    pub n_hashes: u8,
+    /// The length of the markers (ν)
+    pub marker_width: u8,
+    /// The number of bits set in each marker (κ)
+    pub n_marker_bits: u8,
+    /// The size in bits of each array, starting with the primary one
+    pub sizes: Vec<usize>,
+}
+
+/// The parameters of one of the arrays of a `BField`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemberInfo {
+    /// Size in bits
+    pub size: usize,
+    /// The number of hash functions (k)
+    pub n_hashes: u8,
+    /// The length of the markers (ν)
+    pub marker_width: u8,
+    /// The number of bits set in each marker (κ)
+    pub n_marker_bits: u8,
+    /// Width in bits of the value type the array was built with
+    pub value_bits: u8,
+}
+
+impl<T: fmt::Debug, V> fmt::Debug for BField<T, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BField")
+            .field("read_only", &self.read_only)
+            .field("members", &self.members)
+            .field("journal", &self.journal)
+            .finish()
+    }
+}
+
+/// A one-line summary, e.g. `BField (3 members of 1000000, 100000, 25000
+/// bits, k=10 ν=39 κ=4, read-only)`.
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> fmt::Display for BField<T, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let params = self.build_parameters();
+        let sizes: Vec<String> = params.sizes.iter().map(|s| s.to_string()).collect();
+        write!(
+            f,
+            "BField ({} members of {} bits, k={} ν={} κ={}, {})",
+            sizes.len(),
+            sizes.join(", "),
+            params.n_hashes,
+            params.marker_width,
+            params.n_marker_bits,
+            if self.read_only {
+                "read-only"
+            } else {
+                "read-write"
+            }
+        )
+    }
+}
+
+/// What [`BField::union_with`] did.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnionReport {
+    /// Number of bits of each array that were only set in the other
+    /// `BField`, i.e. that got saturated by the union
+    pub newly_set: Vec<usize>,
+    /// Estimated indeterminacy rate before the union
+    pub indeterminacy_before: f64,
+    /// Estimated indeterminacy rate after the union
+    pub indeterminacy_after: f64,
+}
+
+/// How far along a long-running operation is, reported to the `progress`
+/// callbacks of [`BField::create_with_progress`],
+/// [`BField::build_from_iter`] and [`BField::persist_to_disk_with_progress`].
+/// Counters that don't apply to the operation are left at 0.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Bytes of bit arrays created or written so far
+    pub bytes_written: u64,
+    /// Total bytes of bit arrays to create or write
+    pub total_bytes: u64,
+    /// Number of members (or passes, for builds) done
+    pub members_completed: usize,
+    /// Total number of members (or passes)
+    pub total_members: usize,
+    /// Number of keys inserted so far, over all the passes
+    pub keys_inserted: u64,
+}
+
+impl Progress {
+    /// Nothing done yet for members of `sizes` bits.
+    fn new(sizes: &[usize]) -> Self {
+        Progress {
+            total_bytes: sizes.iter().map(|&s| byte_len(s) as u64).sum(),
+            total_members: sizes.len(),
+            ..Progress::default()
+        }
+    }
+
+    /// Counts a member of `size` bits as done.
+    fn member_written(&mut self, size: usize) {
+        self.bytes_written += byte_len(size) as u64;
+        self.members_completed += 1;
+    }
+}
+
+/// Settings for [`BField::load_with`].
+#[derive(Clone, Debug, Default)]
+pub struct LoadOptions {
+    read_only: bool,
+    verify_checksums: bool,
+    in_memory: bool,
+    populate: bool,
+    read_retries: Option<u32>,
+    secret: Option<HashSecret>,
+    upgrade: Option<ParamsUpgrade>,
+}
+
+impl LoadOptions {
+    /// The default settings: read-write, without verifying checksums.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether to open the members read-only.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Whether to check the members against the checksums recorded in the
+    /// manifest when loading (which reads every member in full).
+    pub fn verify_checksums(mut self, verify_checksums: bool) -> Self {
+        self.verify_checksums = verify_checksums;
+        self
+    }
+
+    /// Whether to read the members into memory instead of mapping them.
+    pub fn in_memory(mut self, in_memory: bool) -> Self {
+        self.in_memory = in_memory;
+        self
+    }
+
+    /// Whether to pre-fault all the pages of the members once they're
+    /// loaded (see [`BField::warm_up`]).
+    pub fn populate(mut self, populate: bool) -> Self {
+        self.populate = populate;
+        self
+    }
+
+    /// Whether to read the members into memory with plain reads, retrying
+    /// each read that fails up to `retries` times, instead of mapping them:
+    /// for databases on network filesystems, where a read error in a mapped
+    /// file kills the process (with `SIGBUS`) on the lookup that hits it.
+    /// Errors that persist are returned by `load_with`, and lookups never
+    /// touch the files afterwards.
+    pub fn guarded_reads(mut self, retries: u32) -> Self {
+        self.read_retries = Some(retries);
+        self
+    }
+
+    /// The secret the members were hashed with, if they were (see
+    /// [`BField::use_keyed_hashing`]).
+    pub fn hash_secret(mut self, secret: HashSecret) -> Self {
+        self.secret = Some(secret);
+        self
+    }
+
+    /// Converts the params of `BField`s written with a version older than
+    /// `version` (see [`BField::set_params_version`]): `upgrade` is called
+    /// with their version and their JSON encoding, and returns the JSON of
+    /// the params in the current version. Fields added with
+    /// `#[serde(default)]` don't need an upgrade, nor do removed ones.
+    pub fn upgrade_params<F>(mut self, version: u32, upgrade: F) -> Self
+    where
+        F: Fn(u32, serde_json::Value) -> Result<serde_json::Value, io::Error>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.upgrade = Some(ParamsUpgrade {
+            version,
+            upgrade: Arc::new(upgrade),
+        });
+        self
+    }
+
+    /// Opens the member at `path` with these settings.
+    pub(crate) fn open_member<T: Clone + DeserializeOwned + Serialize>(
+        &self,
+        path: &Path,
+    ) -> Result<BFieldMember<T>, io::Error> {
+        let upgrade = self.upgrade.as_ref();
+        let mut member = if let Some(retries) = self.read_retries {
+            BFieldMember::read_guarded(path, upgrade, retries)?
+        } else if self.in_memory {
+            BFieldMember::read_upgraded(path, upgrade)?
+        } else {
+            BFieldMember::open_upgraded(path, self.read_only, upgrade)?
+        };
+        if let Some(secret) = self.secret {
+            member.set_hash_secret(secret)?;
+        }
+        Ok(member)
+    }
+}
+
+/// Checks κ is supported and fits in the marker.
+pub(crate) fn check_marker_params(marker_width: u8, n_marker_bits: u8) -> Result<(), io::Error> {
+    if n_marker_bits == 0 || n_marker_bits > MAX_MARKER_BITS || n_marker_bits > marker_width {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "n_marker_bits must be between 1 and {} and at most marker_width ({}), got {}",
+                MAX_MARKER_BITS, marker_width, n_marker_bits
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// The number of bytes the bit arrays of a `BField` created with these
+/// parameters (see [`BField::create`]) will take, in memory or on disk, e.g.
+/// to check there's enough room before creating it. Each `.bfd` file also has
+/// a header of a few dozen bytes (plus the serialized `other_params`).
+pub fn estimate_footprint(
+    size: usize,
+    secondary_scaledown: f64,
+    max_scaledown: f64,
+    n_secondaries: u8,
+) -> u64 {
+    member_sizes(size, secondary_scaledown, max_scaledown, n_secondaries)
+        .into_iter()
+        .map(|s| byte_len(s) as u64)
+        .sum()
+}
+
+/// A path that can't be the one of the primary array of a `BField` (see
+/// [`BField::load`]). Loading one returns an `InvalidInput` `io::Error`
+/// wrapping this (see `io::Error::get_ref`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidPath {
+    /// The path given
+    pub path: PathBuf,
+    /// What's wrong with it
+    pub reason: &'static str,
+}
+
+impl fmt::Display for InvalidPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} isn't the path of the primary array of a BField (`{{filename}}.0.bfd`): {}",
+            self.path, self.reason
+        )
+    }
+}
+
+impl std::error::Error for InvalidPath {}
+
+impl From<InvalidPath> for io::Error {
+    fn from(error: InvalidPath) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, error)
+    }
+}
+
+/// Checks `main_db_path` can be the path of a primary array, i.e. that it
+/// has a parent and a (UTF-8) file name ending with `0.bfd`.
+pub(crate) fn check_primary_path(main_db_path: &Path) -> Result<(), InvalidPath> {
+    let invalid = |reason| {
+        Err(InvalidPath {
+            path: main_db_path.to_path_buf(),
+            reason,
+        })
+    };
+    if main_db_path.parent().is_none() {
+        return invalid("it has no parent directory");
+    }
+    match main_db_path.file_name().map(|f| f.to_str()) {
+        None => invalid("it has no file name"),
+        Some(None) => invalid("its file name isn't valid UTF-8"),
+        Some(Some(filename)) if !filename.ends_with("0.bfd") => {
+            invalid("its file name doesn't end with `0.bfd`")
+        }
+        Some(Some(_)) => Ok(()),
+    }
+}
+
+/// The path of the file marking a rebuild in progress (see
+/// [`BField::rebuild`]) for a `BField` whose primary array is at
+/// `main_db_path` (`{filename}.0.bfd` -> `{filename}.rebuilding`).
+fn rebuild_marker_path(main_db_path: &Path) -> Option<PathBuf> {
+    let filename = main_db_path.file_name()?.to_str()?;
+    let base = filename.strip_suffix("0.bfd")?;
+    Some(main_db_path.with_file_name(format!("{base}rebuilding")))
+}
+
+/// The path of the `n`th member of the `BField` whose primary array is at
+/// `main_db_path` (the one ending with `0.bfd`).
+pub(crate) fn member_path(main_db_path: &Path, n: usize) -> PathBuf {
+    let filename = main_db_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    main_db_path.with_file_name(filename.replace("0.bfd", &format!("{n}.bfd")))
+}
+
+/// The sizes of the primary and secondary arrays for the given parameters
+/// (see `BField::create`).
+pub(crate) fn member_sizes(
+    size: usize,
+    secondary_scaledown: f64,
+    max_scaledown: f64,
+    n_secondaries: u8,
+) -> Vec<usize> {
+    let mut sizes = Vec::with_capacity(usize::from(n_secondaries));
+    let mut cur_size = size;
+    for _ in 0..n_secondaries {
+        sizes.push(cur_size);
+        cur_size = f64::max(
+            cur_size as f64 * secondary_scaledown,
+            size as f64 * max_scaledown,
+        ) as usize;
+    }
+    sizes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
     fn can_build_and_query_file_bfield() {
         let tmp_dir = tempfile::tempdir().unwrap();
-        let n_secondaries = 4;
-        let bfield = BField::create(
+        let n_secondaries = 4;
+        let bfield = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            1_000_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            n_secondaries,
+            false,
+            String::new(),
+        )
+        .expect("to build");
+
+        // Identity database
+        let max_value: u32 = 10_000;
+        for p in 0..n_secondaries {
+            for i in 0..max_value {
+                bfield.insert(&i.to_be_bytes().to_vec(), i, p as usize);
+            }
+        }
+
+        for i in 0..max_value {
+            let val = bfield.get(&i.to_be_bytes().to_vec()).unwrap();
+            assert_eq!(i, val);
+        }
+        assert_eq!(bfield.max_value(), 82250);
+        drop(bfield);
+
+        // and we can load them
+        let bfield = BField::<String>::load(&tmp_dir.path().join("bfield.0.bfd"), true).unwrap();
+        for i in 0..max_value {
+            let val = bfield.get(&i.to_be_bytes().to_vec()).unwrap();
+            assert_eq!(i, val);
+        }
+    }
+
+    #[test]
+    fn can_build_and_query_in_memory_bfield() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let n_secondaries = 4;
+        let mut bfield = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            1_000_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            n_secondaries,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+
+        // Identity database
+        let max_value: u32 = 10_000;
+        for p in 0..n_secondaries {
+            for i in 0..max_value {
+                bfield.insert(&i.to_be_bytes().to_vec(), i, p as usize);
+            }
+        }
+
+        for i in 0..max_value {
+            let val = bfield.get(&i.to_be_bytes().to_vec()).unwrap();
+            assert_eq!(i, val);
+        }
+        bfield = bfield.persist_to_disk().unwrap();
+        for m in &bfield.members {
+            assert!(m.filename.exists());
+        }
+        for i in 0..max_value {
+            let val = bfield.get(&i.to_be_bytes().to_vec()).unwrap();
+            assert_eq!(i, val);
+        }
+    }
+
+    #[test]
+    fn can_use_blocked_layout() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            false,
+            String::new(),
+        )
+        .unwrap();
+        bfield.use_blocked_layout().unwrap();
+        bfield
+            .build_from_iter((0..1_000u32).map(|i| (i.to_be_bytes(), i)), |_| {})
+            .unwrap();
+        for i in 0..1_000u32 {
+            assert_eq!(bfield.get(&i.to_be_bytes()), Some(i));
+        }
+        let err = bfield.use_blocked_layout().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        bfield.update_checksums().unwrap();
+        drop(bfield);
+
+        // the layout is recorded in the headers
+        let path = tmp_dir.path().join("bfield.0.bfd");
+        let file = fs::read(&path).unwrap();
+        assert_eq!(file[4] & 0x80, 0x80);
+        let loaded = BField::<String>::load(&path, true).unwrap();
+        assert!(loaded.members.iter().all(|m| m.is_blocked()));
+        assert_eq!(loaded.build_parameters().n_hashes, 10);
+        let secondary = fs::read(tmp_dir.path().join("bfield.1.bfd")).unwrap();
+        let view = crate::BFieldView::from_members(&[&file, &secondary]).unwrap();
+        for i in (0..1_000u32).chain(5_000..5_500) {
+            let key = i.to_be_bytes();
+            assert_eq!(view.get(&key), loaded.get(&key).map(u64::from));
+            if i < 1_000 {
+                assert_eq!(loaded.get(&key), Some(i));
+            }
+        }
+
+        // every array needs to hold at least one block
+        let mut small: BField<String> = BField::create(
+            tmp_dir.path(),
+            "small",
+            4_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.1,
+            2,
+            true,
+            String::new(),
+        )
+        .unwrap();
+        assert!(small.use_blocked_layout().is_err());
+        assert!(!small.members[0].is_blocked());
+    }
+
+    #[test]
+    fn can_build_and_query_words_bfield() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let create = |backing| {
+            BField::<String>::create(
+                tmp_dir.path(),
+                "bfield",
+                100_000,
+                10,
+                39,
+                4,
+                0.1,
+                0.025,
+                2,
+                backing,
+                String::new(),
+            )
+            .unwrap()
+        };
+        let words = create(Backing::Words);
+        let memory = create(Backing::Memory);
+        assert_eq!(words.members[0].storage().kind(), "words");
+        assert!(!tmp_dir.path().join("bfield.0.bfd").exists());
+        let pairs = (0..1_000u32).map(|i| (i.to_be_bytes(), i));
+        words.build_from_iter(pairs.clone(), |_| {}).unwrap();
+        memory.build_from_iter(pairs, |_| {}).unwrap();
+        // the bits are laid out the same way as in the other backends
+        for (a, b) in words.members.iter().zip(&memory.members) {
+            assert_eq!(a.storage().as_bytes(), b.storage().as_bytes());
+        }
+        for i in 0..1_000u32 {
+            assert_eq!(words.get(&i.to_be_bytes()), Some(i));
+        }
+
+        let copy = words.try_clone().unwrap();
+        assert_eq!(copy.members[1].storage().backing(), Backing::Words);
+        let words = words.persist_to_disk().unwrap();
+        assert!(tmp_dir.path().join("bfield.1.bfd").exists());
+        assert_eq!(words.get(&7u32.to_be_bytes()), Some(7));
+        assert_eq!(copy.get(&7u32.to_be_bytes()), Some(7));
+    }
+
+    #[test]
+    fn can_use_typed_keys() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            1,
+            true,
+            String::new(),
+        )
+        .unwrap();
+        assert!(bfield.insert(42u64, 1, 0));
+        assert!(bfield.insert("forty-two", 2, 0));
+        assert!(bfield.insert(-42i16, 3, 0));
+        // keys are stored as their bytes
+        assert_eq!(bfield.get(42u64.to_be_bytes()), Some(1));
+        assert_eq!(bfield.get(&b"forty-two"[..]), Some(2));
+        assert_eq!(bfield.get(&"forty-two".to_string()), Some(2));
+        assert_eq!(bfield.get(vec![0xff, 0xd6]), Some(3));
+        assert_eq!(bfield.get(42u32), None);
+
+        // so do the other methods taking keys
+        assert_eq!(bfield.contains(42u64), Tristate::Present);
+        assert_eq!(bfield.get_with_provenance("forty-two"), Some((2, 0)));
+        assert_eq!(bfield.get_or_insert(-42i16, 5, 0), Some(3));
+        assert!(bfield.try_insert(7u8, 4, 0).unwrap());
+        assert_eq!(bfield.insert_auto(8u8, 4), Some(0));
+        assert!(bfield.update(7u8, 6).unwrap());
+        assert_eq!(bfield.get([7u8]), Some(6));
+        assert!(bfield.remove(8u8, 4).unwrap());
+        assert_eq!(bfield.get(8u8), None);
+        bfield.mask_or_insert(String::from("key"), 3).unwrap();
+        assert_eq!(bfield.get("key"), Some(3));
+    }
+
+    #[test]
+    fn can_use_keyed_hashing() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let secret = HashSecret::new(*b"0123456789abcdef");
+        let mut bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            false,
+            String::new(),
+        )
+        .unwrap();
+        bfield.use_keyed_hashing(secret).unwrap();
+        bfield.enable_prefilter(1_000, 0.01).unwrap();
+        bfield
+            .build_from_iter((0..1_000u32).map(|i| (i.to_be_bytes(), i)), |_| {})
+            .unwrap();
+        assert_eq!(bfield.get(&7u32.to_be_bytes()), Some(7));
+        // the prefilter hashes keys with the secret too
+        let prefilter = bfield.prefilter.as_ref().unwrap();
+        let unkeyed = (0..1_000u32)
+            .filter(|i| prefilter.contains(&i.to_be_bytes(), None))
+            .count();
+        assert!(unkeyed < 100);
+        // too late to change the hashing
+        assert!(bfield.use_keyed_hashing(secret).is_err());
+        drop(bfield);
+
+        let path = tmp_dir.path().join("bfield.0.bfd");
+        let err = BField::<String>::load(&path, true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let options = LoadOptions::new().read_only(true);
+        let wrong = options.clone().hash_secret(HashSecret::new([0; 16]));
+        assert!(BField::<String>::load_with(&path, &wrong).is_err());
+        let loaded = BField::<String>::load_with(&path, &options.hash_secret(secret)).unwrap();
+        for i in 0..1_000u32 {
+            assert_eq!(loaded.get(&i.to_be_bytes()), Some(i));
+        }
+        let bytes = fs::read(&path).unwrap();
+        assert!(crate::BFieldView::from_members(&[&bytes]).is_err());
+    }
+
+    #[test]
+    fn can_use_hash_seed() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let create = |name| {
+            BField::<String>::create(
+                tmp_dir.path(),
+                name,
+                100_000,
+                10,
+                39,
+                4,
+                0.1,
+                0.025,
+                2,
+                false,
+                String::new(),
+            )
+            .unwrap()
+        };
+        let unseeded = create("unseeded");
+        let mut seeded = create("seeded");
+        let seed = seeded.use_random_hash_seed().unwrap();
+        assert_eq!(seeded.hash_seed(), seed);
+        assert_eq!(unseeded.hash_seed(), 0);
+        let pairs = (0..1_000u32).map(|i| (i.to_be_bytes(), i));
+        seeded.build_from_iter(pairs.clone(), |_| {}).unwrap();
+        unseeded.build_from_iter(pairs, |_| {}).unwrap();
+        assert_ne!(
+            seeded.members[0].storage().as_bytes(),
+            unseeded.members[0].storage().as_bytes()
+        );
+        assert!(seeded.use_hash_seed(1).is_err());
+        // each array hashes keys independently
+        assert_ne!(seeded.members[1].hash_seed(), seed);
+        assert_eq!(seeded.members[1].hash_seed(), member_seed(seed, 1));
+        drop(seeded);
+
+        let path = tmp_dir.path().join("seeded.0.bfd");
+        let loaded = BField::<String>::load(&path, true).unwrap();
+        assert_eq!(loaded.hash_seed(), seed);
+        let files: Vec<Vec<u8>> = (0..2)
+            .map(|n| fs::read(tmp_dir.path().join(format!("seeded.{}.bfd", n))).unwrap())
+            .collect();
+        let view = crate::BFieldView::from_members(&[&files[0], &files[1]]).unwrap();
+        for i in 0..1_000u32 {
+            assert_eq!(loaded.get(&i.to_be_bytes()), Some(i));
+            assert_eq!(view.get(&i.to_be_bytes()), Some(u64::from(i)));
+        }
+    }
+
+    #[test]
+    fn can_sort_writes() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let create = || {
+            BField::<String>::create(
+                tmp_dir.path(),
+                "bfield",
+                100_000,
+                10,
+                39,
+                4,
+                0.1,
+                0.025,
+                2,
+                true,
+                String::new(),
+            )
+            .unwrap()
+        };
+        let pairs = (0..1_000u32).map(|i| (i.to_be_bytes(), i));
+        let unsorted = create();
+        unsorted.build_from_iter(pairs.clone(), |_| {}).unwrap();
+        let mut sorted = create();
+        sorted.sort_writes(600);
+        sorted.build_from_iter(pairs, |_| {}).unwrap();
+        // the same bits end up set, in a different order
+        for (a, b) in sorted.members.iter().zip(&unsorted.members) {
+            assert_eq!(a.storage().as_bytes(), b.storage().as_bytes());
+            assert_eq!(a.n_keys(), b.n_keys());
+        }
+        assert_eq!(sorted.get(&42u32.to_be_bytes()), Some(42));
+    }
+
+    #[test]
+    fn can_dedup_keys() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let create = |name| {
+            let mut bfield = BField::<String>::create(
+                tmp_dir.path(),
+                name,
+                100_000,
+                10,
+                39,
+                4,
+                0.1,
+                0.025,
+                2,
+                true,
+                String::new(),
+            )
+            .unwrap();
+            // so the digests don't depend on the creation time
+            bfield.set_deterministic(true).unwrap();
+            bfield
+        };
+        // every key comes back with another value
+        let pairs = (0..2_000u32).map(|i| ((i % 1_000).to_be_bytes(), i));
+        let unique = create("unique");
+        unique
+            .build_from_iter(pairs.clone().take(1_000), |_| {})
+            .unwrap();
+
+        let mut deduped = create("deduped");
+        deduped.dedup_keys(Some(
+            DedupOptions::new()
+                .max_in_memory(300)
+                .spill_dir(tmp_dir.path()),
+        ));
+        deduped.build_from_iter(pairs.clone(), |_| {}).unwrap();
+        assert_eq!(deduped.digest(), unique.digest());
+        assert_eq!(deduped.get(&42u32.to_be_bytes()), Some(42));
+
+        let repeated = create("repeated");
+        repeated.build_from_iter(pairs, |_| {}).unwrap();
+        assert_eq!(repeated.members[0].n_keys(), 2_000);
+        assert_eq!(repeated.get(&42u32.to_be_bytes()), None);
+    }
+
+    #[test]
+    fn can_insert_without_passes() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        // small enough for plenty of keys to need the secondary arrays
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            60_000,
+            10,
+            39,
+            4,
+            0.5,
+            0.1,
+            3,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        let mut n_per_member = [0; 3];
+        for i in 0..1_000u32 {
+            let member = bfield.insert_auto(&i.to_be_bytes(), i).unwrap();
+            n_per_member[member] += 1;
+            assert_eq!(bfield.get(&i.to_be_bytes()), Some(i));
+        }
+        assert!(n_per_member[0] > 900 && n_per_member[1] > 0);
+        assert_eq!(bfield.members()[0].n_keys(), 1_000);
+        // an inserted key goes into the next members when it's indeterminate
+        assert_eq!(
+            bfield.members()[1].n_keys() as usize,
+            n_per_member[1] + n_per_member[2]
+        );
+        // the keys inserted first may have become indeterminate since
+        let n_found = (0..1_000u32)
+            .filter(|&i| bfield.get(&i.to_be_bytes()) == Some(i))
+            .count();
+        assert!(n_found > 950);
+    }
+
+    #[test]
+    fn can_reject_invalid_paths() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let reason = |path: &Path| {
+            let err = BField::<String>::load(path, true).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            let invalid = err.get_ref().unwrap().downcast_ref::<InvalidPath>();
+            invalid.unwrap().reason
+        };
+        assert_eq!(reason(Path::new("/")), "it has no parent directory");
+        assert_eq!(
+            reason(&tmp_dir.path().join("bfield.1.bfd")),
+            "its file name doesn't end with `0.bfd`"
+        );
+        assert_eq!(reason(&tmp_dir.path().join("..")), "it has no file name");
+        // valid paths fail on the missing files instead
+        let err = BField::<String>::load(tmp_dir.path().join("bfield.0.bfd"), true);
+        assert_eq!(err.err().unwrap().kind(), io::ErrorKind::NotFound);
+        assert!(check_primary_path(Path::new("bfield.0.bfd")).is_ok());
+    }
+
+    #[test]
+    fn can_lock_files() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("bfield.0.bfd");
+        let load = |read_only| BField::<String>::load(&path, read_only);
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            1,
+            false,
+            String::new(),
+        )
+        .unwrap();
+        bfield.insert(b"key", 1, 0);
+        // the files are locked for writing as long as the BField is open
+        assert_eq!(load(true).err().unwrap().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(load(false).err().unwrap().kind(), io::ErrorKind::WouldBlock);
+        // but read-only handles share its lock
+        let clone = bfield.try_clone().unwrap();
+        assert_eq!(clone.get(b"key"), Some(1));
+        drop(bfield);
+        assert_eq!(load(true).err().unwrap().kind(), io::ErrorKind::WouldBlock);
+        drop(clone);
+
+        let readers = [load(true).unwrap(), load(true).unwrap()];
+        assert!(readers.iter().all(|reader| reader.get(b"key") == Some(1)));
+        assert!(load(false).is_err());
+        drop(readers);
+        assert!(load(false).is_ok());
+    }
+
+    #[test]
+    fn can_record_provenance() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            10_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            1,
+            false,
+            String::new(),
+        )
+        .unwrap();
+        let provenance = bfield.provenance().unwrap();
+        assert!(provenance.created_at.is_some());
+        assert_eq!(provenance.crate_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(provenance.max_scaledown, 0.025);
+        bfield.set_build_label("refseq-2024.1").unwrap();
+        bfield.declare_n_elements(100).unwrap();
+        bfield.set_deterministic(true).unwrap();
+        assert!(bfield.insert(b"key", 1, 0));
+        assert!(bfield.set_build_label("refseq-2024.2").is_err());
+        bfield.update_checksums().unwrap();
+        drop(bfield);
+
+        let loaded: BField<String> =
+            BField::load(tmp_dir.path().join("bfield.0.bfd"), true).unwrap();
+        let provenance = loaded.provenance().unwrap();
+        assert_eq!(provenance.created_at, None);
+        assert_eq!(provenance.secondary_scaledown, 0.1);
+        assert_eq!(provenance.n_elements, Some(100));
+        assert_eq!(provenance.label.as_deref(), Some("refseq-2024.1"));
+        assert_eq!(loaded.get(b"key"), Some(1));
+    }
+
+    #[test]
+    fn can_upgrade_params_on_load() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            10_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            1,
+            false,
+            "taxonomy".to_string(),
+        )
+        .unwrap();
+        bfield.set_params_version(1).unwrap();
+        drop(bfield);
+        let path = tmp_dir.path().join("bfield.0.bfd");
+        let loaded: BField<String> = BField::load(&path, true).unwrap();
+        assert_eq!(loaded.params_version(), 1);
+        drop(loaded);
+
+        let options = LoadOptions::new()
+            .read_only(true)
+            .upgrade_params(2, |version, value| {
+                assert_eq!(version, 1);
+                Ok(serde_json::json!(format!("{}-v2", value.as_str().unwrap())))
+            });
+        let loaded: BField<String> = BField::load_with(&path, &options).unwrap();
+        assert_eq!(loaded.params(), &Some("taxonomy-v2".to_string()));
+        assert_eq!(loaded.params_version(), 2);
+        // params already at the version aren't converted
+        let options = LoadOptions::new()
+            .read_only(true)
+            .upgrade_params(1, |_, _| unreachable!());
+        assert!(BField::<String>::load_with(&path, &options).is_ok());
+    }
+
+    #[test]
+    fn can_build_deterministically() {
+        let build = |dir: &Path, backing: Backing| {
+            let mut bfield: BField<String> = BField::create(
+                dir,
+                "bfield",
+                100_000,
+                10,
+                39,
+                4,
+                0.1,
+                0.025,
+                2,
+                backing,
+                String::new(),
+            )
+            .unwrap();
+            bfield.set_deterministic(true).unwrap();
+            bfield.use_hash_seed(42).unwrap();
+            assert!(bfield.use_random_hash_seed().is_err());
+            bfield
+                .build_from_iter((0..2_000u32).map(|i| (i.to_be_bytes(), i)), |_| {})
+                .unwrap();
+            bfield
+        };
+        let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+        let first = build(dirs[0].path(), Backing::File);
+        let second = build(dirs[1].path(), Backing::File);
+        assert_eq!(first.digest(), second.digest());
+        for ix in 0..2 {
+            let file = format!("bfield.{}.bfd", ix);
+            let bytes = fs::read(dirs[0].path().join(&file)).unwrap();
+            assert_eq!(bytes, fs::read(dirs[1].path().join(&file)).unwrap());
+        }
+        // the headers were updated by the build, without update_checksums
+        drop(first);
+        let loaded: BField<String> =
+            BField::load(dirs[0].path().join("bfield.0.bfd"), true).unwrap();
+        assert_eq!(loaded.members[0].n_keys(), 2_000);
+        assert_eq!(loaded.digest(), second.digest());
+
+        // an in-memory build has the same contents
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let in_memory = build(tmp_dir.path(), Backing::Memory);
+        assert_eq!(in_memory.digest(), second.digest());
+        in_memory.insert(b"another key", 7, 0);
+        assert_ne!(in_memory.digest(), second.digest());
+    }
+
+    #[test]
+    fn can_reject_values_out_of_range() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        // markers of 16 bits with 4 set encode values up to 1819
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            16,
+            4,
+            0.1,
+            0.025,
+            1,
+            true,
+            String::new(),
+        )
+        .unwrap();
+        assert_eq!(bfield.try_insert(b"key", 1819, 0), Ok(true));
+        assert_eq!(bfield.get(b"key"), Some(1819));
+        let err = bfield.try_insert(b"other", 1820, 0).unwrap_err();
+        assert!(matches!(
+            err,
+            RankError::ValueOutOfRange {
+                value: 1820,
+                max_value: 1819,
+                ..
+            }
+        ));
+        assert_eq!(bfield.get(b"other"), None);
+
+        let pairs = vec![(b"a".to_vec(), 1), (b"b".to_vec(), 5000)];
+        let err = bfield.build_from_iter(pairs, |_| {}).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(bfield.get(b"a"), None);
+    }
+
+    #[test]
+    fn can_pipeline_lookups() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .unwrap();
+        bfield
+            .build_from_iter((0..1_000u32).map(|i| (i.to_be_bytes(), i)), |_| {})
+            .unwrap();
+        let keys: Vec<[u8; 4]> = (0..2_000u32).map(u32::to_be_bytes).collect();
+        let expected: Vec<Option<u32>> = keys.iter().map(|key| bfield.get(key)).collect();
+        assert!(expected[..1_000].iter().all(Option::is_some));
+        for &depth in &[0, 1, 8, 5_000] {
+            assert_eq!(bfield.get_many_pipelined(&keys, depth), expected);
+        }
+        assert_eq!(bfield.get_many(&keys[..3]), &expected[..3]);
+        assert!(bfield.get_many(Vec::<&[u8]>::new()).is_empty());
+    }
+
+    #[test]
+    fn can_use_large_kappa() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            1_000_000,
+            10,
+            64,
+            12,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        for p in 0..2 {
+            for i in 0..1_000u32 {
+                bfield.insert(&i.to_be_bytes(), i * 1_000_000, p);
+            }
+        }
+        for i in 0..1_000u32 {
+            assert_eq!(bfield.get(&i.to_be_bytes()), Some(i * 1_000_000));
+        }
+        // 64 choose 12 is ~3.3e12
+        assert_eq!(bfield.max_value(), u32::MAX);
+
+        assert!(BField::<String>::create(
+            tmp_dir.path(),
+            "bfield",
+            1_000_000,
+            10,
+            128,
+            17,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn can_store_u64_values() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String, u64> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            1_000_000,
+            10,
+            128,
+            9,
+            0.1,
+            0.025,
+            2,
+            false,
+            String::new(),
+        )
+        .expect("to build");
+
+        let offset = u64::from(u32::MAX);
+        for p in 0..2 {
+            for i in 0..1_000u64 {
+                bfield.insert(&i.to_be_bytes(), offset + i, p);
+            }
+        }
+        for i in 0..1_000u64 {
+            assert_eq!(bfield.get(&i.to_be_bytes()), Some(offset + i));
+        }
+        drop(bfield);
+
+        // u64 values can't be loaded back as u32s
+        let path = tmp_dir.path().join("bfield.0.bfd");
+        assert!(BField::<String, u32>::load(&path, true).is_err());
+        let bfield = BField::<String, u64>::load(&path, true).unwrap();
+        assert_eq!(bfield.get(&999u64.to_be_bytes()), Some(offset + 999));
+    }
+
+    #[test]
+    fn can_advise_access_pattern() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            1_000_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            false,
+            String::new(),
+        )
+        .expect("to build");
+        bfield.advise(AccessPattern::Sequential).unwrap();
+        for p in 0..2 {
+            for i in 0..1_000u32 {
+                bfield.insert(&i.to_be_bytes(), i, p);
+            }
+        }
+        // dropping the pages of a file-backed map doesn't lose any data
+        bfield.advise(AccessPattern::DontNeed).unwrap();
+        bfield.advise(AccessPattern::Random).unwrap();
+        for i in 0..1_000u32 {
+            assert_eq!(bfield.get(&i.to_be_bytes()), Some(i));
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn can_use_huge_pages() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            1 << 24,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        bfield.use_huge_pages().unwrap();
+        for p in 0..2 {
+            for i in 0..1_000u32 {
+                bfield.insert(&i.to_be_bytes(), i, p);
+            }
+        }
+        for i in 0..1_000u32 {
+            assert_eq!(bfield.get(&i.to_be_bytes()), Some(i));
+        }
+    }
+
+    #[test]
+    fn can_load_in_memory() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_003,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            3,
+            false,
+            "params".to_string(),
+        )
+        .expect("to build");
+        for p in 0..3 {
+            for i in 0..1_000u32 {
+                bfield.insert(&i.to_be_bytes(), i, p);
+            }
+        }
+        drop(bfield);
+
+        let path = tmp_dir.path().join("bfield.0.bfd");
+        let bfield = BField::<String>::load_in_memory(&path).unwrap();
+        assert_eq!(bfield.params(), &Some("params".to_string()));
+        assert_eq!(
+            bfield.member_info(),
+            BField::<String>::load(&path, true).unwrap().member_info()
+        );
+        for i in 0..1_000u32 {
+            assert_eq!(bfield.get(&i.to_be_bytes()), Some(i));
+        }
+        let guarded: BField<String> =
+            BField::load_with(&path, &LoadOptions::new().guarded_reads(3)).unwrap();
+        assert!(matches!(
+            guarded.members()[0].storage(),
+            Storage::Heap { .. }
+        ));
+        assert_eq!(guarded.digest(), bfield.digest());
+
+        // inserts only touch memory until persisted
+        bfield.insert(&1_000u32.to_be_bytes(), 1_000, 0);
+        assert_eq!(
+            BField::<String>::load(&path, true)
+                .unwrap()
+                .get(&1_000u32.to_be_bytes()),
+            None
+        );
+        drop(bfield.persist_to_disk().unwrap());
+        let bfield = BField::<String>::load(&path, true).unwrap();
+        assert_eq!(bfield.get(&1_000u32.to_be_bytes()), Some(1_000));
+        bfield.verify_checksums().unwrap();
+    }
+
+    #[test]
+    fn can_populate_on_load() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            1_000_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            false,
+            String::new(),
+        )
+        .expect("to build");
+        for p in 0..2 {
+            for i in 0..1_000u32 {
+                bfield.insert(&i.to_be_bytes(), i, p);
+            }
+        }
+        drop(bfield);
+
+        let options = LoadOptions::new().read_only(true).populate(true);
+        let bfield =
+            BField::<String>::load_with(tmp_dir.path().join("bfield.0.bfd"), &options).unwrap();
+        for i in 0..1_000u32 {
+            assert_eq!(bfield.get(&i.to_be_bytes()), Some(i));
+        }
+        bfield.warm_up().unwrap();
+    }
+
+    #[test]
+    fn can_insert_from_several_threads() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            1_000_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        let n_threads = 4;
+        let max_value = 10_000u32;
+        for p in 0..2 {
+            std::thread::scope(|s| {
+                for t in 0..n_threads {
+                    let bfield = &bfield;
+                    s.spawn(move || {
+                        for i in (t..max_value).step_by(n_threads as usize) {
+                            bfield.insert(&i.to_be_bytes(), i, p);
+                        }
+                    });
+                }
+            });
+        }
+        for i in 0..max_value {
+            assert_eq!(bfield.get(&i.to_be_bytes()), Some(i));
+        }
+    }
+
+    #[test]
+    fn bfield_is_send_and_sync() {
+        fn assert_send_sync<S: Send + Sync>() {}
+        assert_send_sync::<BField<String>>();
+        assert_send_sync::<BField<Vec<u8>, u64>>();
+        assert_send_sync::<crate::ShardedBField<String>>();
+    }
+
+    #[test]
+    fn can_clone_handles() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        for in_memory in [false, true] {
+            let bfield: BField<String> = BField::create(
+                tmp_dir.path(),
+                "bfield",
+                100_000,
+                10,
+                39,
+                4,
+                0.1,
+                0.025,
+                2,
+                in_memory,
+                "params".to_string(),
+            )
+            .expect("to build");
+            for p in 0..2 {
+                for i in 0..1_000u32 {
+                    bfield.insert(&i.to_be_bytes(), i, p);
+                }
+            }
+            let clone = bfield.try_clone().unwrap();
+            assert_eq!(clone.params(), &Some("params".to_string()));
+            assert_eq!(clone.build_parameters(), bfield.build_parameters());
+            for i in 0..1_000u32 {
+                assert_eq!(clone.get(&i.to_be_bytes()), Some(i));
+            }
+            // file-backed clones see later inserts, in-memory ones are copies
+            bfield.insert(&5_000u32.to_be_bytes(), 5_000, 0);
+            let expected = if in_memory { None } else { Some(5_000) };
+            assert_eq!(clone.get(&5_000u32.to_be_bytes()), expected);
+        }
+    }
+
+    #[test]
+    fn can_format_bfield() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            true,
+            "params".to_string(),
+        )
+        .expect("to build");
+        assert_eq!(
+            bfield.to_string(),
+            "BField (2 members of 100000, 10000 bits, k=10 ν=39 κ=4, read-write)"
+        );
+        let debug = format!("{:?}", bfield);
+        assert!(debug.contains("read_only: false"), "{}", debug);
+        assert!(debug.contains("anonymous map"), "{}", debug);
+        assert!(debug.contains("\"params\""), "{}", debug);
+    }
+
+    #[test]
+    fn can_describe_members() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String, u64> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        let params = bfield.build_parameters();
+        assert_eq!(params.sizes, vec![100_000, 10_000]);
+        assert_eq!(
+            (params.n_hashes, params.marker_width, params.n_marker_bits),
+            (10, 39, 4)
+        );
+        let info = bfield.member_info();
+        assert_eq!(info[1].size, 10_000);
+        assert_eq!(info[1].value_bits, 64);
+        let json = serde_json::to_string(&params).unwrap();
+        assert_eq!(serde_json::from_str::<BuildParams>(&json).unwrap(), params);
+    }
+
+    #[test]
+    fn can_build_from_iter_with_progress() {
+        use std::cell::RefCell;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let reports = RefCell::new(Vec::new());
+        let bfield: BField<String> = BField::create_with_progress(
+            tmp_dir.path(),
+            "bfield",
+            4_000_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            3,
+            true,
+            String::new(),
+            |p| reports.borrow_mut().push(p),
+        )
+        .expect("to build");
+        let last = *reports.borrow().last().unwrap();
+        assert_eq!(reports.borrow().len(), 3);
+        assert_eq!(last.members_completed, 3);
+        assert_eq!(last.bytes_written, last.total_bytes);
+        assert_eq!(last.total_bytes, (4_000_000 + 400_000 + 100_000) / 8);
+
+        reports.borrow_mut().clear();
+        let keys: Vec<u32> = (0..40_000).collect();
+        bfield
+            .build_from_iter(keys.iter().map(|&i| (i.to_be_bytes(), i)), |p| {
+                reports.borrow_mut().push(p)
+            })
+            .unwrap();
+        for i in 0..40_000u32 {
+            assert_eq!(bfield.get(&i.to_be_bytes()), Some(i));
+        }
+        let reports = reports.take();
+        // one report every 100,000 keys plus one per pass
+        assert_eq!(reports.len(), 1 + 3);
+        assert_eq!(reports.last().unwrap().keys_inserted, 3 * 40_000);
+        assert_eq!(reports.last().unwrap().members_completed, 3);
+
+        let bytes = RefCell::new(Vec::new());
+        let bfield = bfield
+            .persist_to_disk_with_progress(|p| bytes.borrow_mut().push(p.bytes_written))
+            .unwrap();
+        let bytes = bytes.take();
+        assert!(bytes.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(*bytes.last().unwrap(), last.total_bytes);
+        drop(bfield);
+        let bfield = BField::<String>::load(tmp_dir.path().join("bfield.0.bfd"), true).unwrap();
+        bfield.verify_checksums().unwrap();
+        assert_eq!(bfield.get(&1_000u32.to_be_bytes()), Some(1_000));
+    }
+
+    #[test]
+    fn can_estimate_footprint() {
+        assert_eq!(
+            estimate_footprint(1_000_000, 0.1, 0.025, 4),
+            (1_000_000 + 100_000 + 25_000 + 25_000) / 8
+        );
+        assert_eq!(estimate_footprint(1_001, 0.5, 0.5, 1), 126);
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            1_000_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            4,
+            false,
+            String::new(),
+        )
+        .expect("to build");
+        let on_disk: u64 = (0..4)
+            .map(|n| {
+                let path = tmp_dir.path().join(format!("bfield.{}.bfd", n));
+                std::fs::metadata(path).unwrap().len()
+            })
+            .sum();
+        let estimate = estimate_footprint(1_000_000, 0.1, 0.025, 4);
+        assert!(on_disk >= estimate && on_disk < estimate + 4 * 64);
+        drop(bfield);
+    }
+
+    #[test]
+    fn can_merge_bfields() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let build = |size: usize| -> BField<String> {
+            BField::create(
+                tmp_dir.path(),
+                "bfield",
+                size,
+                10,
+                39,
+                4,
+                0.1,
+                0.025,
+                3,
+                true,
+                String::new(),
+            )
+            .expect("to build")
+        };
+        let mut bfield = build(1_000_000);
+        let other = build(1_000_000);
+        for p in 0..3 {
+            for i in 0..1_000u32 {
+                bfield.insert(&i.to_be_bytes(), i, p);
+                other.insert(&(i + 1_000).to_be_bytes(), i, p);
+            }
+        }
+        bfield.merge(&other).unwrap();
+        for i in 0..1_000u32 {
+            assert_eq!(bfield.get(&i.to_be_bytes()), Some(i));
+            assert_eq!(bfield.get(&(i + 1_000).to_be_bytes()), Some(i));
+        }
+        assert_eq!(bfield.stats().members[0].n_keys, 2_000);
+
+        let err = bfield.merge(&build(500_000)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // the arrays must be hashed the same way
+        let mut seeded = build(1_000_000);
+        seeded.use_hash_seed(7).unwrap();
+        let err = bfield.merge(&seeded).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(bfield.union_with(&seeded, None).is_err());
+        let secret = HashSecret::new(*b"0123456789abcdef");
+        let mut keyed = build(1_000_000);
+        keyed.use_keyed_hashing(secret).unwrap();
+        let err = bfield.merge(&keyed).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let mut other_keyed = build(1_000_000);
+        other_keyed
+            .use_keyed_hashing(HashSecret::new([0; 16]))
+            .unwrap();
+        assert!(keyed.merge(&other_keyed).is_err());
+        let mut same_keyed = build(1_000_000);
+        same_keyed.use_keyed_hashing(secret).unwrap();
+        keyed.merge(&same_keyed).unwrap();
+        let mut blocked = build(1_000_000);
+        blocked.use_blocked_layout().unwrap();
+        let err = bfield.merge(&blocked).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn can_union_with_threshold() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let build = || -> BField<String> {
+            BField::create(
+                tmp_dir.path(),
+                "bfield",
+                100_000,
+                10,
+                39,
+                4,
+                0.1,
+                0.025,
+                1,
+                true,
+                String::new(),
+            )
+            .expect("to build")
+        };
+        let mut bfield = build();
+        let other = build();
+        for i in 0..1_000u32 {
+            bfield.insert(&i.to_be_bytes(), i, 0);
+            other.insert(&(i + 1_000).to_be_bytes(), i, 0);
+        }
+        let before = bfield.stats();
+        // far too strict: nothing changes
+        let err = bfield.union_with(&other, Some(1e-12)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(bfield.stats(), before);
+
+        let report = bfield.union_with(&other, Some(0.5)).unwrap();
+        assert_eq!(report.newly_set[0], bfield.stats().n_set - before.n_set);
+        assert!(report.newly_set[0] > 0);
+        assert!(report.indeterminacy_after > report.indeterminacy_before);
+        assert_eq!(report.indeterminacy_before, before.indeterminacy_rate);
+        assert_eq!(
+            report.indeterminacy_after,
+            bfield.stats().indeterminacy_rate
+        );
+    }
+
+    #[test]
+    fn can_persist_files_in_place() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = tmp_dir.path().join("B-fields (données)");
+        fs::create_dir(&dir).unwrap();
+        let bfield: BField<String> = BField::create(
+            &dir,
+            "my bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            false,
+            "params".to_string(),
+        )
+        .unwrap();
+        bfield
+            .build_from_iter((0..1_000u32).map(|i| (i.to_be_bytes(), i)), |_| {})
+            .unwrap();
+        // the mapped files only get their headers rewritten
+        let bfield = bfield.persist_to_disk().unwrap();
+        assert_eq!(bfield.get(&7u32.to_be_bytes()), Some(7));
+        drop(bfield);
+        #[cfg(windows)]
+        assert!(dir.join("my bfield.0.bfd.lock").exists());
+
+        let loaded: BField<String> = BField::load_with(
+            dir.join("my bfield.0.bfd"),
+            &LoadOptions::new().read_only(true).verify_checksums(true),
+        )
+        .unwrap();
+        assert_eq!(loaded.members()[0].n_keys(), 1_000);
+        for i in 0..1_000u32 {
+            assert_eq!(loaded.get(&i.to_be_bytes()), Some(i));
+        }
+    }
+
+    #[test]
+    fn can_rebuild_into_new_params() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        // far too small for 10,000 keys
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            20_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            3,
+            false,
+            "other".to_string(),
+        )
+        .expect("to build");
+        let pairs: Vec<(Vec<u8>, u32)> = (0..10_000u32)
+            .map(|i| (i.to_be_bytes().to_vec(), i))
+            .collect();
+        let pairs = pairs.iter().map(|(k, v)| (k, *v));
+        bfield.build_from_iter(pairs.clone(), |_| {}).unwrap();
+        assert!((0..10_000u32).any(|i| bfield.get(&i.to_be_bytes()) != Some(i)));
+
+        let params = BuildParams {
+            n_hashes: 10,
+            marker_width: 39,
+            n_marker_bits: 4,
+            sizes: vec![1_000_000, 100_000],
+        };
+        // Windows can't replace mapped files
+        #[cfg(windows)]
+        let bfield = {
+            let err = bfield.rebuild(&params, pairs.clone(), |_| {}).err();
+            assert_eq!(err.unwrap().kind(), io::ErrorKind::Unsupported);
+            drop(bfield);
+            BField::load_in_memory(tmp_dir.path().join("bfield.0.bfd")).unwrap()
+        };
+        let rebuilt = bfield.rebuild(&params, pairs, |_| {}).unwrap();
+        drop(bfield);
+        assert_eq!(rebuilt.build_parameters(), params);
+        assert_eq!(rebuilt.params(), &Some("other".to_string()));
+        for i in 0..10_000u32 {
+            assert_eq!(rebuilt.get(&i.to_be_bytes()), Some(i));
+        }
+        assert!(!tmp_dir.path().join("bfield.2.bfd").exists());
+        assert!(!tmp_dir.path().join("bfield.0.bfd.tmp").exists());
+        assert!(!tmp_dir.path().join("bfield.rebuilding").exists());
+        drop(rebuilt);
+
+        let loaded: BField<String> = BField::load_with(
+            tmp_dir.path().join("bfield.0.bfd"),
+            &LoadOptions::new().read_only(true).verify_checksums(true),
+        )
+        .unwrap();
+        assert_eq!(loaded.build_parameters(), params);
+        for i in 0..10_000u32 {
+            assert_eq!(loaded.get(&i.to_be_bytes()), Some(i));
+        }
+        let bad = BuildParams {
+            sizes: vec![],
+            ..params
+        };
+        let err = loaded.rebuild(&bad, Vec::<(Vec<u8>, u32)>::new(), |_| {});
+        assert_eq!(err.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        drop(loaded);
+
+        // as left by a crash while the files were being replaced
+        fs::File::create(tmp_dir.path().join("bfield.rebuilding")).unwrap();
+        let err = BField::<String>::load(tmp_dir.path().join("bfield.0.bfd"), true);
+        assert_eq!(err.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn can_remove_keys() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
             tmp_dir.path(),
             "bfield",
             1_000_000,
@@ -242,39 +3693,230 @@ mod tests {
             4,
             0.1,
             0.025,
-            n_secondaries,
+            2,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        let pairs = (0..10_000u32).map(|i| (i.to_be_bytes(), i));
+        bfield.build_from_iter(pairs, |_| {}).unwrap();
+
+        assert!(!bfield.remove(&1u32.to_be_bytes(), 2).unwrap());
+        assert_eq!(bfield.get(&1u32.to_be_bytes()), Some(1));
+        for i in 0..100u32 {
+            assert!(bfield.remove(&i.to_be_bytes(), i).unwrap());
+            assert_eq!(bfield.get(&i.to_be_bytes()), None);
+            assert!(!bfield.remove(&i.to_be_bytes(), i).unwrap());
+        }
+        let n_found = (100..10_000u32)
+            .filter(|&i| bfield.get(&i.to_be_bytes()) == Some(i))
+            .count();
+        assert!(n_found >= 9_850, "{}", n_found);
+    }
+
+    #[test]
+    fn can_update_values() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            1_000_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            1,
             false,
             String::new(),
         )
         .expect("to build");
+        let pairs = (0..10_000u32).map(|i| (i.to_be_bytes(), i));
+        bfield.build_from_iter(pairs, |_| {}).unwrap();
 
-        // Identity database
-        let max_value: u32 = 10_000;
-        for p in 0..n_secondaries {
-            for i in 0..max_value {
-                bfield.insert(&i.to_be_bytes().to_vec(), i, p as usize);
+        assert!(!bfield.update(&20_000u32.to_be_bytes(), 1).unwrap());
+        assert_eq!(bfield.get(&20_000u32.to_be_bytes()), None);
+        for i in 0..100u32 {
+            assert!(bfield.update(&i.to_be_bytes(), i + 1).unwrap());
+            assert_eq!(bfield.get(&i.to_be_bytes()), Some(i + 1));
+        }
+        // a secondary array was added for the new values
+        assert_eq!(bfield.build_parameters().sizes, vec![1_000_000, 1_000_000]);
+        assert!(tmp_dir.path().join("bfield.1.bfd").exists());
+        // updating it again masks it in the secondary too
+        assert!(bfield.update(&5u32.to_be_bytes(), 5).unwrap());
+        assert_eq!(bfield.get(&5u32.to_be_bytes()), Some(5));
+        assert_eq!(bfield.member_info().len(), 3);
+
+        let mut read_only = bfield.try_clone().unwrap();
+        let err = read_only.update(&1u32.to_be_bytes(), 3).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn can_mask_or_insert_into_built_bfield() {
+        let mut bfield: BField<String> = BField::create(
+            "",
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        // saturated, so force_insert-ed keys would often be indeterminate
+        let pairs = (0..5_000u32).map(|i| (i.to_be_bytes(), i));
+        bfield.build_from_iter(pairs, |_| {}).unwrap();
+        for i in 0..200u32 {
+            let key = (i + 1_000_000).to_be_bytes();
+            bfield.mask_or_insert(&key, i).unwrap();
+            assert_eq!(bfield.get(&key), Some(i));
+        }
+        bfield.mask_or_insert(&1u32.to_be_bytes(), 7).unwrap();
+        assert_eq!(bfield.get(&1u32.to_be_bytes()), Some(7));
+    }
+
+    #[test]
+    fn can_merge_conflicting_values() {
+        let mut bfield: BField<String> = BField::create(
+            "",
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            1,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        // a taxonomy where 1 is the root, with children 2 and 3, and 2 has
+        // children 4 and 5
+        let parent = |taxon: u32| [0, 0, 1, 1, 2, 2][taxon as usize];
+        let lineage = |mut taxon: u32| {
+            let mut lineage = vec![taxon];
+            while taxon != 1 {
+                taxon = parent(taxon);
+                lineage.push(taxon);
             }
+            lineage
+        };
+        let lca = |a: u32, b: u32| {
+            let ancestors = lineage(a);
+            lineage(b)
+                .into_iter()
+                .find(|t| ancestors.contains(t))
+                .unwrap()
+        };
+
+        assert_eq!(bfield.insert_with_merge(b"kmer", 4, lca).unwrap(), 4);
+        assert_eq!(bfield.insert_with_merge(b"kmer", 4, lca).unwrap(), 4);
+        assert_eq!(bfield.member_info().len(), 1);
+        assert_eq!(bfield.insert_with_merge(b"kmer", 5, lca).unwrap(), 2);
+        assert_eq!(bfield.get(b"kmer"), Some(2));
+        assert_eq!(bfield.insert_with_merge(b"kmer", 3, lca).unwrap(), 1);
+        assert_eq!(bfield.get(b"kmer"), Some(1));
+        // merging into the value already stored changes nothing
+        let n_members = bfield.member_info().len();
+        assert_eq!(bfield.insert_with_merge(b"kmer", 4, lca).unwrap(), 1);
+        assert_eq!(bfield.member_info().len(), n_members);
+    }
+
+    #[test]
+    fn can_keep_indeterminate_keys_in_sidecar() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        // too small for all the keys to be determinate
+        let mut bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            false,
+            String::new(),
+        )
+        .expect("to build");
+        assert_eq!(bfield.n_sidecar_keys(), None);
+        bfield.enable_sidecar();
+        let pairs = (0..5_000u32).map(|i| (i.to_be_bytes(), i));
+        bfield.build_from_iter(pairs, |_| {}).unwrap();
+        assert!(bfield.n_sidecar_keys().unwrap() > 0);
+        for i in 0..5_000u32 {
+            assert_eq!(bfield.get(&i.to_be_bytes()), Some(i));
         }
+        drop(bfield);
 
-        for i in 0..max_value {
-            let val = bfield.get(&i.to_be_bytes().to_vec()).unwrap();
-            assert_eq!(i, val);
+        let bfield: BField<String> =
+            BField::load(tmp_dir.path().join("bfield.0.bfd"), false).unwrap();
+        assert!(bfield.n_sidecar_keys().unwrap() > 0);
+        for i in 0..5_000u32 {
+            assert_eq!(bfield.get(&i.to_be_bytes()), Some(i));
         }
+        let i = (0..5_000u32)
+            .find(|i| {
+                bfield
+                    .members()
+                    .iter()
+                    .all(|m| m.get(&i.to_be_bytes()) == BFieldLookup::Indeterminate)
+            })
+            .unwrap();
+        assert!(bfield.remove(&i.to_be_bytes(), i).unwrap());
+        assert_eq!(bfield.get(&i.to_be_bytes()), None);
+    }
+
+    #[test]
+    fn can_use_prefilter() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            1_000_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            false,
+            String::new(),
+        )
+        .expect("to build");
+        bfield.enable_prefilter(10_000, 0.001).unwrap();
+        let pairs = (0..10_000u32).map(|i| (i.to_be_bytes(), i));
+        bfield.build_from_iter(pairs, |_| {}).unwrap();
+        let err = bfield.enable_prefilter(10_000, 0.001).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
         drop(bfield);
 
-        // and we can load them
-        let bfield = BField::<String>::load(&tmp_dir.path().join("bfield.0.bfd"), true).unwrap();
-        for i in 0..max_value {
-            let val = bfield.get(&i.to_be_bytes().to_vec()).unwrap();
-            assert_eq!(i, val);
+        let bfield: BField<String> =
+            BField::load(tmp_dir.path().join("bfield.0.bfd"), true).unwrap();
+        for i in 0..10_000u32 {
+            assert_eq!(bfield.get(&i.to_be_bytes()), Some(i));
         }
+        let prefilter = bfield.prefilter.as_ref().unwrap();
+        let n_passed = (10_000..110_000u32)
+            .filter(|i| prefilter.contains(&i.to_be_bytes(), None))
+            .count();
+        assert!(n_passed < 300, "{}", n_passed);
+        assert!((10_000..110_000u32).all(|i| bfield.get(&i.to_be_bytes()).is_none()));
     }
 
+    #[cfg(feature = "cache")]
     #[test]
-    fn can_build_and_query_in_memory_bfield() {
+    fn can_cache_lookups() {
         let tmp_dir = tempfile::tempdir().unwrap();
-        let n_secondaries = 4;
-        let mut bfield = BField::create(
+        let mut bfield: BField<String> = BField::create(
             tmp_dir.path(),
             "bfield",
             1_000_000,
@@ -283,32 +3925,207 @@ mod tests {
             4,
             0.1,
             0.025,
-            n_secondaries,
-            true,
+            2,
+            false,
             String::new(),
         )
         .expect("to build");
+        let pairs = (0..10_000u32).map(|i| (i.to_be_bytes(), i));
+        bfield.build_from_iter(pairs, |_| {}).unwrap();
+        let err = bfield.enable_cache(100).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
 
-        // Identity database
-        let max_value: u32 = 10_000;
-        for p in 0..n_secondaries {
-            for i in 0..max_value {
-                bfield.insert(&i.to_be_bytes().to_vec(), i, p as usize);
+        let mut bfield = bfield.try_clone().unwrap();
+        bfield.enable_cache(100).unwrap();
+        for _ in 0..2 {
+            for i in 0..20_000u32 {
+                let expected = if i < 10_000 { Some(i) } else { None };
+                assert_eq!(bfield.get(&i.to_be_bytes()), expected);
             }
         }
+    }
 
-        for i in 0..max_value {
-            let val = bfield.get(&i.to_be_bytes().to_vec()).unwrap();
-            assert_eq!(i, val);
+    #[test]
+    fn can_get_with_provenance() {
+        let mut bfield: BField<String> = BField::create(
+            "",
+            "bfield",
+            250_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        bfield.enable_sidecar();
+        let pairs = (0..5_000u32).map(|i| (i.to_be_bytes(), i));
+        bfield.build_from_iter(pairs, |_| {}).unwrap();
+        let mut counts = [0; 3];
+        for i in 0..5_000u32 {
+            let (value, ix) = bfield.get_with_provenance(&i.to_be_bytes()).unwrap();
+            assert_eq!(value, i);
+            let expected = bfield
+                .members()
+                .iter()
+                .position(|m| m.get(&i.to_be_bytes()) != BFieldLookup::Indeterminate)
+                .unwrap_or(2);
+            assert_eq!(ix, expected);
+            counts[ix] += 1;
         }
-        bfield = bfield.persist_to_disk().unwrap();
-        for m in &bfield.members {
-            assert!(m.filename.exists());
+        assert!(counts.iter().all(|&c| c > 0), "{:?}", counts);
+        assert_eq!(
+            bfield.get_with_provenance(&10_000_000u32.to_be_bytes()),
+            None
+        );
+    }
+
+    #[test]
+    fn can_check_membership() {
+        let bfield: BField<String> = BField::create(
+            "",
+            "bfield",
+            250_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        let pairs = (0..5_000u32).map(|i| (i.to_be_bytes(), i));
+        bfield.build_from_iter(pairs, |_| {}).unwrap();
+        for i in 0..10_000u32 {
+            let expected = match bfield.lookup(&i.to_be_bytes()) {
+                BFieldLookup::Some(_) => Tristate::Present,
+                BFieldLookup::None => Tristate::Absent,
+                BFieldLookup::Indeterminate => Tristate::Indeterminate,
+            };
+            assert_eq!(bfield.contains(&i.to_be_bytes()), expected);
         }
-        for i in 0..max_value {
-            let val = bfield.get(&i.to_be_bytes().to_vec()).unwrap();
-            assert_eq!(i, val);
+        assert_eq!(bfield.contains(&1u32.to_be_bytes()), Tristate::Present);
+        assert_eq!(bfield.contains(&100_000u32.to_be_bytes()), Tristate::Absent);
+    }
+
+    #[test]
+    fn can_get_or_insert() {
+        let bfield: BField<String> = BField::create(
+            "",
+            "bfield",
+            1_000_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        for i in 0..5_000u32 {
+            assert_eq!(bfield.get_or_insert(&i.to_be_bytes(), i, 0), None);
+        }
+        // the first value inserted wins
+        let n_existing = (0..5_000u32)
+            .filter(|&i| bfield.get_or_insert(&i.to_be_bytes(), i + 1, 0) == Some(i))
+            .count();
+        assert!(n_existing > 4_900, "{}", n_existing);
+        assert_eq!(
+            bfield.members[0].n_keys(),
+            5_000 + (5_000 - n_existing) as u64
+        );
+
+        for i in 0..5_000u32 {
+            let existing = bfield.get_or_insert(&i.to_be_bytes(), i, 1);
+            assert!(existing == Some(i) || existing.is_none());
+        }
+        for i in 0..5_000u32 {
+            assert_eq!(bfield.get(&i.to_be_bytes()), Some(i));
+        }
+    }
+
+    #[test]
+    fn can_create_bitset_bfield() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String, u8> = BField::create_bitset(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            3,
+            8,
+            false,
+            String::new(),
+        )
+        .expect("to build");
+        for i in 0..1_000u32 {
+            bfield.insert(&i.to_be_bytes(), 1 << (i % 4), 0);
+            bfield.insert(&(i / 2).to_be_bytes(), 0b1000_0000, 0);
+        }
+        drop(bfield);
+
+        let bfield: BField<String, u8> =
+            BField::load(tmp_dir.path().join("bfield.0.bfd"), true).unwrap();
+        assert!(bfield.members[0].is_bitset());
+        for i in 0..500u32 {
+            let bits = bfield.get(&i.to_be_bytes()).unwrap();
+            assert_eq!(bits & 0b1000_1111, 0b1000_0000 | 1 << (i % 4));
+        }
+        assert!(BField::<String, u8>::create_bitset(
+            "",
+            "bfield",
+            1_000,
+            3,
+            9,
+            true,
+            String::new()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn can_update_bitset_bfield() {
+        let mut bfield: BField<String, u8> =
+            BField::create_bitset("", "bfield", 100_000, 3, 8, true, String::new())
+                .expect("to build");
+        for i in 0..1_000u32 {
+            bfield.insert(&i.to_be_bytes(), 0b0001, 0);
+        }
+        for i in 0..500u32 {
+            assert!(bfield.update(&i.to_be_bytes(), 0b0010).unwrap());
         }
+        bfield.mask_or_insert(&7u32.to_be_bytes(), 0b0100).unwrap();
+        // the categories are added to the only array
+        assert_eq!(bfield.members.len(), 1);
+        for i in 0..1_000u32 {
+            let bits = bfield.get(&i.to_be_bytes()).unwrap();
+            let expected = match i {
+                7 => 0b0111,
+                0..=499 => 0b0011,
+                _ => 0b0001,
+            };
+            assert_eq!(bits & expected, expected);
+        }
+        assert!(!bfield.update(b"missing", 0b0010).unwrap());
+
+        let err = bfield.remove(&1u32.to_be_bytes(), 0b0011).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(bfield.get(&1u32.to_be_bytes()).is_some());
+
+        // bitsets can't be merged with markers of the same shape
+        let mut ranked: BField<String, u8> =
+            BField::create_bitset("", "ranked", 100_000, 3, 8, true, String::new())
+                .expect("to build");
+        ranked.members[0].params.bitset = false;
+        assert_eq!(ranked.member_info(), bfield.member_info());
+        let err = bfield.merge(&ranked).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
     }
 }
 
@@ -1,151 +1,1081 @@
-use std::cell::UnsafeCell;
 use std::cmp::Ordering;
-#[cfg(feature = "prefetching")]
-use std::intrinsics;
-use std::io;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::Arc;
 
-use crate::combinatorial::{rank, unrank};
-use bincode::{deserialize, serialize};
-use mmap_bitvec::{BitVector, MmapBitVec};
-use murmurhash3::murmurhash3_x64_128;
+use crate::combinatorial::{capacity, check_rank, rank_many, RankError};
+use crate::corruption::{check_member_file, check_params, CorruptFile};
+use crate::guarded::RetryingReader;
+use crate::keyed::HashSecret;
+use crate::lookup::{
+    blocked_marker_pos, fmix64, hash_key, marker_pos, BLOCKED_FLAG, BLOCK_BITS, KEYED_FLAG,
+    SEEDED_FLAG,
+};
+use crate::marker::{Marker, WideMarker};
+use crate::params::{decode_other, encode_other, ParamsUpgrade};
+use crate::provenance::Provenance;
+#[cfg(feature = "mmap")]
+use crate::storage::{byte_len, FileLock, BF_MAGIC};
+use crate::storage::{
+    encode_member_prefix, parse_member, parse_member_prefix, AccessPattern, Backing, BitStorage,
+    Mapping, Region, Storage,
+};
+use bincode::Options;
+#[cfg(feature = "mmap")]
+use mmap_bitvec::MmapBitVec;
 use serde::de::DeserializeOwned;
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
+use xxhash_rust::xxh3::Xxh3;
 
-struct BitVec {
-    bv: UnsafeCell<MmapBitVec>,
+// Fields added after v0.3 are appended after `other` so older files
+// (which simply end there) can still be decoded; see `decode_params`.
+#[derive(Clone, Debug)]
+pub(crate) struct BFieldParams<T> {
+    pub(crate) n_hashes: u8,      // k
+    pub(crate) marker_width: u8,  // nu
+    pub(crate) n_marker_bits: u8, // kappa
+    pub(crate) other: Option<T>,
+    /// The version `other` is tagged with (see `BField::set_params_version`);
+    /// written with it, see `params`
+    pub(crate) params_version: u32,
+    /// Where the `BField` comes from (only in primary arrays); written with
+    /// `other`, see `params`
+    pub(crate) provenance: Option<Provenance>,
+    /// Width in bits of the value type the member was built with
+    pub(crate) value_bits: u8,
+    /// Number of keys inserted as of when the header was written
+    pub(crate) n_keys: u64,
+    /// Whether values are bitsets stored positionally (see
+    /// `BFieldMember::create_bitset`); only written when set, so other
+    /// headers are unchanged
+    pub(crate) bitset: bool,
+    /// Whether the markers of a key are confined to one block (see
+    /// `BField::use_blocked_layout`); stored as `BLOCKED_FLAG` in the
+    /// `n_hashes` byte, so every reader sees it
+    pub(crate) blocked: bool,
+    /// The check value of the secret keys are hashed with, if any (see
+    /// `BField::use_keyed_hashing`); written after `bitset`, with
+    /// `KEYED_FLAG` set in the `n_hashes` byte
+    pub(crate) secret_check: Option<u64>,
+    /// The secret itself, which is never written
+    pub(crate) secret: Option<HashSecret>,
+    /// The seed keys are hashed with (see `BField::use_hash_seed`); written
+    /// last when it isn't 0, with `SEEDED_FLAG` set in the `n_hashes` byte
+    pub(crate) seed: u64,
 }
 
-impl BitVec {
-    pub fn new(bv: MmapBitVec) -> Self {
-        Self {
-            bv: UnsafeCell::new(bv),
+impl<T> BFieldParams<T> {
+    pub fn new(
+        n_hashes: u8,
+        marker_width: u8,
+        n_marker_bits: u8,
+        value_bits: u8,
+        other: Option<T>,
+    ) -> Self {
+        BFieldParams {
+            n_hashes,
+            marker_width,
+            n_marker_bits,
+            other,
+            params_version: 0,
+            provenance: None,
+            value_bits,
+            n_keys: 0,
+            bitset: false,
+            blocked: false,
+            secret_check: None,
+            secret: None,
+            seed: 0,
         }
     }
+}
 
-    #[allow(clippy::mut_from_ref)]
-    pub fn get(&self) -> &mut MmapBitVec {
-        unsafe { &mut *self.bv.get() }
-    }
+/// One of the bit arrays of a `BField`, stored in a `Storage` unless
+/// another `BitStorage` backend is given.
+pub(crate) struct BFieldMember<T, S = Storage> {
+    storage: S,
+    // Used when loading mmap in memory to know where to save it if needed
+    pub(crate) filename: PathBuf,
+    pub(crate) params: BFieldParams<T>,
+    /// Number of keys inserted, including the ones recorded in the header
+    n_keys: AtomicU64,
+    /// The lock on the member's `.bfd` file while it's mapped, shared with
+    /// the read-only handles from `try_clone`
+    #[cfg(feature = "mmap")]
+    lock: Option<Arc<FileLock>>,
 }
 
-unsafe impl Send for BitVec {}
-unsafe impl Sync for BitVec {}
+/// The default value type of a `BField`.
+pub type BFieldVal = u32;
 
-// Empty function on some archs
-#[allow(unused_variables)]
-#[inline]
-fn prefetch_read(pointer: *const u8) {
-    #[cfg(all(target_arch = "x86_64", target_feature = "sse"))]
-    {
-        use std::arch::x86_64 as arch_impl;
+/// Unsigned integer types that can be stored as values in a `BField`.
+///
+/// Values are encoded internally as `u64`s, so using `u64` values allows
+/// encoding value spaces larger than 2^32 (e.g. with ν=128 and κ≥8).
+pub trait BFieldValue: Copy {
+    /// The width of the type in bits, recorded in the header at creation.
+    const BITS: u8;
 
-        unsafe {
-            arch_impl::_mm_prefetch::<{ arch_impl::_MM_HINT_NTA }>(pointer as *const i8);
-        }
-    }
-}
+    /// Widens the value to the `u64` used for encoding markers.
+    fn into_u64(self) -> u64;
 
-#[derive(Debug, Deserialize, Serialize)]
-pub(crate) struct BFieldParams<T> {
-    n_hashes: u8,      // k
-    marker_width: u8,  // nu
-    n_marker_bits: u8, // kappa
-    pub(crate) other: Option<T>,
+    /// Narrows a decoded value, returning `None` if it doesn't fit in `Self`.
+    fn from_u64(value: u64) -> Option<Self>;
 }
 
-pub(crate) struct BFieldMember<T> {
-    bitvec: BitVec,
-    // Used when loading mmap in memory to know where to save it if needed
-    pub(crate) filename: PathBuf,
-    pub(crate) params: BFieldParams<T>,
+macro_rules! impl_bfield_value {
+    ($($t:ty),*) => {
+        $(
+            impl BFieldValue for $t {
+                const BITS: u8 = <$t>::BITS as u8;
+
+                #[inline]
+                fn into_u64(self) -> u64 {
+                    u64::from(self)
+                }
+
+                #[inline]
+                fn from_u64(value: u64) -> Option<Self> {
+                    <$t>::try_from(value).ok()
+                }
+            }
+        )*
+    };
 }
 
-/// A simple type alias to make the code more readable
-pub type BFieldVal = u32;
-/// Magic bytes used to indicate the `bfield` file type for `MmapBitvec`
-const BF_MAGIC: [u8; 2] = [0xBF, 0x1D];
+impl_bfield_value!(u8, u16, u32, u64);
+
+/// Whether a key is in a `BField`, see [`BField::contains`](crate::BField::contains).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tristate {
+    /// The key is (or, with the false positive rate, seems to be) present
+    Present,
+    /// The key is definitely absent
+    Absent,
+    /// The key is indeterminate in every member
+    Indeterminate,
+}
 
 #[derive(Debug, PartialEq)]
 pub(crate) enum BFieldLookup {
     Indeterminate,
-    Some(BFieldVal),
+    Some(u64),
     None,
 }
 
+/// The bincode settings used for member headers. These match the ones used by
+/// `bincode::serialize` (which wrote headers up to v0.3) but are spelled out
+/// so the header format doesn't depend on the library defaults: integers are
+/// always little-endian with a fixed width, and `usize`s are written as `u64`s.
+pub(crate) fn header_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+        .allow_trailing_bytes()
+}
+
+/// The seed of the `ix`th member of a `BField` hashing keys with `seed`: the
+/// primary array uses `seed` itself and each secondary one derived from it,
+/// so the markers of a key are placed independently in each array. Without
+/// a seed (0), keys are hashed the same way in every array.
+pub(crate) fn member_seed(seed: u64, ix: usize) -> u64 {
+    if seed == 0 || ix == 0 {
+        return seed;
+    }
+    fmix64(seed ^ (ix as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)).max(1)
+}
+
+/// Encodes the params to be stored in the member header.
+pub(crate) fn encode_params<T: Serialize>(params: &BFieldParams<T>) -> Vec<u8> {
+    let mut header = header_options()
+        .serialize(&(params.n_hashes, params.marker_width, params.n_marker_bits))
+        .unwrap();
+    encode_other(
+        &mut header,
+        params.other.as_ref(),
+        params.params_version,
+        params.provenance.as_ref(),
+    );
+    header.extend(
+        header_options()
+            .serialize(&(params.value_bits, params.n_keys))
+            .unwrap(),
+    );
+    if params.bitset {
+        header.extend(header_options().serialize(&true).unwrap());
+    }
+    if params.blocked {
+        header[0] |= BLOCKED_FLAG;
+    }
+    if params.secret_check.is_some() || params.seed != 0 {
+        // the fields are read in order, so the ones before have to be
+        // written too
+        if !params.bitset {
+            header.extend(header_options().serialize(&false).unwrap());
+        }
+        header.extend(header_options().serialize(&params.secret_check).unwrap());
+    }
+    if params.secret_check.is_some() {
+        header[0] |= KEYED_FLAG;
+    }
+    if params.seed != 0 {
+        header.extend(header_options().serialize(&params.seed).unwrap());
+        header[0] |= SEEDED_FLAG;
+    }
+    header
+}
+
+/// Decodes the member header, filling in defaults for any fields that were
+/// appended after the file was written.
+pub(crate) fn decode_params<T: DeserializeOwned>(
+    header: &[u8],
+) -> Result<BFieldParams<T>, io::Error> {
+    decode_params_with(header, None)
+}
+
+/// Same as `decode_params`, converting params written with an older version
+/// with `upgrade`.
+pub(crate) fn decode_params_with<T: DeserializeOwned>(
+    header: &[u8],
+    upgrade: Option<&ParamsUpgrade>,
+) -> Result<BFieldParams<T>, io::Error> {
+    let mut cursor = io::Cursor::new(header);
+    let (n_hashes, marker_width, n_marker_bits): (u8, u8, u8) = read_field(&mut cursor)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "BField header is empty"))?;
+    let decoded = decode_other(&mut cursor, upgrade)?;
+    // files written before v0.4 only stored `u32` values
+    let value_bits = read_field(&mut cursor)?.unwrap_or(32);
+    let n_keys = read_field(&mut cursor)?.unwrap_or(0);
+    let bitset = read_field(&mut cursor)?.unwrap_or(false);
+    let secret_check = read_field(&mut cursor)?.flatten();
+    let seed = read_field(&mut cursor)?.unwrap_or(0);
+    if (n_hashes & KEYED_FLAG != 0) != secret_check.is_some()
+        || (n_hashes & SEEDED_FLAG != 0) != (seed != 0)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "BField header is inconsistent about how keys are hashed",
+        ));
+    }
+
+    Ok(BFieldParams {
+        n_hashes: n_hashes & !(BLOCKED_FLAG | KEYED_FLAG | SEEDED_FLAG),
+        marker_width,
+        n_marker_bits,
+        other: decoded.other,
+        params_version: decoded.version,
+        provenance: decoded.provenance,
+        value_bits,
+        n_keys,
+        bitset,
+        blocked: n_hashes & BLOCKED_FLAG != 0,
+        secret_check,
+        secret: None,
+        seed,
+    })
+}
+
+/// Reads the next header field, or `None` if the header ends before it.
+fn read_field<F: DeserializeOwned>(cursor: &mut io::Cursor<&[u8]>) -> Result<Option<F>, io::Error> {
+    if cursor.position() as usize >= cursor.get_ref().len() {
+        return Ok(None);
+    }
+    header_options()
+        .deserialize_from(cursor)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
 impl<T: Clone + DeserializeOwned + Serialize> BFieldMember<T> {
+    #[allow(clippy::too_many_arguments)]
     pub fn create<P: AsRef<Path>>(
         filename: P,
-        in_memory: bool,
+        backing: impl Into<Backing>,
         size: usize,
         n_hashes: u8,
         marker_width: u8,
         n_marker_bits: u8,
+        value_bits: u8,
         other_params: Option<T>,
     ) -> Result<Self, io::Error> {
-        let bf_params = BFieldParams {
+        let bf_params = BFieldParams::new(
             n_hashes,
             marker_width,
             n_marker_bits,
-            other: other_params,
+            value_bits,
+            other_params,
+        );
+        Self::create_with_params(filename.as_ref(), backing.into(), size, bf_params)
+    }
+
+    /// Creates the bit array of a new member with `params`, locking its
+    /// `.bfd` file (if it has one) before writing it.
+    pub(crate) fn create_with_params(
+        filename: &Path,
+        backing: Backing,
+        size: usize,
+        params: BFieldParams<T>,
+    ) -> Result<Self, io::Error> {
+        #[cfg(feature = "mmap")]
+        let lock = match backing {
+            Backing::File => Some(Arc::new(FileLock::acquire(filename, true)?)),
+            _ => None,
         };
+        let storage = Storage::create(backing, filename, size, &encode_params(&params))?;
+        #[allow(unused_mut)]
+        let mut member = BFieldMember::new(filename.to_path_buf(), storage, params);
+        #[cfg(feature = "mmap")]
+        {
+            member.lock = lock;
+        }
+        Ok(member)
+    }
 
-        let bv = if in_memory {
-            MmapBitVec::from_memory(size)?
-        } else {
-            let header: Vec<u8> = serialize(&bf_params).unwrap();
-            MmapBitVec::create(&filename, size, Some(BF_MAGIC), &header)?
+    /// Creates a member in "bitset" mode: each value is a set of up to
+    /// `marker_width` (at most `value_bits`) category bits, written into the marker
+    /// as is instead of being ranked into κ bits. Inserting a key again ORs
+    /// its categories together, and lookups return the union of every
+    /// category inserted for the key (plus, with the false positive rate,
+    /// some of other keys'); keys are never indeterminate.
+    pub fn create_bitset<P: AsRef<Path>>(
+        filename: P,
+        backing: impl Into<Backing>,
+        size: usize,
+        n_hashes: u8,
+        marker_width: u8,
+        value_bits: u8,
+        other_params: Option<T>,
+    ) -> Result<Self, io::Error> {
+        if marker_width == 0 || marker_width > value_bits {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Bitset markers must be between 1 and {} bits wide, got {}",
+                    value_bits, marker_width
+                ),
+            ));
+        }
+        let mut bf_params = BFieldParams::new(n_hashes, marker_width, 0, value_bits, other_params);
+        bf_params.bitset = true;
+        Self::create_with_params(filename.as_ref(), backing.into(), size, bf_params)
+    }
+
+    /// The serialized params, as stored in the file header.
+    pub fn header(&self) -> Vec<u8> {
+        self.encode_header(self.n_keys())
+    }
+
+    fn encode_header(&self, n_keys: u64) -> Vec<u8> {
+        let mut params = self.params.clone();
+        params.n_keys = n_keys;
+        encode_params(&params)
+    }
+
+    /// Number of keys inserted into the member (including masked ones).
+    pub fn n_keys(&self) -> u64 {
+        self.n_keys.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Switches the (empty) member to the blocked layout, see
+    /// `BField::use_blocked_layout`.
+    pub fn use_blocked_layout(&mut self) -> Result<(), io::Error> {
+        self.check_blocked_layout()?;
+        self.params.blocked = true;
+        self.write_header()
+    }
+
+    /// Checks the member can be switched to the blocked layout: it has to be
+    /// empty and hold at least one block.
+    pub fn check_blocked_layout(&self) -> Result<(), io::Error> {
+        if self.n_keys() > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can only change the layout of empty arrays",
+            ));
+        }
+        if self.storage.size() < BLOCK_BITS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Arrays need at least {} bits for the blocked layout, got {}",
+                    BLOCK_BITS,
+                    self.storage.size()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Makes the (empty) member hash keys with `secret`, see
+    /// `BField::use_keyed_hashing`. The header grows to record the secret's
+    /// check value, so a `.bfd` file is created again.
+    pub fn use_keyed_hashing(&mut self, secret: HashSecret) -> Result<(), io::Error> {
+        self.check_hashing_change()?;
+        self.params.secret_check = Some(secret.check());
+        self.params.secret = Some(secret);
+        self.recreate_file()
+    }
+
+    /// Makes the (empty) member hash keys with `seed`, see
+    /// `BField::use_hash_seed`.
+    pub fn use_hash_seed(&mut self, seed: u64) -> Result<(), io::Error> {
+        self.check_hashing_change()?;
+        self.params.seed = seed;
+        self.recreate_file()
+    }
+
+    /// Makes the (empty) member hash keys like the `ix`th member of the
+    /// `BField` whose primary array is `primary`.
+    pub fn use_hashing_of(
+        &mut self,
+        primary: &BFieldMember<T>,
+        ix: usize,
+    ) -> Result<(), io::Error> {
+        if primary.params.secret_check.is_none() && primary.params.seed == 0 {
+            return Ok(());
+        }
+        self.check_hashing_change()?;
+        self.params.secret_check = primary.params.secret_check;
+        self.params.secret = primary.params.secret;
+        self.params.seed = member_seed(primary.params.seed, ix);
+        self.recreate_file()
+    }
+
+    /// Records `provenance` in the header of the (empty) member, see
+    /// `BField::provenance`.
+    pub fn set_provenance(&mut self, provenance: Provenance) -> Result<(), io::Error> {
+        if self.n_keys() > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can only change the provenance of empty arrays",
+            ));
+        }
+        self.params.provenance = Some(provenance);
+        self.recreate_file()
+    }
+
+    /// Checks the way the member hashes keys can be changed: it has to be
+    /// empty, and `n_hashes` can't overlap the flags recording it.
+    pub fn check_hashing_change(&self) -> Result<(), io::Error> {
+        if self.n_keys() > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can only change the hashing of empty arrays",
+            ));
+        }
+        if self.params.n_hashes >= SEEDED_FLAG {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Can only change the hashing of arrays with fewer than {} hashes",
+                    SEEDED_FLAG
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Creates the (empty) member's `.bfd` file again after a change to the
+    /// length of its header, as it can't be rewritten in place.
+    fn recreate_file(&mut self) -> Result<(), io::Error> {
+        if self.storage.is_file() {
+            let size = self.storage.size();
+            // unmap the file before creating it again
+            self.storage = Storage::words(0);
+            self.storage = Storage::create_file(&self.filename, size, &self.header())?;
+        }
+        Ok(())
+    }
+
+    /// Sets the secret the keys of a member read from a file were hashed
+    /// with, checking it against the one recorded in its header.
+    pub fn set_hash_secret(&mut self, secret: HashSecret) -> Result<(), io::Error> {
+        match self.params.secret_check {
+            Some(check) if check == secret.check() => {
+                self.params.secret = Some(secret);
+                Ok(())
+            }
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} was hashed with another secret", self.filename),
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} isn't hashed with a secret", self.filename),
+            )),
+        }
+    }
+
+    /// Rewrites the header of the member's `.bfd` file in place to record
+    /// the current number of keys. Does nothing for members that aren't
+    /// stored in a writable `.bfd` file, or whose file has an older (shorter)
+    /// header that can't be rewritten without moving the bit array.
+    pub fn write_header(&self) -> Result<(), io::Error> {
+        self.rewrite_header().map(|_| ())
+    }
+
+    /// Same as `write_header`, returning whether the header was rewritten.
+    fn rewrite_header(&self) -> Result<bool, io::Error> {
+        if !self.storage.is_file() || !self.storage.is_writable() {
+            return Ok(false);
+        }
+        let header = self.header();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.filename)?;
+        let mut prefix = [0; 4];
+        file.read_exact(&mut prefix)?;
+        if usize::from(u16::from_be_bytes([prefix[2], prefix[3]])) != header.len() {
+            return Ok(false);
+        }
+        file.write_all(&header)?;
+        Ok(true)
+    }
+
+    /// An xxh3 checksum of the header and bit array, used to detect
+    /// corrupted files. The number of keys isn't included, as it's updated
+    /// in place in the header.
+    pub fn checksum(&self) -> u64 {
+        self.hash_contents(0)
+    }
+
+    /// Same as `checksum`, but including the number of keys, i.e. everything
+    /// the member's `.bfd` file holds.
+    pub fn digest(&self) -> u64 {
+        self.hash_contents(self.n_keys())
+    }
+
+    fn hash_contents(&self, n_keys: u64) -> u64 {
+        let storage = self.storage();
+        let mut hasher = Xxh3::new();
+        hasher.update(&self.encode_header(n_keys));
+        hasher.update(&(storage.size() as u64).to_be_bytes());
+        hasher.update(storage.as_bytes());
+        hasher.digest()
+    }
+
+    /// The bit array backing the member.
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    /// See `BField::advise`.
+    pub fn advise(&self, pattern: AccessPattern) -> Result<(), io::Error> {
+        self.storage().advise(pattern)
+    }
+
+    /// See `BField::warm_up`.
+    pub fn warm_up(&self) -> Result<(), io::Error> {
+        self.storage().warm_up()
+    }
+
+    /// See `BField::use_huge_pages`.
+    pub fn use_huge_pages(&self) -> Result<(), io::Error> {
+        self.storage().advise_huge_pages()
+    }
+
+    /// Creates an in-memory member of `size` bits, reading its bit array
+    /// from `reader`.
+    pub fn read_bits<R: Read + ?Sized>(
+        filename: PathBuf,
+        params: BFieldParams<T>,
+        size: usize,
+        reader: &mut R,
+    ) -> Result<Self, io::Error> {
+        let mut storage = Storage::anonymous(size)?;
+        storage.read_from(reader)?;
+        Ok(BFieldMember::new(filename, storage, params))
+    }
+
+    /// Writes the member to `writer` in the same format as a `.bfd` file.
+    pub fn write_to<W: Write + ?Sized>(&self, writer: &mut W) -> Result<(), io::Error> {
+        self.write_to_reporting(writer, &|_| {})
+    }
+
+    /// Same as `write_to`, calling `report` with the number of bytes of the
+    /// bit array written so far after each chunk.
+    fn write_to_reporting<W: Write + ?Sized>(
+        &self,
+        writer: &mut W,
+        report: &dyn Fn(usize),
+    ) -> Result<(), io::Error> {
+        let storage = self.storage();
+        writer.write_all(&encode_member_prefix(&self.header(), storage.size()))?;
+        let mut written = 0;
+        for chunk in storage.as_bytes().chunks(1 << 24) {
+            writer.write_all(chunk)?;
+            written += chunk.len();
+            report(written);
+        }
+        Ok(())
+    }
+
+    /// Writes the member to a new `.bfd` file at `path`, leaving its own
+    /// storage (and filename) as they are.
+    pub fn write_file<P: AsRef<Path>>(&self, path: P) -> Result<(), io::Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.into_inner()?.sync_all()
+    }
+
+    /// Loads a member from the contents of a `.bfd` file, without copying
+    /// them.
+    pub fn from_bytes(bytes: &'static [u8]) -> Result<Self, io::Error> {
+        let image = parse_member(bytes)?;
+        let params = decode_params(image.header)?;
+        check_params(&params, image.size).map_err(|corruption| {
+            io::Error::from(CorruptFile {
+                path: PathBuf::from("<bytes>"),
+                corruption,
+            })
+        })?;
+        let storage = Storage::Region(Region::new(
+            Arc::new(Mapping::Borrowed(bytes)),
+            image.data_offset,
+            image.size,
+        ));
+        Ok(BFieldMember::new(PathBuf::new(), storage, params))
+    }
+
+    /// A read-only handle on the same bit array: `.bfd` files are reopened,
+    /// members of a shared mapping share it, and in-memory members are
+    /// copied.
+    pub fn try_clone(&self) -> Result<Self, io::Error> {
+        let storage = match &self.storage {
+            #[cfg(feature = "mmap")]
+            Storage::Mmap(_) => {
+                Storage::Mmap(MmapBitVec::open(&self.filename, Some(&BF_MAGIC), true)?)
+            }
+            Storage::Region(region) => Storage::Region(region.clone()),
+            storage @ Storage::Words { .. } => {
+                let mut copy = Storage::words(storage.size());
+                copy.read_from(&mut storage.as_bytes())?;
+                copy
+            }
+            storage => {
+                let mut copy = Storage::heap(storage.size());
+                copy.read_from(&mut storage.as_bytes())?;
+                copy
+            }
         };
+        let mut params = self.params.clone();
+        params.n_keys = self.n_keys();
+        #[allow(unused_mut)]
+        let mut member = BFieldMember::new(self.filename.clone(), storage, params);
+        #[cfg(feature = "mmap")]
+        {
+            member.lock = self.lock.clone();
+        }
+        Ok(member)
+    }
 
-        Ok(BFieldMember {
-            filename: filename.as_ref().to_path_buf(),
-            bitvec: BitVec::new(bv),
-            params: bf_params,
+    /// Reads a `.bfd` file into an owned buffer instead of mapping it,
+    /// converting params written with an older version with `upgrade`.
+    pub(crate) fn read_upgraded(
+        filename: &Path,
+        upgrade: Option<&ParamsUpgrade>,
+    ) -> Result<Self, io::Error> {
+        check_member_file::<T>(filename, upgrade)?;
+        Self::read_file_with(filename, |header| decode_params_with(header, upgrade))
+    }
+
+    /// Same as [`BFieldMember::read_upgraded`], with the params decoded from the
+    /// header by `decode` (e.g. to read the headers of older versions).
+    pub(crate) fn read_file_with<F>(filename: &Path, decode: F) -> Result<Self, io::Error>
+    where
+        F: FnOnce(&[u8]) -> Result<BFieldParams<T>, io::Error>,
+    {
+        Self::read_from_with(filename, File::open(filename)?, decode)
+    }
+
+    /// Same as [`BFieldMember::read_upgraded`], retrying the reads that fail
+    /// up to `retries` times (see `LoadOptions::guarded_reads`).
+    pub(crate) fn read_guarded(
+        filename: &Path,
+        upgrade: Option<&ParamsUpgrade>,
+        retries: u32,
+    ) -> Result<Self, io::Error> {
+        check_member_file::<T>(filename, upgrade)?;
+        let reader = RetryingReader::new(|| File::open(filename), retries);
+        Self::read_from_with(filename, reader, |header| {
+            decode_params_with(header, upgrade)
         })
     }
 
+    /// Reads the member in the `.bfd` file at `filename` from `reader`.
+    fn read_from_with<R, F>(filename: &Path, reader: R, decode: F) -> Result<Self, io::Error>
+    where
+        R: Read,
+        F: FnOnce(&[u8]) -> Result<BFieldParams<T>, io::Error>,
+    {
+        let mut reader = BufReader::new(reader);
+        let mut prefix = vec![0; 4];
+        reader.read_exact(&mut prefix)?;
+        let header_len = usize::from(u16::from_be_bytes([prefix[2], prefix[3]]));
+        prefix.resize(4 + header_len + 8, 0);
+        reader.read_exact(&mut prefix[4..])?;
+        let image = parse_member_prefix(&prefix)?;
+        let params = decode(image.header)?;
+        check_params(&params, image.size)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut storage = Storage::heap(image.size);
+        storage.read_from(&mut reader)?;
+        Ok(BFieldMember::new(filename.to_path_buf(), storage, params))
+    }
+
+    /// Maps a `.bfd` file, or reads it into memory without the `mmap`
+    /// feature.
+    #[cfg(any(feature = "zstd", test))]
     pub fn open<P: AsRef<Path>>(filename: P, read_only: bool) -> Result<Self, io::Error> {
-        let bv = MmapBitVec::open(&filename, Some(&BF_MAGIC), read_only)?;
-        let bf_params: BFieldParams<T> = {
-            let header = bv.header();
-            deserialize(header).unwrap()
-        };
+        Self::open_upgraded(filename.as_ref(), read_only, None)
+    }
 
-        Ok(BFieldMember {
-            filename: filename.as_ref().to_path_buf(),
-            bitvec: BitVec::new(bv),
-            params: bf_params,
-        })
+    /// Same as [`BFieldMember::open`], converting params written with an
+    /// older version with `upgrade`.
+    #[cfg(feature = "mmap")]
+    pub(crate) fn open_upgraded(
+        filename: &Path,
+        read_only: bool,
+        upgrade: Option<&ParamsUpgrade>,
+    ) -> Result<Self, io::Error> {
+        let lock = FileLock::acquire(filename, !read_only)?;
+        check_member_file::<T>(filename, upgrade)?;
+        let bv = MmapBitVec::open(filename, Some(&BF_MAGIC), read_only)?;
+        let bf_params: BFieldParams<T> = decode_params_with(bv.header(), upgrade)?;
+
+        let mut member = BFieldMember::new(filename.to_path_buf(), Storage::Mmap(bv), bf_params);
+        member.lock = Some(Arc::new(lock));
+        Ok(member)
+    }
+
+    /// Same as [`BFieldMember::open`], converting params written with an
+    /// older version with `upgrade`.
+    #[cfg(not(feature = "mmap"))]
+    pub(crate) fn open_upgraded(
+        filename: &Path,
+        _read_only: bool,
+        upgrade: Option<&ParamsUpgrade>,
+    ) -> Result<Self, io::Error> {
+        Self::read_upgraded(filename, upgrade)
     }
 
-    pub fn persist_to_disk(self) -> Result<Self, io::Error> {
-        let header: Vec<u8> = serialize(&self.params).unwrap();
-        self.bitvec
-            .get()
-            .save_to_disk(&self.filename, Some(BF_MAGIC), &header)?;
-        let bitvec = BitVec::new(MmapBitVec::open(&self.filename, Some(&BF_MAGIC), false)?);
+    /// Writes the member to its `.bfd` file, calling `report` with the
+    /// number of bytes of the bit array written so far.
+    pub fn persist_to_disk_reporting(mut self, report: &dyn Fn(usize)) -> Result<Self, io::Error> {
+        match &self.storage {
+            #[cfg(feature = "mmap")]
+            Storage::Mmap(bv) => {
+                // the bits are already in the mapped file, so only the header
+                // needs rewriting (truncating a mapped file to write it again
+                // fails on Windows)
+                if !self.rewrite_header()? {
+                    let header: Vec<u8> = self.header();
+                    bv.save_to_disk(&self.filename, Some(BF_MAGIC), &header)?;
+                }
+                report(byte_len(self.storage.size()));
+            }
+            // members of a container are written in place
+            storage @ Storage::Region(_) => {
+                storage.persist()?;
+                return Ok(self);
+            }
+            _ => {
+                #[cfg(feature = "mmap")]
+                let lock = FileLock::acquire(&self.filename, true)?;
+                let mut writer = BufWriter::new(File::create(&self.filename)?);
+                self.write_to_reporting(&mut writer, report)?;
+                writer.into_inner()?.sync_all()?;
+                // members loaded in memory stay there
+                if matches!(self.storage, Storage::Heap { .. }) {
+                    self.storage.set_persisted(true);
+                    return Ok(self);
+                }
+                #[cfg(feature = "mmap")]
+                {
+                    self.lock = Some(Arc::new(lock));
+                }
+            }
+        }
+        self.reopen_mapped()
+    }
+
+    /// Maps the member's `.bfd` file in place of its current storage.
+    #[cfg(feature = "mmap")]
+    fn reopen_mapped(self) -> Result<Self, io::Error> {
+        let storage = Storage::Mmap(MmapBitVec::open(&self.filename, Some(&BF_MAGIC), false)?);
         Ok(Self {
-            bitvec,
+            storage,
             filename: self.filename,
             params: self.params,
+            n_keys: self.n_keys,
+            lock: self.lock,
         })
     }
 
-    pub fn insert(&self, key: &[u8], value: BFieldVal) {
-        // TODO: need to do a check that `value` < allowable range based on
-        // self.params.marker_width and self.params.n_marker_bits
+    /// There are no mapped files without the `mmap` feature.
+    #[cfg(not(feature = "mmap"))]
+    fn reopen_mapped(self) -> Result<Self, io::Error> {
+        Ok(self)
+    }
+
+    /// ORs the bit array of `other` into this member's and adds up their
+    /// numbers of keys. The members must be the same size.
+    pub fn merge(&self, other: &BFieldMember<T>) {
+        self.storage.or_with(&other.storage);
+        self.n_keys
+            .fetch_add(other.n_keys(), AtomicOrdering::Relaxed);
+    }
+}
+
+impl<T, S: BitStorage> BFieldMember<T, S> {
+    pub fn new(filename: PathBuf, storage: S, params: BFieldParams<T>) -> Self {
+        BFieldMember {
+            filename,
+            storage,
+            n_keys: AtomicU64::new(params.n_keys),
+            params,
+            #[cfg(feature = "mmap")]
+            lock: None,
+        }
+    }
+
+    pub fn insert(&self, key: &[u8], value: u64) {
+        self.check_value(value);
+        if self.params.bitset {
+            self.insert_raw(key, u128::from(value))
+        } else if self.is_wide() {
+            self.insert_as::<WideMarker>(key, value)
+        } else {
+            self.insert_as::<u128>(key, value)
+        }
+    }
+
+    /// Same as calling `insert` with each of `keys` and `values`, encoding
+    /// the values together (see `rank_many`).
+    pub fn insert_many(&self, keys: &[&[u8]], values: &[u64]) {
+        assert_eq!(
+            keys.len(),
+            values.len(),
+            "Keys and values of different lengths"
+        );
+        if self.params.bitset || self.is_wide() {
+            for (key, &value) in keys.iter().zip(values) {
+                self.insert(key, value);
+            }
+            return;
+        }
+        for &value in values {
+            self.check_value(value);
+        }
+        let markers = rank_many(values, self.params.n_marker_bits);
+        for (key, marker) in keys.iter().zip(markers) {
+            self.insert_raw(key, marker);
+        }
+    }
+
+    /// Same as `insert_many`, but works out the positions of all the markers
+    /// first and writes them in ascending order, so a disk-backed array is
+    /// written mostly front to back instead of at random. Buffers a position
+    /// and a marker index for each of the `n_hashes` markers of every key.
+    pub fn insert_many_sorted(&self, keys: &[&[u8]], values: &[u64]) {
+        assert_eq!(
+            keys.len(),
+            values.len(),
+            "Keys and values of different lengths"
+        );
+        for &value in values {
+            self.check_value(value);
+        }
         let k = self.params.n_marker_bits;
-        self.insert_raw(key, rank(value as usize, k));
+        if self.params.bitset {
+            self.write_sorted(
+                keys,
+                values.iter().map(|&value| u128::from(value)).collect(),
+            );
+        } else if self.is_wide() {
+            let markers = values.iter().map(|&value| WideMarker::rank(value, k));
+            self.write_sorted(keys, markers.collect());
+        } else {
+            self.write_sorted(keys, rank_many(values, k));
+        }
+    }
+
+    fn write_sorted<M: Marker>(&self, keys: &[&[u8]], markers: Vec<M>) {
+        let n_hashes = self.params.n_hashes as usize;
+        let mut writes = Vec::with_capacity(keys.len() * n_hashes);
+        for (key_ix, key) in keys.iter().enumerate() {
+            let hash = self.hash(key);
+            for marker_ix in 0..n_hashes {
+                writes.push((self.marker_pos(hash, marker_ix), key_ix));
+            }
+        }
+        writes.sort_unstable();
+        let marker_width = self.params.marker_width as usize;
+        for (pos, key_ix) in writes {
+            M::write(&self.storage, pos, marker_width, markers[key_ix]);
+        }
+        self.n_keys
+            .fetch_add(keys.len() as u64, AtomicOrdering::Relaxed);
+    }
+
+    /// The position of the `marker_ix`th marker of the key with `hash`.
+    #[inline]
+    fn marker_pos(&self, hash: (u64, u64), marker_ix: usize) -> usize {
+        let marker_width = self.params.marker_width as usize;
+        if self.params.blocked {
+            blocked_marker_pos(hash, marker_ix, self.storage.size(), marker_width)
+        } else {
+            marker_pos(hash, marker_ix, self.storage.size(), marker_width)
+        }
+    }
+
+    /// The 128-bit hash of `key` its marker positions are derived from.
+    #[inline]
+    pub fn hash(&self, key: &[u8]) -> (u64, u64) {
+        match &self.params.secret {
+            Some(secret) => secret.hash(key, self.params.seed),
+            None => hash_key(key, self.params.seed),
+        }
+    }
+
+    /// The seed keys are hashed with, 0 by default.
+    pub fn hash_seed(&self) -> u64 {
+        self.params.seed
+    }
+
+    /// Whether keys are hashed with a secret, see `BField::use_keyed_hashing`.
+    pub fn is_keyed(&self) -> bool {
+        self.params.secret_check.is_some()
+    }
+
+    /// The secret keys are hashed with, if it's known.
+    pub fn hash_secret(&self) -> Option<HashSecret> {
+        self.params.secret
+    }
+
+    /// Hints that the markers of the key with `hash` are about to be read.
+    #[inline]
+    pub fn prefetch_hashed(&self, hash: (u64, u64)) {
+        for marker_ix in 0..self.params.n_hashes as usize {
+            self.storage.prefetch(self.marker_pos(hash, marker_ix));
+        }
+    }
+
+    /// Whether the markers of each key are confined to one block, see
+    /// `BField::use_blocked_layout`.
+    #[inline]
+    pub fn is_blocked(&self) -> bool {
+        self.params.blocked
+    }
+
+    /// Whether the member stores bitsets, see `create_bitset`.
+    #[inline]
+    pub fn is_bitset(&self) -> bool {
+        self.params.bitset
+    }
+
+    /// Whether markers are too wide to fit in a `u128`.
+    #[inline]
+    fn is_wide(&self) -> bool {
+        self.params.marker_width > 128
+    }
+
+    /// The largest value that can be stored in this member.
+    #[inline]
+    pub fn max_value(&self) -> u64 {
+        if self.params.bitset {
+            return u64::MAX >> (64 - u32::from(self.params.marker_width));
+        }
+        capacity(self.params.marker_width, self.params.n_marker_bits)
+    }
+
+    /// Returns an error if `value` can't be encoded in a ν-wide marker with
+    /// κ bits set, i.e. if `value >= choose(marker_width, n_marker_bits)`
+    /// (or if it has bits past the marker width, for bitset members).
+    pub fn try_check_value(&self, value: u64) -> Result<(), RankError> {
+        let max_value = self.max_value();
+        if self.params.bitset && value > max_value {
+            return Err(RankError::ValueOutOfRange {
+                value,
+                marker_width: self.params.marker_width,
+                n_marker_bits: self.params.n_marker_bits,
+                max_value,
+            });
+        } else if self.params.bitset {
+            return Ok(());
+        }
+        check_rank(value, self.params.marker_width, self.params.n_marker_bits)
+    }
+
+    /// Panics if `value` can't be encoded, see `try_check_value`.
+    #[inline]
+    fn check_value(&self, value: u64) {
+        if let Err(e) = self.try_check_value(value) {
+            panic!("{}", e);
+        }
+    }
+
+    #[inline]
+    fn insert_as<M: Marker>(&self, key: &[u8], value: u64) {
+        self.insert_raw(key, M::rank(value, self.params.n_marker_bits));
+    }
+
+    /// Makes `key` indeterminate in this member if it's stored in it, by
+    /// flipping an extra bit of its marker (with the same caveat as
+    /// `mask_or_insert`). Returns whether it was stored; bitset members can't
+    /// mask keys, so this always returns `false` for them.
+    pub fn mask(&self, key: &[u8]) -> bool {
+        if self.params.bitset {
+            false
+        } else if self.is_wide() {
+            self.mask_as::<WideMarker>(key)
+        } else {
+            self.mask_as::<u128>(key)
+        }
+    }
+
+    fn mask_as<M: Marker>(&self, key: &[u8]) -> bool {
+        let k = u32::from(self.params.n_marker_bits);
+        let existing_marker: M = self.get_raw(key, k);
+        if existing_marker.count_ones() != k {
+            return false;
+        }
+        self.mask_marker(key, existing_marker);
+        true
+    }
+
+    /// Overwrites `existing_marker` (a valid one) with a marker that has an
+    /// extra bit set, so `key` becomes indeterminate.
+    fn mask_marker<M: Marker>(&self, key: &[u8], existing_marker: M) {
+        let k = u32::from(self.params.n_marker_bits);
+        // try to find a new, invalid marker that has an extra
+        // bit over the existing marker so that it'll become
+        // indeterminate once we overwrite it
+        let mut pos = 0;
+        let mut new_marker = existing_marker;
+        while new_marker.count_ones() == k {
+            new_marker = existing_marker.with_bit(pos);
+            pos += 1;
+        }
+        // mask out the existing!
+        self.insert_raw(key, new_marker);
     }
 
     #[inline]
-    fn insert_raw(&self, key: &[u8], marker: u128) {
+    fn insert_raw<M: Marker>(&self, key: &[u8], marker: M) {
+        self.n_keys.fetch_add(1, AtomicOrdering::Relaxed);
         let marker_width = self.params.marker_width as usize;
-        let hash = murmurhash3_x64_128(key, 0);
+        let hash = self.hash(key);
 
         for marker_ix in 0usize..self.params.n_hashes as usize {
-            let pos = marker_pos(hash, marker_ix, self.bitvec.get().size(), marker_width);
-            self.bitvec.get().set_range(pos..pos + marker_width, marker);
+            let pos = self.marker_pos(hash, marker_ix);
+            M::write(&self.storage, pos, marker_width, marker);
         }
     }
 
@@ -155,11 +1085,23 @@ impl<T: Clone + DeserializeOwned + Serialize> BFieldMember<T> {
     ///
     /// Returns `true` if the value was inserted or was already present with
     /// the correct value; `false` if masking occured or if it was already
-    /// indeterminate.
-    pub fn mask_or_insert(&self, key: &[u8], value: BFieldVal) -> bool {
-        let correct_marker = rank(value as usize, self.params.n_marker_bits);
+    /// indeterminate. Bitset members add the value's categories instead.
+    pub fn mask_or_insert(&self, key: &[u8], value: u64) -> bool {
+        self.check_value(value);
+        if self.params.bitset {
+            self.insert_raw(key, u128::from(value));
+            true
+        } else if self.is_wide() {
+            self.mask_or_insert_as::<WideMarker>(key, value)
+        } else {
+            self.mask_or_insert_as::<u128>(key, value)
+        }
+    }
+
+    fn mask_or_insert_as<M: Marker>(&self, key: &[u8], value: u64) -> bool {
+        let correct_marker = M::rank(value, self.params.n_marker_bits);
         let k = u32::from(self.params.n_marker_bits);
-        let existing_marker = self.get_raw(key, k);
+        let existing_marker: M = self.get_raw(key, k);
 
         match existing_marker.count_ones().cmp(&k) {
             Ordering::Greater => false, // already indeterminate
@@ -168,17 +1110,7 @@ impl<T: Clone + DeserializeOwned + Serialize> BFieldMember<T> {
                 if existing_marker == correct_marker {
                     return true;
                 }
-                // try to find a new, invalid marker that has an extra
-                // bit over the existing marker so that it'll become
-                // indeterminate once we overwrite it
-                let mut pos = 0;
-                let mut new_marker = existing_marker;
-                while new_marker.count_ones() == k {
-                    new_marker = existing_marker | (1 << pos);
-                    pos += 1;
-                }
-                // mask out the existing!
-                self.insert_raw(key, new_marker);
+                self.mask_marker(key, existing_marker);
                 false
             }
             Ordering::Less => {
@@ -191,38 +1123,134 @@ impl<T: Clone + DeserializeOwned + Serialize> BFieldMember<T> {
 
     #[inline]
     pub fn get(&self, key: &[u8]) -> BFieldLookup {
+        self.get_hashed(self.hash(key))
+    }
+
+    /// Same as `get`, given the key's hash.
+    pub fn get_hashed(&self, hash: (u64, u64)) -> BFieldLookup {
+        if self.params.bitset {
+            match self.get_raw_hashed::<u128>(hash, 1) {
+                0 => BFieldLookup::None,
+                bits => BFieldLookup::Some(bits as u64),
+            }
+        } else if self.is_wide() {
+            self.get_as::<WideMarker>(hash)
+        } else {
+            self.get_as::<u128>(hash)
+        }
+    }
+
+    #[inline]
+    fn get_as<M: Marker>(&self, hash: (u64, u64)) -> BFieldLookup {
         let k = u32::from(self.params.n_marker_bits);
-        let putative_marker = self.get_raw(key, k);
+        let putative_marker: M = self.get_raw_hashed(hash, k);
         match putative_marker.count_ones().cmp(&k) {
             Ordering::Greater => BFieldLookup::Indeterminate,
-            Ordering::Equal => BFieldLookup::Some(unrank(putative_marker) as u32),
+            Ordering::Equal => BFieldLookup::Some(putative_marker.unrank()),
             Ordering::Less => BFieldLookup::None,
         }
     }
 
+    /// Same as `get`, without decoding the value.
+    pub fn contains(&self, key: &[u8]) -> Tristate {
+        if self.params.bitset {
+            match self.get_raw::<u128>(key, 1) {
+                0 => Tristate::Absent,
+                _ => Tristate::Present,
+            }
+        } else if self.is_wide() {
+            self.contains_as::<WideMarker>(key)
+        } else {
+            self.contains_as::<u128>(key)
+        }
+    }
+
+    #[inline]
+    fn contains_as<M: Marker>(&self, key: &[u8]) -> Tristate {
+        let k = u32::from(self.params.n_marker_bits);
+        let putative_marker: M = self.get_raw(key, k);
+        match putative_marker.count_ones().cmp(&k) {
+            Ordering::Greater => Tristate::Indeterminate,
+            Ordering::Equal => Tristate::Present,
+            Ordering::Less => Tristate::Absent,
+        }
+    }
+
+    /// Given the hash of a key, returns the value stored for it if there's
+    /// one, and otherwise inserts `value`, computing the key's positions only
+    /// once.
+    pub fn get_or_insert(&self, hash: (u64, u64), value: u64) -> Option<u64> {
+        self.check_value(value);
+        if self.params.bitset {
+            if let BFieldLookup::Some(bits) = self.get_hashed(hash) {
+                return Some(bits);
+            }
+            let marker_width = self.params.marker_width as usize;
+            self.n_keys.fetch_add(1, AtomicOrdering::Relaxed);
+            for marker_ix in 0usize..self.params.n_hashes as usize {
+                let pos = self.marker_pos(hash, marker_ix);
+                u128::write(&self.storage, pos, marker_width, u128::from(value));
+            }
+            None
+        } else if self.is_wide() {
+            self.get_or_insert_as::<WideMarker>(hash, value)
+        } else {
+            self.get_or_insert_as::<u128>(hash, value)
+        }
+    }
+
+    fn get_or_insert_as<M: Marker>(&self, hash: (u64, u64), value: u64) -> Option<u64> {
+        assert!(self.params.n_hashes <= 16);
+        let n_hashes = self.params.n_hashes as usize;
+        let marker_width = self.params.marker_width as usize;
+        let mut positions: [usize; 16] = [0; 16];
+        let mut merged_marker = M::ONES;
+        for (marker_ix, pos) in positions.iter_mut().take(n_hashes).enumerate() {
+            *pos = self.marker_pos(hash, marker_ix);
+            merged_marker = merged_marker.and(M::read(&self.storage, *pos, marker_width));
+        }
+        if merged_marker.count_ones() == u32::from(self.params.n_marker_bits) {
+            return Some(merged_marker.unrank());
+        }
+        self.n_keys.fetch_add(1, AtomicOrdering::Relaxed);
+        let marker = M::rank(value, self.params.n_marker_bits);
+        for pos in positions.iter().take(n_hashes) {
+            M::write(&self.storage, *pos, marker_width, marker);
+        }
+        None
+    }
+
     #[inline]
-    fn get_raw(&self, key: &[u8], k: u32) -> u128 {
+    fn get_raw<M: Marker>(&self, key: &[u8], k: u32) -> M {
+        self.get_raw_hashed(self.hash(key), k)
+    }
+
+    #[inline]
+    fn get_raw_hashed<M: Marker>(&self, hash: (u64, u64), k: u32) -> M {
         assert!(self.params.n_hashes <= 16);
         let marker_width = self.params.marker_width as usize;
-        let hash = murmurhash3_x64_128(key, 0);
-        let mut merged_marker = u128::MAX;
+        let mut merged_marker = M::ONES;
         let mut positions: [usize; 16] = [0; 16]; // support up to 16 hashes
         #[allow(clippy::needless_range_loop)]
         for marker_ix in 0usize..self.params.n_hashes as usize {
-            let pos = marker_pos(hash, marker_ix, self.bitvec.get().size(), marker_width);
+            let pos = self.marker_pos(hash, marker_ix);
             positions[marker_ix] = pos;
-            unsafe {
-                let byte_idx_st = pos >> 3;
-                let ptr: *const u8 = self.bitvec.get().mmap.as_ptr().add(byte_idx_st);
-                prefetch_read(ptr);
-            }
+            self.storage.prefetch(pos);
+        }
+        let positions = &positions[..self.params.n_hashes as usize];
+        if let Some(merged_marker) = M::read_and(&self.storage, positions, marker_width) {
+            return if merged_marker.count_ones() < k {
+                M::ZERO
+            } else {
+                merged_marker
+            };
         }
 
-        for pos in positions.iter().take(self.params.n_hashes as usize) {
-            let marker = self.bitvec.get().get_range(*pos..*pos + marker_width);
-            merged_marker &= marker;
+        for pos in positions {
+            let marker = M::read(&self.storage, *pos, marker_width);
+            merged_marker = merged_marker.and(marker);
             if merged_marker.count_ones() < k {
-                return 0;
+                return M::ZERO;
             }
         }
         merged_marker
@@ -230,7 +1258,7 @@ impl<T: Clone + DeserializeOwned + Serialize> BFieldMember<T> {
 
     pub fn info(&self) -> (usize, u8, u8, u8) {
         (
-            self.bitvec.get().size(),
+            self.storage.size(),
             self.params.n_hashes,
             self.params.marker_width,
             self.params.n_marker_bits,
@@ -238,19 +1266,26 @@ impl<T: Clone + DeserializeOwned + Serialize> BFieldMember<T> {
     }
 }
 
-#[inline]
-fn marker_pos(hash: (u64, u64), n: usize, total_size: usize, marker_size: usize) -> usize {
-    ((hash.0 as usize).wrapping_add(n.wrapping_mul(hash.1 as usize))) % (total_size - marker_size)
+impl<T: fmt::Debug> fmt::Debug for BFieldMember<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BFieldMember")
+            .field("filename", &self.filename)
+            .field("storage", &self.storage.kind())
+            .field("size", &self.storage.size())
+            .field("params", &self.params)
+            .finish()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use murmurhash3::murmurhash3_x64_128;
 
     #[test]
     fn test_bfield() {
         let bfield: BFieldMember<usize> =
-            BFieldMember::create("test", true, 1024, 3, 64, 4, None).unwrap();
+            BFieldMember::create("test", true, 1024, 3, 64, 4, 32, None).unwrap();
         // check that inserting keys adds new entries
         bfield.insert(b"test", 2);
         assert_eq!(bfield.get(b"test"), BFieldLookup::Some(2));
@@ -267,7 +1302,7 @@ mod tests {
         // comically small bfield with too many (16) hashes
         // and too many bits (8) to cause saturation
         let bfield: BFieldMember<usize> =
-            BFieldMember::create("test", true, 128, 16, 64, 8, None).unwrap();
+            BFieldMember::create("test", true, 128, 16, 64, 8, 32, None).unwrap();
 
         bfield.insert(b"test", 100);
         assert_eq!(bfield.get(b"test"), BFieldLookup::Indeterminate);
@@ -276,20 +1311,20 @@ mod tests {
     #[test]
     fn test_bfield_bits_set() {
         let bfield: BFieldMember<usize> =
-            BFieldMember::create("test", true, 128, 2, 16, 4, None).unwrap();
+            BFieldMember::create("test", true, 128, 2, 16, 4, 32, None).unwrap();
 
         bfield.insert(b"test", 100);
-        assert_eq!(bfield.bitvec.get().rank(0..128), 8);
+        assert_eq!(bfield.storage.rank(0..128), 8);
         bfield.insert(b"test2", 200);
-        assert_eq!(bfield.bitvec.get().rank(0..128), 16);
+        assert_eq!(bfield.storage.rank(0..128), 16);
         bfield.insert(b"test3", 300);
-        assert!(bfield.bitvec.get().rank(0..128) < 24); // 23 bits set
+        assert!(bfield.storage.rank(0..128) < 24); // 23 bits set
     }
 
     #[test]
     fn test_bfield_mask_or_insert() {
         let bfield: BFieldMember<usize> =
-            BFieldMember::create("test", true, 1024, 2, 16, 4, None).unwrap();
+            BFieldMember::create("test", true, 1024, 2, 16, 4, 32, None).unwrap();
 
         bfield.insert(b"test", 2);
         assert_eq!(bfield.get(b"test"), BFieldLookup::Some(2));
@@ -310,4 +1345,288 @@ mod tests {
         assert_eq!(bfield.mask_or_insert(b"test2", 2), true);
         assert_eq!(bfield.get(b"test2"), BFieldLookup::Some(2));
     }
+
+    #[test]
+    fn test_bfield_value_range() {
+        // 16 choose 4 = 1820 possible values
+        let bfield: BFieldMember<usize> =
+            BFieldMember::create("test", true, 1024, 2, 16, 4, 32, None).unwrap();
+        bfield.insert(b"test", 1819);
+        assert_eq!(bfield.get(b"test"), BFieldLookup::Some(1819));
+    }
+
+    #[test]
+    #[should_panic(expected = "value 1820 is out of range")]
+    fn test_bfield_value_out_of_range() {
+        let bfield: BFieldMember<usize> =
+            BFieldMember::create("test", true, 1024, 2, 16, 4, 32, None).unwrap();
+        bfield.insert(b"test", 1820);
+    }
+
+    #[test]
+    #[should_panic(expected = "value 1820 is out of range")]
+    fn test_bfield_mask_or_insert_value_out_of_range() {
+        let bfield: BFieldMember<usize> =
+            BFieldMember::create("test", true, 1024, 2, 16, 4, 32, None).unwrap();
+        bfield.mask_or_insert(b"test", 1820);
+    }
+
+    #[test]
+    fn test_bfield_large_values() {
+        // 128 choose 9 is ~2.3e13, which doesn't fit in a u32
+        let bfield: BFieldMember<usize> =
+            BFieldMember::create("test", true, 4096, 3, 128, 9, 64, None).unwrap();
+        let value = u64::from(u32::MAX) * 1000;
+        bfield.insert(b"test", value);
+        assert_eq!(bfield.get(b"test"), BFieldLookup::Some(value));
+    }
+
+    #[test]
+    fn test_bfield_wide_markers() {
+        let bfield: BFieldMember<usize> =
+            BFieldMember::create("test", true, 8192, 3, 200, 3, 32, None).unwrap();
+        // 200 choose 3 = 1313400; the largest values use the top bits
+        bfield.insert(b"test", 1_313_399);
+        assert_eq!(bfield.get(b"test"), BFieldLookup::Some(1_313_399));
+        bfield.insert(b"test2", 5);
+        assert_eq!(bfield.get(b"test2"), BFieldLookup::Some(5));
+        assert_eq!(bfield.get(b"test3"), BFieldLookup::None);
+
+        assert_eq!(bfield.mask_or_insert(b"test", 1_313_399), true);
+        assert_eq!(bfield.mask_or_insert(b"test", 6), false);
+        assert_eq!(bfield.get(b"test"), BFieldLookup::Indeterminate);
+    }
+
+    #[test]
+    fn test_insert_many_sorted() {
+        let keys: Vec<&[u8]> = vec![b"test", b"test2", b"test3"];
+        let values = [1_313_399, 5, 70];
+        let sorted: BFieldMember<usize> =
+            BFieldMember::create("test", true, 8192, 3, 200, 3, 32, None).unwrap();
+        let unsorted: BFieldMember<usize> =
+            BFieldMember::create("test", true, 8192, 3, 200, 3, 32, None).unwrap();
+        sorted.insert_many_sorted(&keys, &values);
+        for (key, &value) in keys.iter().zip(&values) {
+            unsorted.insert(key, value);
+            assert_eq!(sorted.get(key), BFieldLookup::Some(value));
+        }
+        assert_eq!(sorted.storage().as_bytes(), unsorted.storage().as_bytes());
+        assert_eq!(sorted.n_keys(), 3);
+    }
+
+    #[test]
+    fn test_member_seed() {
+        assert_eq!(member_seed(0, 3), 0);
+        assert_eq!(member_seed(42, 0), 42);
+        let seeds: Vec<u64> = (0..100).map(|ix| member_seed(42, ix)).collect();
+        for (ix, seed) in seeds.iter().enumerate() {
+            assert_ne!(*seed, 0);
+            assert!(!seeds[..ix].contains(seed));
+        }
+    }
+
+    #[test]
+    fn test_blocked_layout() {
+        let mut member: BFieldMember<usize> =
+            BFieldMember::create("test", true, 100_000, 10, 39, 4, 32, None).unwrap();
+        member.use_blocked_layout().unwrap();
+        for key in 0..100u32 {
+            let hash = hash_key(&key.to_be_bytes(), 0);
+            let block = member.marker_pos(hash, 0) / BLOCK_BITS;
+            for marker_ix in 1..10 {
+                let pos = member.marker_pos(hash, marker_ix);
+                assert_eq!(pos / BLOCK_BITS, block);
+                assert!(pos % BLOCK_BITS + 39 <= BLOCK_BITS);
+            }
+            member.insert(&key.to_be_bytes(), u64::from(key));
+        }
+        for key in 0..100u32 {
+            assert_eq!(
+                member.get(&key.to_be_bytes()),
+                BFieldLookup::Some(u64::from(key))
+            );
+        }
+        assert_eq!(member.header()[0], 10 | BLOCKED_FLAG);
+    }
+
+    #[test]
+    fn test_decode_legacy_params() {
+        // v0.3 headers stop after `other`
+        let legacy = bincode::serialize(&(3u8, 64u8, 4u8, Some(12usize))).unwrap();
+        let params: BFieldParams<usize> = decode_params(&legacy).unwrap();
+        assert_eq!(params.n_hashes, 3);
+        assert_eq!(params.other, Some(12));
+        assert_eq!(params.value_bits, 32);
+        assert_eq!(params.n_keys, 0);
+
+        let params = BFieldParams {
+            n_hashes: 3,
+            marker_width: 64,
+            n_marker_bits: 4,
+            other: Some(12usize),
+            params_version: 0,
+            provenance: None,
+            value_bits: 64,
+            n_keys: 7,
+            bitset: false,
+            blocked: true,
+            secret_check: None,
+            secret: None,
+            seed: 0,
+        };
+        let decoded: BFieldParams<usize> = decode_params(&encode_params(&params)).unwrap();
+        assert_eq!(decoded.value_bits, 64);
+        assert_eq!(decoded.n_keys, 7);
+        assert_eq!((decoded.n_hashes, decoded.blocked), (3, true));
+        assert_eq!(decoded.secret_check, None);
+
+        let keyed = BFieldParams {
+            secret_check: Some(42),
+            secret: Some(HashSecret::new([1; 16])),
+            ..params.clone()
+        };
+        let decoded: BFieldParams<usize> = decode_params(&encode_params(&keyed)).unwrap();
+        assert_eq!((decoded.n_hashes, decoded.bitset), (3, false));
+        assert_eq!(decoded.secret_check, Some(42));
+        // the secret itself isn't written
+        assert!(decoded.secret.is_none());
+
+        let seeded = BFieldParams { seed: 7, ..params };
+        let header = encode_params(&seeded);
+        // the seed ends the header, where `BFieldView` looks for it
+        assert_eq!(header[header.len() - 8..], 7u64.to_le_bytes());
+        let decoded: BFieldParams<usize> = decode_params(&header).unwrap();
+        assert_eq!((decoded.n_hashes, decoded.seed), (3, 7));
+        assert_eq!(decoded.secret_check, None);
+    }
+
+    #[test]
+    fn test_portable_layout() {
+        // the header is little-endian with `usize` written as a `u64`,
+        // whatever the platform
+        let params = BFieldParams::new(3, 64, 4, 32, Some(0x0102usize));
+        let header = encode_params(&params);
+        assert_eq!(
+            header,
+            [
+                3, 64, 4, 2, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, b'2', b'5', b'8', 32, 0, 0, 0, 0,
+                0, 0, 0, 0
+            ]
+        );
+
+        // a file assembled byte by byte: the size is big-endian and bits are
+        // numbered from the most significant bit of each byte
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("test.bfd");
+        let mut image = vec![0xBF, 0x1D, 0, header.len() as u8];
+        image.extend_from_slice(&header);
+        image.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 128]);
+        image.extend_from_slice(&[0b1010_0000, 0b0000_0101]);
+        image.extend_from_slice(&[0; 14]);
+        std::fs::write(&path, &image).unwrap();
+
+        let member: BFieldMember<usize> = BFieldMember::open(&path, true).unwrap();
+        assert_eq!(member.info(), (128, 3, 64, 4));
+        assert_eq!(member.params.other, Some(0x0102));
+        assert_eq!(member.storage().get_range(0..16), 0b1010_0000_0000_0101);
+        assert_eq!(member.header(), header);
+    }
+
+    #[test]
+    fn test_marker_pos_uses_64_bit_arithmetic() {
+        let hash = (u64::MAX - 1, 0x8000_0000_0000_0001);
+        let expected =
+            (u128::from(hash.0) + 3 * u128::from(hash.1)) % (1u128 << 64) % (1_000_000 - 39);
+        assert_eq!(marker_pos(hash, 3, 1_000_000, 39) as u128, expected);
+    }
+
+    #[test]
+    fn test_bfield_mask() {
+        let bfield: BFieldMember<usize> =
+            BFieldMember::create("test", true, 1024, 2, 16, 4, 32, None).unwrap();
+        assert!(!bfield.mask(b"test"));
+        bfield.insert(b"test", 2);
+        assert!(bfield.mask(b"test"));
+        assert_eq!(bfield.get(b"test"), BFieldLookup::Indeterminate);
+        assert!(!bfield.mask(b"test"));
+    }
+
+    #[test]
+    fn test_bfield_bitset() {
+        let bfield: BFieldMember<usize> =
+            BFieldMember::create_bitset("test", true, 4096, 3, 8, 32, None).unwrap();
+        assert!(bfield.is_bitset());
+        assert_eq!(bfield.max_value(), 255);
+        bfield.insert(b"test", 0b0000_0011);
+        assert_eq!(bfield.get(b"test"), BFieldLookup::Some(0b0000_0011));
+        // categories are ORed together instead of masking the key
+        assert!(bfield.mask_or_insert(b"test", 0b0100_0000));
+        assert_eq!(bfield.get(b"test"), BFieldLookup::Some(0b0100_0011));
+        assert_eq!(bfield.contains(b"test"), Tristate::Present);
+        assert!(!bfield.mask(b"test"));
+        assert_eq!(bfield.get(b"test2"), BFieldLookup::None);
+        assert_eq!(bfield.contains(b"test2"), Tristate::Absent);
+
+        let hash = murmurhash3_x64_128(b"test2", 0);
+        assert_eq!(bfield.get_or_insert(hash, 0b1000_0000), None);
+        assert_eq!(bfield.get_or_insert(hash, 1), Some(0b1000_0000));
+
+        let params: BFieldParams<usize> = decode_params(&bfield.header()).unwrap();
+        assert!(params.bitset);
+        assert_eq!(params.n_keys, 3);
+        assert!(BFieldMember::<usize>::create_bitset("test", true, 4096, 3, 33, 32, None).is_err());
+    }
+
+    /// A (slow) backend storing each bit in its own `bool`.
+    struct Bools(std::sync::Mutex<Vec<bool>>);
+
+    impl BitStorage for Bools {
+        fn size(&self) -> usize {
+            self.0.lock().unwrap().len()
+        }
+
+        fn get_range(&self, r: std::ops::Range<usize>) -> u128 {
+            let bits = self.0.lock().unwrap();
+            r.fold(0, |x, i| (x << 1) | u128::from(bits[i]))
+        }
+
+        fn set_range(&self, r: std::ops::Range<usize>, x: u128) {
+            let mut bits = self.0.lock().unwrap();
+            let end = r.end;
+            for i in r {
+                bits[i] |= (x >> (end - 1 - i)) & 1 == 1;
+            }
+        }
+
+        fn persist(&self) -> Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_alternative_backend() {
+        let params = || BFieldParams::new(3, 200, 4, 32, None::<usize>);
+        let bools = BFieldMember::new(
+            PathBuf::new(),
+            Bools(std::sync::Mutex::new(vec![false; 4096])),
+            params(),
+        );
+        let storage = BFieldMember::new(PathBuf::new(), Storage::heap(4096), params());
+        for i in 0..40u64 {
+            let key = i.to_be_bytes();
+            bools.insert(&key, i * 1_000);
+            storage.insert(&key, i * 1_000);
+        }
+        for i in 0..80u64 {
+            let key = i.to_be_bytes();
+            assert_eq!(bools.get(&key), storage.get(&key));
+        }
+        // both backends lay the bits out the same way
+        for (i, &bit) in bools.storage.0.lock().unwrap().iter().enumerate() {
+            assert_eq!(bit, Storage::get_range(&storage.storage, i..i + 1) == 1);
+        }
+        assert!(bools.mask(&1u64.to_be_bytes()));
+        assert_eq!(bools.get(&1u64.to_be_bytes()), BFieldLookup::Indeterminate);
+        assert_eq!(bools.info(), (4096, 3, 200, 4));
+    }
 }
@@ -0,0 +1,367 @@
+//! `bfield build`: builds a B-field from a file of key-value pairs, with
+//! either explicit parameters or ones derived from the input.
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+
+use bfield::{capacity, choose, BField, BFieldVal, Progress, ScalingOptions};
+use clap::Args;
+
+/// Smallest primary array picked by `auto_params`, in bits
+const MIN_AUTO_SIZE: usize = 4096;
+
+#[derive(Args, Debug)]
+pub struct BuildArgs {
+    /// File of `key<TAB>value` lines (or comma-separated with `--csv`);
+    /// values are integers up to 2^32 - 1
+    input: PathBuf,
+    /// Directory to write the B-field to
+    #[arg(short, long, default_value = ".")]
+    output: PathBuf,
+    /// Name of the B-field, whose arrays are written to `NAME.0.bfd`,
+    /// `NAME.1.bfd`, etc.
+    #[arg(short, long, default_value = "bfield")]
+    name: String,
+    /// Read comma-separated values instead of tab-separated ones
+    #[arg(long)]
+    csv: bool,
+    /// Skip the first line of the input
+    #[arg(long)]
+    header: bool,
+    /// Target false positive rate, used to derive the parameters when
+    /// `--size` isn't given (secondary arrays are then added until no key
+    /// is indeterminate)
+    #[arg(long, default_value_t = 0.001)]
+    false_positive_rate: f64,
+    /// Size in bits of the primary array
+    #[arg(long, requires_all = ["n_hashes", "marker_width", "n_marker_bits"])]
+    size: Option<usize>,
+    /// Number of hash functions (k)
+    #[arg(long, requires = "size")]
+    n_hashes: Option<u8>,
+    /// Length of the markers (ν)
+    #[arg(long, requires = "size")]
+    marker_width: Option<u8>,
+    /// Number of bits set in each marker (κ)
+    #[arg(long, requires = "size")]
+    n_marker_bits: Option<u8>,
+    /// Scaling factor (β) from each array to the next one
+    #[arg(long, default_value_t = 0.1, requires = "size")]
+    secondary_scaledown: f64,
+    /// Smallest size of a secondary array, as a fraction of the primary one
+    #[arg(long, default_value_t = 0.025, requires = "size")]
+    max_scaledown: f64,
+    /// Number of arrays, including the primary one
+    #[arg(long, default_value_t = 4, requires = "size")]
+    n_secondaries: u8,
+    /// Seed to hash keys with (0 is the default hashing), e.g. to build
+    /// again after an unlucky build left too many keys indeterminate
+    #[arg(long, conflicts_with = "random_seed")]
+    seed: Option<u64>,
+    /// Hash keys with a random seed, which is printed
+    #[arg(long)]
+    random_seed: bool,
+}
+
+/// Sets the seed asked for with `--seed` or `--random-seed`.
+fn use_seed(bfield: &mut BField<String>, args: &BuildArgs) -> io::Result<()> {
+    if let Some(seed) = args.seed {
+        bfield.use_hash_seed(seed)?;
+    } else if args.random_seed {
+        let seed = bfield.use_random_hash_seed()?;
+        eprintln!("Using the hash seed {}", seed);
+    }
+    Ok(())
+}
+
+/// The pairs of the input file, which is read again on each pass.
+#[derive(Clone, Copy)]
+struct Pairs<'a>(&'a BuildArgs);
+
+impl<'a> IntoIterator for Pairs<'a> {
+    type Item = (Vec<u8>, BFieldVal);
+    type IntoIter = Box<dyn Iterator<Item = Self::Item> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        // the whole input was checked by `scan` before the first pass
+        let records = read_pairs(self.0).expect("input file can't be read anymore");
+        Box::new(records.map(|pair| pair.expect("input file changed during the build")))
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_pairs(
+    args: &BuildArgs,
+) -> io::Result<impl Iterator<Item = io::Result<(Vec<u8>, BFieldVal)>>> {
+    let file = BufReader::new(File::open(&args.input)?);
+    let reader = csv::ReaderBuilder::new()
+        .delimiter(if args.csv { b',' } else { b'\t' })
+        .quoting(args.csv)
+        .has_headers(args.header)
+        .flexible(true)
+        .from_reader(file);
+    Ok(reader.into_byte_records().map(|record| {
+        let record = record?;
+        let line = record.position().map_or(0, |p| p.line());
+        match (record.get(0), record.get(1), record.len()) {
+            (Some(key), Some(value), 2) => {
+                let value = std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|v| v.trim().parse().ok())
+                    .ok_or_else(|| invalid(format!("Line {}: invalid value", line)))?;
+                Ok((key.to_vec(), value))
+            }
+            _ => Err(invalid(format!(
+                "Line {}: expected a key and a value",
+                line
+            ))),
+        }
+    }))
+}
+
+/// Reads the whole input, returning the number of pairs and the largest
+/// value.
+fn scan(args: &BuildArgs) -> io::Result<(u64, BFieldVal)> {
+    let mut n_keys = 0;
+    let mut max_value = 0;
+    for pair in read_pairs(args)? {
+        let (_, value) = pair?;
+        n_keys += 1;
+        max_value = max_value.max(value);
+    }
+    Ok((n_keys, max_value))
+}
+
+/// The parameters `auto_params` picked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct AutoParams {
+    size: usize,
+    n_hashes: u8,
+    marker_width: u8,
+    n_marker_bits: u8,
+}
+
+/// Probability of a key that wasn't inserted getting exactly `kappa` of the
+/// `nu` bits of its marker set, each with probability `p`.
+fn false_positive_rate(nu: u8, kappa: u8, p: f64) -> f64 {
+    choose(u64::from(nu), kappa) as f64
+        * p.powi(i32::from(kappa))
+        * (1.0 - p).powi(i32::from(nu - kappa))
+}
+
+/// Picks the parameters for `n_keys` keys with values up to `max_value`
+/// whose primary array has about the given false positive rate (see 5(b)
+/// in the README), minimizing the total size of the arrays: for each κ the
+/// narrowest markers holding `max_value` are used and the primary array
+/// sized so that a bit of a looked up marker is set with the largest
+/// probability `p` keeping the false positive rate on target. The arrays
+/// then take about `size / (1 - β)` bits, `β` being the indeterminacy rate.
+fn auto_params(n_keys: u64, max_value: u64, target_rate: f64) -> AutoParams {
+    let n_keys = n_keys.max(1) as f64;
+    let mut best: Option<(f64, AutoParams)> = None;
+    for n_marker_bits in 1..=8 {
+        let marker_width =
+            match (n_marker_bits + 1..=128).find(|&nu| capacity(nu, n_marker_bits) >= max_value) {
+                Some(nu) => nu,
+                None => continue,
+            };
+        // the rate increases with p up to κ / ν
+        let (mut lo, mut hi) = (0.0, f64::from(n_marker_bits) / f64::from(marker_width));
+        for _ in 0..64 {
+            let mid = (lo + hi) / 2.0;
+            if false_positive_rate(marker_width, n_marker_bits, mid) <= target_rate {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        let p = lo;
+        let indeterminacy = 1.0 - (1.0 - p).powi(i32::from(marker_width - n_marker_bits));
+        for n_hashes in 1..=16u8 {
+            // p = d^k for an array with a density d of bits set, which is
+            // 1 - e^(-n k κ / size) once n keys are inserted
+            let density = p.powf(1.0 / f64::from(n_hashes));
+            let bits_per_key = f64::from(n_hashes) * f64::from(n_marker_bits);
+            let size = (-n_keys * bits_per_key / (-density).ln_1p()).ceil();
+            let total_size = size / (1.0 - indeterminacy);
+            if best.is_none_or(|(best_size, _)| total_size < best_size) {
+                let params = AutoParams {
+                    size: (size as usize).max(MIN_AUTO_SIZE),
+                    n_hashes,
+                    marker_width,
+                    n_marker_bits,
+                };
+                best = Some((total_size, params));
+            }
+        }
+    }
+    best.expect("κ = 8 holds any u32 value").1
+}
+
+/// Prints a line to stderr each time a pass is done.
+fn report_passes(last_pass: &Cell<usize>) -> impl Fn(Progress) + '_ {
+    move |progress| {
+        if progress.members_completed > last_pass.get() {
+            last_pass.set(progress.members_completed);
+            eprintln!(
+                "Pass {}/{} done ({} keys inserted)",
+                progress.members_completed, progress.total_members, progress.keys_inserted
+            );
+        }
+    }
+}
+
+pub fn run(args: &BuildArgs) -> io::Result<()> {
+    let (n_keys, max_value) = scan(args)?;
+    let last_pass = Cell::new(0);
+    let bfield = match args.size {
+        Some(size) => {
+            let mut bfield: BField<String> = BField::create(
+                &args.output,
+                &args.name,
+                size,
+                args.n_hashes.unwrap_or_default(),
+                args.marker_width.unwrap_or_default(),
+                args.n_marker_bits.unwrap_or_default(),
+                args.secondary_scaledown,
+                args.max_scaledown,
+                args.n_secondaries,
+                false,
+                String::new(),
+            )?;
+            if max_value > bfield.max_value() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "The largest value ({}) is over the {} the parameters can hold",
+                        max_value,
+                        bfield.max_value()
+                    ),
+                ));
+            }
+            use_seed(&mut bfield, args)?;
+            bfield.build_from_iter(Pairs(args), report_passes(&last_pass))?;
+            bfield
+        }
+        None => {
+            let params = auto_params(n_keys, u64::from(max_value), args.false_positive_rate);
+            eprintln!(
+                "Using a {} bit primary array, k = {}, ν = {}, κ = {}",
+                params.size, params.n_hashes, params.marker_width, params.n_marker_bits
+            );
+            let mut bfield: BField<String> = BField::create(
+                &args.output,
+                &args.name,
+                params.size,
+                params.n_hashes,
+                params.marker_width,
+                params.n_marker_bits,
+                0.1,
+                0.025,
+                1,
+                false,
+                String::new(),
+            )?;
+            use_seed(&mut bfield, args)?;
+            let options = ScalingOptions::new(0.0).size_from_indeterminate_keys(1.0);
+            bfield.build_scalable(Pairs(args), &options, report_passes(&last_pass))?;
+            bfield
+        }
+    };
+    // records the number of keys in the headers, for `bfield inspect`
+    bfield.update_checksums()?;
+    let sizes = bfield.build_parameters().sizes;
+    eprintln!(
+        "Wrote {} keys to {} arrays ({} bits) in {:?}",
+        n_keys,
+        sizes.len(),
+        sizes.iter().sum::<usize>(),
+        args.output
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::io::Write;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: BuildArgs,
+    }
+
+    #[test]
+    fn can_derive_params() {
+        let params = auto_params(1_000_000, 1_000, 0.001);
+        assert!(capacity(params.marker_width, params.n_marker_bits) >= 1_000);
+        assert!(params.marker_width <= 64, "{:?}", params);
+        // a few bytes per key
+        assert!(
+            params.size > 8_000_000 && params.size < 80_000_000,
+            "{:?}",
+            params
+        );
+        assert_eq!(auto_params(0, 1, 0.001).size, MIN_AUTO_SIZE);
+        assert!(auto_params(1_000, 1, 0.0001).size > auto_params(1_000, 1, 0.01).size);
+    }
+
+    #[test]
+    fn can_build_from_tsv_and_csv() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let input = tmp_dir.path().join("pairs.tsv");
+        let mut file = File::create(&input).unwrap();
+        for i in 0..2_000u32 {
+            writeln!(file, "key{}\t{}", i, i % 300).unwrap();
+        }
+        drop(file);
+        let output = tmp_dir.path().to_str().unwrap();
+        let cli = Cli::parse_from(["build", input.to_str().unwrap(), "-o", output]);
+        run(&cli.args).unwrap();
+        let bfield: BField<String> =
+            BField::load(tmp_dir.path().join("bfield.0.bfd"), true).unwrap();
+        for i in 0..2_000u32 {
+            assert_eq!(bfield.get(format!("key{}", i).as_bytes()), Some(i % 300));
+        }
+
+        let input = tmp_dir.path().join("pairs.csv");
+        std::fs::write(&input, "key,value\n\"a,b\",7\nc,8\n").unwrap();
+        let cli = Cli::parse_from([
+            "build",
+            input.to_str().unwrap(),
+            "-o",
+            output,
+            "-n",
+            "csv",
+            "--csv",
+            "--header",
+            "--size",
+            "10000",
+            "--n-hashes",
+            "3",
+            "--marker-width",
+            "8",
+            "--n-marker-bits",
+            "2",
+            "--seed",
+            "42",
+        ]);
+        run(&cli.args).unwrap();
+        let bfield: BField<String> = BField::load(tmp_dir.path().join("csv.0.bfd"), true).unwrap();
+        assert_eq!(bfield.build_parameters().sizes.len(), 4);
+        assert_eq!(bfield.hash_seed(), 42);
+        assert_eq!(bfield.get(b"a,b"), Some(7));
+        assert_eq!(bfield.get(b"c"), Some(8));
+
+        std::fs::write(&input, "a,1\nb\n").unwrap();
+        let cli = Cli::parse_from(["build", input.to_str().unwrap(), "-o", output, "--csv"]);
+        let err = run(&cli.args).unwrap_err();
+        assert_eq!(err.to_string(), "Line 2: expected a key and a value");
+    }
+}
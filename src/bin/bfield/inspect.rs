@@ -0,0 +1,162 @@
+//! `bfield inspect`: prints the parameters and fill rates of a B-field.
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use clap::Args;
+use serde_json::json;
+
+#[derive(Args, Debug)]
+pub struct InspectArgs {
+    /// The primary array (`NAME.0.bfd`) or container file of the B-field
+    path: PathBuf,
+    /// Print JSON instead of a table
+    #[arg(long)]
+    json: bool,
+}
+
+pub fn run<W: Write>(args: &InspectArgs, out: &mut W) -> io::Result<()> {
+    let bfield = crate::open(&args.path)?;
+    let params = bfield.build_parameters();
+    let info = bfield.member_info();
+    let stats = bfield.stats();
+    let other_params = bfield.params().as_ref().map(|p| p.to_json());
+
+    if args.json {
+        let members: Vec<_> = info
+            .iter()
+            .zip(&stats.members)
+            .map(|(info, stats)| {
+                json!({
+                    "size": info.size,
+                    "value_bits": info.value_bits,
+                    "n_keys": stats.n_keys,
+                    "n_set": stats.n_set,
+                    "density": stats.density,
+                    "false_positive_rate": stats.false_positive_rate,
+                    "indeterminacy_rate": stats.indeterminacy_rate,
+                })
+            })
+            .collect();
+        let summary = json!({
+            "n_hashes": params.n_hashes,
+            "marker_width": params.marker_width,
+            "n_marker_bits": params.n_marker_bits,
+            "max_value": bfield.max_value(),
+            "params": other_params,
+            "provenance": bfield.provenance(),
+            "members": members,
+            "size": stats.size,
+            "n_set": stats.n_set,
+            "false_positive_rate": stats.false_positive_rate,
+            "indeterminacy_rate": stats.indeterminacy_rate,
+        });
+        serde_json::to_writer_pretty(&mut *out, &summary)?;
+        return writeln!(out);
+    }
+
+    writeln!(
+        out,
+        "k = {}, ν = {}, κ = {}, max value = {}",
+        params.n_hashes,
+        params.marker_width,
+        params.n_marker_bits,
+        bfield.max_value()
+    )?;
+    if let Some(other_params) = other_params {
+        writeln!(out, "params: {}", other_params)?;
+    }
+    if let Some(provenance) = bfield.provenance() {
+        write!(out, "built with bfield {}", provenance.crate_version)?;
+        if let Some(label) = &provenance.label {
+            write!(out, " ({})", label)?;
+        }
+        writeln!(out)?;
+    }
+    writeln!(
+        out,
+        "{:>6} {:>14} {:>12} {:>14} {:>8} {:>10} {:>10}",
+        "member", "size", "keys", "bits set", "density", "fp rate", "indet rate"
+    )?;
+    for (ix, member) in stats.members.iter().enumerate() {
+        writeln!(
+            out,
+            "{:>6} {:>14} {:>12} {:>14} {:>8.4} {:>10.3e} {:>10.3e}",
+            ix,
+            member.size,
+            member.n_keys,
+            member.n_set,
+            member.density,
+            member.false_positive_rate,
+            member.indeterminacy_rate
+        )?;
+    }
+    writeln!(
+        out,
+        "{:>6} {:>14} {:>12} {:>14} {:>8} {:>10.3e} {:>10.3e}",
+        "total",
+        stats.size,
+        "",
+        stats.n_set,
+        "",
+        stats.false_positive_rate,
+        stats.indeterminacy_rate
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bfield::BField;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: InspectArgs,
+    }
+
+    #[test]
+    fn can_inspect_bfield() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            false,
+            "taxonomy".to_string(),
+        )
+        .unwrap();
+        bfield
+            .build_from_iter((0..100u32).map(|i| (format!("key{}", i), i)), |_| {})
+            .unwrap();
+        // records the number of keys in the headers
+        bfield.update_checksums().unwrap();
+        drop(bfield);
+
+        let path = tmp_dir.path().join("bfield.0.bfd");
+        let cli = Cli::parse_from(["inspect", path.to_str().unwrap(), "--json"]);
+        let mut out = Vec::new();
+        run(&cli.args, &mut out).unwrap();
+        let summary: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(summary["marker_width"], 39);
+        assert_eq!(summary["params"], "taxonomy");
+        assert_eq!(summary["provenance"]["max_scaledown"], 0.025);
+        assert_eq!(summary["members"][0]["size"], 100_000);
+        assert_eq!(summary["members"][0]["n_keys"], 100);
+        assert_eq!(summary["members"][1]["size"], 10_000);
+
+        let cli = Cli::parse_from(["inspect", path.to_str().unwrap()]);
+        let mut out = Vec::new();
+        run(&cli.args, &mut out).unwrap();
+        let table = String::from_utf8(out).unwrap();
+        assert!(table.starts_with("k = 10, ν = 39, κ = 4, max value = 82250\n"));
+        assert!(table.contains("built with bfield "));
+        assert_eq!(table.lines().count(), 7, "{}", table);
+    }
+}
@@ -0,0 +1,108 @@
+//! The `bfield` command line tool, built with the `cli` feature.
+use std::io;
+use std::path::Path;
+use std::process::ExitCode;
+
+use bfield::{BField, BFieldVal};
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+mod build;
+mod inspect;
+mod query;
+#[cfg(feature = "serve")]
+mod serve;
+
+/// Builds and queries B-fields.
+#[derive(Debug, Parser)]
+#[command(name = "bfield", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Builds a B-field from a file of `key<TAB>value` lines (or CSV)
+    Build(build::BuildArgs),
+    /// Looks keys up, printing their value (or `absent`/`indeterminate`)
+    Query(query::QueryArgs),
+    /// Prints the parameters, array sizes and fill rates of a B-field
+    Inspect(inspect::InspectArgs),
+    /// Answers lookups over HTTP
+    #[cfg(feature = "serve")]
+    Serve(serve::ServeArgs),
+}
+
+/// A B-field opened by the subcommands.
+type Db = BField<Params, BFieldVal>;
+
+/// The `other` parameters of a B-field, whatever their type: their JSON, or
+/// the raw bytes of those written with bincode before v0.4 (e.g. strings).
+#[derive(Clone, Debug)]
+enum Params {
+    Json(Value),
+    Bytes(Vec<u8>),
+}
+
+impl Params {
+    fn to_json(&self) -> Value {
+        match self {
+            Params::Json(value) => value.clone(),
+            Params::Bytes(bytes) => Value::String(String::from_utf8_lossy(bytes).into_owned()),
+        }
+    }
+}
+
+impl Serialize for Params {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Params::Json(value) => value.serialize(serializer),
+            Params::Bytes(bytes) => bytes.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Params {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // only the JSON encoding is self-describing
+        if deserializer.is_human_readable() {
+            Value::deserialize(deserializer).map(Params::Json)
+        } else {
+            Vec::deserialize(deserializer).map(Params::Bytes)
+        }
+    }
+}
+
+/// Opens the B-field (read-only) whose primary array is at `path`, or the
+/// container at `path`.
+fn open(path: &Path) -> io::Result<Db> {
+    let is_primary = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with("0.bfd"));
+    if is_primary {
+        BField::load(path, true)
+    } else {
+        BField::load_container(path, true)
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match &cli.command {
+        Command::Build(args) => build::run(args),
+        Command::Query(args) => query::run(args, io::stdin().lock(), &mut io::stdout().lock()),
+        Command::Inspect(args) => inspect::run(args, &mut io::stdout().lock()),
+        #[cfg(feature = "serve")]
+        Command::Serve(args) => serve::run(args),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! `bfield query`: looks keys up in an existing B-field.
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+use bfield::{BFieldVal, Tristate};
+use clap::Args;
+
+use crate::Db;
+
+#[derive(Args, Debug)]
+pub struct QueryArgs {
+    /// The primary array (`NAME.0.bfd`) or container file of the B-field
+    path: PathBuf,
+    /// Keys to look up; read from stdin, one per line, if there are none
+    keys: Vec<String>,
+}
+
+/// The result of looking a key up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lookup {
+    Found(BFieldVal),
+    Absent,
+    Indeterminate,
+}
+
+pub fn lookup(bfield: &Db, key: &[u8]) -> Lookup {
+    match bfield.get(key) {
+        Some(value) => Lookup::Found(value),
+        None if bfield.contains(key) == Tristate::Indeterminate => Lookup::Indeterminate,
+        None => Lookup::Absent,
+    }
+}
+
+/// Prints `key<TAB>value` for each key, with `absent` or `indeterminate`
+/// as the value for keys without one.
+pub fn run<R: BufRead, W: Write>(args: &QueryArgs, stdin: R, out: &mut W) -> io::Result<()> {
+    let bfield = crate::open(&args.path)?;
+    let mut query = |key: &[u8]| -> io::Result<()> {
+        out.write_all(key)?;
+        match lookup(&bfield, key) {
+            Lookup::Found(value) => writeln!(out, "\t{}", value),
+            Lookup::Absent => writeln!(out, "\tabsent"),
+            Lookup::Indeterminate => writeln!(out, "\tindeterminate"),
+        }
+    };
+    if !args.keys.is_empty() {
+        for key in &args.keys {
+            query(key.as_bytes())?;
+        }
+        return Ok(());
+    }
+    for line in stdin.split(b'\n') {
+        let line = line?;
+        query(line.strip_suffix(b"\r").unwrap_or(&line))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bfield::BField;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: QueryArgs,
+    }
+
+    #[test]
+    fn can_query_keys() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            false,
+            String::new(),
+        )
+        .unwrap();
+        bfield
+            .build_from_iter((0..100u32).map(|i| (format!("key{}", i), i)), |_| {})
+            .unwrap();
+        drop(bfield);
+
+        let path = tmp_dir.path().join("bfield.0.bfd");
+        let cli = Cli::parse_from(["query", path.to_str().unwrap(), "key5", "missing"]);
+        let mut out = Vec::new();
+        run(&cli.args, &b""[..], &mut out).unwrap();
+        assert_eq!(out, b"key5\t5\nmissing\tabsent\n");
+
+        let cli = Cli::parse_from(["query", path.to_str().unwrap()]);
+        let mut out = Vec::new();
+        run(&cli.args, &b"key7\r\nkey99\nnope\n"[..], &mut out).unwrap();
+        assert_eq!(out, b"key7\t7\nkey99\t99\nnope\tabsent\n");
+    }
+}
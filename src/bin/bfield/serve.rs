@@ -0,0 +1,193 @@
+//! `bfield serve`: answers lookups against a read-only B-field over HTTP.
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+use clap::Args;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::query::{lookup, Lookup};
+use crate::Db;
+
+/// Largest batch request body read, in bytes
+const MAX_BODY_LEN: u64 = 64 << 20;
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    /// The primary array (`NAME.0.bfd`) or container file of the B-field
+    path: PathBuf,
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    addr: String,
+    /// Number of threads answering requests
+    #[arg(long, default_value_t = 4)]
+    threads: usize,
+}
+
+/// The JSON result for `key`: its `status` (`found`, `absent` or
+/// `indeterminate`) and `value` (`null` unless found).
+fn lookup_json(bfield: &Db, key: &str) -> Value {
+    let (status, value) = match lookup(bfield, key.as_bytes()) {
+        Lookup::Found(value) => ("found", Some(value)),
+        Lookup::Absent => ("absent", None),
+        Lookup::Indeterminate => ("indeterminate", None),
+    };
+    json!({ "key": key, "status": status, "value": value })
+}
+
+/// Answers `GET /lookup?key=…` with the result for one key, and
+/// `POST /lookup` with a JSON array of keys as its body with an array of
+/// results. Keys are UTF-8 strings.
+fn handle(bfield: &Db, method: &Method, url: &str, body: &[u8]) -> (u16, Value) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    if path != "/lookup" {
+        return (404, json!({ "error": "Not found" }));
+    }
+    match method {
+        Method::Get => {
+            match form_urlencoded::parse(query.as_bytes()).find(|(name, _)| name == "key") {
+                Some((_, key)) => (200, lookup_json(bfield, &key)),
+                None => (400, json!({ "error": "Missing `key` parameter" })),
+            }
+        }
+        Method::Post => match serde_json::from_slice::<Vec<String>>(body) {
+            Ok(keys) => {
+                let results = keys.iter().map(|key| lookup_json(bfield, key)).collect();
+                (200, Value::Array(results))
+            }
+            Err(e) => (
+                400,
+                json!({ "error": format!("Expected a JSON array of keys: {}", e) }),
+            ),
+        },
+        _ => (405, json!({ "error": "Method not allowed" })),
+    }
+}
+
+fn respond(bfield: &Db, mut request: Request) -> io::Result<()> {
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(MAX_BODY_LEN)
+        .read_to_end(&mut body)?;
+    let (status, value) = handle(bfield, request.method(), request.url(), &body);
+    let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+    let response = Response::from_string(value.to_string())
+        .with_status_code(status)
+        .with_header(content_type);
+    request.respond(response)
+}
+
+/// Answers requests to `server` on `threads` threads, until it's unblocked.
+fn serve(server: Arc<Server>, bfield: Arc<Db>, threads: usize) {
+    let workers: Vec<_> = (0..threads.max(1))
+        .map(|_| {
+            let (server, bfield) = (Arc::clone(&server), Arc::clone(&bfield));
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    if let Err(e) = respond(&bfield, request) {
+                        eprintln!("error: {}", e);
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        let _ = worker.join();
+    }
+}
+
+pub fn run(args: &ServeArgs) -> io::Result<()> {
+    let bfield = Arc::new(crate::open(&args.path)?);
+    let server = Server::http(&args.addr).map_err(io::Error::other)?;
+    eprintln!("Listening on http://{}", server.server_addr());
+    serve(Arc::new(server), bfield, args.threads);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bfield::BField;
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    fn request(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn can_serve_lookups() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            false,
+            String::new(),
+        )
+        .unwrap();
+        bfield
+            .build_from_iter((0..100u32).map(|i| (format!("key {}", i), i)), |_| {})
+            .unwrap();
+        drop(bfield);
+        let bfield = Arc::new(crate::open(&tmp_dir.path().join("bfield.0.bfd")).unwrap());
+
+        let (status, found) = handle(&bfield, &Method::Get, "/lookup?key=key+5", b"");
+        assert_eq!(status, 200);
+        assert_eq!(
+            found,
+            json!({ "key": "key 5", "status": "found", "value": 5 })
+        );
+        let (_, absent) = handle(&bfield, &Method::Get, "/lookup?key=missing", b"");
+        assert_eq!(absent["status"], "absent");
+        assert_eq!(absent["value"], Value::Null);
+        assert_eq!(handle(&bfield, &Method::Get, "/lookup", b"").0, 400);
+        assert_eq!(handle(&bfield, &Method::Get, "/other", b"").0, 404);
+        assert_eq!(handle(&bfield, &Method::Delete, "/lookup", b"").0, 405);
+        assert_eq!(handle(&bfield, &Method::Post, "/lookup", b"{}").0, 400);
+
+        let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+        let addr = server.server_addr().to_ip().unwrap();
+        let handle = {
+            let server = Arc::clone(&server);
+            thread::spawn(move || serve(server, bfield, 2))
+        };
+        let response = request(
+            addr,
+            "GET /lookup?key=key%207 HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with(r#"{"key":"key 7","status":"found","value":7}"#));
+        let body = r#"["key 1","nope"]"#;
+        let response = request(
+            addr,
+            &format!(
+                "POST /lookup HTTP/1.1\r\nHost: x\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            ),
+        );
+        let results: Value =
+            serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(results[0]["value"], 1);
+        assert_eq!(results[1]["status"], "absent");
+
+        for _ in 0..2 {
+            server.unblock();
+        }
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,183 @@
+//! A bounded LRU cache of lookup results, for query workloads where the same
+//! keys come up over and over.
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::keyed::{hash_with, HashSecret};
+
+/// The cache is split into this many independently locked shards, so
+/// threads looking up different keys rarely wait on each other
+const N_SHARDS: usize = 16;
+const NIL: usize = usize::MAX;
+
+/// Results of `BField::get` by 128-bit key hash, evicting the least
+/// recently used ones.
+#[derive(Debug)]
+pub(crate) struct LookupCache {
+    shards: Box<[Mutex<LruShard>]>,
+}
+
+impl LookupCache {
+    /// A cache holding about `capacity` results.
+    pub fn new(capacity: usize) -> Self {
+        let shard_capacity = capacity.div_ceil(N_SHARDS).max(1);
+        LookupCache {
+            shards: (0..N_SHARDS)
+                .map(|_| Mutex::new(LruShard::new(shard_capacity)))
+                .collect(),
+        }
+    }
+
+    fn shard(&self, hash: u128) -> MutexGuard<'_, LruShard> {
+        self.shards[hash as usize % N_SHARDS]
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The cached result for `key` (hashed with `secret` if there's one),
+    /// or calls `lookup` and caches its result.
+    pub fn get_or_insert_with<F>(
+        &self,
+        key: &[u8],
+        secret: Option<&HashSecret>,
+        lookup: F,
+    ) -> Option<u64>
+    where
+        F: FnOnce() -> Option<u64>,
+    {
+        let (high, low) = hash_with(secret, key, 0);
+        let hash = (u128::from(high) << 64) | u128::from(low);
+        if let Some(value) = self.shard(hash).get(hash) {
+            return value;
+        }
+        // looked up without holding the lock
+        let value = lookup();
+        self.shard(hash).insert(hash, value);
+        value
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+    hash: u128,
+    value: Option<u64>,
+    prev: usize,
+    next: usize,
+}
+
+/// An LRU map as a doubly-linked list (most recently used first) stored in
+/// a `Vec`, indexed by a `HashMap`.
+#[derive(Debug)]
+struct LruShard {
+    capacity: usize,
+    index: HashMap<u128, usize>,
+    nodes: Vec<Node>,
+    head: usize,
+    tail: usize,
+}
+
+impl LruShard {
+    fn new(capacity: usize) -> Self {
+        LruShard {
+            capacity,
+            index: HashMap::with_capacity(capacity),
+            nodes: Vec::with_capacity(capacity),
+            head: NIL,
+            tail: NIL,
+        }
+    }
+
+    fn get(&mut self, hash: u128) -> Option<Option<u64>> {
+        let ix = *self.index.get(&hash)?;
+        self.unlink(ix);
+        self.push_front(ix);
+        Some(self.nodes[ix].value)
+    }
+
+    fn insert(&mut self, hash: u128, value: Option<u64>) {
+        if let Some(&ix) = self.index.get(&hash) {
+            self.nodes[ix].value = value;
+            self.unlink(ix);
+            self.push_front(ix);
+            return;
+        }
+        let ix = if self.nodes.len() < self.capacity {
+            self.nodes.push(Node {
+                hash,
+                value,
+                prev: NIL,
+                next: NIL,
+            });
+            self.nodes.len() - 1
+        } else {
+            // reuse the least recently used node
+            let ix = self.tail;
+            self.unlink(ix);
+            self.index.remove(&self.nodes[ix].hash);
+            self.nodes[ix].hash = hash;
+            self.nodes[ix].value = value;
+            ix
+        };
+        self.index.insert(hash, ix);
+        self.push_front(ix);
+    }
+
+    fn unlink(&mut self, ix: usize) {
+        let (prev, next) = (self.nodes[ix].prev, self.nodes[ix].next);
+        match prev {
+            NIL => self.head = next,
+            prev => self.nodes[prev].next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.nodes[next].prev = prev,
+        }
+    }
+
+    fn push_front(&mut self, ix: usize) {
+        self.nodes[ix].prev = NIL;
+        self.nodes[ix].next = self.head;
+        match self.head {
+            NIL => self.tail = ix,
+            head => self.nodes[head].prev = ix,
+        }
+        self.head = ix;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut shard = LruShard::new(2);
+        shard.insert(1, Some(1));
+        shard.insert(2, None);
+        assert_eq!(shard.get(1), Some(Some(1)));
+        shard.insert(3, Some(3));
+        assert_eq!(shard.get(2), None);
+        assert_eq!(shard.get(1), Some(Some(1)));
+        assert_eq!(shard.get(3), Some(Some(3)));
+        shard.insert(3, Some(4));
+        shard.insert(5, Some(5));
+        assert_eq!(shard.get(1), None);
+        assert_eq!(shard.get(3), Some(Some(4)));
+        assert_eq!(shard.index.len(), 2);
+    }
+
+    #[test]
+    fn only_looks_up_misses() {
+        let cache = LookupCache::new(100);
+        assert_eq!(cache.get_or_insert_with(b"a", None, || Some(1)), Some(1));
+        assert_eq!(
+            cache.get_or_insert_with(b"a", None, || unreachable!()),
+            Some(1)
+        );
+        assert_eq!(cache.get_or_insert_with(b"b", None, || None), None);
+        assert_eq!(
+            cache.get_or_insert_with(b"b", None, || unreachable!()),
+            None
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! Classifying reads by the values of their k-mers.
+use std::collections::HashMap;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bfield::BField;
+use crate::bfield_member::{BFieldLookup, BFieldValue};
+use crate::kmers::{canonical_kmers, kmer_key};
+
+/// The values found for the k-mers of a read, see [`BField::classify`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Classification<V> {
+    /// Number of k-mers in the read (not counting those spanning bases
+    /// other than A, C, G and T)
+    pub n_kmers: usize,
+    /// Number of k-mers found with each value, the most frequent first (and
+    /// smaller values first on ties)
+    pub counts: Vec<(V, usize)>,
+    /// Number of k-mers that are absent
+    pub n_absent: usize,
+    /// Number of k-mers that are indeterminate in every array
+    pub n_indeterminate: usize,
+}
+
+impl<V: Copy> Classification<V> {
+    /// The value most k-mers were found with, and their number.
+    pub fn top(&self) -> Option<(V, usize)> {
+        self.counts.first().copied()
+    }
+
+    /// Number of k-mers found with a value.
+    pub fn n_found(&self) -> usize {
+        self.counts.iter().map(|&(_, n)| n).sum()
+    }
+
+    /// Fraction of the k-mers found with a value (0 for reads without
+    /// k-mers).
+    pub fn coverage(&self) -> f64 {
+        ratio(self.n_found(), self.n_kmers)
+    }
+
+    /// Fraction of the k-mers that are indeterminate.
+    pub fn fraction_indeterminate(&self) -> f64 {
+        ratio(self.n_indeterminate, self.n_kmers)
+    }
+}
+
+fn ratio(n: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.
+    } else {
+        n as f64 / total as f64
+    }
+}
+
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> BField<T, V> {
+    /// Looks up every canonical k-mer of `read` (see
+    /// [`canonical_kmers`](crate::kmers::canonical_kmers)) and counts the
+    /// values found, and the k-mers that are absent or indeterminate.
+    /// Decoded values that don't fit in `V` count as absent, as with `get`.
+    ///
+    /// Panics if `k` is 0 or over [`MAX_K`](crate::kmers::MAX_K).
+    pub fn classify(&self, read: &[u8], k: u8) -> Classification<V> {
+        let mut counts: HashMap<u64, usize> = HashMap::new();
+        let mut classification = Classification {
+            n_kmers: 0,
+            counts: Vec::new(),
+            n_absent: 0,
+            n_indeterminate: 0,
+        };
+        for kmer in canonical_kmers(read, k) {
+            classification.n_kmers += 1;
+            match self.lookup(&kmer_key(kmer)) {
+                BFieldLookup::Some(value) if V::from_u64(value).is_some() => {
+                    *counts.entry(value).or_insert(0) += 1;
+                }
+                BFieldLookup::Some(_) | BFieldLookup::None => classification.n_absent += 1,
+                BFieldLookup::Indeterminate => classification.n_indeterminate += 1,
+            }
+        }
+        let mut counts: Vec<(u64, usize)> = counts.into_iter().collect();
+        counts.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        classification.counts = counts
+            .into_iter()
+            .filter_map(|(value, n)| Some((V::from_u64(value)?, n)))
+            .collect();
+        classification
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_classify_reads() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            1,
+            true,
+            String::new(),
+        )
+        .unwrap();
+        let genome_a = b"GATTACAGATTACACCCGGGTTTAAAGGCATCGATCGATCG";
+        let genome_b = b"TTGACCAGTAGGACCCATTACGGCATCAGCATTCGAACTGA";
+        bfield.insert_kmers(genome_a, 15, 1, 0);
+        bfield.insert_kmers(genome_b, 15, 2, 0);
+
+        // a read mostly from genome A, then a few bases of neither
+        let read = [&genome_a[5..35], &b"AAAATTTTGGGGCCCC"[..]].concat();
+        let classification = bfield.classify(&read, 15);
+        assert_eq!(classification.n_kmers, 32);
+        assert_eq!(classification.top(), Some((1, 16)));
+        assert_eq!(classification.n_found() + classification.n_absent, 32);
+        assert_eq!(classification.coverage(), 0.5);
+        assert_eq!(classification.fraction_indeterminate(), 0.);
+
+        let values = bfield.get_many(canonical_kmers(genome_b, 15).map(kmer_key));
+        assert!(values.iter().all(|&value| value == Some(2)));
+
+        let empty = bfield.classify(b"ACGT", 15);
+        assert_eq!(empty.top(), None);
+        assert_eq!(empty.coverage(), 0.);
+    }
+}
@@ -1,116 +1,387 @@
-use once_cell::sync::Lazy;
-use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
 
-const MARKER_TABLE_SIZE: usize = 200_000;
-
-// TODO: replace with const fn when it is possible
-// (for and if are not allowed in const fn on current stable)
-// https://github.com/rust-lang/rust/issues/87575
-static MARKER_TABLES: Lazy<HashMap<u8, Vec<u128>>> = Lazy::new(|| {
-    let mut m = HashMap::new();
-    for k in 1..10u8 {
-        let mut table = vec![0u128; MARKER_TABLE_SIZE];
-        let table_size = if k == 1 {
-            128
-        } else if k == 2 {
-            8128
-        } else {
-            table.len()
-        };
+use once_cell::sync::OnceCell;
+
+#[cfg(test)]
+use crate::lookup::choose;
+pub(crate) use crate::lookup::{choose_saturating, unrank};
+use crate::marker::WideMarker;
+
+/// Number of values in each marker table (all of them for κ=2, as 128
+/// choose 2 = 8128); larger values are encoded with `rank_direct`
+const MARKER_TABLE_SIZE: usize = 8128;
+/// Markers for larger κ are always computed with `rank_direct`
+const MAX_TABLE_MARKER_BITS: u8 = 9;
+/// The largest supported κ (128 choose 16 is already past the range of `u64` values)
+pub(crate) const MAX_MARKER_BITS: u8 = 16;
 
-        table[0] = ((1 << k) - 1) as u128;
-        for i in 1..table_size {
+/// The markers of the first values of each κ up to `MAX_TABLE_MARKER_BITS`
+/// (κ - 1 being the index), built at compile time so lookups are a plain
+/// index into read-only memory shared by every process using the library.
+static MARKER_TABLES: [[u128; MARKER_TABLE_SIZE]; MAX_TABLE_MARKER_BITS as usize] = marker_tables();
+
+const fn marker_tables() -> [[u128; MARKER_TABLE_SIZE]; MAX_TABLE_MARKER_BITS as usize] {
+    let mut tables = [[0; MARKER_TABLE_SIZE]; MAX_TABLE_MARKER_BITS as usize];
+    let mut k = 1;
+    while k <= MAX_TABLE_MARKER_BITS {
+        let table = &mut tables[k as usize - 1];
+        table[0] = (1 << k) - 1;
+        let mut i = 1;
+        while i < marker_table_len(k) {
             table[i] = next_rank(table[i - 1]);
+            i += 1;
         }
-        m.insert(k, table);
+        k += 1;
     }
-    m
-});
+    tables
+}
 
-/// https://en.wikipedia.org/wiki/Combinatorial_number_system
-pub fn rank(value: usize, k: u8) -> u128 {
-    assert!(k > 0 && k < 10, "kappa needs to be less than 10");
-    // it's possible this may overflow if value > (128 choose k) or return
-    // a bad value (0) if value > (128 choose k) and k == 1 or 2
-    if value >= MARKER_TABLE_SIZE {
-        let mut marker = MARKER_TABLES[&k][MARKER_TABLE_SIZE - 1];
-        for _ in 0..(value - MARKER_TABLE_SIZE) {
-            // next_rank would overflow if we pass 0, we return it instead
-            if marker == 0 {
-                return marker;
-            }
-            marker = next_rank(marker);
-        }
-        marker
+/// Number of values in the marker table of κ = `k` (only 128 for κ=1).
+const fn marker_table_len(k: u8) -> usize {
+    if k == 1 {
+        128
     } else {
-        MARKER_TABLES[&k][value]
+        MARKER_TABLE_SIZE
     }
 }
 
-/// https://en.wikipedia.org/wiki/Combinatorial_number_system
-pub fn unrank(marker: u128) -> usize {
-    // val = choose(rank(0), 1) + choose(rank(1), 2) + choose(rank(2), 3) + ...
-    let mut working_marker = marker;
-    let mut value = 0u64;
-    let mut idx = 0;
-    while working_marker != 0 {
-        let rank = u64::from(working_marker.trailing_zeros());
-        working_marker -= 1 << rank;
-        idx += 1;
-        value += choose(rank, idx);
+/// Number of values of each κ (the index) that `set_marker_table_size`
+/// asked to tabulate, taken (leaving `None`) when the runtime table of that
+/// κ is built.
+static RUNTIME_TABLE_SIZES: Mutex<[Option<usize>; MAX_MARKER_BITS as usize + 1]> =
+    Mutex::new([Some(0); MAX_MARKER_BITS as usize + 1]);
+
+/// The markers of each κ (the index) from the end of its compiled table up to
+/// the size set with `set_marker_table_size`. Each is only built on the first
+/// encoding of a value of its κ past the compiled table, so a process only
+/// pays for the κ it uses (as it does for the compiled tables, whose pages
+/// are only read in when they're used).
+static RUNTIME_TABLES: [OnceCell<Vec<u128>>; MAX_MARKER_BITS as usize + 1] =
+    [const { OnceCell::new() }; MAX_MARKER_BITS as usize + 1];
+
+/// Number of values at the start of the compiled table of κ = `k`.
+fn compiled_table_len(k: u8) -> usize {
+    if k > MAX_TABLE_MARKER_BITS {
+        0
+    } else {
+        marker_table_len(k)
+    }
+}
+
+fn runtime_table(k: u8) -> &'static [u128] {
+    RUNTIME_TABLES[usize::from(k)].get_or_init(|| {
+        let size = RUNTIME_TABLE_SIZES.lock().unwrap()[usize::from(k)].take();
+        build_runtime_table(k, size.unwrap_or(0))
+    })
+}
+
+/// The markers of the values of κ = `k` from the end of its compiled table
+/// up to `n_values`.
+fn build_runtime_table(k: u8, n_values: usize) -> Vec<u128> {
+    let start = compiled_table_len(k);
+    if n_values <= start {
+        return Vec::new();
+    }
+    let mut table = Vec::with_capacity(n_values - start);
+    table.push(rank_direct(start as u64, k));
+    for i in 1..n_values - start {
+        table.push(next_rank(table[i - 1]));
+    }
+    table
+}
+
+/// Tabulates the markers of the first `n_values` values of κ = `k`, so
+/// encoding any of them is a single lookup (`rank` otherwise computes the
+/// markers of values past the compiled tables, the first 8128 values of κ up
+/// to 9, from their binomial expansion). The table takes 16 bytes per value
+/// past the compiled ones, e.g. 32MB for 2 million values.
+///
+/// The table of κ = `k` is built the first time a value of that κ past the
+/// compiled table is encoded, so this has to be called before then, usually
+/// right at startup; it fails afterwards.
+pub fn set_marker_table_size(k: u8, n_values: usize) -> Result<(), io::Error> {
+    if k == 0 || k > MAX_MARKER_BITS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("kappa needs to be between 1 and {}", MAX_MARKER_BITS),
+        ));
+    }
+    let max_values = usize::try_from(choose_saturating(128, k)).unwrap_or(usize::MAX);
+    match &mut RUNTIME_TABLE_SIZES.lock().unwrap()[usize::from(k)] {
+        Some(size) => {
+            *size = n_values.min(max_values);
+            Ok(())
+        }
+        None => Err(io::Error::other(format!(
+            "the marker table of kappa {} was already built",
+            k
+        ))),
+    }
+}
+
+/// Why a value can't be encoded into a marker, see [`try_rank`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RankError {
+    /// κ is 0 or over 16
+    InvalidKappa(u8),
+    /// The value is past the largest one the marker can encode
+    ValueOutOfRange {
+        /// The value to encode
+        value: u64,
+        /// Width of the marker (ν)
+        marker_width: u8,
+        /// Number of bits set in the marker (κ)
+        n_marker_bits: u8,
+        /// The largest value the marker can encode
+        max_value: u64,
+    },
+}
+
+impl fmt::Display for RankError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RankError::InvalidKappa(k) => write!(
+                f,
+                "kappa needs to be between 1 and {}, got {}",
+                MAX_MARKER_BITS, k
+            ),
+            RankError::ValueOutOfRange {
+                value,
+                marker_width,
+                n_marker_bits,
+                max_value,
+            } => write!(
+                f,
+                "value {} is out of range: a marker of width {} with {} bits set can only encode values up to {}",
+                value, marker_width, n_marker_bits, max_value
+            ),
+        }
     }
-    value as usize
 }
 
-/// (Hopefully) fast implementation of a binomial.
+impl std::error::Error for RankError {}
+
+impl From<RankError> for io::Error {
+    fn from(error: RankError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, error)
+    }
+}
+
+/// Returns an error unless `value` can be encoded in a marker of
+/// `marker_width` bits with `k` of them set.
+pub(crate) fn check_rank(value: u64, marker_width: u8, k: u8) -> Result<(), RankError> {
+    if k == 0 || k > MAX_MARKER_BITS {
+        return Err(RankError::InvalidKappa(k));
+    }
+    let max_value = capacity(marker_width, k);
+    if value > max_value {
+        return Err(RankError::ValueOutOfRange {
+            value,
+            marker_width,
+            n_marker_bits: k,
+            max_value,
+        });
+    }
+    Ok(())
+}
+
+/// Same as `rank`, but returns an error instead of panicking if `k` is out of
+/// range, or instead of a wrong marker if `value` is over the largest value
+/// a 128-bit marker with `k` bits set can encode (`choose(128, k) - 1`).
+pub fn try_rank(value: u64, k: u8) -> Result<u128, RankError> {
+    check_rank(value, 128, k)?;
+    Ok(rank(value, k))
+}
+
+/// https://en.wikipedia.org/wiki/Combinatorial_number_system
 ///
-/// This function uses a preset group of equations for k < 8 and then falls back to a
-/// multiplicative implementation that tries to prevent overflows while
-/// maintaining all results as exact integers.
+/// Panics if `k` is out of range, and returns a wrong marker for values over
+/// `choose(128, k) - 1` (see `try_rank`).
+pub fn rank(value: u64, k: u8) -> u128 {
+    assert!(
+        k > 0 && k <= MAX_MARKER_BITS,
+        "kappa needs to be between 1 and {}",
+        MAX_MARKER_BITS
+    );
+    // it's possible this may return a bad value if value > (128 choose k)
+    let compiled_len = compiled_table_len(k);
+    if value < compiled_len as u64 {
+        return MARKER_TABLES[usize::from(k) - 1][value as usize];
+    }
+    rank_uncompiled(value, k, compiled_len)
+}
+
+/// The marker of a `value` past the compiled table (of `compiled_len`
+/// markers), from the runtime table if it's in there. The index is checked
+/// in `u64`, so large values don't wrap around on 32-bit targets.
+fn rank_uncompiled(value: u64, k: u8, compiled_len: usize) -> u128 {
+    let cached = usize::try_from(value - compiled_len as u64)
+        .ok()
+        .and_then(|ix| runtime_table(k).get(ix));
+    match cached {
+        Some(&marker) => marker,
+        None => rank_direct(value, k),
+    }
+}
+
+/// Same as calling `rank` on each of `values`, looking up the marker
+/// table once for the whole batch, e.g. to encode the values of many keys
+/// during a build.
+pub fn rank_many(values: &[u64], k: u8) -> Vec<u128> {
+    assert!(
+        k > 0 && k <= MAX_MARKER_BITS,
+        "kappa needs to be between 1 and {}",
+        MAX_MARKER_BITS
+    );
+    let compiled_len = compiled_table_len(k);
+    let compiled = match compiled_len {
+        0 => &[][..],
+        len => &MARKER_TABLES[usize::from(k) - 1][..len],
+    };
+    values
+        .iter()
+        .map(|&value| {
+            if value < compiled_len as u64 {
+                compiled[value as usize]
+            } else {
+                rank_uncompiled(value, k, compiled_len)
+            }
+        })
+        .collect()
+}
+
+/// `choose_saturating(n, i)` for every bit position `n` of a 128-bit marker
+/// and `i` up to `MAX_MARKER_BITS`, so `unrank_many` and `rank_direct` never
+/// compute a binomial.
+static CHOOSE_TABLE: [[u128; MAX_MARKER_BITS as usize + 1]; 128] = choose_table();
+
+/// Pascal's triangle, saturating (though 127 choose 16 fits in a `u128`).
+const fn choose_table() -> [[u128; MAX_MARKER_BITS as usize + 1]; 128] {
+    let mut table = [[0u128; MAX_MARKER_BITS as usize + 1]; 128];
+    let mut n = 0;
+    while n < 128 {
+        table[n][0] = 1;
+        let mut i = 1;
+        while n > 0 && i <= MAX_MARKER_BITS as usize {
+            table[n][i] = table[n - 1][i - 1].saturating_add(table[n - 1][i]);
+            i += 1;
+        }
+        n += 1;
+    }
+    table
+}
+
+/// `choose_saturating(n, k)`, from `CHOOSE_TABLE` when it holds it.
 #[inline]
-pub fn choose(n: u64, k: u8) -> u64 {
-    // (extra border condition for speed-up?)
-    // if n == u64::from(k) {
-    //     return 1;
-    // }
-    match k {
-        0 => 1,
-        1 => n,
-        2 => n * (n - 1) / 2,
-        3 => n * (n - 1) * (n - 2) / 6,
-        4 => n * (n - 1) * (n - 2) * (n - 3) / 24,
-        5 => n * (n - 1) * (n - 2) * (n - 3) * (n - 4) / 120,
-        6 => n * (n - 1) * (n - 2) * (n - 3) * (n - 4) * (n - 5) / 720,
-        7 => n * (n - 1) * (n - 2) * (n - 3) * (n - 4) * (n - 5) * (n - 6) / 5040,
-        _ => {
-            let mut num: u128 = 1;
-            let mut denom: u128 = 1;
-            for i in 1..=u128::from(k) {
-                num *= u128::from(n) + 1 - i;
-                if num % i == 0 {
-                    num /= i;
-                    continue;
-                }
-                denom *= i;
-                if num % denom == 0 {
-                    num /= denom;
-                    denom = 1;
-                }
+fn choose_lookup(n: u64, k: u8) -> u128 {
+    if n < 128 {
+        CHOOSE_TABLE[n as usize][usize::from(k)]
+    } else {
+        choose_saturating(n, k)
+    }
+}
+
+/// Same as calling `unrank` on each of `markers`, with the binomials read
+/// from a table instead of computed for each bit.
+pub fn unrank_many(markers: &[u128]) -> Vec<u64> {
+    let table = &CHOOSE_TABLE;
+    markers
+        .iter()
+        .map(|&marker| {
+            // only possible for markers that were never inserted
+            if marker.count_ones() > u32::from(MAX_MARKER_BITS) {
+                return unrank(marker);
+            }
+            let mut working_marker = marker;
+            let mut value = 0u128;
+            let mut idx = 0;
+            while working_marker != 0 {
+                let rank = working_marker.trailing_zeros() as usize;
+                working_marker &= working_marker - 1;
+                idx += 1;
+                value += table[rank][idx];
+            }
+            u64::try_from(value).unwrap_or(u64::MAX)
+        })
+        .collect()
+}
+
+/// Computes the marker for `value` directly from the combinatorial number
+/// system. Used for κ without a marker table and for values past the end of
+/// the tables, where walking with `next_rank` would take
+/// `value - MARKER_TABLE_SIZE` steps.
+fn rank_direct(value: u64, k: u8) -> u128 {
+    let mut marker = 0u128;
+    rank_positions(value, k, 128, |pos| marker |= 1 << pos);
+    marker
+}
+
+/// Same as `rank` for markers wider than 128 bits.
+pub(crate) fn rank_wide(value: u64, k: u8) -> WideMarker {
+    let mut marker = WideMarker { hi: 0, lo: 0 };
+    rank_positions(value, k, 256, |pos| {
+        if pos < 128 {
+            marker.lo |= 1 << pos;
+        } else {
+            marker.hi |= 1 << (pos - 128);
+        }
+    });
+    marker
+}
+
+/// Greedily picks the highest position `c < upper` with `choose(c, i) <= value`
+/// for i = k..1, calling `set_bit` with each of them.
+fn rank_positions(value: u64, k: u8, upper: u64, mut set_bit: impl FnMut(u64)) {
+    let mut remaining = u128::from(value);
+    // (exclusive) upper bound on the position of the next bit to set
+    let mut upper = upper;
+    for i in (1..=k).rev() {
+        // choose(i - 1, i) == 0 so `lower` always satisfies the condition
+        let mut lower = u64::from(i) - 1;
+        let mut bound = upper;
+        while bound - lower > 1 {
+            let mid = lower + (bound - lower) / 2;
+            if choose_lookup(mid, i) <= remaining {
+                lower = mid;
+            } else {
+                bound = mid;
             }
-            TryFrom::try_from(num / denom)
-                .unwrap_or_else(|_| panic!("{} choose {} is greater than 2**64", n, k))
-            // (or recursively) choose(n - 1, k - 1) + choose(n-1, k)
-            // for floats, this should work since they handle fractions:
-            // (1..u64::from(k)).map(|i| (n + 1 - i) / i).product(),
         }
+        set_bit(lower);
+        remaining -= choose_lookup(lower, i);
+        upper = lower;
     }
 }
 
+/// Returns the largest value that can be encoded in a marker of
+/// `marker_width` (ν) bits with `n_marker_bits` (κ) of them set, i.e.
+/// `choose(ν, κ) - 1`, capped at `u64::MAX`.
+///
+/// `n_marker_bits` is expected to be between 1 and `marker_width`.
+pub fn capacity(marker_width: u8, n_marker_bits: u8) -> u64 {
+    let n_values = choose_saturating(u64::from(marker_width), n_marker_bits);
+    u64::try_from(n_values.saturating_sub(1)).unwrap_or(u64::MAX)
+}
+
+/// The marker encoding the value after the one `marker` encodes, i.e.
+/// `rank(unrank(marker) + 1, marker.count_ones())`, computed without
+/// decoding it. Returns `None` for 0 and for the last marker with its number
+/// of bits set (with all of them at the top), which has no successor.
+pub fn checked_next_rank(marker: u128) -> Option<u128> {
+    let t = marker | marker.wrapping_sub(1);
+    if marker == 0 || t == u128::MAX {
+        return None;
+    }
+    Some(next_rank(marker))
+}
+
 #[inline]
-fn next_rank(marker: u128) -> u128 {
+const fn next_rank(marker: u128) -> u128 {
     if marker == 0 {
-        unreachable!("Got next_rank called with marker == 0");
+        panic!("Got next_rank called with marker == 0");
     }
     let t = marker | (marker - 1);
     (t + 1) | (((!t & (t + 1)) - 1) >> (marker.trailing_zeros() + 1))
@@ -132,6 +403,151 @@ mod tests {
         assert_eq!(rank(41663, 3).count_ones(), 3);
     }
 
+    #[test]
+    fn test_rank_direct() {
+        // matches the tables where they overlap
+        for k in 1..10u8 {
+            for value in [0u64, 1, 23, 45, 127].iter() {
+                assert_eq!(rank_direct(*value, k), rank(*value, k));
+            }
+        }
+        for k in 2..10u8 {
+            let last = MARKER_TABLE_SIZE as u64 - 1;
+            assert_eq!(rank_direct(last, k), rank(last, k));
+        }
+        assert_eq!(CHOOSE_TABLE[127][16], choose_saturating(127, 16));
+        assert_eq!(rank_direct(199_999, 4), rank(199_999, 4));
+        assert_eq!(
+            rank_direct(MARKER_TABLE_SIZE as u64, 5),
+            next_rank(rank(MARKER_TABLE_SIZE as u64 - 1, 5))
+        );
+
+        // and round-trips past them, including values > 2^32
+        for value in [200_000u64, 1 << 33, choose(128, 9) - 1].iter() {
+            let marker = rank(*value, 9);
+            assert_eq!(marker.count_ones(), 9);
+            assert_eq!(unrank(marker), *value);
+        }
+    }
+
+    #[test]
+    fn test_rank_large_kappa() {
+        for k in 10..=MAX_MARKER_BITS {
+            let max_value = u64::try_from(choose_saturating(128, k) - 1).unwrap_or(u64::MAX);
+            for value in [0u64, 1, 23, 199_999, 1 << 40, max_value].iter() {
+                let marker = rank(*value, k);
+                assert_eq!(marker.count_ones(), u32::from(k));
+                assert_eq!(unrank(marker), *value);
+            }
+        }
+        assert_eq!(rank(0, 16), 0xFFFF);
+        // the largest value for κ=15 sets the top 15 bits
+        assert_eq!(rank(choose(128, 15) - 1, 15), 0x7FFF << 113);
+        // and markers beyond the u64 range saturate instead of overflowing
+        assert_eq!(unrank(0xFFFF << 112), u64::MAX);
+    }
+
+    #[test]
+    fn test_rank_and_unrank_many() {
+        for k in 1..=MAX_MARKER_BITS {
+            let max_value = u64::try_from(choose_saturating(128, k) - 1).unwrap_or(u64::MAX);
+            let values: Vec<u64> = [
+                0,
+                1,
+                23,
+                127,
+                8127,
+                199_999,
+                200_000,
+                (1 << 32) + 23,
+                1 << 40,
+                max_value,
+            ]
+            .iter()
+            .map(|&value| value.min(max_value))
+            .collect();
+            let markers = rank_many(&values, k);
+            let expected: Vec<u128> = values.iter().map(|&value| rank(value, k)).collect();
+            assert_eq!(markers, expected);
+            // values past 2^32 don't wrap around into the tables on 32-bit targets
+            let direct: Vec<u128> = values.iter().map(|&value| rank_direct(value, k)).collect();
+            assert_eq!(markers, direct);
+            let decoded: Vec<u64> = markers.iter().map(|&marker| unrank(marker)).collect();
+            assert_eq!(unrank_many(&markers), decoded);
+        }
+        // including markers that were never inserted
+        let markers = [0, 0xFFFF << 112, u128::MAX];
+        let decoded: Vec<u64> = markers.iter().map(|&marker| unrank(marker)).collect();
+        assert_eq!(unrank_many(&markers), decoded);
+    }
+
+    #[test]
+    fn test_runtime_marker_tables() {
+        assert!(build_runtime_table(3, 0).is_empty());
+        assert!(build_runtime_table(3, 100).is_empty());
+        // κ=4 continues its compiled table, κ=12 starts from 0
+        let table = build_runtime_table(4, 10_000);
+        assert_eq!(table.len(), 10_000 - MARKER_TABLE_SIZE);
+        assert_eq!(table[0], rank_direct(MARKER_TABLE_SIZE as u64, 4));
+        assert_eq!(table.last(), Some(&rank_direct(9999, 4)));
+        let table = build_runtime_table(12, 100);
+        assert_eq!(table.len(), 100);
+        assert_eq!(table[99], rank_direct(99, 12));
+
+        assert!(set_marker_table_size(0, 10).is_err());
+        assert!(set_marker_table_size(17, 10).is_err());
+        // and the size of a κ is fixed once its table is built
+        let _ = rank(1 << 20, 4);
+        assert!(set_marker_table_size(4, 10).is_err());
+    }
+
+    #[test]
+    fn test_checked_next_rank() {
+        for k in [1u8, 3, 9, 16].iter() {
+            for value in [0u64, 41, 8127, 200_000].iter() {
+                if *value >= capacity(128, *k) {
+                    continue;
+                }
+                let marker = rank(*value, *k);
+                assert_eq!(checked_next_rank(marker), Some(rank(value + 1, *k)));
+            }
+        }
+        assert_eq!(checked_next_rank(0), None);
+        assert_eq!(checked_next_rank(1 << 127), None);
+        assert_eq!(checked_next_rank(0b111 << 125), None);
+        assert_eq!(checked_next_rank(0b101 << 125), Some(0b11 << 126));
+        assert_eq!(checked_next_rank(u128::MAX), None);
+    }
+
+    #[test]
+    fn test_try_rank() {
+        assert_eq!(try_rank(35001, 4), Ok(rank(35001, 4)));
+        assert_eq!(try_rank(8127, 2), Ok(0b11 << 126));
+        assert_eq!(
+            try_rank(8128, 2),
+            Err(RankError::ValueOutOfRange {
+                value: 8128,
+                marker_width: 128,
+                n_marker_bits: 2,
+                max_value: 8127,
+            })
+        );
+        assert_eq!(try_rank(0, 0), Err(RankError::InvalidKappa(0)));
+        assert_eq!(try_rank(0, 17), Err(RankError::InvalidKappa(17)));
+        // every u64 fits with κ=16
+        assert!(try_rank(u64::MAX, 16).is_ok());
+
+        let err = io::Error::from(check_rank(1820, 16, 4).unwrap_err());
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().starts_with("value 1820 is out of range"));
+    }
+
+    #[test]
+    #[should_panic(expected = "kappa needs to be between 1 and 16")]
+    fn test_rank_kappa_too_large() {
+        rank(0, 17);
+    }
+
     #[test]
     fn test_unrank() {
         // 3 bit markers
@@ -142,7 +558,7 @@ mod tests {
     #[test]
     fn test_rank_and_unrank() {
         for k in 1..4u8 {
-            for value in [1 as usize, 23, 45].iter() {
+            for value in [1 as u64, 23, 45].iter() {
                 assert_eq!(unrank(rank(*value, k)), *value);
             }
         }
@@ -186,6 +602,34 @@ mod tests {
         assert_eq!(choose(256, 9), 11288510714272000);
     }
 
+    #[test]
+    fn test_choose_saturating() {
+        for n in 0..70u64 {
+            for k in 0..10u8 {
+                if n >= u64::from(k) {
+                    assert_eq!(choose_saturating(n, k), u128::from(choose(n, k)));
+                } else {
+                    assert_eq!(choose_saturating(n, k), 0);
+                }
+            }
+        }
+        assert_eq!(
+            choose_saturating(256, 20),
+            280_437_550_101_996_454_288_136_030_400
+        );
+        assert_eq!(choose_saturating(250, 125), u128::MAX);
+    }
+
+    #[test]
+    fn test_capacity() {
+        assert_eq!(capacity(16, 4), 1819);
+        assert_eq!(capacity(39, 4), 82250);
+        assert_eq!(capacity(128, 1), 127);
+        assert_eq!(capacity(128, 15), choose(128, 15) - 1);
+        assert_eq!(capacity(128, 16), u64::MAX);
+        assert_eq!(capacity(200, 3), 1_313_399);
+    }
+
     #[test]
     #[should_panic(expected = "256 choose 20 is greater than 2**64")]
     fn test_choose_overflow() {
@@ -0,0 +1,141 @@
+//! zstd-compressed export/import, for archiving and shipping `BField`s.
+//!
+//! An export is simply a zstd stream of the container format (see
+//! `container`), which is mostly zeroes for a typical `BField`.
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bfield::BField;
+use crate::bfield_member::{BFieldMember, BFieldValue};
+use crate::container::read_container_image;
+
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> BField<T, V> {
+    /// Writes all the members of this `BField` to a single zstd-compressed
+    /// file at `path`, to be rehydrated with [`BField::import_compressed`].
+    pub fn export_compressed<P: AsRef<Path>>(&self, path: P) -> Result<(), io::Error> {
+        let file = File::create(path)?;
+        let mut encoder = zstd::Encoder::new(BufWriter::new(file), 0)?;
+        self.write_to(&mut encoder)?;
+        let mut writer = encoder.finish()?;
+        writer.flush()?;
+        writer.into_inner()?.sync_all()
+    }
+
+    /// Decompresses a file written by [`BField::export_compressed`] into
+    /// `.bfd` files in `directory` named after `filename` (as with
+    /// [`BField::create`]) and loads them.
+    ///
+    /// A manifest with the member checksums is written alongside them, so
+    /// the result can be checked with [`BField::verify_checksums`].
+    pub fn import_compressed<P, Q>(
+        path: P,
+        directory: Q,
+        filename: &str,
+        read_only: bool,
+    ) -> Result<Self, io::Error>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        debug_assert!(!filename.is_empty());
+        let mut decoder = zstd::Decoder::new(BufReader::new(File::open(path)?))?;
+        let mut member_paths = Vec::new();
+        read_container_image(&mut decoder, |prefix, _, data| {
+            let member_path =
+                directory
+                    .as_ref()
+                    .join(format!("{}.{}.bfd", filename, member_paths.len()));
+            let mut writer = BufWriter::new(File::create(&member_path)?);
+            writer.write_all(prefix)?;
+            io::copy(data, &mut writer)?;
+            writer.into_inner()?.sync_all()?;
+            member_paths.push(member_path);
+            Ok(())
+        })?;
+
+        let members = member_paths
+            .iter()
+            .map(|path| BFieldMember::open(path, read_only))
+            .collect::<Result<Vec<_>, _>>()?;
+        let main_db_path = directory.as_ref().join(format!("{filename}.0.bfd"));
+        let bfield = Self::from_members(members, read_only, &main_db_path)?;
+        bfield.write_manifest(true)?;
+        Ok(bfield)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_export_and_import_compressed() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            1_000_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            3,
+            true,
+            "params".to_string(),
+        )
+        .expect("to build");
+        for p in 0..3 {
+            for i in 0..1_000u32 {
+                bfield.insert(&i.to_be_bytes(), i, p);
+            }
+        }
+        let path = tmp_dir.path().join("bfield.bfd.zst");
+        bfield.export_compressed(&path).unwrap();
+        // the arrays take ~140kB uncompressed
+        assert!(std::fs::metadata(&path).unwrap().len() < 100_000);
+
+        let out_dir = tempfile::tempdir().unwrap();
+        let imported =
+            BField::<String>::import_compressed(&path, out_dir.path(), "copy", true).unwrap();
+        assert_eq!(imported.member_info(), bfield.member_info());
+        assert_eq!(imported.params(), &Some("params".to_string()));
+        for i in 0..1_000u32 {
+            assert_eq!(imported.get(&i.to_be_bytes()), Some(i));
+        }
+        imported.verify_checksums().unwrap();
+        drop(imported);
+
+        // the rehydrated files load like any other
+        let loaded = BField::<String>::load(out_dir.path().join("copy.0.bfd"), true).unwrap();
+        assert_eq!(loaded.get(&5u32.to_be_bytes()), Some(5));
+    }
+
+    #[test]
+    fn rejects_truncated_export() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            3,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        let path = tmp_dir.path().join("bfield.bfd.zst");
+        bfield.export_compressed(&path).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() / 2]).unwrap();
+        assert!(BField::<String>::import_compressed(&path, tmp_dir.path(), "copy", true).is_err());
+    }
+}
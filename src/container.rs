@@ -0,0 +1,541 @@
+//! A single-file layout holding all the members of a `BField`.
+//!
+//! The file starts with an index of where each member is stored:
+//! - 4 magic bytes (`0xBF 0x1D 'C'` followed by the format version)
+//! - the number of members as a big-endian `u32`
+//! - for each member, its offset and length in bytes as big-endian `u64`s
+//!
+//! Each member is then stored exactly as it would be in its own `.bfd` file
+//! (see `storage::encode_member_prefix`), placed so that its bit array
+//! starts on a page boundary.
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+#[cfg(feature = "mmap")]
+use std::io::{Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bfield::BField;
+#[cfg(feature = "mmap")]
+use crate::bfield::{check_marker_params, member_sizes};
+#[cfg(feature = "mmap")]
+use crate::bfield_member::encode_params;
+use crate::bfield_member::{decode_params, BFieldMember, BFieldParams, BFieldValue};
+use crate::corruption::{check_params, CorruptFile};
+use crate::lookup::CONTAINER_MAGIC;
+#[cfg(feature = "mmap")]
+use crate::storage::FileLock;
+use crate::storage::{
+    byte_len, encode_member_prefix, parse_member, parse_member_prefix, replace_file, Mapping,
+    Region, Storage, BF_MAGIC,
+};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Where each member is stored in the container: `(offset, length)` in bytes.
+fn layout(prefixes: &[Vec<u8>], sizes: &[usize]) -> (Vec<(u64, u64)>, u64) {
+    let mut index = Vec::with_capacity(prefixes.len());
+    let mut end = (CONTAINER_MAGIC.len() + 4 + 16 * prefixes.len()) as u64;
+    for (prefix, size) in prefixes.iter().zip(sizes) {
+        let prefix_len = prefix.len() as u64;
+        let data_start = (end + prefix_len).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let len = prefix_len + byte_len(*size) as u64;
+        index.push((data_start - prefix_len, len));
+        end = data_start - prefix_len + len;
+    }
+    (index, end)
+}
+
+/// Encodes the container index.
+fn encode_index(index: &[(u64, u64)]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(CONTAINER_MAGIC.len() + 4 + 16 * index.len());
+    header.extend_from_slice(&CONTAINER_MAGIC);
+    header.extend_from_slice(&(index.len() as u32).to_be_bytes());
+    for (offset, len) in index {
+        header.extend_from_slice(&offset.to_be_bytes());
+        header.extend_from_slice(&len.to_be_bytes());
+    }
+    header
+}
+
+/// Writes the index and member prefixes to `file`, replacing anything it
+/// held and leaving the space for the bit arrays zeroed.
+#[cfg(feature = "mmap")]
+fn write_skeleton(mut file: &File, prefixes: &[Vec<u8>], sizes: &[usize]) -> io::Result<()> {
+    let (index, total_len) = layout(prefixes, sizes);
+    file.set_len(0)?;
+    file.set_len(total_len)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&encode_index(&index))?;
+    for (prefix, (offset, _)) in prefixes.iter().zip(&index) {
+        file.seek(SeekFrom::Start(*offset))?;
+        file.write_all(prefix)?;
+    }
+    Ok(())
+}
+
+/// Writes a container holding `members` to `writer`, front to back (so
+/// `writer` doesn't need to be seekable).
+pub(crate) fn write_container_image<T, W>(
+    members: &[BFieldMember<T>],
+    writer: &mut W,
+) -> io::Result<()>
+where
+    T: Clone + DeserializeOwned + Serialize,
+    W: Write + ?Sized,
+{
+    let prefixes: Vec<Vec<u8>> = members
+        .iter()
+        .map(|m| encode_member_prefix(&m.header(), m.storage().size()))
+        .collect();
+    let sizes: Vec<usize> = members.iter().map(|m| m.storage().size()).collect();
+    let (index, _) = layout(&prefixes, &sizes);
+
+    let header = encode_index(&index);
+    writer.write_all(&header)?;
+    let mut pos = header.len() as u64;
+    for (member, (offset, len)) in members.iter().zip(&index) {
+        io::copy(&mut io::repeat(0).take(offset - pos), writer)?;
+        member.write_to(writer)?;
+        pos = offset + len;
+    }
+    Ok(())
+}
+
+/// Reads a container written by `write_container_image` from `reader`, front
+/// to back, calling `f` with the prefix (see `encode_member_prefix`) and
+/// size of each member and a reader over its bit array.
+pub(crate) fn read_container_image<R, F>(reader: &mut R, mut f: F) -> io::Result<()>
+where
+    R: Read + ?Sized,
+    F: FnMut(&[u8], usize, &mut dyn Read) -> io::Result<()>,
+{
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut start = [0; 8];
+    reader.read_exact(&mut start)?;
+    if start[..4] != CONTAINER_MAGIC {
+        return Err(invalid("Not a BField container (bad magic bytes)"));
+    }
+    let n_members = u32::from_be_bytes(start[4..].try_into().unwrap()) as usize;
+    // `n_secondaries` is a `u8`
+    if n_members > usize::from(u8::MAX) {
+        return Err(invalid("BField container has too many members"));
+    }
+    let mut index = vec![0; 16 * n_members];
+    reader.read_exact(&mut index)?;
+
+    let mut pos = (8 + index.len()) as u64;
+    for entry in index.chunks_exact(16) {
+        let offset = u64::from_be_bytes(entry[..8].try_into().unwrap());
+        let len = u64::from_be_bytes(entry[8..].try_into().unwrap());
+        if offset < pos {
+            return Err(invalid("BField container members are out of order"));
+        }
+        io::copy(&mut Read::take(&mut *reader, offset - pos), &mut io::sink())?;
+
+        let mut prefix = vec![0; 4];
+        reader.read_exact(&mut prefix)?;
+        let header_len = usize::from(u16::from_be_bytes([prefix[2], prefix[3]]));
+        prefix.resize(4 + header_len + 8, 0);
+        reader.read_exact(&mut prefix[4..])?;
+        let size = parse_member_prefix(&prefix)?.size;
+        if prefix.len() as u64 + byte_len(size) as u64 != len {
+            return Err(invalid("BField container member has an unexpected length"));
+        }
+
+        let mut data = Read::take(&mut *reader, byte_len(size) as u64);
+        f(&prefix, size, &mut data)?;
+        if data.limit() != 0 {
+            return Err(invalid("BField container member is truncated"));
+        }
+        pos = offset + len;
+    }
+    Ok(())
+}
+
+/// Checks the params decoded from the header of a member of the container at
+/// `path` describe a member with a bit array of `size` bits.
+fn check_member<T>(params: &BFieldParams<T>, size: usize, path: &Path) -> io::Result<()> {
+    check_params(params, size).map_err(|corruption| {
+        io::Error::from(CorruptFile {
+            path: path.to_path_buf(),
+            corruption,
+        })
+    })
+}
+
+/// Parses the container index, returning `(offset, length)` for each member.
+fn read_index(bytes: &[u8]) -> io::Result<Vec<(usize, usize)>> {
+    Ok(crate::lookup::read_index(bytes)?)
+}
+
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> BField<T, V> {
+    /// Creates a `BField` with all its members stored in a single container
+    /// file at `path` instead of one `.bfd` file per member.
+    ///
+    /// The parameters are the same as for [`BField::create`]; inserts are
+    /// written straight to the (memory-mapped) container.
+    #[allow(clippy::too_many_arguments)]
+    #[cfg(feature = "mmap")]
+    pub fn create_container<P: AsRef<Path>>(
+        path: P,
+        size: usize,
+        n_hashes: u8,             // k
+        marker_width: u8,         // nu
+        n_marker_bits: u8,        // kappa
+        secondary_scaledown: f64, // beta
+        max_scaledown: f64,
+        n_secondaries: u8,
+        other_params: T,
+    ) -> Result<Self, io::Error> {
+        check_marker_params(marker_width, n_marker_bits)?;
+        let sizes = member_sizes(size, secondary_scaledown, max_scaledown, n_secondaries);
+        let prefixes: Vec<Vec<u8>> = sizes
+            .iter()
+            .enumerate()
+            .map(|(n, size)| {
+                let other = if n == 0 {
+                    Some(other_params.clone())
+                } else {
+                    None
+                };
+                let params =
+                    BFieldParams::new(n_hashes, marker_width, n_marker_bits, V::BITS, other);
+                encode_member_prefix(&encode_params(&params), *size)
+            })
+            .collect();
+
+        // locked before writing, so a container in use elsewhere is left alone
+        let lock = FileLock::acquire(path.as_ref(), true)?;
+        write_skeleton(lock.file(), &prefixes, &sizes)?;
+        let map = Arc::new(Mapping::open(lock, false)?);
+        Self::from_mapping(map, path.as_ref(), false)
+    }
+
+    /// Loads a `BField` from a container file written by [`BField::create_container`]
+    /// or [`BField::write_container`].
+    #[cfg(feature = "mmap")]
+    pub fn load_container<P: AsRef<Path>>(path: P, read_only: bool) -> Result<Self, io::Error> {
+        let lock = FileLock::acquire(path.as_ref(), !read_only)?;
+        let map = Arc::new(Mapping::open(lock, read_only)?);
+        Self::from_mapping(map, path.as_ref(), read_only)
+    }
+
+    /// Loads a `BField` from a container image in `bytes` (e.g. embedded with
+    /// `include_bytes!`) without copying it. `bytes` can also hold a single
+    /// `.bfd` file, giving a `BField` with only a primary array.
+    ///
+    /// The `BField` is read-only; inserting into it panics.
+    pub fn from_bytes(bytes: &'static [u8]) -> Result<Self, io::Error> {
+        // Safety: the bytes are static and immutable
+        unsafe { Self::from_raw_bytes(bytes) }
+    }
+
+    /// Same as [`BField::from_bytes`] for bytes that aren't `'static`, e.g. a
+    /// mapping managed by the caller.
+    ///
+    /// # Safety
+    ///
+    /// `bytes` must stay alive and unchanged for as long as the returned
+    /// `BField` is in use.
+    pub unsafe fn from_raw_bytes(bytes: &[u8]) -> Result<Self, io::Error> {
+        let bytes: &'static [u8] = std::slice::from_raw_parts(bytes.as_ptr(), bytes.len());
+        let path = Path::new("<bytes>");
+        // the container magic starts with `BF_MAGIC` too
+        if !bytes.starts_with(&CONTAINER_MAGIC) && bytes.starts_with(&BF_MAGIC) {
+            let member = BFieldMember::from_bytes(bytes)?;
+            return Self::from_members(vec![member], true, path);
+        }
+        Self::from_mapping(Arc::new(Mapping::Borrowed(bytes)), path, true)
+    }
+
+    /// Loads the members of the container mapped in `map`.
+    fn from_mapping(map: Arc<Mapping>, path: &Path, read_only: bool) -> Result<Self, io::Error> {
+        let index = read_index(map.as_slice())?;
+
+        let mut members = Vec::with_capacity(index.len());
+        for (offset, len) in index {
+            let image = parse_member(&map.as_slice()[offset..offset + len])?;
+            let params = decode_params(image.header)?;
+            check_member(&params, image.size, path)?;
+            let storage = Storage::Region(Region::new(
+                Arc::clone(&map),
+                offset + image.data_offset,
+                image.size,
+            ));
+            members.push(BFieldMember::new(path.to_path_buf(), storage, params));
+        }
+        Self::from_members(members, read_only, path)
+    }
+
+    /// Writes all the members of this `BField` to a single container file at
+    /// `path`, which can then be opened with [`BField::load_container`].
+    ///
+    /// The container is written to a temporary file next to `path` first and
+    /// then renamed over it, so a container already at `path` (even the one
+    /// this `BField` was loaded from) is never truncated while mapped.
+    pub fn write_container<P: AsRef<Path>>(&self, path: P) -> Result<(), io::Error> {
+        let path = path.as_ref();
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+        let written = File::create(&tmp_path).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            self.write_to(&mut writer)?;
+            writer.into_inner()?.sync_all()
+        });
+        if let Err(e) = written {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        replace_file(&tmp_path, path)
+    }
+
+    /// Writes all the members of this `BField` to `writer` in the container
+    /// format, e.g. to stream it to object storage or over a socket.
+    pub fn write_to<W: Write + ?Sized>(&self, writer: &mut W) -> Result<(), io::Error> {
+        write_container_image(self.members(), writer)
+    }
+
+    /// Reads a `BField` written by [`BField::write_to`] (or a container file)
+    /// from `reader`.
+    ///
+    /// The members are kept in memory and aren't associated with any files;
+    /// use [`BField::write_to`] or [`BField::write_container`] to save them.
+    pub fn read_from<R: Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        let path = Path::new("<reader>");
+        let mut members = Vec::new();
+        read_container_image(reader, |prefix, size, data| {
+            let params = decode_params(parse_member_prefix(prefix)?.header)?;
+            check_member(&params, size, path)?;
+            members.push(BFieldMember::read_bits(PathBuf::new(), params, size, data)?);
+            Ok(())
+        })?;
+        Self::from_members(members, false, path)
+    }
+}
+
+// most of these need file-backed members
+#[cfg(all(test, feature = "mmap"))]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn can_build_and_query_container() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("bfield.bfc");
+        let bfield: BField<String> =
+            BField::create_container(&path, 100_000, 10, 39, 4, 0.1, 0.025, 3, "params".into())
+                .expect("to build");
+        for p in 0..3 {
+            for i in 0..1_000u32 {
+                bfield.insert(&i.to_be_bytes(), i, p);
+            }
+        }
+        drop(bfield);
+
+        let bfield = BField::<String>::load_container(&path, true).unwrap();
+        assert_eq!(bfield.params(), &Some("params".to_string()));
+        assert_eq!(bfield.member_info().len(), 3);
+        for i in 0..1_000u32 {
+            assert_eq!(bfield.get(&i.to_be_bytes()), Some(i));
+        }
+    }
+
+    #[test]
+    fn can_pack_bfield_into_container() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            3,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        for p in 0..3 {
+            for i in 0..1_000u32 {
+                bfield.insert(&i.to_be_bytes(), i, p);
+            }
+        }
+        let path = tmp_dir.path().join("bfield.bfc");
+        bfield.write_container(&path).unwrap();
+
+        let packed = BField::<String>::load_container(&path, true).unwrap();
+        assert_eq!(packed.member_info(), bfield.member_info());
+        for i in 0..1_000u32 {
+            assert_eq!(packed.get(&i.to_be_bytes()), Some(i));
+        }
+
+        // writing over the container it's mapped from leaves it readable
+        packed.write_container(&path).unwrap();
+        assert_eq!(packed.get(&5u32.to_be_bytes()), Some(5));
+        let repacked = BField::<String>::load_container(&path, true).unwrap();
+        assert_eq!(repacked.get(&5u32.to_be_bytes()), Some(5));
+        assert!(!tmp_dir.path().join("bfield.bfc.tmp").exists());
+    }
+
+    #[test]
+    fn rejects_truncated_container() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("bfield.bfc");
+        let bfield: BField<String> =
+            BField::create_container(&path, 100_000, 10, 39, 4, 0.1, 0.025, 3, String::new())
+                .expect("to build");
+        drop(bfield);
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 1).unwrap();
+        drop(file);
+        assert!(BField::<String>::load_container(&path, true).is_err());
+    }
+
+    #[test]
+    fn can_stream_bfield() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_003,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            3,
+            true,
+            "params".into(),
+        )
+        .expect("to build");
+        for p in 0..3 {
+            for i in 0..1_000u32 {
+                bfield.insert(&i.to_be_bytes(), i, p);
+            }
+        }
+        let mut buf = Vec::new();
+        bfield.write_to(&mut buf).unwrap();
+
+        let streamed = BField::<String>::read_from(&mut &buf[..]).unwrap();
+        assert_eq!(streamed.member_info(), bfield.member_info());
+        assert_eq!(streamed.params(), &Some("params".to_string()));
+        for i in 0..1_000u32 {
+            assert_eq!(streamed.get(&i.to_be_bytes()), Some(i));
+        }
+        for (a, b) in streamed.members().iter().zip(bfield.members()) {
+            assert_eq!(a.storage().as_bytes(), b.storage().as_bytes());
+        }
+
+        // the stream is a valid container file
+        let path = tmp_dir.path().join("bfield.bfc");
+        std::fs::write(&path, &buf).unwrap();
+        let loaded = BField::<String>::load_container(&path, true).unwrap();
+        assert_eq!(loaded.get(&5u32.to_be_bytes()), Some(5));
+
+        assert!(BField::<String>::read_from(&mut &buf[..buf.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn can_load_from_bytes() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            3,
+            false,
+            "params".into(),
+        )
+        .expect("to build");
+        for p in 0..3 {
+            for i in 0..1_000u32 {
+                bfield.insert(&i.to_be_bytes(), i, p);
+            }
+        }
+        let mut buf = Vec::new();
+        bfield.write_to(&mut buf).unwrap();
+
+        let loaded =
+            BField::<String>::from_bytes(Box::leak(buf.clone().into_boxed_slice())).unwrap();
+        assert_eq!(loaded.member_info(), bfield.member_info());
+        assert_eq!(loaded.params(), &Some("params".to_string()));
+        let borrowed = unsafe { BField::<String>::from_raw_bytes(&buf) }.unwrap();
+        for i in 0..1_000u32 {
+            assert_eq!(loaded.get(&i.to_be_bytes()), Some(i));
+            assert_eq!(borrowed.get(&i.to_be_bytes()), Some(i));
+        }
+        drop(borrowed);
+
+        // a single member file
+        let primary = std::fs::read(tmp_dir.path().join("bfield.0.bfd")).unwrap();
+        let primary = BField::<String>::from_bytes(Box::leak(primary.into_boxed_slice())).unwrap();
+        assert_eq!(primary.member_info(), &bfield.member_info()[..1]);
+        assert_eq!(primary.params(), &Some("params".to_string()));
+    }
+
+    #[test]
+    fn rejects_tampered_container() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("bfield.bfc");
+        let bfield: BField<String> =
+            BField::create_container(&path, 10_000, 10, 39, 4, 0.1, 0.025, 3, String::new())
+                .expect("to build");
+        drop(bfield);
+        // zero hashes in the header of the second member
+        let mut bytes = std::fs::read(&path).unwrap();
+        let offset = u64::from_be_bytes(bytes[24..32].try_into().unwrap()) as usize;
+        bytes[offset + 4] = 0;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let is_corrupt = |err: io::Error| {
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            err.get_ref().unwrap().is::<CorruptFile>()
+        };
+        let err = BField::<String>::load_container(&path, true).err().unwrap();
+        assert!(is_corrupt(err));
+        let err = BField::<String>::read_from(&mut &bytes[..]).err().unwrap();
+        assert!(is_corrupt(err));
+        let bytes = Box::leak(bytes.into_boxed_slice());
+        let err = BField::<String>::from_bytes(bytes).err().unwrap();
+        assert!(is_corrupt(err));
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't")]
+    fn cant_insert_into_bytes() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("bfield.bfc");
+        drop(
+            BField::<String>::create_container(
+                &path,
+                10_000,
+                10,
+                39,
+                4,
+                0.1,
+                0.025,
+                3,
+                String::new(),
+            )
+            .unwrap(),
+        );
+        let bytes = std::fs::read(&path).unwrap();
+        let bfield = BField::<String>::from_bytes(Box::leak(bytes.into_boxed_slice())).unwrap();
+        bfield.insert(b"test", 1, 0);
+    }
+}
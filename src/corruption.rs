@@ -0,0 +1,250 @@
+//! Detecting corrupted or truncated `.bfd` files when they're opened, before
+//! mapping them (a truncated file would otherwise fault on the first lookup
+//! past its end).
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::bfield_member::{decode_params_with, BFieldParams};
+use crate::combinatorial::MAX_MARKER_BITS;
+use crate::lookup::{byte_len, BLOCK_BITS};
+use crate::params::ParamsUpgrade;
+use crate::storage::BF_MAGIC;
+
+/// What's wrong with a corrupted `.bfd` file, see [`CorruptFile`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Corruption {
+    /// The file doesn't start with the magic bytes of a `.bfd` file
+    BadMagic,
+    /// The file ends before the end of its header
+    TruncatedHeader {
+        /// Length of the file, in bytes
+        file_len: u64,
+        /// Where the header (and the bit array size after it) ends
+        header_end: u64,
+    },
+    /// The header can't be decoded
+    InvalidHeader(String),
+    /// The file is shorter than its header and bit array
+    TruncatedArray {
+        /// Length of the file, in bytes
+        file_len: u64,
+        /// Length of the header and bit array, in bytes
+        expected_len: u64,
+    },
+    /// The parameters in the header can't describe a member
+    InvalidParams(String),
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Corruption::BadMagic => f.write_str("bad magic bytes"),
+            Corruption::TruncatedHeader {
+                file_len,
+                header_end,
+            } => write!(
+                f,
+                "truncated header (the file is {} bytes, the header ends at {})",
+                file_len, header_end
+            ),
+            Corruption::InvalidHeader(reason) => write!(f, "invalid header ({})", reason),
+            Corruption::TruncatedArray {
+                file_len,
+                expected_len,
+            } => write!(
+                f,
+                "truncated bit array (the file is {} bytes, expected {})",
+                file_len, expected_len
+            ),
+            Corruption::InvalidParams(reason) => write!(f, "invalid parameters ({})", reason),
+        }
+    }
+}
+
+impl Error for Corruption {}
+
+/// A `.bfd` file that can't be opened because it's corrupted or truncated.
+/// Loading one returns an `InvalidData` `io::Error` wrapping this (see
+/// `io::Error::get_ref`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorruptFile {
+    /// The corrupted file
+    pub path: PathBuf,
+    /// What's wrong with it
+    pub corruption: Corruption,
+}
+
+impl fmt::Display for CorruptFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} is corrupt: {}", self.path, self.corruption)
+    }
+}
+
+impl Error for CorruptFile {}
+
+impl From<CorruptFile> for io::Error {
+    fn from(error: CorruptFile) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// Checks the parameters decoded from a member header describe a member
+/// with a bit array of `size` bits.
+pub(crate) fn check_params<T>(params: &BFieldParams<T>, size: usize) -> Result<(), Corruption> {
+    let (n_hashes, marker_width, n_marker_bits) =
+        (params.n_hashes, params.marker_width, params.n_marker_bits);
+    let invalid = |reason: String| Err(Corruption::InvalidParams(reason));
+    if n_hashes == 0 || n_hashes > 16 {
+        return invalid(format!("{} hashes", n_hashes));
+    }
+    if marker_width == 0 || usize::from(marker_width) >= size {
+        return invalid(format!(
+            "markers of {} bits in an array of {} bits",
+            marker_width, size
+        ));
+    }
+    if ![8, 16, 32, 64].contains(&params.value_bits) {
+        return invalid(format!("{}-bit values", params.value_bits));
+    }
+    if params.bitset {
+        if marker_width > params.value_bits {
+            return invalid(format!(
+                "bitsets of {} bits for {}-bit values",
+                marker_width, params.value_bits
+            ));
+        }
+    } else if n_marker_bits == 0 || n_marker_bits > MAX_MARKER_BITS || n_marker_bits > marker_width
+    {
+        return invalid(format!(
+            "markers of {} bits with {} set",
+            marker_width, n_marker_bits
+        ));
+    }
+    if params.blocked && size < BLOCK_BITS {
+        return invalid(format!("a blocked layout in {} bits", size));
+    }
+    Ok(())
+}
+
+/// Checks the `.bfd` file at `path` has the magic bytes, a header that
+/// decodes to valid parameters and a bit array as long as the header says,
+/// before it's mapped or read (with params written with an older version
+/// converted with `upgrade`).
+pub(crate) fn check_member_file<T: serde::de::DeserializeOwned>(
+    path: &Path,
+    upgrade: Option<&ParamsUpgrade>,
+) -> Result<(), io::Error> {
+    let corrupt = |corruption| {
+        io::Error::from(CorruptFile {
+            path: path.to_path_buf(),
+            corruption,
+        })
+    };
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut prefix = vec![0; 4];
+    if file_len < 4 {
+        return Err(corrupt(Corruption::TruncatedHeader {
+            file_len,
+            header_end: 4,
+        }));
+    }
+    file.read_exact(&mut prefix)?;
+    if prefix[..2] != BF_MAGIC {
+        return Err(corrupt(Corruption::BadMagic));
+    }
+    let header_len = usize::from(u16::from_be_bytes([prefix[2], prefix[3]]));
+    let header_end = (4 + header_len + 8) as u64;
+    if file_len < header_end {
+        return Err(corrupt(Corruption::TruncatedHeader {
+            file_len,
+            header_end,
+        }));
+    }
+    prefix.resize(header_end as usize, 0);
+    file.read_exact(&mut prefix[4..])?;
+    let header = &prefix[4..4 + header_len];
+    let size = u64::from_be_bytes(prefix[4 + header_len..].try_into().unwrap());
+    let params = decode_params_with::<T>(header, upgrade)
+        .map_err(|e| corrupt(Corruption::InvalidHeader(e.to_string())))?;
+    let expected_len = usize::try_from(size)
+        .ok()
+        .map(|size| header_end.saturating_add(byte_len(size) as u64))
+        .unwrap_or(u64::MAX);
+    if file_len < expected_len {
+        return Err(corrupt(Corruption::TruncatedArray {
+            file_len,
+            expected_len,
+        }));
+    }
+    check_params(&params, size as usize).map_err(corrupt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bfield_member::BFieldMember;
+    use std::fs;
+
+    #[test]
+    fn can_detect_corrupt_files() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("member.bfd");
+        let member: BFieldMember<String> =
+            BFieldMember::create(&path, false, 1024, 3, 16, 4, 32, None).unwrap();
+        member.insert(b"key", 7);
+        drop(member);
+        let bytes = fs::read(&path).unwrap();
+        check_member_file::<String>(&path, None).unwrap();
+
+        let corruption = |bytes: &[u8]| {
+            fs::write(&path, bytes).unwrap();
+            let err = check_member_file::<String>(&path, None).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            let corrupt = err.get_ref().unwrap().downcast_ref::<CorruptFile>();
+            corrupt.unwrap().corruption.clone()
+        };
+        assert_eq!(
+            corruption(b"BF"),
+            Corruption::TruncatedHeader {
+                file_len: 2,
+                header_end: 4,
+            }
+        );
+        assert_eq!(corruption(b"XXXXXXXX"), Corruption::BadMagic);
+        assert!(matches!(
+            corruption(&bytes[..10]),
+            Corruption::TruncatedHeader { file_len: 10, .. }
+        ));
+        assert_eq!(
+            corruption(&bytes[..bytes.len() - 1]),
+            Corruption::TruncatedArray {
+                file_len: bytes.len() as u64 - 1,
+                expected_len: bytes.len() as u64,
+            }
+        );
+        // n_hashes is the first byte of the header
+        let mut no_hashes = bytes.clone();
+        no_hashes[4] = 0;
+        assert!(matches!(
+            corruption(&no_hashes),
+            Corruption::InvalidParams(_)
+        ));
+        // and a header length covering the bit array size makes it undecodable
+        let mut short_header = bytes.clone();
+        short_header[3] = 1;
+        assert!(matches!(
+            corruption(&short_header),
+            Corruption::InvalidHeader(_) | Corruption::InvalidParams(_)
+        ));
+
+        // loading goes through the same checks
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let err = BFieldMember::<String>::open(&path, true).err().unwrap();
+        assert!(err.to_string().contains("truncated bit array"));
+    }
+}
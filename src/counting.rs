@@ -0,0 +1,313 @@
+//! A `BField` that also keeps approximate counts of how many times each key
+//! was inserted, in the manner of a counting Bloom filter.
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::bfield::{BField, LoadOptions};
+use crate::bfield_member::{BFieldMember, BFieldVal, BFieldValue, Tristate};
+
+/// A [`BField`] with layers of single-bit arrays counting insertions: a key
+/// inserted `n` times is in the first `n` layers, so its count is the number
+/// of leading layers it's in. Like a counting Bloom filter, counts can be
+/// overestimated (with about the false positive rate of a layer) but never
+/// underestimated, up to the number of layers.
+///
+/// Layers are stored next to the `BField`'s `.bfd` files as
+/// `{filename}.count{n}.bfd`.
+#[derive(Debug)]
+pub struct CountingBField<T, V = BFieldVal> {
+    bfield: BField<T, V>,
+    layers: Vec<BFieldMember<T>>,
+    /// Serialize the increments of each key, by key hash, so concurrent ones
+    /// don't both find the same layer empty
+    increments: Box<[Mutex<()>]>,
+}
+
+/// Number of locks increments are spread over.
+const N_INCREMENT_LOCKS: usize = 64;
+
+fn increment_locks() -> Box<[Mutex<()>]> {
+    (0..N_INCREMENT_LOCKS).map(|_| Mutex::new(())).collect()
+}
+
+/// The path of count layer `n` for a `BField` whose primary array is at
+/// `main_db_path` (`{filename}.0.bfd` -> `{filename}.count{n}.bfd`).
+fn layer_path(main_db_path: &Path, n: usize) -> Option<PathBuf> {
+    let filename = main_db_path.file_name()?.to_str()?;
+    let base = filename.strip_suffix("0.bfd")?;
+    Some(main_db_path.with_file_name(format!("{base}count{n}.bfd")))
+}
+
+fn invalid_filename(main_db_path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Can't add count layers to the BField at {:?}", main_db_path),
+    )
+}
+
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> CountingBField<T, V> {
+    /// Adds `max_count` count layers of `layer_size` bits each, using
+    /// `n_hashes` hashes, to an empty `bfield`. They're stored like its
+    /// primary array: in `.bfd` files if it is, in memory otherwise, and
+    /// hash keys like it (with its secret and seed, if any).
+    pub fn new(
+        bfield: BField<T, V>,
+        layer_size: usize,
+        n_hashes: u8,
+        max_count: u8,
+    ) -> Result<Self, io::Error> {
+        let primary = &bfield.members()[0];
+        let backing = primary.storage().backing();
+        let layers = (0..usize::from(max_count))
+            .map(|n| {
+                let path = layer_path(&primary.filename, n)
+                    .ok_or_else(|| invalid_filename(&primary.filename))?;
+                let mut layer =
+                    BFieldMember::create_bitset(path, backing, layer_size, n_hashes, 1, 8, None)?;
+                layer.use_hashing_of(primary, 0)?;
+                Ok(layer)
+            })
+            .collect::<Result<_, io::Error>>()?;
+        Ok(CountingBField {
+            bfield,
+            layers,
+            increments: increment_locks(),
+        })
+    }
+
+    /// Loads a `BField` and its count layers given the path to its primary
+    /// array data file, see [`BField::load`].
+    pub fn load<P: AsRef<Path>>(main_db_path: P, read_only: bool) -> Result<Self, io::Error> {
+        Self::load_with(main_db_path, &LoadOptions::new().read_only(read_only))
+    }
+
+    /// Same as [`CountingBField::load`], with the extra settings in
+    /// `options` (e.g. the secret keys were hashed with, which the count
+    /// layers are checked against too).
+    pub fn load_with<P: AsRef<Path>>(
+        main_db_path: P,
+        options: &LoadOptions,
+    ) -> Result<Self, io::Error> {
+        let main_db_path = main_db_path.as_ref();
+        let bfield = BField::load_with(main_db_path, options)?;
+        let primary = &bfield.members()[0];
+        let mut layers = Vec::new();
+        loop {
+            let path = layer_path(main_db_path, layers.len())
+                .ok_or_else(|| invalid_filename(main_db_path))?;
+            if !path.exists() {
+                break;
+            }
+            let layer: BFieldMember<T> = options.open_member(&path)?;
+            if layer.params.secret_check != primary.params.secret_check
+                || layer.hash_seed() != primary.hash_seed()
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?} doesn't hash keys like {:?}", path, main_db_path),
+                ));
+            }
+            layers.push(layer);
+        }
+        Ok(CountingBField {
+            bfield,
+            layers,
+            increments: increment_locks(),
+        })
+    }
+
+    /// Writes the `BField` and its count layers to disk, see
+    /// [`BField::persist_to_disk`].
+    pub fn persist_to_disk(self) -> Result<Self, io::Error> {
+        let bfield = self.bfield.persist_to_disk()?;
+        let layers = self
+            .layers
+            .into_iter()
+            .map(|layer| layer.persist_to_disk_reporting(&|_| {}))
+            .collect::<Result<_, io::Error>>()?;
+        Ok(CountingBField {
+            bfield,
+            layers,
+            increments: self.increments,
+        })
+    }
+
+    /// The underlying `BField`.
+    pub fn bfield(&self) -> &BField<T, V> {
+        &self.bfield
+    }
+
+    /// The largest count that can be recorded.
+    pub fn max_count(&self) -> u32 {
+        self.layers.len() as u32
+    }
+
+    /// Inserts `key` with `value` like [`BField::insert`], counting it on the
+    /// first pass only (so each key is counted once per time it's given to
+    /// a build).
+    pub fn insert(&self, key: &[u8], value: V, pass: usize) -> bool {
+        if pass == 0 {
+            self.increment(key);
+        }
+        self.bfield.insert(key, value, pass)
+    }
+
+    /// Counts an insertion of `key` without storing a value, returning its
+    /// new count (which stays at `max_count` once reached). Increments of the
+    /// same key from several threads are serialized, so none are lost.
+    pub fn increment(&self, key: &[u8]) -> u32 {
+        let lock = &self.increments[(xxh3_64(key) % N_INCREMENT_LOCKS as u64) as usize];
+        let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+        for (n, layer) in self.layers.iter().enumerate() {
+            if layer.contains(key) == Tristate::Absent {
+                layer.insert(key, 1);
+                return n as u32 + 1;
+            }
+        }
+        self.max_count()
+    }
+
+    /// Approximately how many times `key` was inserted, capped at
+    /// `max_count`.
+    pub fn count(&self, key: &[u8]) -> u32 {
+        self.layers
+            .iter()
+            .take_while(|layer| layer.contains(key) == Tristate::Present)
+            .count() as u32
+    }
+
+    /// The value stored for `key`, see [`BField::get`].
+    pub fn get(&self, key: &[u8]) -> Option<V> {
+        self.bfield.get(key)
+    }
+
+    /// The value stored for `key` and how many times it was inserted, or
+    /// `None` if it has no value.
+    pub fn get_with_count(&self, key: &[u8]) -> Option<(V, u32)> {
+        let value = self.bfield.get(key)?;
+        Some((value, self.count(key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyed::HashSecret;
+
+    #[test]
+    fn can_count_insertions() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            1_000_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        let counting = CountingBField::new(bfield, 200_000, 4, 8).unwrap();
+        for pass in 0..2 {
+            for i in 0..1_000u32 {
+                // key i is inserted (i % 5) + 1 times
+                for _ in 0..=(i % 5) {
+                    counting.insert(&i.to_be_bytes(), i, pass);
+                }
+            }
+        }
+        for _ in 0..20 {
+            counting.increment(b"frequent");
+        }
+        let counting = counting.persist_to_disk().unwrap();
+        drop(counting);
+
+        let counting: CountingBField<String> =
+            CountingBField::load(tmp_dir.path().join("bfield.0.bfd"), true).unwrap();
+        assert_eq!(counting.max_count(), 8);
+        let n_exact = (0..1_000u32)
+            .filter(|&i| counting.get_with_count(&i.to_be_bytes()) == Some((i, i % 5 + 1)))
+            .count();
+        assert!(n_exact > 990, "{}", n_exact);
+        assert!((0..1_000u32).all(|i| counting.count(&i.to_be_bytes()) > i % 5));
+        assert_eq!(counting.count(b"frequent"), 8);
+        assert_eq!(counting.get(b"frequent"), None);
+    }
+
+    #[test]
+    fn can_count_with_keyed_hashing() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            1,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        let secret = HashSecret::new([7; 16]);
+        bfield.use_keyed_hashing(secret).unwrap();
+        bfield.use_hash_seed(3).unwrap();
+        let counting = CountingBField::new(bfield, 100_000, 4, 4).unwrap();
+        assert_eq!(counting.layers[0].hash_secret(), Some(secret));
+        assert_eq!(counting.layers[0].hash_seed(), 3);
+        counting.insert(b"key", 1, 0);
+        counting.insert(b"key", 1, 0);
+        drop(counting.persist_to_disk().unwrap());
+
+        let path = tmp_dir.path().join("bfield.0.bfd");
+        let options = LoadOptions::new().read_only(true).hash_secret(secret);
+        let counting: CountingBField<String> = CountingBField::load_with(&path, &options).unwrap();
+        assert_eq!(counting.count(b"key"), 2);
+        assert_eq!(counting.get(b"key"), Some(1));
+
+        let options = LoadOptions::new()
+            .read_only(true)
+            .hash_secret(HashSecret::new([8; 16]));
+        assert!(CountingBField::<String>::load_with(&path, &options).is_err());
+    }
+
+    #[test]
+    fn can_count_from_several_threads() {
+        let bfield: BField<String> = BField::create(
+            "",
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            1,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        let counting = CountingBField::new(bfield, 100_000, 4, 8).unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for i in 0..1_000u32 {
+                        counting.increment(&i.to_be_bytes());
+                    }
+                });
+            }
+        });
+        assert!((0..1_000u32).all(|i| counting.count(&i.to_be_bytes()) == 8));
+    }
+}
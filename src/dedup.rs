@@ -0,0 +1,220 @@
+//! Skipping the keys a build has already seen, see [`BField::dedup_keys`].
+//!
+//! Before the first pass, the 128-bit hash of every key is recorded with its
+//! position; sorting these by hash finds the positions of the repeated keys,
+//! which every pass then skips. Once there are too many hashes to keep in
+//! memory, they're spilled to 256 files by their first byte, which are then
+//! sorted one at a time.
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use xxhash_rust::xxh3::xxh3_128;
+
+/// Number of files the hashes are spilled to.
+const N_SPILL_FILES: usize = 256;
+
+/// Size of a spilled `(hash, position)` record.
+const RECORD_LEN: usize = 24;
+
+/// How [`BField::dedup_keys`](crate::BField::dedup_keys) finds the keys a
+/// build has already seen.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DedupOptions {
+    max_in_memory: usize,
+    spill_dir: Option<PathBuf>,
+}
+
+impl Default for DedupOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DedupOptions {
+    /// Keeps up to 2^24 key hashes (384MiB) in memory, then spills them to
+    /// the system's temporary directory.
+    pub fn new() -> Self {
+        DedupOptions {
+            max_in_memory: 1 << 24,
+            spill_dir: None,
+        }
+    }
+
+    /// The number of key hashes kept in memory (24 bytes each) before
+    /// spilling them to disk.
+    pub fn max_in_memory(mut self, n_keys: usize) -> Self {
+        self.max_in_memory = n_keys.max(1);
+        self
+    }
+
+    /// Where the key hashes are spilled, instead of the system's temporary
+    /// directory.
+    pub fn spill_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.spill_dir = Some(dir.into());
+        self
+    }
+}
+
+/// The files hashes are spilled to, in a directory removed on drop.
+struct Spill {
+    dir: PathBuf,
+    files: Vec<BufWriter<File>>,
+}
+
+impl Spill {
+    fn create(parent: &Path) -> Result<Self, io::Error> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        let dir = parent.join(format!("bfield-dedup-{}-{}", std::process::id(), nanos));
+        fs::create_dir(&dir)?;
+        let mut spill = Spill {
+            dir,
+            files: Vec::with_capacity(N_SPILL_FILES),
+        };
+        for i in 0..N_SPILL_FILES {
+            let file = File::create(spill.path(i))?;
+            spill.files.push(BufWriter::new(file));
+        }
+        Ok(spill)
+    }
+
+    fn path(&self, i: usize) -> PathBuf {
+        self.dir.join(format!("{:02x}.bin", i))
+    }
+
+    fn write(&mut self, records: &[(u128, u64)]) -> Result<(), io::Error> {
+        for &(hash, position) in records {
+            let file = &mut self.files[(hash >> 120) as usize];
+            file.write_all(&hash.to_le_bytes())?;
+            file.write_all(&position.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn read(&self, i: usize) -> Result<Vec<(u128, u64)>, io::Error> {
+        let mut reader = BufReader::new(File::open(self.path(i))?);
+        let mut records = Vec::new();
+        let mut record = [0; RECORD_LEN];
+        loop {
+            match reader.read_exact(&mut record) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(records),
+                Err(e) => return Err(e),
+            }
+            let (hash, position) = record.split_at(16);
+            records.push((
+                u128::from_le_bytes(hash.try_into().unwrap()),
+                u64::from_le_bytes(position.try_into().unwrap()),
+            ));
+        }
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Adds the positions of the repeated keys of `records` to `duplicates`,
+/// keeping the first position of each key.
+fn find_duplicates(records: &mut [(u128, u64)], duplicates: &mut Vec<u64>) {
+    records.sort_unstable();
+    for pair in records.windows(2) {
+        if pair[0].0 == pair[1].0 {
+            duplicates.push(pair[1].1);
+        }
+    }
+}
+
+/// The positions in `keys` of the keys already seen before, in ascending
+/// order.
+pub(crate) fn duplicate_positions<I, K>(
+    keys: I,
+    options: &DedupOptions,
+) -> Result<Vec<u64>, io::Error>
+where
+    I: IntoIterator<Item = K>,
+    K: AsRef<[u8]>,
+{
+    let mut records = Vec::new();
+    let mut spill: Option<Spill> = None;
+    for (position, key) in keys.into_iter().enumerate() {
+        records.push((xxh3_128(key.as_ref()), position as u64));
+        if records.len() >= options.max_in_memory {
+            let spill = match &mut spill {
+                Some(spill) => spill,
+                None => {
+                    let dir = options.spill_dir.clone().unwrap_or_else(std::env::temp_dir);
+                    spill.insert(Spill::create(&dir)?)
+                }
+            };
+            spill.write(&records)?;
+            records.clear();
+        }
+    }
+
+    let mut duplicates = Vec::new();
+    match spill {
+        None => find_duplicates(&mut records, &mut duplicates),
+        Some(mut spill) => {
+            spill.write(&records)?;
+            drop(records);
+            for file in &mut spill.files {
+                file.flush()?;
+            }
+            for i in 0..N_SPILL_FILES {
+                find_duplicates(&mut spill.read(i)?, &mut duplicates);
+            }
+        }
+    }
+    duplicates.sort_unstable();
+    Ok(duplicates)
+}
+
+/// The items of `iter` but the ones at `skipped` positions (in ascending
+/// order).
+pub(crate) fn skip_positions<'a, I: Iterator + 'a>(
+    iter: I,
+    skipped: &'a [u64],
+) -> impl Iterator<Item = I::Item> + 'a {
+    let mut skipped = skipped.iter().peekable();
+    iter.enumerate().filter_map(move |(position, item)| {
+        if skipped.next_if_eq(&&(position as u64)).is_some() {
+            None
+        } else {
+            Some(item)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_find_duplicates() {
+        let keys = vec!["a", "b", "a", "c", "b", "a", "d"];
+        let in_memory = duplicate_positions(&keys, &DedupOptions::new()).unwrap();
+        assert_eq!(in_memory, [2, 4, 5]);
+        let kept: Vec<&str> = skip_positions(keys.iter().copied(), &in_memory).collect();
+        assert_eq!(kept, ["a", "b", "c", "d"]);
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let options = DedupOptions::new()
+            .max_in_memory(2)
+            .spill_dir(tmp_dir.path());
+        assert_eq!(duplicate_positions(&keys, &options).unwrap(), in_memory);
+        // the spilled hashes are removed
+        assert_eq!(fs::read_dir(tmp_dir.path()).unwrap().count(), 0);
+
+        let many: Vec<u32> = (0..10_000).map(|i| i % 7_000).collect();
+        let keys = many.iter().map(|i| i.to_be_bytes());
+        let spilled = duplicate_positions(keys, &options.max_in_memory(1_000)).unwrap();
+        assert_eq!(spilled, (7_000..10_000).collect::<Vec<u64>>());
+    }
+}
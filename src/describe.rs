@@ -0,0 +1,110 @@
+//! A summary of a `BField` that services can serialize and expose as is.
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::bfield::{BField, BuildParams, MemberInfo};
+use crate::bfield_member::BFieldValue;
+use crate::provenance::Provenance;
+use crate::stats::Stats;
+
+/// Everything there is to know about a `BField` without looking keys up,
+/// see [`BField::describe`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Description<T> {
+    /// k, ν, κ and the size of each array
+    pub build_params: BuildParams,
+    /// The parameters of each array, starting with the primary one
+    pub members: Vec<MemberInfo>,
+    /// The fill rates of the arrays and the error rates they imply
+    pub stats: Stats,
+    /// The params given to `create`
+    pub params: Option<T>,
+    /// The version the params are tagged with, see
+    /// [`BField::set_params_version`]
+    pub params_version: u32,
+    /// Where the `BField` comes from, if it was recorded
+    pub provenance: Option<Provenance>,
+    /// The largest value that can be stored, see [`BField::max_value`]
+    pub max_value: u64,
+    /// Number of keys inserted into the primary array (including masked
+    /// ones)
+    pub n_keys: u64,
+    /// Whether the markers of a key are confined to one block, see
+    /// [`BField::use_blocked_layout`]
+    pub blocked: bool,
+    /// Whether keys are hashed with a secret, see
+    /// [`BField::use_keyed_hashing`]
+    pub keyed: bool,
+    /// The seed keys are hashed with, see [`BField::use_hash_seed`]
+    pub hash_seed: u64,
+    /// Whether values are bitsets, see [`BField::create_bitset`]
+    pub bitset: bool,
+    /// Whether the `BField` was opened read-only
+    pub read_only: bool,
+}
+
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> BField<T, V> {
+    /// A summary of the `BField`: its parameters, arrays, fill rates,
+    /// params and provenance, e.g. to expose as JSON. This computes the
+    /// [`BField::stats`], so it reads every array in full.
+    pub fn describe(&self) -> Description<T> {
+        let primary = &self.members()[0];
+        Description {
+            build_params: self.build_parameters(),
+            members: self.member_info(),
+            stats: self.stats(),
+            params: self.params().clone(),
+            params_version: self.params_version(),
+            provenance: self.provenance().cloned(),
+            max_value: self.max_value().into_u64(),
+            n_keys: primary.n_keys(),
+            blocked: primary.is_blocked(),
+            keyed: primary.is_keyed(),
+            hash_seed: self.hash_seed(),
+            bitset: primary.is_bitset(),
+            read_only: self.is_read_only(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_describe_bfield() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            true,
+            "taxonomy".to_string(),
+        )
+        .unwrap();
+        bfield.set_build_label("test").unwrap();
+        bfield
+            .build_from_iter((0..100u32).map(|i| (format!("key {}", i), i)), |_| {})
+            .unwrap();
+
+        let description = bfield.describe();
+        assert_eq!(description.build_params.sizes, [100_000, 10_000]);
+        assert_eq!(description.members[1].size, 10_000);
+        assert_eq!(description.stats.members.len(), 2);
+        assert_eq!(description.n_keys, 100);
+        assert_eq!(description.params.as_deref(), Some("taxonomy"));
+        assert!(!description.bitset && !description.read_only);
+
+        let json = serde_json::to_value(&description).unwrap();
+        assert_eq!(json["provenance"]["label"], "test");
+        assert_eq!(json["max_value"], 82250);
+        let parsed: Description<String> = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.members, description.members);
+    }
+}
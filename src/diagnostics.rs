@@ -0,0 +1,229 @@
+//! Exporting the statistics of each array of a `BField`, with the density of
+//! the regions of its bit array, as JSON or CSV for dashboards and notebooks.
+use std::io::{self, Write};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::bfield::BField;
+use crate::bfield_member::BFieldValue;
+use crate::stats::MemberStats;
+
+/// Number of regions the bit array of each member is split into, see
+/// [`RegionDensity`].
+pub const DIAGNOSTICS_REGIONS: usize = 64;
+
+/// The format of [`BField::export_diagnostics`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiagnosticsFormat {
+    /// A single `Diagnostics` object
+    Json,
+    /// One row per region of each array, with the statistics of the array
+    /// repeated on each one
+    Csv,
+}
+
+/// The bits set in a slice of a bit array, to spot hashing skew or damaged
+/// ranges that an overall density hides.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RegionDensity {
+    /// First bit of the region
+    pub start: usize,
+    /// Bit after the end of the region
+    pub end: usize,
+    /// Number of bits set
+    pub n_set: usize,
+    /// Fraction of the bits set
+    pub density: f64,
+}
+
+/// The statistics of one of the arrays of a `BField`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MemberDiagnostics {
+    /// Size, fill rate and estimated error rates of the array
+    #[serde(flatten)]
+    pub stats: MemberStats,
+    /// Density of `DIAGNOSTICS_REGIONS` slices of the bit array (fewer for
+    /// arrays of fewer bytes)
+    pub regions: Vec<RegionDensity>,
+}
+
+/// The statistics of a whole `BField`, see [`BField::diagnostics`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostics {
+    /// The statistics of each array, starting with the primary one
+    pub members: Vec<MemberDiagnostics>,
+    /// Estimated probability that `get` returns a value for a key that
+    /// wasn't inserted
+    pub false_positive_rate: f64,
+    /// Estimated probability that `get` can't determine the value of an
+    /// inserted key
+    pub indeterminacy_rate: f64,
+}
+
+const CSV_HEADER: &str = "member,size,n_keys,n_set,density,false_positive_rate,\
+indeterminacy_rate,region,region_start,region_end,region_n_set,region_density";
+
+/// Splits `bytes` (a bit array of `size` bits) into at most `n_regions`
+/// regions of whole bytes and counts the bits set in each.
+pub(crate) fn region_densities(bytes: &[u8], size: usize, n_regions: usize) -> Vec<RegionDensity> {
+    let n_regions = n_regions.min(bytes.len());
+    (0..n_regions)
+        .map(|i| {
+            let (first, last) = (
+                i * bytes.len() / n_regions,
+                (i + 1) * bytes.len() / n_regions,
+            );
+            let (start, end) = (first * 8, (last * 8).min(size));
+            // the padding bits of the last byte are never set
+            let n_set = bytes[first..last]
+                .iter()
+                .map(|b| b.count_ones() as usize)
+                .sum();
+            RegionDensity {
+                start,
+                end,
+                n_set,
+                density: n_set as f64 / (end - start) as f64,
+            }
+        })
+        .collect()
+}
+
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> BField<T, V> {
+    /// The [`BField::stats`] of each array, with the density of
+    /// `DIAGNOSTICS_REGIONS` regions of its bit array. This reads every
+    /// array in full.
+    pub fn diagnostics(&self) -> Diagnostics {
+        let stats = self.stats();
+        let members = stats
+            .members
+            .into_iter()
+            .zip(self.members())
+            .map(|(stats, member)| {
+                let storage = member.storage();
+                MemberDiagnostics {
+                    regions: region_densities(
+                        storage.as_bytes(),
+                        storage.size(),
+                        DIAGNOSTICS_REGIONS,
+                    ),
+                    stats,
+                }
+            })
+            .collect();
+        Diagnostics {
+            members,
+            false_positive_rate: stats.false_positive_rate,
+            indeterminacy_rate: stats.indeterminacy_rate,
+        }
+    }
+
+    /// Writes the [`BField::diagnostics`] to `writer` as JSON or CSV.
+    pub fn export_diagnostics<W: Write>(
+        &self,
+        mut writer: W,
+        format: DiagnosticsFormat,
+    ) -> Result<(), io::Error> {
+        let diagnostics = self.diagnostics();
+        match format {
+            DiagnosticsFormat::Json => serde_json::to_writer_pretty(&mut writer, &diagnostics)?,
+            DiagnosticsFormat::Csv => {
+                writeln!(writer, "{}", CSV_HEADER)?;
+                for (ix, member) in diagnostics.members.iter().enumerate() {
+                    let stats = &member.stats;
+                    for (region, r) in member.regions.iter().enumerate() {
+                        writeln!(
+                            writer,
+                            "{},{},{},{},{},{},{},{},{},{},{},{}",
+                            ix,
+                            stats.size,
+                            stats.n_keys,
+                            stats.n_set,
+                            stats.density,
+                            stats.false_positive_rate,
+                            stats.indeterminacy_rate,
+                            region,
+                            r.start,
+                            r.end,
+                            r.n_set,
+                            r.density,
+                        )?;
+                    }
+                }
+            }
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_region_densities() {
+        let regions = region_densities(&[0xff, 0x0f, 0x00, 0x80], 26, 2);
+        assert_eq!(regions.len(), 2);
+        assert_eq!(
+            (regions[0].start, regions[0].end, regions[0].n_set),
+            (0, 16, 12)
+        );
+        assert_eq!(
+            (regions[1].start, regions[1].end, regions[1].n_set),
+            (16, 26, 1)
+        );
+        assert_eq!(regions[1].density, 0.1);
+        assert_eq!(region_densities(&[0x01], 8, 64).len(), 1);
+    }
+
+    #[test]
+    fn can_export_diagnostics() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .unwrap();
+        for i in 0..1_000u32 {
+            bfield.insert(&i.to_be_bytes(), i, 0);
+        }
+
+        let diagnostics = bfield.diagnostics();
+        let primary = &diagnostics.members[0];
+        assert_eq!(primary.regions.len(), DIAGNOSTICS_REGIONS);
+        assert_eq!(primary.regions.last().unwrap().end, 100_000);
+        assert_eq!(
+            primary.regions.iter().map(|r| r.n_set).sum::<usize>(),
+            primary.stats.n_set
+        );
+
+        let mut json = Vec::new();
+        bfield
+            .export_diagnostics(&mut json, DiagnosticsFormat::Json)
+            .unwrap();
+        let parsed: Diagnostics = serde_json::from_slice(&json).unwrap();
+        assert_eq!(parsed.members.len(), 2);
+        assert_eq!(parsed.members[0].stats.n_keys, 1_000);
+
+        let mut csv = Vec::new();
+        bfield
+            .export_diagnostics(&mut csv, DiagnosticsFormat::Csv)
+            .unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(lines.len(), 1 + 2 * DIAGNOSTICS_REGIONS);
+        assert!(lines[1].starts_with("0,100000,1000,"));
+        assert!(lines.iter().all(|l| l.split(',').count() == 12));
+    }
+}
@@ -0,0 +1,251 @@
+//! A `BField` storing arbitrary serializable values, by keeping each distinct
+//! value in a dictionary and storing its id in the `BField`.
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bfield::BField;
+
+/// The distinct values, in the order they were given ids.
+#[derive(Debug)]
+struct Values<D> {
+    values: Vec<D>,
+    /// The id of each value, by its JSON encoding (so `D` needn't be `Hash`)
+    ids: HashMap<Vec<u8>, u32>,
+}
+
+/// A [`BField`] whose values are any serializable `D`: each distinct value
+/// is given the next free id (from 0) the first time it's inserted, and the
+/// `BField` stores the ids. The values are stored next to the `BField`'s
+/// `.bfd` files as a JSON array, `{filename}.values.json`, so the number of
+/// distinct values is bounded by [`BField::max_value`] and they should fit
+/// in memory.
+#[derive(Debug)]
+pub struct ValueDictionary<T, D> {
+    bfield: BField<T, u32>,
+    values: RwLock<Values<D>>,
+}
+
+/// The path of the dictionary for a `BField` whose primary array is at
+/// `main_db_path` (`{filename}.0.bfd` -> `{filename}.values.json`).
+fn dictionary_path(main_db_path: &Path) -> Option<PathBuf> {
+    let filename = main_db_path.file_name()?.to_str()?;
+    let base = filename.strip_suffix("0.bfd")?;
+    Some(main_db_path.with_file_name(format!("{base}values.json")))
+}
+
+fn invalid_filename(main_db_path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "Can't store a value dictionary for the BField at {:?}",
+            main_db_path
+        ),
+    )
+}
+
+impl<T, D> ValueDictionary<T, D>
+where
+    T: Clone + DeserializeOwned + Serialize,
+    D: Clone + DeserializeOwned + Serialize,
+{
+    /// Adds an empty dictionary to an empty `bfield`.
+    pub fn new(bfield: BField<T, u32>) -> Self {
+        ValueDictionary {
+            bfield,
+            values: RwLock::new(Values {
+                values: Vec::new(),
+                ids: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Loads a `BField` and its dictionary given the path to its primary
+    /// array data file, see [`BField::load`].
+    pub fn load<P: AsRef<Path>>(main_db_path: P, read_only: bool) -> Result<Self, io::Error> {
+        let main_db_path = main_db_path.as_ref();
+        let path = dictionary_path(main_db_path).ok_or_else(|| invalid_filename(main_db_path))?;
+        let bfield = BField::load(main_db_path, read_only)?;
+        let values: Vec<D> = serde_json::from_slice(&fs::read(&path)?).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} isn't a valid value dictionary: {}", path, e),
+            )
+        })?;
+        let ids = values
+            .iter()
+            .enumerate()
+            .map(|(id, value)| Ok((serde_json::to_vec(value)?, id as u32)))
+            .collect::<Result<_, serde_json::Error>>()?;
+        Ok(ValueDictionary {
+            bfield,
+            values: RwLock::new(Values { values, ids }),
+        })
+    }
+
+    /// Writes the `BField` and its dictionary to disk, see
+    /// [`BField::persist_to_disk`].
+    pub fn persist_to_disk(self) -> Result<Self, io::Error> {
+        let main_db_path = &self.bfield.members()[0].filename;
+        let path = dictionary_path(main_db_path).ok_or_else(|| invalid_filename(main_db_path))?;
+        let values = self.values.into_inner().unwrap_or_else(|e| e.into_inner());
+        fs::write(path, serde_json::to_vec(&values.values)?)?;
+        Ok(ValueDictionary {
+            bfield: self.bfield.persist_to_disk()?,
+            values: RwLock::new(values),
+        })
+    }
+
+    /// The underlying `BField`, storing the ids of the values.
+    pub fn bfield(&self) -> &BField<T, u32> {
+        &self.bfield
+    }
+
+    /// Number of distinct values.
+    pub fn n_values(&self) -> usize {
+        self.read_values().values.len()
+    }
+
+    fn read_values(&self) -> RwLockReadGuard<'_, Values<D>> {
+        self.values.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write_values(&self) -> RwLockWriteGuard<'_, Values<D>> {
+        self.values.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The id of `value`, giving it the next free one if it doesn't have
+    /// one yet, e.g. to build the underlying `BField` from `(key, id)` pairs
+    /// with [`BField::build_from_iter`]. Fails if the `BField` can't store
+    /// that many distinct values.
+    pub fn value_id(&self, value: &D) -> Result<u32, io::Error> {
+        let encoded = serde_json::to_vec(value)?;
+        if let Some(&id) = self.read_values().ids.get(&encoded) {
+            return Ok(id);
+        }
+        let mut values = self.write_values();
+        let next = values.values.len() as u32;
+        // another thread may have added it in the meantime
+        if let Some(&id) = values.ids.get(&encoded) {
+            return Ok(id);
+        }
+        if next > self.bfield.max_value() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "The BField can't store more than {} distinct values",
+                    u64::from(self.bfield.max_value()) + 1
+                ),
+            ));
+        }
+        values.values.push(value.clone());
+        values.ids.insert(encoded, next);
+        Ok(next)
+    }
+
+    /// The value with id `id`, if there is one.
+    pub fn value(&self, id: u32) -> Option<D> {
+        self.read_values().values.get(id as usize).cloned()
+    }
+
+    /// Inserts `key` with `value` like [`BField::insert_auto`], returning
+    /// the member that determines it (or `None` if it's indeterminate in the
+    /// last one).
+    pub fn insert_value(&self, key: &[u8], value: &D) -> Result<Option<usize>, io::Error> {
+        let id = self.value_id(value)?;
+        Ok(self.bfield.insert_auto(key, id))
+    }
+
+    /// The value stored for `key`, see [`BField::get`].
+    pub fn get_value(&self, key: &[u8]) -> Option<D> {
+        self.bfield.get(key).and_then(|id| self.value(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Taxon {
+        name: String,
+        score: f64,
+    }
+
+    fn taxon(name: &str, score: f64) -> Taxon {
+        Taxon {
+            name: name.to_string(),
+            score,
+        }
+    }
+
+    #[test]
+    fn can_store_values_in_dictionary() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String, u32> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            false,
+            String::new(),
+        )
+        .unwrap();
+        let dictionary = ValueDictionary::new(bfield);
+        let taxa = [taxon("E. coli", 0.5), taxon("S. aureus", 1.5)];
+        for i in 0..100u32 {
+            let value = &taxa[i as usize % 2];
+            assert!(dictionary
+                .insert_value(&i.to_be_bytes(), value)
+                .unwrap()
+                .is_some());
+        }
+        assert_eq!(dictionary.n_values(), 2);
+        assert_eq!(dictionary.value_id(&taxa[1]).unwrap(), 1);
+        assert_eq!(
+            dictionary.get_value(&7u32.to_be_bytes()),
+            Some(taxa[1].clone())
+        );
+        assert_eq!(dictionary.get_value(b"missing"), None);
+
+        dictionary.persist_to_disk().unwrap();
+        let path = tmp_dir.path().join("bfield.0.bfd");
+        let loaded: ValueDictionary<String, Taxon> = ValueDictionary::load(&path, true).unwrap();
+        assert_eq!(loaded.n_values(), 2);
+        assert_eq!(loaded.get_value(&8u32.to_be_bytes()), Some(taxa[0].clone()));
+        assert_eq!(loaded.value(2), None);
+
+        // the ids are bounded by the BField's largest value
+        let small: BField<String, u32> = BField::create(
+            tmp_dir.path(),
+            "small",
+            1_000,
+            3,
+            4,
+            1,
+            0.1,
+            0.025,
+            1,
+            true,
+            String::new(),
+        )
+        .unwrap();
+        let small = ValueDictionary::new(small);
+        for i in 0..4 {
+            small.value_id(&i).unwrap();
+        }
+        let err = small.value_id(&4).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
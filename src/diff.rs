@@ -0,0 +1,153 @@
+//! Comparing the bit arrays of two `BField`s, e.g. to check builds are
+//! reproducible.
+use std::io;
+use std::ops::Range;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bfield::BField;
+use crate::bfield_member::BFieldValue;
+
+/// How two `BField`s differ, see [`BField::diff`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BFieldDiff {
+    /// How each array differs, starting with the primary one
+    pub members: Vec<MemberDiff>,
+}
+
+impl BFieldDiff {
+    /// Whether all the arrays are the same.
+    pub fn is_identical(&self) -> bool {
+        self.members.iter().all(|m| m.n_differing_bits == 0)
+    }
+}
+
+/// How one of the arrays of two `BField`s differ.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemberDiff {
+    /// Number of bits set in one array but not the other
+    pub n_differing_bits: usize,
+    /// The first few ranges of bits (rounded out to whole bytes) that
+    /// differ
+    pub regions: Vec<Range<usize>>,
+}
+
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> BField<T, V> {
+    /// Compares the arrays of this `BField` with the ones of `other`, which
+    /// must have been built with the same parameters and hashing (an
+    /// `InvalidInput` error is returned otherwise, like for
+    /// [`BField::merge`]), listing up to `max_regions` differing ranges of
+    /// bits for each array.
+    pub fn diff(&self, other: &BField<T, V>, max_regions: usize) -> Result<BFieldDiff, io::Error> {
+        let (ours, theirs) = (self.member_info(), other.member_info());
+        if ours != theirs {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Can only compare BFields with the same parameters: {:?} vs {:?}",
+                    ours, theirs
+                ),
+            ));
+        }
+        self.check_same_hashing(other)?;
+        let members = self
+            .members()
+            .iter()
+            .zip(other.members())
+            .map(|(ours, theirs)| {
+                diff_bytes(
+                    ours.storage().as_bytes(),
+                    theirs.storage().as_bytes(),
+                    max_regions,
+                )
+            })
+            .collect();
+        Ok(BFieldDiff { members })
+    }
+}
+
+/// Compares two bit arrays of the same size.
+fn diff_bytes(ours: &[u8], theirs: &[u8], max_regions: usize) -> MemberDiff {
+    let mut n_differing_bits = 0;
+    let mut regions: Vec<Range<usize>> = Vec::new();
+    for (i, (a, b)) in ours.iter().zip(theirs).enumerate() {
+        let n = (a ^ b).count_ones() as usize;
+        if n == 0 {
+            continue;
+        }
+        n_differing_bits += n;
+        let n_regions = regions.len();
+        match regions.last_mut() {
+            Some(last) if last.end == 8 * i => last.end += 8,
+            _ if n_regions < max_regions => regions.push(8 * i..8 * (i + 1)),
+            _ => {}
+        }
+    }
+    MemberDiff {
+        n_differing_bits,
+        regions,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_bytes() {
+        let diff = diff_bytes(&[0, 1, 3, 0, 0, 7], &[0, 0, 1, 0, 0, 0], 10);
+        assert_eq!(diff.n_differing_bits, 5);
+        assert_eq!(diff.regions, vec![8..24, 40..48]);
+        assert_eq!(
+            diff_bytes(&[0, 1, 3, 0, 0, 7], &[0; 6], 1).regions,
+            vec![8..24]
+        );
+    }
+
+    #[test]
+    fn can_diff_bfields() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let build_seeded = |size: usize, seed: u64| -> BField<String> {
+            let mut bfield = BField::create(
+                tmp_dir.path(),
+                "bfield",
+                size,
+                10,
+                39,
+                4,
+                0.1,
+                0.025,
+                2,
+                true,
+                String::new(),
+            )
+            .expect("to build");
+            if seed != 0 {
+                bfield.use_hash_seed(seed).unwrap();
+            }
+            for i in 0..1_000u32 {
+                bfield.insert(&i.to_be_bytes(), i, 0);
+            }
+            bfield
+        };
+        let build = |size| build_seeded(size, 0);
+        let bfield = build(100_000);
+        let other = build(100_000);
+        assert!(bfield.diff(&other, 10).unwrap().is_identical());
+
+        other.insert(&5_000u32.to_be_bytes(), 1, 0);
+        let diff = bfield.diff(&other, 10).unwrap();
+        assert!(!diff.is_identical());
+        assert!(diff.members[0].n_differing_bits > 0);
+        assert!(diff.members[0].n_differing_bits <= 10 * 4);
+        assert!(!diff.members[0].regions.is_empty());
+        assert_eq!(diff.members[1].n_differing_bits, 0);
+
+        let err = bfield.diff(&build(50_000), 10).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // the bits of arrays hashed differently can't be compared
+        let err = bfield.diff(&build_seeded(100_000, 7), 10).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
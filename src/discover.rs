@@ -0,0 +1,215 @@
+//! Finding the `BField` stored in a directory, see [`BField::load_from_dir`].
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bfield::{BField, LoadOptions};
+use crate::bfield_member::BFieldValue;
+
+/// The name and number of a member file (`{name}.{n}.bfd`), or the name of
+/// a manifest (`{name}.manifest.json`, with no number).
+fn parse_filename(filename: &str) -> Option<(&str, Option<usize>)> {
+    if let Some(name) = filename.strip_suffix(".manifest.json") {
+        return Some((name, None));
+    }
+    let (name, n) = filename.strip_suffix(".bfd")?.rsplit_once('.')?;
+    // `n.parse` would accept a sign
+    if name.is_empty() || !n.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((name, Some(n.parse().ok()?)))
+}
+
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> BField<T, V> {
+    /// Loads the `BField` stored in `directory`: the one called `name` (the
+    /// `filename` given to `create`), or the only one there if `name` is
+    /// `None`. Its members are the ones listed in its manifest if it has
+    /// one, else the `{name}.{n}.bfd` files, which must be numbered from 0
+    /// without gaps. Fails if the members don't all have the same
+    /// parameters, or if `name` is `None` and the directory holds several
+    /// `BField`s.
+    pub fn load_from_dir<P: AsRef<Path>>(
+        directory: P,
+        name: Option<&str>,
+        options: &LoadOptions,
+    ) -> Result<Self, io::Error> {
+        let directory = directory.as_ref();
+        // the members of each BField, and whether it has a manifest
+        let mut found: BTreeMap<String, (Vec<usize>, bool)> = BTreeMap::new();
+        for entry in fs::read_dir(directory)? {
+            let filename = entry?.file_name();
+            let parsed = filename.to_str().and_then(parse_filename);
+            if let Some((found_name, n)) =
+                parsed.filter(|(f, _)| name.is_none_or(|wanted| wanted == *f))
+            {
+                let (members, has_manifest) = found.entry(found_name.to_string()).or_default();
+                match n {
+                    Some(n) => members.push(n),
+                    None => *has_manifest = true,
+                }
+            }
+        }
+
+        let mut found = found.into_iter();
+        let (name, (mut members, has_manifest)) = match (found.next(), found.next()) {
+            (Some(only), None) => only,
+            (None, _) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    match name {
+                        Some(name) => format!("No BField called {:?} in {:?}", name, directory),
+                        None => format!("No BField in {:?}", directory),
+                    },
+                ))
+            }
+            (Some((first, _)), Some((second, _))) => {
+                let names: Vec<String> = vec![first, second]
+                    .into_iter()
+                    .chain(found.map(|(name, _)| name))
+                    .collect();
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Found several BFields in {:?} ({}), pick one by name",
+                        directory,
+                        names.join(", ")
+                    ),
+                ));
+            }
+        };
+        members.sort_unstable();
+        // with a manifest, `load_with` checks the members against it
+        if !has_manifest && !members.iter().copied().eq(0..members.len()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "The members of {:?} in {:?} aren't numbered from 0 without gaps: {:?}",
+                    name, directory, members
+                ),
+            ));
+        }
+
+        let bfield = Self::load_with(directory.join(format!("{name}.0.bfd")), options)?;
+        let info = bfield.member_info();
+        let mismatch = info.iter().find(|m| {
+            (m.n_hashes, m.marker_width, m.n_marker_bits, m.value_bits)
+                != (
+                    info[0].n_hashes,
+                    info[0].marker_width,
+                    info[0].n_marker_bits,
+                    info[0].value_bits,
+                )
+        });
+        if let Some(mismatch) = mismatch {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "The members of {:?} in {:?} have different parameters: {:?} and {:?}",
+                    name, directory, info[0], mismatch
+                ),
+            ));
+        }
+        Ok(bfield)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(dir: &Path, name: &str) {
+        let bfield: BField<String> = BField::create(
+            dir,
+            name,
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            3,
+            true,
+            name.to_string(),
+        )
+        .unwrap();
+        bfield
+            .build_from_iter((0..100u32).map(|i| (i.to_be_bytes(), i)), |_| {})
+            .unwrap();
+        bfield.persist_to_disk().unwrap();
+    }
+
+    #[test]
+    fn test_parse_filename() {
+        assert_eq!(parse_filename("bfield.2.bfd"), Some(("bfield", Some(2))));
+        assert_eq!(parse_filename("db.v2.10.bfd"), Some(("db.v2", Some(10))));
+        assert_eq!(parse_filename("db.manifest.json"), Some(("db", None)));
+        assert_eq!(parse_filename("bfield.count0.bfd"), None);
+        assert_eq!(parse_filename("bfield.+1.bfd"), None);
+        assert_eq!(parse_filename("bfield.0.bfd.tmp"), None);
+        assert_eq!(parse_filename(".0.bfd"), None);
+    }
+
+    #[test]
+    fn can_load_from_dir() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = tmp_dir.path();
+        let options = LoadOptions::new().read_only(true);
+        let load = |name| BField::<String>::load_from_dir(dir, name, &options);
+        assert_eq!(load(None).err().unwrap().kind(), io::ErrorKind::NotFound);
+
+        build(dir, "first");
+        fs::write(dir.join("notes.txt"), "not a BField").unwrap();
+        let bfield = load(None).unwrap();
+        assert_eq!(bfield.params(), &Some("first".to_string()));
+        assert_eq!(bfield.get(&7u32.to_be_bytes()), Some(7));
+
+        build(dir, "second");
+        assert_eq!(
+            load(None).err().unwrap().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        let bfield = load(Some("second")).unwrap();
+        assert_eq!(bfield.members().len(), 3);
+        assert_eq!(bfield.params(), &Some("second".to_string()));
+        assert_eq!(
+            load(Some("third")).err().unwrap().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        // without a manifest, the members are found by their names
+        fs::remove_file(dir.join("second.manifest.json")).unwrap();
+        assert_eq!(load(Some("second")).unwrap().members().len(), 3);
+        fs::rename(dir.join("second.2.bfd"), dir.join("second.3.bfd")).unwrap();
+        let err = load(Some("second")).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("[0, 1, 3]"));
+        fs::rename(dir.join("second.3.bfd"), dir.join("second.2.bfd")).unwrap();
+
+        // members from another BField
+        fs::copy(dir.join("first.1.bfd"), dir.join("second.1.bfd")).unwrap();
+        assert!(load(Some("second")).is_ok());
+        let bfield: BField<String, u8> = BField::create(
+            dir,
+            "small",
+            1_000,
+            3,
+            10,
+            2,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .unwrap();
+        bfield.persist_to_disk().unwrap();
+        fs::copy(dir.join("small.1.bfd"), dir.join("second.1.bfd")).unwrap();
+        let err = load(Some("second")).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("different parameters"));
+    }
+}
@@ -0,0 +1,194 @@
+//! Building a `BField` of the k-mers of FASTA/FASTQ files.
+use std::cell::RefCell;
+use std::io;
+use std::path::Path;
+
+use needletail::errors::ParseError;
+use needletail::{parse_fastx_file, FastxReader};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bfield::{BField, Progress};
+use crate::bfield_member::BFieldValue;
+use crate::kmers::{canonical_kmers, kmer_key, MAX_K};
+
+fn parse_error(path: &Path, e: ParseError) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), e),
+    )
+}
+
+/// The `(key, value)` pairs of the canonical k-mers of the records of
+/// `paths`, which are read again from the start on each pass of a build.
+/// Reading stops at the first error, which is kept in `error`.
+struct FastxKmers<'a, P, F> {
+    paths: &'a [P],
+    k: u8,
+    value_fn: &'a F,
+    error: &'a RefCell<Option<io::Error>>,
+}
+
+// not derived, as neither `P` nor `F` need to be `Clone`
+impl<P, F> Clone for FastxKmers<'_, P, F> {
+    fn clone(&self) -> Self {
+        FastxKmers { ..*self }
+    }
+}
+
+impl<'a, P, F, V> IntoIterator for FastxKmers<'a, P, F>
+where
+    P: AsRef<Path>,
+    F: Fn(&Path, &[u8]) -> V,
+    V: Copy + 'a,
+{
+    type Item = ([u8; 8], V);
+    type IntoIter = Box<dyn Iterator<Item = Self::Item> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let FastxKmers {
+            paths,
+            k,
+            value_fn,
+            error,
+        } = self;
+        let mut paths = paths.iter().map(AsRef::as_ref);
+        let mut reader: Option<(&Path, Box<dyn FastxReader>)> = None;
+        let records = std::iter::from_fn(move || loop {
+            if error.borrow().is_some() {
+                return None;
+            }
+            let (path, records) = match &mut reader {
+                Some(reader) => reader,
+                None => {
+                    let path = paths.next()?;
+                    match parse_fastx_file(path) {
+                        Ok(records) => reader = Some((path, records)),
+                        Err(e) => *error.borrow_mut() = Some(parse_error(path, e)),
+                    }
+                    continue;
+                }
+            };
+            match records.next() {
+                Some(Ok(record)) => {
+                    let value = value_fn(path, record.id());
+                    let kmers: Vec<u64> = canonical_kmers(&record.seq(), k).collect();
+                    return Some((kmers, value));
+                }
+                Some(Err(e)) => *error.borrow_mut() = Some(parse_error(path, e)),
+                None => reader = None,
+            }
+        });
+        Box::new(
+            records.flat_map(|(kmers, value)| {
+                kmers.into_iter().map(move |kmer| (kmer_key(kmer), value))
+            }),
+        )
+    }
+}
+
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> BField<T, V> {
+    /// Inserts the canonical k-mers (see [`canonical_kmers`]) of every
+    /// record of the FASTA or FASTQ files at `paths` (optionally compressed),
+    /// with the value `value_fn` returns for the file's path and the
+    /// record's id. The files are read once per member, as in
+    /// [`BField::build_from_iter`], which `progress` is called like.
+    pub fn build_from_fasta<P, F, G>(
+        &self,
+        paths: &[P],
+        k: u8,
+        value_fn: F,
+        progress: G,
+    ) -> Result<(), io::Error>
+    where
+        P: AsRef<Path>,
+        F: Fn(&Path, &[u8]) -> V,
+        G: Fn(Progress),
+    {
+        if k == 0 || k > MAX_K {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("k must be between 1 and {}, got {}", MAX_K, k),
+            ));
+        }
+        let error = RefCell::new(None);
+        let kmers = FastxKmers {
+            paths,
+            k,
+            value_fn: &value_fn,
+            error: &error,
+        };
+        self.build_from_iter(kmers, progress)?;
+        match error.into_inner() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn can_build_from_fasta_and_fastq() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let fasta = tmp_dir.path().join("genomes.fa");
+        fs::write(
+            &fasta,
+            ">one\nGATTACAGATTACACCCGGG\nTTTAAANGATTACAG\n>two\nACGTAGCTAGCTAGCATCGACTAGCGGCA\n",
+        )
+        .unwrap();
+        let fastq = tmp_dir.path().join("reads.fq");
+        fs::write(
+            &fastq,
+            "@three\nTTGACCAGTAGGACCCATTAC\n+\nIIIIIIIIIIIIIIIIIIIII\n",
+        )
+        .unwrap();
+
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .unwrap();
+        let value_fn = |_: &Path, id: &[u8]| match id {
+            b"one" => 1,
+            b"two" => 2,
+            _ => 3,
+        };
+        let last = std::cell::Cell::new(Progress::default());
+        bfield
+            .build_from_fasta(&[&fasta, &fastq], 11, value_fn, |p| last.set(p))
+            .unwrap();
+        assert_eq!(last.get().members_completed, 2);
+        assert!(bfield
+            .get_kmers(b"GATTACAGATTACACCCGGG", 11)
+            .all(|v| v == Some(1)));
+        assert!(bfield
+            .get_kmers(b"ACGTAGCTAGCTAGCATCGACTAGCGGCA", 11)
+            .all(|v| v == Some(2)));
+        assert!(bfield
+            .get_kmers(b"GTAATGGGTCCTACTGGTCAA", 11)
+            .all(|v| v == Some(3)));
+
+        let missing = tmp_dir.path().join("missing.fa");
+        let err = bfield
+            .build_from_fasta(&[&missing], 11, value_fn, |_| {})
+            .unwrap_err();
+        assert!(err.to_string().contains("missing.fa"));
+        let err = bfield
+            .build_from_fasta(&[&fasta], 33, value_fn, |_| {})
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
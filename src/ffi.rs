@@ -0,0 +1,351 @@
+//! A C interface to `BField`s with `u32` values, see `include/bfield.h`
+//! (generated with `cbindgen --config cbindgen.toml --crate bfield --output include/bfield.h`).
+//! The shared library is built with
+//! `cargo rustc --release --features ffi --crate-type cdylib`.
+use std::ffi::CStr;
+use std::io;
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use std::slice;
+
+use crate::bfield::BField;
+use crate::bfield_member::BFieldVal;
+use crate::params::RawParams;
+
+/// An opaque handle on a `BField`. The `other` parameters are read whatever
+/// their type, e.g. the `String` parameters of `BField`s built from Rust.
+pub struct BFieldHandle(BField<RawParams, BFieldVal>);
+
+/// The result of a `bfield_*` call.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BFieldStatus {
+    /// The call succeeded
+    Ok = 0,
+    /// `bfield_get` didn't find the key
+    NotFound = 1,
+    /// `bfield_insert` didn't insert the key, as an earlier member has it
+    NotInserted = 2,
+    /// A pointer was null, a string wasn't UTF-8 or a value or parameter
+    /// was out of range
+    InvalidArgument = -1,
+    /// A file couldn't be created, read or written
+    Io = -2,
+    /// The `BField` was loaded read-only
+    ReadOnly = -3,
+    /// The `BField` files are corrupted or inconsistent
+    InvalidData = -4,
+    /// Unexpected internal error
+    Panic = -5,
+}
+
+impl From<io::Error> for BFieldStatus {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::InvalidInput => BFieldStatus::InvalidArgument,
+            io::ErrorKind::InvalidData => BFieldStatus::InvalidData,
+            io::ErrorKind::PermissionDenied => BFieldStatus::ReadOnly,
+            _ => BFieldStatus::Io,
+        }
+    }
+}
+
+/// Runs `f`, turning panics into `BFieldStatus::Panic` so they don't unwind
+/// into C.
+fn guard<F: FnOnce() -> Result<BFieldStatus, BFieldStatus>>(f: F) -> BFieldStatus {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(status)) | Ok(Err(status)) => status,
+        Err(_) => BFieldStatus::Panic,
+    }
+}
+
+unsafe fn path_arg<'a>(path: *const c_char) -> Result<&'a Path, BFieldStatus> {
+    if path.is_null() {
+        return Err(BFieldStatus::InvalidArgument);
+    }
+    let path = CStr::from_ptr(path)
+        .to_str()
+        .map_err(|_| BFieldStatus::InvalidArgument)?;
+    Ok(Path::new(path))
+}
+
+unsafe fn key_arg<'a>(key: *const u8, key_len: usize) -> Result<&'a [u8], BFieldStatus> {
+    if key.is_null() {
+        if key_len == 0 {
+            return Ok(&[]);
+        }
+        return Err(BFieldStatus::InvalidArgument);
+    }
+    Ok(slice::from_raw_parts(key, key_len))
+}
+
+/// Creates a `BField` stored in `.bfd` files in `directory`, named
+/// `{filename}.{n}.bfd`, and sets `*out` to a handle on it (see
+/// `BField::create` for the parameters).
+///
+/// # Safety
+///
+/// `directory` and `filename` must be null-terminated strings and `out` a
+/// valid pointer. The handle must be freed with `bfield_free`.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn bfield_create(
+    directory: *const c_char,
+    filename: *const c_char,
+    size: usize,
+    n_hashes: u8,
+    marker_width: u8,
+    n_marker_bits: u8,
+    secondary_scaledown: f64,
+    max_scaledown: f64,
+    n_secondaries: u8,
+    out: *mut *mut BFieldHandle,
+) -> BFieldStatus {
+    guard(|| {
+        let directory = path_arg(directory)?;
+        let filename = path_arg(filename)?
+            .to_str()
+            .ok_or(BFieldStatus::InvalidArgument)?;
+        if out.is_null() || filename.is_empty() {
+            return Err(BFieldStatus::InvalidArgument);
+        }
+        let bfield = BField::create(
+            directory,
+            filename,
+            size,
+            n_hashes,
+            marker_width,
+            n_marker_bits,
+            secondary_scaledown,
+            max_scaledown,
+            n_secondaries,
+            false,
+            RawParams::Bytes(Vec::new()),
+        )?;
+        *out = Box::into_raw(Box::new(BFieldHandle(bfield)));
+        Ok(BFieldStatus::Ok)
+    })
+}
+
+/// Loads the `BField` whose primary array is at `path` (the file ending in
+/// `0.bfd`) and sets `*out` to a handle on it.
+///
+/// # Safety
+///
+/// `path` must be a null-terminated string and `out` a valid pointer. The
+/// handle must be freed with `bfield_free`.
+#[no_mangle]
+pub unsafe extern "C" fn bfield_load(
+    path: *const c_char,
+    read_only: bool,
+    out: *mut *mut BFieldHandle,
+) -> BFieldStatus {
+    guard(|| {
+        let path = path_arg(path)?;
+        if out.is_null() || !path.to_str().unwrap_or_default().ends_with("0.bfd") {
+            return Err(BFieldStatus::InvalidArgument);
+        }
+        let bfield = BField::load(path, read_only)?;
+        *out = Box::into_raw(Box::new(BFieldHandle(bfield)));
+        Ok(BFieldStatus::Ok)
+    })
+}
+
+/// Looks up the `key_len` bytes at `key`, setting `*value` and returning
+/// `Ok` if they're found and returning `NotFound` otherwise.
+///
+/// # Safety
+///
+/// `bfield` must be a handle from `bfield_create` or `bfield_load`, `key`
+/// must point to `key_len` readable bytes and `value` must be a valid
+/// pointer. Handles can be used from several threads at once.
+#[no_mangle]
+pub unsafe extern "C" fn bfield_get(
+    bfield: *const BFieldHandle,
+    key: *const u8,
+    key_len: usize,
+    value: *mut u32,
+) -> BFieldStatus {
+    guard(|| {
+        let bfield = bfield.as_ref().ok_or(BFieldStatus::InvalidArgument)?;
+        let key = key_arg(key, key_len)?;
+        if value.is_null() {
+            return Err(BFieldStatus::InvalidArgument);
+        }
+        match bfield.0.get(key) {
+            Some(found) => {
+                *value = found;
+                Ok(BFieldStatus::Ok)
+            }
+            None => Ok(BFieldStatus::NotFound),
+        }
+    })
+}
+
+/// Inserts the `key_len` bytes at `key` with `value` for pass `pass` (see
+/// `BField::insert`), returning `NotInserted` if an earlier member already
+/// resolves the key.
+///
+/// # Safety
+///
+/// `bfield` must be a handle from `bfield_create` or `bfield_load` and
+/// `key` must point to `key_len` readable bytes. Inserts can run on several
+/// threads at once, as long as each pass is finished before the next one
+/// starts.
+#[no_mangle]
+pub unsafe extern "C" fn bfield_insert(
+    bfield: *const BFieldHandle,
+    key: *const u8,
+    key_len: usize,
+    value: u32,
+    pass: usize,
+) -> BFieldStatus {
+    guard(|| {
+        let bfield = &bfield.as_ref().ok_or(BFieldStatus::InvalidArgument)?.0;
+        let key = key_arg(key, key_len)?;
+        if bfield.is_read_only() {
+            return Err(BFieldStatus::ReadOnly);
+        }
+        if value > bfield.max_value() || pass >= bfield.members().len() {
+            return Err(BFieldStatus::InvalidArgument);
+        }
+        if bfield.insert(key, value, pass) {
+            Ok(BFieldStatus::Ok)
+        } else {
+            Ok(BFieldStatus::NotInserted)
+        }
+    })
+}
+
+/// Frees a handle. Does nothing if `bfield` is null.
+///
+/// # Safety
+///
+/// `bfield` must be null or a handle from `bfield_create` or `bfield_load`
+/// that isn't used again.
+#[no_mangle]
+pub unsafe extern "C" fn bfield_free(bfield: *mut BFieldHandle) {
+    if !bfield.is_null() {
+        drop(Box::from_raw(bfield));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::ptr;
+
+    #[test]
+    fn can_use_bfield_through_ffi() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let directory = CString::new(tmp_dir.path().to_str().unwrap()).unwrap();
+        let filename = CString::new("bfield").unwrap();
+        unsafe {
+            let mut handle = ptr::null_mut();
+            let status = bfield_create(
+                directory.as_ptr(),
+                filename.as_ptr(),
+                1_000_000,
+                10,
+                39,
+                4,
+                0.1,
+                0.025,
+                2,
+                &mut handle,
+            );
+            assert_eq!(status, BFieldStatus::Ok);
+            for pass in 0..2 {
+                for i in 0..1_000u32 {
+                    let key = i.to_be_bytes();
+                    bfield_insert(handle, key.as_ptr(), key.len(), i, pass);
+                }
+            }
+            assert_eq!(
+                bfield_insert(handle, b"key".as_ptr(), 3, u32::MAX, 0),
+                BFieldStatus::InvalidArgument
+            );
+            bfield_free(handle);
+
+            let path = CString::new(tmp_dir.path().join("bfield.0.bfd").to_str().unwrap()).unwrap();
+            let mut handle = ptr::null_mut();
+            assert_eq!(
+                bfield_load(path.as_ptr(), true, &mut handle),
+                BFieldStatus::Ok
+            );
+            let mut value = 0;
+            let key = 5u32.to_be_bytes();
+            assert_eq!(
+                bfield_get(handle, key.as_ptr(), key.len(), &mut value),
+                BFieldStatus::Ok
+            );
+            assert_eq!(value, 5);
+            assert_eq!(
+                bfield_get(handle, b"missing".as_ptr(), 7, &mut value),
+                BFieldStatus::NotFound
+            );
+            assert_eq!(
+                bfield_insert(handle, key.as_ptr(), key.len(), 1, 0),
+                BFieldStatus::ReadOnly
+            );
+            assert_eq!(
+                bfield_get(ptr::null(), key.as_ptr(), key.len(), &mut value),
+                BFieldStatus::InvalidArgument
+            );
+            bfield_free(handle);
+
+            let missing = CString::new("/nonexistent/bfield.0.bfd").unwrap();
+            assert_eq!(
+                bfield_load(missing.as_ptr(), true, &mut handle),
+                BFieldStatus::Io
+            );
+            assert_eq!(
+                bfield_load(directory.as_ptr(), true, &mut handle),
+                BFieldStatus::InvalidArgument
+            );
+        }
+    }
+
+    #[test]
+    fn can_load_rust_bfield_through_ffi() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            1,
+            false,
+            "params".to_string(),
+        )
+        .unwrap();
+        bfield.insert(b"key", 7, 0);
+        drop(bfield);
+
+        let path = CString::new(tmp_dir.path().join("bfield.0.bfd").to_str().unwrap()).unwrap();
+        unsafe {
+            let mut handle = ptr::null_mut();
+            assert_eq!(
+                bfield_load(path.as_ptr(), true, &mut handle),
+                BFieldStatus::Ok
+            );
+            assert_eq!(
+                (*handle).0.params().as_ref().map(RawParams::to_bytes),
+                Some(b"params".to_vec())
+            );
+            let mut value = 0;
+            assert_eq!(
+                bfield_get(handle, b"key".as_ptr(), 3, &mut value),
+                BFieldStatus::Ok
+            );
+            assert_eq!(value, 7);
+            bfield_free(handle);
+        }
+    }
+}
@@ -0,0 +1,250 @@
+//! A gRPC service answering lookups against a read-only `BField`, defined by
+//! `proto/bfield.proto`.
+use std::pin::Pin;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::bfield::BField;
+use crate::bfield_member::{BFieldLookup, BFieldValue};
+
+/// The messages, client and server generated from `proto/bfield.proto`.
+#[allow(missing_docs, clippy::all)]
+pub mod proto;
+
+use proto::query_server::{Query, QueryServer};
+use proto::{
+    BatchLookupRequest, BatchLookupResponse, LookupRequest, LookupResponse, LookupStatus,
+    MemberStats, StatsRequest, StatsResponse,
+};
+
+/// Serves the `Query` RPCs for a `BField`, e.g. with
+/// `tonic::transport::Server::builder().add_service(service.into_server())`.
+pub struct QueryService<T, V> {
+    bfield: Arc<BField<T, V>>,
+    // `BField::stats` reads every array, so it's computed once up front
+    // rather than blocking the runtime on each request
+    stats: StatsResponse,
+}
+
+impl<T, V> QueryService<T, V>
+where
+    T: Clone + DeserializeOwned + Serialize + Send + Sync + 'static,
+    V: BFieldValue + Send + Sync + 'static,
+{
+    /// Serves `bfield`, which is only ever read.
+    ///
+    /// `bfield` doesn't need to be loaded read-only: the service owns it, so
+    /// nothing can insert into it while it's served (and the locks on its
+    /// files keep other processes from writing to them), which also keeps
+    /// the stats computed here up to date.
+    pub fn new(bfield: BField<T, V>) -> Self {
+        let stats = stats_response(&bfield);
+        QueryService {
+            bfield: Arc::new(bfield),
+            stats,
+        }
+    }
+
+    /// The tonic service to add to a server.
+    pub fn into_server(self) -> QueryServer<Self> {
+        QueryServer::new(self)
+    }
+}
+
+/// The parameters and stats of `bfield`.
+fn stats_response<T, V>(bfield: &BField<T, V>) -> StatsResponse
+where
+    T: Clone + DeserializeOwned + Serialize,
+    V: BFieldValue,
+{
+    let params = bfield.build_parameters();
+    let stats = bfield.stats();
+    StatsResponse {
+        n_hashes: params.n_hashes.into(),
+        marker_width: params.marker_width.into(),
+        n_marker_bits: params.n_marker_bits.into(),
+        max_value: bfield.max_value().into_u64(),
+        members: stats
+            .members
+            .iter()
+            .map(|member| MemberStats {
+                size: member.size as u64,
+                n_keys: member.n_keys,
+                n_set: member.n_set as u64,
+                density: member.density,
+                false_positive_rate: member.false_positive_rate,
+                indeterminacy_rate: member.indeterminacy_rate,
+            })
+            .collect(),
+        size: stats.size as u64,
+        n_set: stats.n_set as u64,
+        false_positive_rate: stats.false_positive_rate,
+        indeterminacy_rate: stats.indeterminacy_rate,
+    }
+}
+
+/// The result for `key`, reporting values that don't fit in `V` as absent
+/// like `BField::get` does.
+fn lookup_response<T, V>(bfield: &BField<T, V>, key: Vec<u8>) -> LookupResponse
+where
+    T: Clone + DeserializeOwned + Serialize,
+    V: BFieldValue,
+{
+    let (status, value) = match bfield.lookup(&key) {
+        BFieldLookup::Some(value) if V::from_u64(value).is_some() => (LookupStatus::Found, value),
+        BFieldLookup::Some(_) | BFieldLookup::None => (LookupStatus::Absent, 0),
+        BFieldLookup::Indeterminate => (LookupStatus::Indeterminate, 0),
+    };
+    LookupResponse {
+        key,
+        status: status.into(),
+        value,
+    }
+}
+
+#[tonic::async_trait]
+impl<T, V> Query for QueryService<T, V>
+where
+    T: Clone + DeserializeOwned + Serialize + Send + Sync + 'static,
+    V: BFieldValue + Send + Sync + 'static,
+{
+    async fn lookup(
+        &self,
+        request: Request<LookupRequest>,
+    ) -> Result<Response<LookupResponse>, Status> {
+        let key = request.into_inner().key;
+        Ok(Response::new(lookup_response(&self.bfield, key)))
+    }
+
+    type BatchLookupStream =
+        Pin<Box<dyn Stream<Item = Result<BatchLookupResponse, Status>> + Send + 'static>>;
+
+    // `Status` is large, but it's the error type of every tonic stream
+    #[allow(clippy::result_large_err)]
+    async fn batch_lookup(
+        &self,
+        request: Request<Streaming<BatchLookupRequest>>,
+    ) -> Result<Response<Self::BatchLookupStream>, Status> {
+        let bfield = Arc::clone(&self.bfield);
+        // each batch is answered as soon as it's received, so the whole set
+        // of keys is never held in memory
+        let results = request.into_inner().map(move |batch| {
+            let keys = batch?.keys;
+            Ok(BatchLookupResponse {
+                results: keys
+                    .into_iter()
+                    .map(|key| lookup_response(&bfield, key))
+                    .collect(),
+            })
+        });
+        Ok(Response::new(Box::pin(results)))
+    }
+
+    async fn stats(&self, _: Request<StatsRequest>) -> Result<Response<StatsResponse>, Status> {
+        Ok(Response::new(self.stats.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::query_client::QueryClient;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::{Endpoint, Server};
+
+    #[test]
+    fn can_serve_lookups_over_grpc() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            false,
+            String::new(),
+        )
+        .unwrap();
+        bfield
+            .build_from_iter((0..1000u32).map(|i| (format!("key {}", i), i)), |_| {})
+            .unwrap();
+        let service = QueryService::new(bfield);
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async move {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+            tokio::spawn(
+                Server::builder()
+                    .add_service(service.into_server())
+                    .serve_with_incoming(incoming),
+            );
+            let channel = Endpoint::from_shared(format!("http://{}", addr))
+                .unwrap()
+                .connect()
+                .await
+                .unwrap();
+            let mut client = QueryClient::new(channel);
+
+            let found = client
+                .lookup(LookupRequest {
+                    key: b"key 5".to_vec(),
+                })
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(found.status(), LookupStatus::Found);
+            assert_eq!(found.value, 5);
+            let absent = client
+                .lookup(LookupRequest {
+                    key: b"missing".to_vec(),
+                })
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(absent.status(), LookupStatus::Absent);
+
+            let batches = (0..10).map(|batch| BatchLookupRequest {
+                keys: (batch * 100..(batch + 1) * 100)
+                    .map(|i| format!("key {}", i).into_bytes())
+                    .collect(),
+            });
+            let mut responses = client
+                .batch_lookup(tokio_stream::iter(batches))
+                .await
+                .unwrap()
+                .into_inner();
+            let mut n_found = 0;
+            while let Some(response) = responses.message().await.unwrap() {
+                assert_eq!(response.results.len(), 100);
+                for result in response.results {
+                    let key = String::from_utf8(result.key).unwrap();
+                    assert_eq!(key, format!("key {}", n_found));
+                    assert_eq!(result.value, n_found);
+                    n_found += 1;
+                }
+            }
+            assert_eq!(n_found, 1000);
+
+            let stats = client.stats(StatsRequest {}).await.unwrap().into_inner();
+            assert_eq!(
+                (stats.n_hashes, stats.marker_width, stats.n_marker_bits),
+                (10, 39, 4)
+            );
+            assert_eq!(stats.members.len(), 2);
+            assert_eq!(stats.members[0].size, 100_000);
+        });
+    }
+}
@@ -0,0 +1,152 @@
+//! Reading `.bfd` files on network filesystems (NFS, FUSE mounts...), where
+//! reads can fail transiently. A read error in a mapped file kills the
+//! process with `SIGBUS` on the lookup that hits it, so `BField`s loaded
+//! with [`LoadOptions::guarded_reads`](crate::LoadOptions::guarded_reads) are
+//! read into memory with plain reads instead, retrying the ones that fail.
+use std::io::{self, Read, Seek, SeekFrom};
+use std::thread;
+use std::time::Duration;
+
+/// The longest wait between two attempts at a read.
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Whether a failed read is worth retrying: the errors network filesystems
+/// return for a server that's unreachable for a moment (`EIO`, `ESTALE`,
+/// timeouts...), as opposed to a file that's missing, unreadable or too
+/// short.
+fn is_transient(error: &io::Error) -> bool {
+    !matches!(
+        error.kind(),
+        io::ErrorKind::NotFound
+            | io::ErrorKind::PermissionDenied
+            | io::ErrorKind::InvalidInput
+            | io::ErrorKind::InvalidData
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::Unsupported
+    )
+}
+
+/// A reader that retries failed reads up to `retries` times (waiting 10ms,
+/// then twice as long after each failure, up to a second), reopening the
+/// file with `open` before each attempt as its handle may have gone stale.
+pub(crate) struct RetryingReader<R, F> {
+    open: F,
+    reader: Option<R>,
+    /// Number of bytes read so far, where the reopened file is read from
+    position: u64,
+    retries: u32,
+}
+
+impl<R: Read + Seek, F: FnMut() -> Result<R, io::Error>> RetryingReader<R, F> {
+    pub fn new(open: F, retries: u32) -> Self {
+        RetryingReader {
+            open,
+            reader: None,
+            position: 0,
+            retries,
+        }
+    }
+
+    fn try_read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let reader = match &mut self.reader {
+            Some(reader) => reader,
+            None => {
+                let mut reader = (self.open)()?;
+                reader.seek(SeekFrom::Start(self.position))?;
+                self.reader.insert(reader)
+            }
+        };
+        reader.read(buf)
+    }
+}
+
+impl<R: Read + Seek, F: FnMut() -> Result<R, io::Error>> Read for RetryingReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let mut failures = 0;
+        let mut backoff = Duration::from_millis(10);
+        loop {
+            match self.try_read(buf) {
+                Ok(n) => {
+                    self.position += n as u64;
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if failures < self.retries && is_transient(&e) => {
+                    failures += 1;
+                    self.reader = None;
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A reader of `data` failing with `EIO` on the reads listed in `fail`
+    /// (counting the reads of every reader opened).
+    fn flaky_opener<'a>(
+        data: &'a [u8],
+        fail: &'a [usize],
+        n_reads: &'a std::cell::Cell<usize>,
+    ) -> impl FnMut() -> Result<Flaky<'a>, io::Error> + 'a {
+        move || {
+            Ok(Flaky {
+                inner: Cursor::new(data),
+                fail,
+                n_reads,
+            })
+        }
+    }
+
+    struct Flaky<'a> {
+        inner: Cursor<&'a [u8]>,
+        fail: &'a [usize],
+        n_reads: &'a std::cell::Cell<usize>,
+    }
+
+    impl Read for Flaky<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+            let n = self.n_reads.get();
+            self.n_reads.set(n + 1);
+            if self.fail.contains(&n) {
+                return Err(io::Error::from_raw_os_error(5));
+            }
+            let len = buf.len().min(3);
+            self.inner.read(&mut buf[..len])
+        }
+    }
+
+    impl Seek for Flaky<'_> {
+        fn seek(&mut self, pos: SeekFrom) -> Result<u64, io::Error> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn can_retry_failed_reads() {
+        let data: Vec<u8> = (0..20).collect();
+        let n_reads = std::cell::Cell::new(0);
+        let mut reader = RetryingReader::new(flaky_opener(&data, &[1, 2, 5], &n_reads), 2);
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+
+        // the failures in a row are counted
+        n_reads.set(0);
+        let mut reader = RetryingReader::new(flaky_opener(&data, &[1, 2, 3], &n_reads), 2);
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(5));
+
+        let missing = || std::fs::File::open("missing.bfd");
+        let err = RetryingReader::new(missing, 5)
+            .read(&mut [0; 4])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
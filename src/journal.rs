@@ -0,0 +1,256 @@
+//! An append-only log of the changes made to a `BField` after it was built,
+//! so they can be audited and replayed.
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::bfield::BField;
+use crate::bfield_member::BFieldValue;
+
+/// What a [`JournalEntry`] did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalOp {
+    /// [`BField::mask_or_insert`]
+    Insert,
+    /// [`BField::update`]
+    Update,
+    /// [`BField::remove`]
+    Remove,
+}
+
+/// One change recorded in a [`Journal`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// The kind of change
+    pub op: JournalOp,
+    /// The key changed
+    pub key: Vec<u8>,
+    /// The value inserted, updated to or removed
+    pub value: u64,
+    /// When the change was made, in seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+/// An append-only file recording, one JSON object per line, the changes made
+/// through [`BField::mask_or_insert`], [`BField::update`] and
+/// [`BField::remove`] once it's attached with [`BField::set_journal`].
+///
+/// Each entry is written (and synced) before the change is made to the bit
+/// arrays. As bits can't be unset, rolling changes back means rebuilding the
+/// `BField` (see [`BField::rebuild`]) and replaying the entries to keep with
+/// [`BField::replay_journal`].
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, creating it if needed; new entries are
+    /// appended to it. A truncated last line (from a crash while it was
+    /// written) is cut off first, so the next entry starts on its own line.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, io::Error> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path.as_ref())?;
+        let len = file.metadata()?.len();
+        let complete = complete_len(&mut file, len)?;
+        if complete != len {
+            file.set_len(complete)?;
+            file.sync_data()?;
+        }
+        Ok(Journal {
+            path: path.as_ref().to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// The path of the journal file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads all the entries of the journal at `path`, oldest first. A
+    /// truncated last line (from a crash while it was written) is ignored,
+    /// as its change was never made.
+    pub fn read_entries<P: AsRef<Path>>(path: P) -> Result<Vec<JournalEntry>, io::Error> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut entries = Vec::new();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+                break;
+            }
+            let entry = serde_json::from_str(&line)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    /// Records a change about to be made.
+    pub(crate) fn append(&self, op: JournalOp, key: &[u8], value: u64) -> Result<(), io::Error> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let entry = JournalEntry {
+            op,
+            key: key.to_vec(),
+            value,
+            timestamp,
+        };
+        let mut line = serde_json::to_vec(&entry)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.write_all(&line)?;
+        file.sync_data()
+    }
+}
+
+/// The length of the first `len` bytes of `file` up to (and including) their
+/// last newline.
+fn complete_len(file: &mut File, len: u64) -> Result<u64, io::Error> {
+    let mut buf = [0u8; 4096];
+    let mut end = len;
+    while end > 0 {
+        let start = end.saturating_sub(buf.len() as u64);
+        let chunk = &mut buf[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        if let Some(pos) = chunk.iter().rposition(|&b| b == b'\n') {
+            return Ok(start + pos as u64 + 1);
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+impl fmt::Debug for Journal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Journal").field("path", &self.path).finish()
+    }
+}
+
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> BField<T, V> {
+    /// Applies the changes recorded in `entries` (e.g. read with
+    /// [`Journal::read_entries`]) in order, recording them again in this
+    /// `BField`'s own journal if it has one.
+    pub fn replay_journal(&mut self, entries: &[JournalEntry]) -> Result<(), io::Error> {
+        for entry in entries {
+            let value = V::from_u64(entry.value).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Journal value {} doesn't fit in the value type",
+                        entry.value
+                    ),
+                )
+            })?;
+            match entry.op {
+                JournalOp::Insert => self.mask_or_insert(&entry.key, value)?,
+                JournalOp::Update => {
+                    self.update(&entry.key, value)?;
+                }
+                JournalOp::Remove => {
+                    self.remove(&entry.key, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(pairs: &[(u32, u32)]) -> BField<String> {
+        let bfield: BField<String> = BField::create(
+            "",
+            "bfield",
+            1_000_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        let pairs = pairs.iter().map(|&(k, v)| (k.to_be_bytes(), v));
+        bfield.build_from_iter(pairs, |_| {}).unwrap();
+        bfield
+    }
+
+    #[test]
+    fn can_journal_and_replay_changes() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("bfield.journal");
+        let pairs: Vec<(u32, u32)> = (0..1_000u32).map(|i| (i, i)).collect();
+        let mut bfield = build(&pairs);
+        bfield.set_journal(Some(Journal::open(&path).unwrap()));
+        bfield.mask_or_insert(&5_000u32.to_be_bytes(), 1).unwrap();
+        assert!(bfield.update(&1u32.to_be_bytes(), 2).unwrap());
+        assert!(bfield.remove(&2u32.to_be_bytes(), 2).unwrap());
+        // not found, so not recorded
+        assert!(!bfield.remove(&3u32.to_be_bytes(), 4).unwrap());
+        drop(bfield);
+
+        // a crash while writing an entry leaves a partial line
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"op\":\"ins")
+            .unwrap();
+        let entries = Journal::read_entries(&path).unwrap();
+        let ops: Vec<_> = entries.iter().map(|e| e.op).collect();
+        assert_eq!(
+            ops,
+            vec![JournalOp::Insert, JournalOp::Update, JournalOp::Remove]
+        );
+        assert_eq!(entries[0].key, 5_000u32.to_be_bytes().to_vec());
+
+        let mut rebuilt = build(&pairs);
+        rebuilt.replay_journal(&entries).unwrap();
+        assert_eq!(rebuilt.get(&5_000u32.to_be_bytes()), Some(1));
+        assert_eq!(rebuilt.get(&1u32.to_be_bytes()), Some(2));
+        assert_eq!(rebuilt.get(&2u32.to_be_bytes()), None);
+
+        // rolling back the last change
+        let mut rebuilt = build(&pairs);
+        rebuilt.replay_journal(&entries[..2]).unwrap();
+        assert_eq!(rebuilt.get(&2u32.to_be_bytes()), Some(2));
+
+        // reopening the journal drops the partial line before appending
+        let journal = Journal::open(&path).unwrap();
+        journal.append(JournalOp::Update, b"key", 3).unwrap();
+        drop(journal);
+        let entries = Journal::read_entries(&path).unwrap();
+        let ops: Vec<_> = entries.iter().map(|e| e.op).collect();
+        assert_eq!(
+            ops,
+            vec![
+                JournalOp::Insert,
+                JournalOp::Update,
+                JournalOp::Remove,
+                JournalOp::Update
+            ]
+        );
+        assert_eq!(entries[3].key, b"key".to_vec());
+        assert_eq!(entries[3].value, 3);
+    }
+}
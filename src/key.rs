@@ -0,0 +1,90 @@
+//! The types the methods of `BField` take as keys.
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// A type that can be used as a key: byte slices, arrays and vectors as
+/// they are, strings as their UTF-8 bytes, and integers as their fixed-width
+/// big-endian bytes (so `42u32` is the same key as `42u32.to_be_bytes()`).
+///
+/// Every key is stored as bytes, so keys of different types with the same
+/// bytes are the same key. This trait is sealed: other types should be
+/// encoded to bytes first.
+pub trait BFieldKey: private::Sealed {
+    /// Calls `f` with the bytes of the key.
+    fn with_bytes<R, F: FnOnce(&[u8]) -> R>(&self, f: F) -> R;
+}
+
+impl<K: BFieldKey + ?Sized> private::Sealed for &K {}
+
+impl<K: BFieldKey + ?Sized> BFieldKey for &K {
+    #[inline]
+    fn with_bytes<R, F: FnOnce(&[u8]) -> R>(&self, f: F) -> R {
+        (**self).with_bytes(f)
+    }
+}
+
+macro_rules! impl_bytes_key {
+    ($($t:ty),*) => {
+        $(
+            impl private::Sealed for $t {}
+
+            impl BFieldKey for $t {
+                #[inline]
+                fn with_bytes<R, F: FnOnce(&[u8]) -> R>(&self, f: F) -> R {
+                    f(self.as_ref())
+                }
+            }
+        )*
+    };
+}
+
+impl_bytes_key!([u8], Vec<u8>, str, String);
+
+impl<const N: usize> private::Sealed for [u8; N] {}
+
+impl<const N: usize> BFieldKey for [u8; N] {
+    #[inline]
+    fn with_bytes<R, F: FnOnce(&[u8]) -> R>(&self, f: F) -> R {
+        f(self)
+    }
+}
+
+macro_rules! impl_integer_key {
+    ($($t:ty),*) => {
+        $(
+            impl private::Sealed for $t {}
+
+            impl BFieldKey for $t {
+                #[inline]
+                fn with_bytes<R, F: FnOnce(&[u8]) -> R>(&self, f: F) -> R {
+                    f(&self.to_be_bytes())
+                }
+            }
+        )*
+    };
+}
+
+impl_integer_key!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes<K: BFieldKey>(key: K) -> Vec<u8> {
+        key.with_bytes(<[u8]>::to_vec)
+    }
+
+    #[test]
+    fn test_key_bytes() {
+        assert_eq!(bytes(&b"key"[..]), b"key");
+        assert_eq!(bytes(b"key"), b"key");
+        assert_eq!(bytes("key"), b"key");
+        assert_eq!(bytes(&"key".to_string()), b"key");
+        assert_eq!(bytes(vec![1u8, 2]), [1, 2]);
+        assert_eq!(bytes(0x0102u16), [1, 2]);
+        assert_eq!(bytes(-2i32), [0xff, 0xff, 0xff, 0xfe]);
+        assert_eq!(bytes(&&7u64), 7u64.to_be_bytes());
+    }
+}
@@ -0,0 +1,89 @@
+//! Keyed hashing, so the marker positions of keys can't be predicted (or
+//! collisions constructed) without a secret.
+use std::fmt;
+use std::hash::Hasher;
+
+use siphasher::sip128::{Hasher128, SipHasher13};
+
+/// A secret to hash keys with (using SipHash-1-3) instead of the unkeyed
+/// MurmurHash3, see [`BField::use_keyed_hashing`](crate::BField::use_keyed_hashing).
+///
+/// The secret is never written out: the headers of the arrays only record a
+/// check value derived from it, to refuse loading them with the wrong one.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct HashSecret {
+    k0: u64,
+    k1: u64,
+}
+
+impl HashSecret {
+    /// The secret made of the 16 `bytes`, which should be random (e.g. read
+    /// from `/dev/urandom` once, and kept in a secret store).
+    pub fn new(bytes: [u8; 16]) -> Self {
+        let mut k0 = [0; 8];
+        let mut k1 = [0; 8];
+        k0.copy_from_slice(&bytes[..8]);
+        k1.copy_from_slice(&bytes[8..]);
+        HashSecret {
+            k0: u64::from_le_bytes(k0),
+            k1: u64::from_le_bytes(k1),
+        }
+    }
+
+    /// The 128-bit hash of `key` marker positions are derived from, in
+    /// members with `seed`.
+    #[inline]
+    pub(crate) fn hash(&self, key: &[u8], seed: u64) -> (u64, u64) {
+        let mut hasher = SipHasher13::new_with_keys(self.k0, self.k1);
+        if seed != 0 {
+            hasher.write_u64(seed);
+        }
+        hasher.write(key);
+        let hash = hasher.finish128();
+        (hash.h1, hash.h2)
+    }
+
+    /// The value recorded in the headers of the arrays hashed with this
+    /// secret, which doesn't reveal it.
+    pub(crate) fn check(&self) -> u64 {
+        self.hash(b"bfield hash secret check", 0).0
+    }
+}
+
+/// The 128-bit hash of `key` with `seed` for the structures kept alongside
+/// the members (the sidecar, the prefilter and the lookup cache): keyed with
+/// `secret` if the members are, so colliding keys can't be crafted for them
+/// either, and MurmurHash3 otherwise.
+#[inline]
+pub(crate) fn hash_with(secret: Option<&HashSecret>, key: &[u8], seed: u64) -> (u64, u64) {
+    match secret {
+        Some(secret) => secret.hash(key, seed),
+        None => murmurhash3::murmurhash3_x64_128(key, seed),
+    }
+}
+
+// the secret shouldn't end up in logs
+impl fmt::Debug for HashSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HashSecret(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_secret() {
+        let secret = HashSecret::new(*b"0123456789abcdef");
+        let other = HashSecret::new(*b"0123456789abcdeg");
+        assert_eq!(secret.hash(b"key", 0), secret.hash(b"key", 0));
+        assert_ne!(secret.hash(b"key", 0), other.hash(b"key", 0));
+        assert_ne!(secret.hash(b"key", 0), secret.hash(b"key2", 0));
+        assert_ne!(secret.hash(b"key", 0), secret.hash(b"key", 1));
+        assert_ne!(secret.check(), other.check());
+        assert_eq!(format!("{:?}", secret), "HashSecret(..)");
+        assert_eq!(hash_with(Some(&secret), b"key", 3), secret.hash(b"key", 3));
+        assert_ne!(hash_with(None, b"key", 3), secret.hash(b"key", 3));
+    }
+}
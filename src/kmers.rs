@@ -0,0 +1,361 @@
+//! Canonical DNA k-mers packed 2 bits per base, the keys B-fields of
+//! sequencing data are usually built with, and their minimizers.
+use std::collections::VecDeque;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bfield::BField;
+use crate::bfield_member::BFieldValue;
+
+/// The largest `k` whose k-mers fit in a `u64`
+pub const MAX_K: u8 = 32;
+
+/// Marks the bytes that aren't a base
+const NOT_A_BASE: u8 = 4;
+
+/// The 2-bit code of each byte: A, C, G and T (in either case) are 0 to 3,
+/// so that the complement of `b` is `3 - b`.
+const BASE_CODES: [u8; 256] = {
+    let mut codes = [NOT_A_BASE; 256];
+    codes[b'A' as usize] = 0;
+    codes[b'a' as usize] = 0;
+    codes[b'C' as usize] = 1;
+    codes[b'c' as usize] = 1;
+    codes[b'G' as usize] = 2;
+    codes[b'g' as usize] = 2;
+    codes[b'T' as usize] = 3;
+    codes[b't' as usize] = 3;
+    codes
+};
+
+/// Iterator over the canonical k-mers of a sequence, see [`canonical_kmers`].
+#[derive(Clone, Debug)]
+pub struct Kmers<'a> {
+    seq: &'a [u8],
+    k: u8,
+    mask: u64,
+    forward: u64,
+    reverse: u64,
+    /// Number of bases read since the last one that wasn't A, C, G or T
+    n_valid: u8,
+    /// Number of bytes of the sequence read
+    n_read: usize,
+}
+
+/// The k-mers of `seq`, each packed 2 bits per base (the first base in the
+/// highest bits) as the smaller of its forward and reverse complement
+/// encodings, so a k-mer and its reverse complement are the same key.
+/// K-mers spanning a byte other than A, C, G or T (e.g. N) are skipped.
+///
+/// Panics if `k` is 0 or over [`MAX_K`].
+pub fn canonical_kmers(seq: &[u8], k: u8) -> Kmers<'_> {
+    assert!(
+        0 < k && k <= MAX_K,
+        "k must be between 1 and {}, got {}",
+        MAX_K,
+        k
+    );
+    Kmers {
+        seq,
+        k,
+        mask: u64::MAX >> (64 - 2 * u32::from(k)),
+        forward: 0,
+        reverse: 0,
+        n_valid: 0,
+        n_read: 0,
+    }
+}
+
+/// The key a packed k-mer is stored under: its big-endian bytes, the same
+/// key as the `u64` itself (see [`BFieldKey`](crate::BFieldKey)).
+#[inline]
+pub fn kmer_key(kmer: u64) -> [u8; 8] {
+    kmer.to_be_bytes()
+}
+
+impl Kmers<'_> {
+    /// The next k-mer and its position in the sequence.
+    fn next_with_position(&mut self) -> Option<(usize, u64)> {
+        let shift = 2 * (u32::from(self.k) - 1);
+        while let Some((&byte, rest)) = self.seq.split_first() {
+            self.seq = rest;
+            self.n_read += 1;
+            let code = BASE_CODES[usize::from(byte)];
+            if code == NOT_A_BASE {
+                self.n_valid = 0;
+                continue;
+            }
+            let code = u64::from(code);
+            self.forward = ((self.forward << 2) | code) & self.mask;
+            self.reverse = (self.reverse >> 2) | ((3 - code) << shift);
+            self.n_valid = (self.n_valid + 1).min(self.k);
+            if self.n_valid == self.k {
+                let position = self.n_read - usize::from(self.k);
+                return Some((position, self.forward.min(self.reverse)));
+            }
+        }
+        None
+    }
+}
+
+impl Iterator for Kmers<'_> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        self.next_with_position().map(|(_, kmer)| kmer)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let to_fill = usize::from(self.k - self.n_valid);
+        (0, Some((self.seq.len() + 1).saturating_sub(to_fill.max(1))))
+    }
+}
+
+/// The order minimizers are picked in: a bijective mix of the bits of the
+/// k-mer (MurmurHash3's finalizer), so low-complexity k-mers like poly-A
+/// aren't favored as they would be in lexicographic order.
+#[inline]
+fn minimizer_order(kmer: u64) -> u64 {
+    let mut h = kmer;
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+/// Iterator over the minimizers of a sequence, see [`minimizers`].
+#[derive(Clone, Debug)]
+pub struct Minimizers<'a> {
+    kmers: Kmers<'a>,
+    w: usize,
+    /// `(position, order, k-mer)` of the k-mers of the current window that
+    /// can still be its minimizer, in increasing order
+    window: VecDeque<(usize, u64, u64)>,
+    /// Number of consecutive k-mers read, up to `w`
+    n_consecutive: usize,
+    last_position: Option<usize>,
+    last_emitted: Option<usize>,
+}
+
+/// The `(w, k)` minimizers of `seq`: of every `w` consecutive canonical
+/// k-mers (see [`canonical_kmers`]), the one coming first in a fixed
+/// pseudo-random order, each returned once even if it's the minimizer of
+/// several windows.
+///
+/// Indexing only minimizers keeps about `2 / (w + 1)` of the k-mers, making
+/// B-fields of large genomes that much smaller, at the cost of sensitivity:
+/// a query is only guaranteed to share a minimizer with the indexed
+/// sequences if they have `w + k - 1` consecutive bases in common, so reads
+/// with a lot of sequencing errors (or from diverged strains) are found less
+/// often than with every k-mer indexed. The same `w` and `k` have to be used
+/// for building and querying.
+///
+/// Panics if `w` is 0, or `k` is 0 or over [`MAX_K`].
+pub fn minimizers(seq: &[u8], w: usize, k: u8) -> Minimizers<'_> {
+    assert!(w > 0, "w must be at least 1");
+    Minimizers {
+        kmers: canonical_kmers(seq, k),
+        w,
+        window: VecDeque::with_capacity(w),
+        n_consecutive: 0,
+        last_position: None,
+        last_emitted: None,
+    }
+}
+
+impl Iterator for Minimizers<'_> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        while let Some((position, kmer)) = self.kmers.next_with_position() {
+            // windows don't span the k-mers skipped over non-ACGT bases
+            if self.last_position.is_some_and(|last| last + 1 != position) {
+                self.window.clear();
+                self.n_consecutive = 0;
+            }
+            self.last_position = Some(position);
+            self.n_consecutive = (self.n_consecutive + 1).min(self.w);
+
+            let order = minimizer_order(kmer);
+            while self.window.back().is_some_and(|&(_, o, _)| o >= order) {
+                self.window.pop_back();
+            }
+            self.window.push_back((position, order, kmer));
+            while self
+                .window
+                .front()
+                .is_some_and(|&(p, _, _)| p + self.w <= position)
+            {
+                self.window.pop_front();
+            }
+
+            if self.n_consecutive == self.w {
+                let (position, _, kmer) = self.window[0];
+                if self.last_emitted != Some(position) {
+                    self.last_emitted = Some(position);
+                    return Some(kmer);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> BField<T, V> {
+    /// Inserts every canonical k-mer of `seq` (see [`canonical_kmers`]) with
+    /// `value` at `pass`, like [`BField::insert`]. Returns the number of
+    /// k-mers inserted.
+    ///
+    /// Panics if `k` is 0 or over [`MAX_K`].
+    pub fn insert_kmers(&self, seq: &[u8], k: u8, value: V, pass: usize) -> usize {
+        canonical_kmers(seq, k)
+            .filter(|&kmer| self.insert(&kmer_key(kmer), value, pass))
+            .count()
+    }
+
+    /// The values of the canonical k-mers of `seq` (see [`canonical_kmers`]),
+    /// as returned by [`BField::get`].
+    ///
+    /// Panics if `k` is 0 or over [`MAX_K`].
+    pub fn get_kmers<'a>(&'a self, seq: &'a [u8], k: u8) -> impl Iterator<Item = Option<V>> + 'a {
+        canonical_kmers(seq, k).map(move |kmer| self.get(&kmer_key(kmer)))
+    }
+
+    /// Same as [`BField::insert_kmers`], inserting only the `(w, k)`
+    /// minimizers of `seq` (see [`minimizers`] for the trade-offs).
+    ///
+    /// Panics if `w` is 0, or `k` is 0 or over [`MAX_K`].
+    pub fn insert_minimizers(&self, seq: &[u8], w: usize, k: u8, value: V, pass: usize) -> usize {
+        minimizers(seq, w, k)
+            .filter(|&kmer| self.insert(&kmer_key(kmer), value, pass))
+            .count()
+    }
+
+    /// The values of the `(w, k)` minimizers of `seq`, for a `BField` built
+    /// with [`BField::insert_minimizers`] and the same `w` and `k`.
+    ///
+    /// Panics if `w` is 0, or `k` is 0 or over [`MAX_K`].
+    pub fn query_minimizers<'a>(
+        &'a self,
+        seq: &'a [u8],
+        w: usize,
+        k: u8,
+    ) -> impl Iterator<Item = Option<V>> + 'a {
+        minimizers(seq, w, k).map(move |kmer| self.get(&kmer_key(kmer)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+        seq.iter()
+            .rev()
+            .map(|base| match base {
+                b'A' => b'T',
+                b'C' => b'G',
+                b'G' => b'C',
+                b'T' => b'A',
+                other => *other,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn can_encode_canonical_kmers() {
+        assert_eq!(
+            canonical_kmers(b"ACGT", 4).collect::<Vec<_>>(),
+            [0b00_01_10_11]
+        );
+        // TTGC is the reverse complement of GCAA, which is smaller
+        assert_eq!(
+            canonical_kmers(b"ttgc", 4).collect::<Vec<_>>(),
+            [0b10_01_00_00]
+        );
+        assert_eq!(canonical_kmers(b"ACG", 4).count(), 0);
+        // k-mers over the N are skipped
+        assert_eq!(canonical_kmers(b"AACNAACGA", 3).count(), 4);
+        assert_eq!(canonical_kmers(b"AACNAACGA", 3).size_hint(), (0, Some(7)));
+
+        let seq = b"GATTACAGATTACACCCGGGTTTAAANGATTACAGATTACACCCGGGTTTAAAGATTACAG";
+        for k in [1, 5, 21, 32] {
+            let forward: Vec<_> = canonical_kmers(seq, k).collect();
+            let mut reverse: Vec<_> = canonical_kmers(&reverse_complement(seq), k).collect();
+            reverse.reverse();
+            assert_eq!(forward, reverse);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "k must be between 1 and 32")]
+    fn rejects_kmers_over_max_k() {
+        canonical_kmers(b"ACGT", 33);
+    }
+
+    #[test]
+    fn can_pick_minimizers() {
+        let seq = b"GATTACAGATTACACCCGGGTTTAAAGATTACAGATTACACCCGGGTTTAAAGATTACAGTTGACCAGTAGGACC";
+        let kmers: Vec<_> = canonical_kmers(seq, 15).collect();
+        // windows of one k-mer pick every k-mer
+        assert_eq!(minimizers(seq, 1, 15).collect::<Vec<_>>(), kmers);
+        let picked: Vec<_> = minimizers(seq, 10, 15).collect();
+        assert!(picked.len() < kmers.len() / 2);
+        // each window of 10 k-mers contains one of the minimizers
+        for window in kmers.windows(10) {
+            let min = window.iter().min_by_key(|&&k| minimizer_order(k)).unwrap();
+            assert!(picked.contains(min));
+        }
+        // both strands have the same minimizers
+        let mut reverse: Vec<_> = minimizers(&reverse_complement(seq), 10, 15).collect();
+        let mut forward = picked.clone();
+        forward.sort_unstable();
+        reverse.sort_unstable();
+        forward.dedup();
+        reverse.dedup();
+        assert_eq!(forward, reverse);
+        // windows restart after an N
+        let half: Vec<_> = minimizers(b"GATTACAGA", 3, 5).collect();
+        assert_eq!(
+            minimizers(b"GATTACAGANGATTACAGA", 3, 5).collect::<Vec<_>>(),
+            [&half[..], &half[..]].concat()
+        );
+        assert_eq!(minimizers(b"ACGTANACGTA", 3, 5).count(), 0);
+    }
+
+    #[test]
+    fn can_insert_and_get_kmers() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield_kmers",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            1,
+            true,
+            String::new(),
+        )
+        .unwrap();
+        let seq = b"GATTACAGATTACACCCGGGTTTAAANNNACGTAGCTAGCTAGCATCGACTAGC";
+        assert_eq!(bfield.insert_kmers(seq, 21, 7, 0), 11);
+        assert!(bfield
+            .get_kmers(&reverse_complement(seq), 21)
+            .all(|value| value == Some(7)));
+        assert_eq!(bfield.get_kmers(b"CCCCCCCCCCCCCCCCCCCCCCCC", 21).count(), 4);
+        // the packed k-mers are keys themselves
+        assert!(canonical_kmers(seq, 21).all(|kmer| bfield.get(kmer) == Some(7)));
+
+        let genome = b"TTGACCAGTAGGACCCATTACGGCATCGATCGATCGGACTTACGACTAGCATCAGCATTCGA";
+        let n_inserted = bfield.insert_minimizers(genome, 8, 21, 3, 0);
+        assert!(0 < n_inserted && n_inserted < 42);
+        // reads sharing w + k - 1 bases with the genome share a minimizer
+        let read = &genome[10..38];
+        assert!(bfield.query_minimizers(read, 8, 21).any(|v| v == Some(3)));
+    }
+}
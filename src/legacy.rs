@@ -0,0 +1,221 @@
+//! Loading `BField`s written by v0.1 and v0.2, whose member headers lack the
+//! fields the current format has:
+//! - v0.1 headers only hold `(n_hashes, marker_width, n_marker_bits)`, the
+//!   params given to `create` weren't saved at all
+//! - v0.2 headers add the params, as an `Option<T>` (only set in the primary
+//!   array), and values are always `u32`s
+//!
+//! Neither had a manifest, so the members are found by probing for
+//! `1.bfd`, `2.bfd`, ... until one is missing. They're read into memory
+//! and upgraded to the current representation, and optionally written back
+//! in the current format so they load with [`BField::load`] afterwards.
+use std::io;
+use std::path::Path;
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bfield::{check_primary_path, member_path, BField};
+use crate::bfield_member::{header_options, BFieldMember, BFieldParams, BFieldValue};
+use crate::storage::replace_file;
+
+/// Loads a `BField` written by v0.1, giving it the `params` that weren't
+/// saved (see [`load_v02`] for `rewrite`).
+pub fn load_v01<T, V, P>(
+    main_db_path: P,
+    params: T,
+    rewrite: bool,
+) -> Result<BField<T, V>, io::Error>
+where
+    T: Clone + DeserializeOwned + Serialize,
+    V: BFieldValue,
+    P: AsRef<Path>,
+{
+    load_legacy(main_db_path.as_ref(), Some(params), rewrite, |header| {
+        if header.len() != 3 {
+            return Err(not_legacy("v0.1", header));
+        }
+        let (n_hashes, marker_width, n_marker_bits) = header_options()
+            .deserialize(header)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(BFieldParams::new(
+            n_hashes,
+            marker_width,
+            n_marker_bits,
+            32,
+            None,
+        ))
+    })
+}
+
+/// Loads a `BField` written by v0.2, giving it `params` if the primary
+/// array has none. If `rewrite` is set, the files are also rewritten in the
+/// current format (each one is written next to the old one, then renamed
+/// over it) with a manifest.
+pub fn load_v02<T, V, P>(
+    main_db_path: P,
+    params: Option<T>,
+    rewrite: bool,
+) -> Result<BField<T, V>, io::Error>
+where
+    T: Clone + DeserializeOwned + Serialize,
+    V: BFieldValue,
+    P: AsRef<Path>,
+{
+    load_legacy(main_db_path.as_ref(), params, rewrite, |header| {
+        let mut cursor = io::Cursor::new(header);
+        let (n_hashes, marker_width, n_marker_bits, other): (u8, u8, u8, Option<T>) =
+            header_options()
+                .deserialize_from(&mut cursor)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // newer headers append fields after the params
+        if cursor.position() as usize != header.len() {
+            return Err(not_legacy("v0.2", header));
+        }
+        Ok(BFieldParams::new(
+            n_hashes,
+            marker_width,
+            n_marker_bits,
+            32,
+            other,
+        ))
+    })
+}
+
+fn not_legacy(version: &str, header: &[u8]) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "BField header of {} bytes wasn't written by {}",
+            header.len(),
+            version
+        ),
+    )
+}
+
+fn load_legacy<T, V, F>(
+    main_db_path: &Path,
+    params: Option<T>,
+    rewrite: bool,
+    decode: F,
+) -> Result<BField<T, V>, io::Error>
+where
+    T: Clone + DeserializeOwned + Serialize,
+    V: BFieldValue,
+    F: Fn(&[u8]) -> Result<BFieldParams<T>, io::Error>,
+{
+    check_primary_path(main_db_path)?;
+    let mut members = Vec::new();
+    loop {
+        let path = member_path(main_db_path, members.len());
+        if !path.exists() {
+            break;
+        }
+        members.push(BFieldMember::read_file_with(&path, &decode)?);
+    }
+    if let Some(primary) = members.first_mut() {
+        if primary.params.other.is_none() {
+            primary.params.other = params;
+        }
+    }
+    let bfield = BField::from_members(members, true, main_db_path)?;
+    if rewrite {
+        for member in bfield.members() {
+            let upgraded = member.filename.with_extension("bfd.upgrade");
+            member.write_file(&upgraded)?;
+            replace_file(&upgraded, &member.filename)?;
+        }
+        bfield.write_manifest(true)?;
+    }
+    Ok(bfield)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lookup::parse_member;
+    use crate::manifest::manifest_path;
+    use crate::storage::BF_MAGIC;
+    use std::fs;
+
+    /// Rewrites the member at `path` with the header of an older version:
+    /// v0.1 if `other` is `None`, else v0.2 with `other`.
+    fn downgrade(path: &Path, other: Option<Option<String>>) {
+        let bytes = fs::read(path).unwrap();
+        let image = parse_member(&bytes).unwrap();
+        let (n_hashes, marker_width, n_marker_bits) =
+            (image.header[0], image.header[1], image.header[2]);
+        let header = match other {
+            None => vec![n_hashes, marker_width, n_marker_bits],
+            Some(other) => header_options()
+                .serialize(&(n_hashes, marker_width, n_marker_bits, other))
+                .unwrap(),
+        };
+        let mut legacy = BF_MAGIC.to_vec();
+        legacy.extend((header.len() as u16).to_be_bytes());
+        legacy.extend(&header);
+        legacy.extend((image.size as u64).to_be_bytes());
+        legacy.extend(&bytes[image.data_offset..]);
+        fs::write(path, legacy).unwrap();
+    }
+
+    fn build(dir: &Path) -> Vec<std::path::PathBuf> {
+        let bfield: BField<String> = BField::create(
+            dir,
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            true,
+            "params".to_string(),
+        )
+        .unwrap();
+        bfield
+            .build_from_iter((0..100u32).map(|i| (format!("key {}", i), i)), |_| {})
+            .unwrap();
+        let bfield = bfield.persist_to_disk().unwrap();
+        let paths = bfield
+            .members()
+            .iter()
+            .map(|m| m.filename.clone())
+            .collect();
+        fs::remove_file(manifest_path(&bfield.members()[0].filename).unwrap()).unwrap();
+        paths
+    }
+
+    #[test]
+    fn can_load_legacy_files() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let paths = build(tmp_dir.path());
+        for path in &paths {
+            downgrade(path, None);
+        }
+        assert!(BField::<String>::load(&paths[0], true).is_err());
+        assert!(load_v02::<String, u32, _>(&paths[0], None, false).is_err());
+        let bfield: BField<String> = load_v01(&paths[0], "mocked".to_string(), false).unwrap();
+        assert_eq!(bfield.params(), &Some("mocked".to_string()));
+        for i in 0..100 {
+            assert_eq!(bfield.get(format!("key {}", i).as_bytes()), Some(i));
+        }
+
+        // v0.2 files keep their own params, and can be rewritten
+        downgrade(&paths[0], Some(Some("params".to_string())));
+        for path in &paths[1..] {
+            downgrade(path, Some(None));
+        }
+        assert!(load_v01::<String, u32, _>(&paths[0], String::new(), false).is_err());
+        let bfield: BField<String> = load_v02(&paths[0], Some("mocked".to_string()), true).unwrap();
+        assert_eq!(bfield.params(), &Some("params".to_string()));
+        let bfield: BField<String> = BField::load(&paths[0], true).unwrap();
+        assert_eq!(bfield.params(), &Some("params".to_string()));
+        for i in 0..100 {
+            assert_eq!(bfield.get(format!("key {}", i).as_bytes()), Some(i));
+        }
+        bfield.verify_checksums().unwrap();
+    }
+}
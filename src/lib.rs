@@ -1,4 +1,5 @@
 #![deny(missing_docs)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 //! The B-field datastructure, implemented in Rust.
 //! A space-efficient, probabilistic data structure and storage and retrieval method for key-value information.
@@ -10,12 +11,162 @@
 //!
 //! [^1]: These are not embeddable in the Cargo docs as they include MathJax,
 //! which is currently unsupported.
+//!
+//! Without the default `std` feature, only [`BFieldView`] is available, to
+//! query prebuilt `BField`s from byte slices with just `alloc`.
+
+extern crate alloc;
 
+#[cfg(feature = "arrow")]
+mod arrow;
+#[cfg(all(feature = "std", feature = "tokio"))]
+mod async_io;
+#[cfg(feature = "std")]
 mod bfield;
+#[cfg(feature = "std")]
 mod bfield_member;
+#[cfg(feature = "cache")]
+mod cache;
+#[cfg(feature = "std")]
+mod classify;
 /// Some combinatorial utilities
+#[cfg(feature = "std")]
 mod combinatorial;
+#[cfg(all(feature = "std", feature = "zstd"))]
+mod compress;
+#[cfg(feature = "std")]
+mod container;
+#[cfg(feature = "std")]
+mod corruption;
+#[cfg(feature = "std")]
+mod counting;
+#[cfg(feature = "std")]
+mod dedup;
+#[cfg(feature = "std")]
+mod describe;
+#[cfg(feature = "std")]
+mod diagnostics;
+#[cfg(feature = "std")]
+mod dictionary;
+#[cfg(feature = "std")]
+mod diff;
+#[cfg(feature = "std")]
+mod discover;
+#[cfg(feature = "seq-io")]
+mod fastx;
+/// A C interface, see `include/bfield.h`
+#[cfg(feature = "ffi")]
+pub mod ffi;
+/// A gRPC lookup service, see `proto/bfield.proto`
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "std")]
+mod guarded;
+#[cfg(feature = "std")]
+mod journal;
+#[cfg(feature = "std")]
+mod key;
+#[cfg(feature = "std")]
+mod keyed;
+/// Canonical DNA k-mers packed 2 bits per base
+#[cfg(feature = "std")]
+pub mod kmers;
+#[cfg(feature = "std")]
+pub mod legacy;
+/// The query path, which builds without `std`
+mod lookup;
+#[cfg(feature = "std")]
+mod manifest;
+#[cfg(feature = "std")]
+mod marker;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(all(feature = "std", feature = "rayon"))]
+mod parallel;
+#[cfg(feature = "std")]
+mod params;
+#[cfg(feature = "parquet")]
+mod parquet;
+#[cfg(feature = "std")]
+mod passes;
+#[cfg(feature = "pipeline")]
+mod pipeline;
+#[cfg(feature = "std")]
+mod prefilter;
+#[cfg(feature = "std")]
+mod provenance;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "std")]
+mod saturation;
+#[cfg(feature = "std")]
+mod scaling;
+#[cfg(feature = "std")]
+mod sharded;
+#[cfg(feature = "std")]
+mod sidecar;
+#[cfg(feature = "simd")]
+mod simd;
+#[cfg(feature = "std")]
+mod stats;
+#[cfg(feature = "std")]
+mod storage;
+/// Predicting the error rates of parameters with a scaled-down build
+#[cfg(feature = "std")]
+pub mod tuning;
 
-pub use crate::bfield::BField;
-pub use crate::bfield_member::BFieldVal;
-pub use combinatorial::choose;
+#[cfg(feature = "std")]
+pub use crate::bfield::{
+    estimate_footprint, BField, BuildParams, InvalidPath, LoadOptions, MemberInfo, Progress,
+    UnionReport,
+};
+#[cfg(feature = "std")]
+pub use crate::bfield_member::{BFieldVal, BFieldValue, Tristate};
+#[cfg(feature = "std")]
+pub use crate::classify::Classification;
+#[cfg(feature = "std")]
+pub use crate::corruption::{CorruptFile, Corruption};
+#[cfg(feature = "std")]
+pub use crate::counting::CountingBField;
+#[cfg(feature = "std")]
+pub use crate::dedup::DedupOptions;
+#[cfg(feature = "std")]
+pub use crate::describe::Description;
+#[cfg(feature = "std")]
+pub use crate::diagnostics::{
+    Diagnostics, DiagnosticsFormat, MemberDiagnostics, RegionDensity, DIAGNOSTICS_REGIONS,
+};
+#[cfg(feature = "std")]
+pub use crate::dictionary::ValueDictionary;
+#[cfg(feature = "std")]
+pub use crate::diff::{BFieldDiff, MemberDiff};
+#[cfg(feature = "std")]
+pub use crate::journal::{Journal, JournalEntry, JournalOp};
+#[cfg(feature = "std")]
+pub use crate::key::BFieldKey;
+#[cfg(feature = "std")]
+pub use crate::keyed::HashSecret;
+pub use crate::lookup::{choose, unrank, BFieldView, ViewError};
+#[cfg(feature = "metrics")]
+pub use crate::metrics::LookupMetrics;
+#[cfg(feature = "std")]
+pub use crate::passes::{KeySet, PassReport};
+#[cfg(feature = "std")]
+pub use crate::provenance::Provenance;
+#[cfg(feature = "std")]
+pub use crate::saturation::{SaturationMonitor, SaturationWarning};
+#[cfg(feature = "std")]
+pub use crate::scaling::ScalingOptions;
+#[cfg(feature = "std")]
+pub use crate::sharded::ShardedBField;
+#[cfg(feature = "std")]
+pub use crate::stats::{
+    MemberProbeStats, MemberStats, ProbeStats, Stats, Validation, ValidationError,
+};
+#[cfg(feature = "std")]
+pub use crate::storage::{AccessPattern, Backing};
+#[cfg(feature = "std")]
+pub use combinatorial::{
+    capacity, checked_next_rank, rank, rank_many, set_marker_table_size, try_rank, unrank_many,
+    RankError,
+};
@@ -0,0 +1,580 @@
+//! The query path of a `BField` (hashing, reading markers and decoding
+//! them), which only needs `core` and `alloc` so prebuilt `BField`s can be
+//! queried from a byte slice without `std`.
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::convert::{TryFrom, TryInto};
+use core::fmt;
+use core::ops::Range;
+
+/// Magic bytes used to indicate the `bfield` file type for `MmapBitvec`
+pub(crate) const BF_MAGIC: [u8; 2] = [0xBF, 0x1D];
+/// Magic bytes of a container (`0xBF 0x1D 'C'` followed by the format
+/// version), see `container`
+pub(crate) const CONTAINER_MAGIC: [u8; 4] = [0xBF, 0x1D, b'C', 1];
+
+/// Why bytes couldn't be read as a `BField` member or container.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ViewError(&'static str);
+
+impl fmt::Display for ViewError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ViewError {}
+
+#[cfg(feature = "std")]
+impl From<ViewError> for std::io::Error {
+    fn from(error: ViewError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, error.0)
+    }
+}
+
+/// The 128-bit hash members with `seed` (0 unless set with
+/// `BField::use_hash_seed`) derive marker positions from.
+#[inline]
+pub(crate) fn hash_key(key: &[u8], seed: u64) -> (u64, u64) {
+    #[cfg(feature = "std")]
+    {
+        murmurhash3::murmurhash3_x64_128(key, seed)
+    }
+    #[cfg(not(feature = "std"))]
+    {
+        murmurhash3_x64_128(key, seed)
+    }
+}
+
+/// MurmurHash3 x64 128, for builds without `std` (where the `murmurhash3`
+/// crate isn't available); gives the same hashes as the crate.
+#[cfg(any(test, not(feature = "std")))]
+fn murmurhash3_x64_128(bytes: &[u8], seed: u64) -> (u64, u64) {
+    const C1: u64 = 0x87c3_7b91_1142_53d5;
+    const C2: u64 = 0x4cf5_ad43_2745_937f;
+    let mix_k1 = |k1: u64| k1.wrapping_mul(C1).rotate_left(31).wrapping_mul(C2);
+    let mix_k2 = |k2: u64| k2.wrapping_mul(C2).rotate_left(33).wrapping_mul(C1);
+
+    let (mut h1, mut h2) = (seed, seed);
+    let mut blocks = bytes.chunks_exact(16);
+    for block in &mut blocks {
+        h1 ^= mix_k1(u64::from_le_bytes(block[..8].try_into().unwrap()));
+        h1 = h1.rotate_left(27).wrapping_add(h2);
+        h1 = h1.wrapping_mul(5).wrapping_add(0x52dc_e729);
+        h2 ^= mix_k2(u64::from_le_bytes(block[8..].try_into().unwrap()));
+        h2 = h2.rotate_left(31).wrapping_add(h1);
+        h2 = h2.wrapping_mul(5).wrapping_add(0x3849_5ab5);
+    }
+    let tail = blocks.remainder();
+    let mut buf = [0u8; 16];
+    buf[..tail.len()].copy_from_slice(tail);
+    if tail.len() > 8 {
+        h2 ^= mix_k2(u64::from_le_bytes(buf[8..].try_into().unwrap()));
+    }
+    if !tail.is_empty() {
+        h1 ^= mix_k1(u64::from_le_bytes(buf[..8].try_into().unwrap()));
+    }
+
+    h1 ^= bytes.len() as u64;
+    h2 ^= bytes.len() as u64;
+    h1 = h1.wrapping_add(h2);
+    h2 = h2.wrapping_add(h1);
+    h1 = fmix64(h1);
+    h2 = fmix64(h2);
+    h1 = h1.wrapping_add(h2);
+    h2 = h2.wrapping_add(h1);
+    (h1, h2)
+}
+
+pub(crate) fn fmix64(mut k: u64) -> u64 {
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    k ^ (k >> 33)
+}
+
+/// The position of the `n`th marker of a key with `hash`.
+// Computed in `u64`s so the positions (and therefore the files) are the same
+// on 32-bit platforms; this matches the `usize` arithmetic on 64-bit ones.
+#[inline]
+pub(crate) fn marker_pos(
+    hash: (u64, u64),
+    n: usize,
+    total_size: usize,
+    marker_size: usize,
+) -> usize {
+    let pos = hash.0.wrapping_add((n as u64).wrapping_mul(hash.1));
+    (pos % (total_size - marker_size) as u64) as usize
+}
+
+/// Size in bits of the blocks the markers of a key are confined to in
+/// members with the blocked layout (see `BField::use_blocked_layout`): a
+/// cache line.
+pub(crate) const BLOCK_BITS: usize = 512;
+
+/// Set in the first byte of the header (k) of members with the blocked
+/// layout, so readers that don't know about it reject them instead of
+/// misreading them.
+pub(crate) const BLOCKED_FLAG: u8 = 0x80;
+
+/// Set in the first byte of the header (k) of members hashing keys with a
+/// secret (see `BField::use_keyed_hashing`), which can't be queried without
+/// it.
+pub(crate) const KEYED_FLAG: u8 = 0x40;
+
+/// Set in the first byte of the header (k) of members hashing keys with a
+/// seed (see `BField::use_hash_seed`), which is then the last 8 bytes of the
+/// header (little-endian) so readers can find it without decoding the rest.
+pub(crate) const SEEDED_FLAG: u8 = 0x20;
+
+/// Same as `marker_pos` for members with the blocked layout: the block is
+/// picked with the first half of the hash, and the markers are placed
+/// inside it with the second one.
+// Unlike in `marker_pos`, the offsets aren't an arithmetic progression:
+// in such a small block, that would often put all the markers of a key a
+// few bits apart, and each of them would then hold shifted copies of the
+// others that no AND clears.
+#[inline]
+pub(crate) fn blocked_marker_pos(
+    hash: (u64, u64),
+    n: usize,
+    total_size: usize,
+    marker_size: usize,
+) -> usize {
+    let n_blocks = (total_size / BLOCK_BITS) as u64;
+    let block = (hash.0 % n_blocks) as usize;
+    let mixed = fmix64(hash.1 ^ (n as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    let offset = ((mixed >> 32) * (BLOCK_BITS - marker_size) as u64) >> 32;
+    block * BLOCK_BITS + offset as usize
+}
+
+/// Number of bytes needed to hold `size` bits.
+#[inline]
+pub(crate) fn byte_len(size: usize) -> usize {
+    size.div_ceil(8)
+}
+
+/// Reads the (at most 128) bits in `r` from `bytes`. Bits are numbered from
+/// the most significant bit of the first byte and `r.start` ends up as the
+/// most significant bit of the result, matching `mmap-bitvec`'s layout.
+#[inline]
+pub(crate) fn get_range(bytes: &[u8], r: Range<usize>) -> u128 {
+    let len = r.end - r.start;
+    debug_assert!(len <= 128, "Range too large (>128)");
+    if len == 0 {
+        return 0;
+    }
+    let first = r.start >> 3;
+    if first + 17 <= bytes.len() {
+        // fast path: the range always fits in the 17 bytes from `first`
+        let lead = r.start & 7;
+        let hi = u128::from_be_bytes(bytes[first..first + 16].try_into().unwrap());
+        let top = if lead == 0 {
+            hi
+        } else {
+            (hi << lead) | u128::from(bytes[first + 16] >> (8 - lead))
+        };
+        return top >> (128 - len);
+    }
+
+    let mut value = 0u128;
+    let mut pos = r.start;
+    while pos < r.end {
+        let bit_offset = pos & 7;
+        let n = (8 - bit_offset).min(r.end - pos);
+        let chunk = (bytes[pos >> 3] << bit_offset) >> (8 - n);
+        value = (value << n) | u128::from(chunk);
+        pos += n;
+    }
+    value
+}
+
+/// Decodes a marker back into the value `rank` encoded in it, i.e. the
+/// position of the marker among the 128-bit markers with the same number of
+/// bits set in increasing order (see
+/// https://en.wikipedia.org/wiki/Combinatorial_number_system).
+///
+/// Markers whose value doesn't fit in a `u64` (only possible with 15 or more
+/// bits set) decode to `u64::MAX`, and 0 decodes to 0.
+pub fn unrank(marker: u128) -> u64 {
+    // val = choose(rank(0), 1) + choose(rank(1), 2) + choose(rank(2), 3) + ...
+    let mut working_marker = marker;
+    let mut value = 0u128;
+    let mut idx = 0;
+    while working_marker != 0 {
+        let rank = u64::from(working_marker.trailing_zeros());
+        working_marker -= 1 << rank;
+        idx += 1;
+        // for κ >= 15 the terms (and the sum) can overflow a u64; that's
+        // only possible for markers that were never inserted so we saturate
+        let term = if idx < 8 {
+            u128::from(choose(rank, idx))
+        } else {
+            choose_saturating(rank, idx)
+        };
+        value = value.saturating_add(term);
+    }
+    u64::try_from(value).unwrap_or(u64::MAX)
+}
+
+/// (Hopefully) fast implementation of a binomial.
+///
+/// This function uses a preset group of equations for k < 8 and then falls back to a
+/// multiplicative implementation that tries to prevent overflows while
+/// maintaining all results as exact integers.
+#[inline]
+pub fn choose(n: u64, k: u8) -> u64 {
+    // (extra border condition for speed-up?)
+    // if n == u64::from(k) {
+    //     return 1;
+    // }
+    match k {
+        0 => 1,
+        1 => n,
+        2 => n * (n - 1) / 2,
+        3 => n * (n - 1) * (n - 2) / 6,
+        4 => n * (n - 1) * (n - 2) * (n - 3) / 24,
+        5 => n * (n - 1) * (n - 2) * (n - 3) * (n - 4) / 120,
+        6 => n * (n - 1) * (n - 2) * (n - 3) * (n - 4) * (n - 5) / 720,
+        7 => n * (n - 1) * (n - 2) * (n - 3) * (n - 4) * (n - 5) * (n - 6) / 5040,
+        _ => {
+            let mut num: u128 = 1;
+            let mut denom: u128 = 1;
+            for i in 1..=u128::from(k) {
+                num *= u128::from(n) + 1 - i;
+                if num % i == 0 {
+                    num /= i;
+                    continue;
+                }
+                denom *= i;
+                if num % denom == 0 {
+                    num /= denom;
+                    denom = 1;
+                }
+            }
+            TryFrom::try_from(num / denom)
+                .unwrap_or_else(|_| panic!("{} choose {} is greater than 2**64", n, k))
+            // (or recursively) choose(n - 1, k - 1) + choose(n-1, k)
+            // for floats, this should work since they handle fractions:
+            // (1..u64::from(k)).map(|i| (n + 1 - i) / i).product(),
+        }
+    }
+}
+
+/// Binomial coefficient computed in 128 bits that saturates to `u128::MAX`
+/// (instead of panicking) once it gets close to overflowing; used where `n`
+/// can exceed 128 and only comparisons against `u64` values matter.
+pub(crate) fn choose_saturating(n: u64, k: u8) -> u128 {
+    let mut result = 1u128;
+    // choose(n, i + 1) = choose(n, i) * (n - i) / (i + 1), which is exact
+    for i in 0..u64::from(k) {
+        if i >= n {
+            return 0;
+        }
+        result = match result.checked_mul(u128::from(n - i)) {
+            Some(r) => r / u128::from(i + 1),
+            None => return u128::MAX,
+        };
+    }
+    result
+}
+
+/// A member image (as written by `mmap-bitvec`) parsed from a byte slice.
+pub(crate) struct MemberImage<'a> {
+    pub header: &'a [u8],
+    /// Size in bits
+    pub size: usize,
+    /// Offset of the bit array from the start of the image
+    pub data_offset: usize,
+}
+
+/// Parses the prefix written by `encode_member_prefix`, without checking
+/// the bit array that follows.
+pub(crate) fn parse_member_prefix(bytes: &[u8]) -> Result<MemberImage<'_>, ViewError> {
+    if bytes.len() < 4 || bytes[..2] != BF_MAGIC {
+        return Err(ViewError("Not a BField member (bad magic bytes)"));
+    }
+    let header_len = usize::from(u16::from_be_bytes([bytes[2], bytes[3]]));
+    let data_offset = 4 + header_len + 8;
+    if bytes.len() < data_offset {
+        return Err(ViewError("BField member header is truncated"));
+    }
+    let size = u64::from_be_bytes(bytes[4 + header_len..data_offset].try_into().unwrap());
+    let size = usize::try_from(size)
+        .map_err(|_| ViewError("BField member is too large for this platform"))?;
+    Ok(MemberImage {
+        header: &bytes[4..4 + header_len],
+        size,
+        data_offset,
+    })
+}
+
+/// Parses the prefix written by `encode_member_prefix`, checking the
+/// bit array is fully contained in `bytes`.
+pub(crate) fn parse_member(bytes: &[u8]) -> Result<MemberImage<'_>, ViewError> {
+    let image = parse_member_prefix(bytes)?;
+    if bytes.len() - image.data_offset < byte_len(image.size) {
+        return Err(ViewError("BField member bit array is truncated"));
+    }
+    Ok(image)
+}
+
+/// Parses the container index, returning `(offset, length)` for each member.
+pub(crate) fn read_index(bytes: &[u8]) -> Result<Vec<(usize, usize)>, ViewError> {
+    if bytes.len() < 8 || bytes[..4] != CONTAINER_MAGIC {
+        return Err(ViewError("Not a BField container (bad magic bytes)"));
+    }
+    let n_members = u32::from_be_bytes(bytes[4..8].try_into().unwrap()) as usize;
+    let index_end = n_members
+        .checked_mul(16)
+        .and_then(|len| len.checked_add(8))
+        .filter(|end| *end <= bytes.len())
+        .ok_or(ViewError("BField container index is truncated"))?;
+    bytes[8..index_end]
+        .chunks_exact(16)
+        .map(|entry| {
+            let offset = usize::try_from(u64::from_be_bytes(entry[..8].try_into().unwrap()));
+            let len = usize::try_from(u64::from_be_bytes(entry[8..].try_into().unwrap()));
+            match (offset, len) {
+                (Ok(offset), Ok(len)) if offset.checked_add(len) <= Some(bytes.len()) => {
+                    Ok((offset, len))
+                }
+                _ => Err(ViewError("BField container member is truncated")),
+            }
+        })
+        .collect()
+}
+
+/// A member's bit array and the parameters needed to query it.
+#[derive(Clone, Copy, Debug)]
+struct MemberView<'a> {
+    bits: &'a [u8],
+    size: usize,
+    n_hashes: u8,
+    marker_width: u8,
+    /// 0 for bitset members
+    n_marker_bits: u8,
+    blocked: bool,
+    seed: u64,
+}
+
+impl<'a> MemberView<'a> {
+    fn parse(bytes: &'a [u8]) -> Result<Self, ViewError> {
+        let image = parse_member(bytes)?;
+        // the header starts with k, ν and κ as single bytes
+        let (n_hashes, marker_width, n_marker_bits) = match image.header {
+            [k, nu, kappa, ..] => (*k, *nu, *kappa),
+            _ => return Err(ViewError("BField member header is truncated")),
+        };
+        if n_hashes & KEYED_FLAG != 0 {
+            return Err(ViewError(
+                "BField members hashed with a secret can't be viewed",
+            ));
+        }
+        let seed = if n_hashes & SEEDED_FLAG != 0 {
+            match image.header.len().checked_sub(8) {
+                Some(start) if start >= 3 => {
+                    u64::from_le_bytes(image.header[start..].try_into().unwrap())
+                }
+                _ => return Err(ViewError("BField member header is truncated")),
+            }
+        } else {
+            0
+        };
+        let blocked = n_hashes & BLOCKED_FLAG != 0;
+        let n_hashes = n_hashes & !(BLOCKED_FLAG | SEEDED_FLAG);
+        if n_hashes == 0
+            || n_hashes > 16
+            || usize::from(marker_width) >= image.size
+            || (blocked && image.size < BLOCK_BITS)
+        {
+            return Err(ViewError("Invalid BField member parameters"));
+        }
+        if marker_width > 128 {
+            return Err(ViewError("Markers wider than 128 bits aren't supported"));
+        }
+        Ok(MemberView {
+            bits: &bytes[image.data_offset..image.data_offset + byte_len(image.size)],
+            size: image.size,
+            n_hashes,
+            marker_width,
+            n_marker_bits,
+            blocked,
+            seed,
+        })
+    }
+
+    /// The AND of the markers of a key with `hash`, or 0 once fewer than
+    /// `k` bits are left.
+    fn get_raw(&self, hash: (u64, u64), k: u32) -> u128 {
+        let marker_width = usize::from(self.marker_width);
+        let mut merged_marker = u128::MAX;
+        for marker_ix in 0..usize::from(self.n_hashes) {
+            let pos = if self.blocked {
+                blocked_marker_pos(hash, marker_ix, self.size, marker_width)
+            } else {
+                marker_pos(hash, marker_ix, self.size, marker_width)
+            };
+            merged_marker &= get_range(self.bits, pos..pos + marker_width);
+            if merged_marker.count_ones() < k {
+                return 0;
+            }
+        }
+        merged_marker
+    }
+
+    /// Same as `BFieldMember::get_hashed`: `Err(())` if the key is
+    /// indeterminate in this member.
+    fn get(&self, hash: (u64, u64)) -> Result<Option<u64>, ()> {
+        if self.n_marker_bits == 0 {
+            return Ok(match self.get_raw(hash, 1) {
+                0 => None,
+                bits => Some(bits as u64),
+            });
+        }
+        let k = u32::from(self.n_marker_bits);
+        let marker = self.get_raw(hash, k);
+        match marker.count_ones().cmp(&k) {
+            Ordering::Greater => Err(()),
+            Ordering::Equal => Ok(Some(unrank(marker))),
+            Ordering::Less => Ok(None),
+        }
+    }
+}
+
+/// A read-only `BField` over the bytes of its `.bfd` files or container
+/// (e.g. embedded with `include_bytes!`), which doesn't need `std`.
+///
+/// Lookups give the same values as [`BField::get`](crate::BField::get),
+/// except for keys only stored in the sidecar, and don't use the prefilter.
+/// Members with markers wider than 128 bits aren't supported.
+#[derive(Clone, Debug)]
+pub struct BFieldView<'a> {
+    members: Vec<MemberView<'a>>,
+}
+
+impl<'a> BFieldView<'a> {
+    /// A view of the `BField` whose `.bfd` files (primary array first) hold
+    /// `members`.
+    pub fn from_members(members: &[&'a [u8]]) -> Result<Self, ViewError> {
+        if members.is_empty() {
+            return Err(ViewError("A BField needs at least one member"));
+        }
+        let members = members
+            .iter()
+            .map(|bytes| MemberView::parse(bytes))
+            .collect::<Result<_, _>>()?;
+        Ok(BFieldView { members })
+    }
+
+    /// A view of the `BField` stored in the container `bytes`, see
+    /// [`BField::create_container`](crate::BField::create_container).
+    pub fn from_container(bytes: &'a [u8]) -> Result<Self, ViewError> {
+        let members: Vec<&[u8]> = read_index(bytes)?
+            .into_iter()
+            .map(|(offset, len)| &bytes[offset..offset + len])
+            .collect();
+        Self::from_members(&members)
+    }
+
+    /// Number of members (the primary array and its secondaries).
+    pub fn n_members(&self) -> usize {
+        self.members.len()
+    }
+
+    /// The value stored for `key`, or `None`.
+    pub fn get(&self, key: &[u8]) -> Option<u64> {
+        // the members of a `BField` usually share their seed, so the key is
+        // only hashed again when it changes
+        let mut hashed: Option<(u64, (u64, u64))> = None;
+        self.members
+            .iter()
+            .find_map(|member| {
+                let hash = match hashed {
+                    Some((seed, hash)) if seed == member.seed => hash,
+                    _ => {
+                        let hash = hash_key(key, member.seed);
+                        hashed = Some((member.seed, hash));
+                        hash
+                    }
+                };
+                member.get(hash).ok()
+            })
+            .flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_murmurhash3_matches_reference() {
+        assert_eq!(murmurhash3_x64_128(b"", 0), (0, 0));
+        assert_eq!(
+            murmurhash3_x64_128(b"hello", 0),
+            (0xcbd8_a7b3_41bd_9b02, 0x5b1e_906a_48ae_1d19)
+        );
+        #[cfg(feature = "std")]
+        for len in 0..64 {
+            let key: Vec<u8> = (0..len as u8).map(|i| i.wrapping_mul(37)).collect();
+            for seed in [0, 1, 0xFEED_BEEF] {
+                assert_eq!(
+                    murmurhash3_x64_128(&key, seed),
+                    murmurhash3::murmurhash3_x64_128(&key, seed)
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    fn can_query_bfield_from_bytes() {
+        use crate::bfield::BField;
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            1_000_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            false,
+            String::new(),
+        )
+        .expect("to build");
+        bfield
+            .build_from_iter((0..1_000u32).map(|i| (i.to_be_bytes(), i)), |_| {})
+            .unwrap();
+        let path = tmp_dir.path().join("bfield.container");
+        bfield.write_container(&path).unwrap();
+
+        let files: Vec<Vec<u8>> = (0..2)
+            .map(|n| std::fs::read(tmp_dir.path().join(format!("bfield.{}.bfd", n))).unwrap())
+            .collect();
+        let members: Vec<&[u8]> = files.iter().map(|f| f.as_slice()).collect();
+        let view = BFieldView::from_members(&members).unwrap();
+        assert_eq!(view.n_members(), 2);
+        let container = std::fs::read(&path).unwrap();
+        let from_container = BFieldView::from_container(&container).unwrap();
+        for i in (0..1_000u32).chain(5_000..6_000) {
+            let key = i.to_be_bytes();
+            let expected = bfield.get(&key).map(u64::from);
+            assert_eq!(view.get(&key), expected);
+            assert_eq!(from_container.get(&key), expected);
+        }
+
+        assert!(BFieldView::from_members(&[]).is_err());
+        assert!(BFieldView::from_container(&files[0]).is_err());
+        assert_eq!(
+            BFieldView::from_members(&[&files[0][..files[0].len() - 1]]).unwrap_err(),
+            ViewError("BField member bit array is truncated")
+        );
+    }
+}
@@ -0,0 +1,267 @@
+//! A small JSON file written next to the `.bfd` files listing every member
+//! of a `BField`, so `load` knows exactly which files to expect.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::bfield_member::BFieldMember;
+
+const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    pub version: u32,
+    pub members: Vec<ManifestMember>,
+}
+
+/// What's expected of each member file; `file` is relative to the manifest.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ManifestMember {
+    pub file: String,
+    /// Size in bits
+    pub size: usize,
+    pub n_hashes: u8,
+    pub marker_width: u8,
+    pub n_marker_bits: u8,
+    pub value_bits: u8,
+    /// Checksum of the member's header and bit array (see `BFieldMember::checksum`),
+    /// recorded when the `BField` is persisted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<u64>,
+}
+
+/// The manifest path for a `BField` whose primary array is at `main_db_path`
+/// (`{filename}.0.bfd` -> `{filename}.manifest.json`), or `None` if the path
+/// doesn't follow that naming scheme (e.g. for container files).
+pub(crate) fn manifest_path(main_db_path: &Path) -> Option<PathBuf> {
+    let filename = main_db_path.file_name()?.to_str()?;
+    let base = filename.strip_suffix("0.bfd")?;
+    Some(main_db_path.with_file_name(format!("{base}manifest.json")))
+}
+
+impl Manifest {
+    /// Describes `members`, computing their checksums if `checksums` is set.
+    pub fn from_members<T: Clone + DeserializeOwned + Serialize>(
+        members: &[BFieldMember<T>],
+        checksums: bool,
+    ) -> Self {
+        let members = members
+            .iter()
+            .map(|m| {
+                let (size, n_hashes, marker_width, n_marker_bits) = m.info();
+                ManifestMember {
+                    file: m
+                        .filename
+                        .file_name()
+                        .map(|f| f.to_string_lossy().into_owned())
+                        .unwrap_or_default(),
+                    size,
+                    n_hashes,
+                    marker_width,
+                    n_marker_bits,
+                    value_bits: m.params.value_bits,
+                    checksum: if checksums { Some(m.checksum()) } else { None },
+                }
+            })
+            .collect();
+        Manifest {
+            version: MANIFEST_VERSION,
+            members,
+        }
+    }
+
+    /// Reads the manifest at `path`, returning `None` if there isn't one
+    /// (e.g. for `BField`s written before manifests were introduced).
+    pub fn read(path: &Path) -> Result<Option<Self>, io::Error> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let manifest: Manifest = serde_json::from_slice(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if manifest.version > MANIFEST_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Manifest {:?} has version {}, only up to {} is supported",
+                    path, manifest.version, MANIFEST_VERSION
+                ),
+            ));
+        }
+        Ok(Some(manifest))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), io::Error> {
+        let data = serde_json::to_vec_pretty(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, data)
+    }
+
+    /// Checks the member loaded from `entry.file` matches what was recorded.
+    pub fn check_member<T: Clone + DeserializeOwned + Serialize>(
+        entry: &ManifestMember,
+        member: &BFieldMember<T>,
+    ) -> Result<(), io::Error> {
+        let (size, n_hashes, marker_width, n_marker_bits) = member.info();
+        let found = ManifestMember {
+            file: entry.file.clone(),
+            size,
+            n_hashes,
+            marker_width,
+            n_marker_bits,
+            value_bits: member.params.value_bits,
+            checksum: entry.checksum,
+        };
+        if &found != entry {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Member {:?} doesn't match the manifest: expected {:?}, found {:?}",
+                    member.filename, entry, found
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the checksum of `member` matches the one recorded in `entry`.
+    pub fn verify_member<T: Clone + DeserializeOwned + Serialize>(
+        entry: &ManifestMember,
+        member: &BFieldMember<T>,
+    ) -> Result<(), io::Error> {
+        let expected = entry.checksum.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No checksum recorded for member {:?}", member.filename),
+            )
+        })?;
+        let found = member.checksum();
+        if found != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Checksum mismatch for member {:?}: expected {:016x}, found {:016x}",
+                    member.filename, expected, found
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BField, LoadOptions};
+
+    fn build(dir: &Path, in_memory: bool) -> BField<String> {
+        BField::create(
+            dir,
+            "bfield",
+            10_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            3,
+            in_memory,
+            String::new(),
+        )
+        .expect("to build")
+    }
+
+    #[test]
+    fn test_manifest_path() {
+        assert_eq!(
+            manifest_path(Path::new("/tmp/bfield.0.bfd")),
+            Some(PathBuf::from("/tmp/bfield.manifest.json"))
+        );
+        assert_eq!(manifest_path(Path::new("/tmp/bfield.bfc")), None);
+    }
+
+    #[test]
+    fn test_manifest_written_on_create_and_persist() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("bfield.manifest.json");
+        let bfield = build(tmp_dir.path(), false);
+        let manifest = Manifest::read(&path).unwrap().unwrap();
+        assert_eq!(manifest.members.len(), 3);
+        assert_eq!(manifest.members[1].file, "bfield.1.bfd");
+        assert_eq!(manifest.members[1].size, bfield.member_info()[1].size);
+        drop(bfield);
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("bfield.manifest.json");
+        let bfield = build(tmp_dir.path(), true);
+        assert!(Manifest::read(&path).unwrap().is_none());
+        let _ = bfield.persist_to_disk().unwrap();
+        assert_eq!(Manifest::read(&path).unwrap().unwrap().members.len(), 3);
+    }
+
+    #[test]
+    fn test_load_detects_missing_and_extra_members() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        drop(build(tmp_dir.path(), false));
+        let main_db_path = tmp_dir.path().join("bfield.0.bfd");
+        assert!(BField::<String>::load(&main_db_path, true).is_ok());
+
+        // an extra member lying around
+        fs::copy(
+            tmp_dir.path().join("bfield.2.bfd"),
+            tmp_dir.path().join("bfield.3.bfd"),
+        )
+        .unwrap();
+        let err = BField::<String>::load(&main_db_path, true).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(tmp_dir.path().join("bfield.3.bfd")).unwrap();
+
+        // a missing member
+        fs::remove_file(tmp_dir.path().join("bfield.1.bfd")).unwrap();
+        let err = BField::<String>::load(&main_db_path, true).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_load_without_manifest() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        drop(build(tmp_dir.path(), false));
+        fs::remove_file(tmp_dir.path().join("bfield.manifest.json")).unwrap();
+        let bfield = BField::<String>::load(tmp_dir.path().join("bfield.0.bfd"), true).unwrap();
+        assert_eq!(bfield.member_info().len(), 3);
+    }
+
+    #[test]
+    fn test_verify_checksums() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield = build(tmp_dir.path(), false);
+        for i in 0..1_000u32 {
+            bfield.insert(&i.to_be_bytes(), i, 0);
+        }
+        // nothing recorded yet
+        let err = bfield.verify_checksums().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        bfield.update_checksums().unwrap();
+        bfield.verify_checksums().unwrap();
+        drop(bfield);
+
+        let main_db_path = tmp_dir.path().join("bfield.0.bfd");
+        let options = LoadOptions::new().read_only(true).verify_checksums(true);
+        assert!(BField::<String>::load_with(&main_db_path, &options).is_ok());
+
+        // flip a bit at the end of the primary array
+        let mut data = fs::read(&main_db_path).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        fs::write(&main_db_path, data).unwrap();
+        let err = BField::<String>::load_with(&main_db_path, &options)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // still loads without verification
+        assert!(BField::<String>::load(&main_db_path, true).is_ok());
+    }
+}
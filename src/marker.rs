@@ -0,0 +1,217 @@
+use std::convert::TryFrom;
+use std::ops::Range;
+
+use crate::combinatorial::{choose_saturating, rank, rank_wide, unrank};
+use crate::storage::BitStorage;
+#[cfg(test)]
+use crate::storage::Storage;
+
+/// A ν-bit string with κ bits set, encoding a value.
+///
+/// Markers up to 128 bits wide are plain `u128`s; wider ones use a
+/// [`WideMarker`]. Bit `i` of a marker is stored at `pos + width - 1 - i`
+/// in the bit array, i.e. the most significant bit comes first.
+pub(crate) trait Marker: Copy + Eq {
+    const ZERO: Self;
+
+    /// A marker with every bit set, used as the identity for `and`.
+    const ONES: Self;
+
+    fn count_ones(&self) -> u32;
+
+    fn and(self, other: Self) -> Self;
+
+    /// Returns the marker with bit `i` set.
+    fn with_bit(self, i: u32) -> Self;
+
+    fn rank(value: u64, k: u8) -> Self;
+
+    fn unrank(self) -> u64;
+
+    fn read<S: BitStorage + ?Sized>(bv: &S, pos: usize, width: usize) -> Self;
+
+    fn write<S: BitStorage + ?Sized>(bv: &S, pos: usize, width: usize, marker: Self);
+
+    /// The AND of the markers at `positions`, if `bv` can read them all at
+    /// once (see `BitStorage::and_ranges`).
+    #[inline]
+    fn read_and<S: BitStorage + ?Sized>(
+        _bv: &S,
+        _positions: &[usize],
+        _width: usize,
+    ) -> Option<Self> {
+        None
+    }
+}
+
+impl Marker for u128 {
+    const ZERO: Self = 0;
+
+    const ONES: Self = u128::MAX;
+
+    #[inline]
+    fn count_ones(&self) -> u32 {
+        u128::count_ones(*self)
+    }
+
+    #[inline]
+    fn and(self, other: Self) -> Self {
+        self & other
+    }
+
+    #[inline]
+    fn with_bit(self, i: u32) -> Self {
+        self | (1 << i)
+    }
+
+    #[inline]
+    fn rank(value: u64, k: u8) -> Self {
+        rank(value, k)
+    }
+
+    #[inline]
+    fn unrank(self) -> u64 {
+        unrank(self)
+    }
+
+    #[inline]
+    fn read<S: BitStorage + ?Sized>(bv: &S, pos: usize, width: usize) -> Self {
+        bv.get_range(pos..pos + width)
+    }
+
+    #[inline]
+    fn write<S: BitStorage + ?Sized>(bv: &S, pos: usize, width: usize, marker: Self) {
+        bv.set_range(pos..pos + width, marker);
+    }
+
+    #[inline]
+    fn read_and<S: BitStorage + ?Sized>(bv: &S, positions: &[usize], width: usize) -> Option<Self> {
+        bv.and_ranges(positions, width).map(u128::from)
+    }
+}
+
+/// A marker for widths between 129 and 255 bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct WideMarker {
+    /// Bits 128..256
+    pub(crate) hi: u128,
+    /// Bits 0..128
+    pub(crate) lo: u128,
+}
+
+impl WideMarker {
+    /// Splits the ν-bit window at `pos` into the ranges holding `hi` and `lo`.
+    #[inline]
+    fn ranges(pos: usize, width: usize) -> (Range<usize>, Range<usize>) {
+        debug_assert!(width > 128);
+        let split = pos + width - 128;
+        (pos..split, split..pos + width)
+    }
+}
+
+impl Marker for WideMarker {
+    const ZERO: Self = WideMarker { hi: 0, lo: 0 };
+
+    const ONES: Self = WideMarker {
+        hi: u128::MAX,
+        lo: u128::MAX,
+    };
+
+    #[inline]
+    fn count_ones(&self) -> u32 {
+        self.hi.count_ones() + self.lo.count_ones()
+    }
+
+    #[inline]
+    fn and(self, other: Self) -> Self {
+        WideMarker {
+            hi: self.hi & other.hi,
+            lo: self.lo & other.lo,
+        }
+    }
+
+    #[inline]
+    fn with_bit(self, i: u32) -> Self {
+        if i < 128 {
+            WideMarker {
+                hi: self.hi,
+                lo: self.lo | (1 << i),
+            }
+        } else {
+            WideMarker {
+                hi: self.hi | (1 << (i - 128)),
+                lo: self.lo,
+            }
+        }
+    }
+
+    #[inline]
+    fn rank(value: u64, k: u8) -> Self {
+        rank_wide(value, k)
+    }
+
+    fn unrank(self) -> u64 {
+        // same as `unrank`, but positions past 128 can overflow a u64; such
+        // markers can only be false positives so we just saturate
+        let mut value = 0u128;
+        let mut idx = 0;
+        for (offset, mut bits) in [(0, self.lo), (128, self.hi)] {
+            while bits != 0 {
+                let rank = bits.trailing_zeros();
+                bits &= bits - 1;
+                idx += 1;
+                value = value.saturating_add(choose_saturating(u64::from(offset + rank), idx));
+            }
+        }
+        u64::try_from(value).unwrap_or(u64::MAX)
+    }
+
+    #[inline]
+    fn read<S: BitStorage + ?Sized>(bv: &S, pos: usize, width: usize) -> Self {
+        let (hi, lo) = Self::ranges(pos, width);
+        WideMarker {
+            hi: bv.get_range(hi),
+            lo: bv.get_range(lo),
+        }
+    }
+
+    #[inline]
+    fn write<S: BitStorage + ?Sized>(bv: &S, pos: usize, width: usize, marker: Self) {
+        let (hi, lo) = Self::ranges(pos, width);
+        bv.set_range(hi, marker.hi);
+        bv.set_range(lo, marker.lo);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wide_marker_rank_and_unrank() {
+        // 255 choose 5 is ~8.6e9 so all of these fit
+        for k in 5..9u8 {
+            for value in [0u64, 1, 23, 45, 200_000, 1 << 33].iter() {
+                let marker = WideMarker::rank(*value, k);
+                assert_eq!(marker.count_ones(), u32::from(k));
+                assert_eq!(marker.unrank(), *value);
+            }
+        }
+        // small values match the narrow encoding
+        let marker = WideMarker::rank(12_345, 4);
+        assert_eq!(marker.hi, 0);
+        assert_eq!(marker.lo, <u128 as Marker>::rank(12_345, 4));
+    }
+
+    #[test]
+    fn test_wide_marker_read_write() {
+        let bv = Storage::anonymous(1024).unwrap();
+        let marker = WideMarker::rank(choose_saturating(200, 4) as u64 - 1, 4);
+        // the top bit is bit 199 so it ends up in `hi`
+        assert_ne!(marker.hi, 0);
+        WideMarker::write(&bv, 17, 200, marker);
+        assert_eq!(WideMarker::read(&bv, 17, 200), marker);
+        let n_set: u32 = bv.as_bytes().iter().map(|b| b.count_ones()).sum();
+        assert_eq!(n_set, 4);
+    }
+}
@@ -0,0 +1,149 @@
+//! Prometheus metrics of the lookups answered by a `BField`, to watch its
+//! hit rate, indeterminacy and latency in production.
+use std::time::Duration;
+
+use prometheus::{
+    exponential_buckets, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry,
+};
+
+use crate::bfield_member::BFieldLookup;
+
+/// The metrics of the lookups of one or more `BField`s, see
+/// [`BField::monitor_lookups`](crate::BField::monitor_lookups).
+///
+/// A growing share of indeterminate lookups or of probes of the secondary
+/// arrays means the arrays are getting more saturated than planned.
+#[derive(Clone, Debug)]
+pub struct LookupMetrics {
+    lookups: IntCounterVec,
+    probes: IntCounterVec,
+    latency: Histogram,
+}
+
+impl LookupMetrics {
+    /// Creates the metrics and registers them in `registry`:
+    /// - `bfield_lookups_total`, by `outcome` (`hit`, `miss` or
+    ///   `indeterminate`)
+    /// - `bfield_member_probes_total`, by `member` (0 for the primary array)
+    /// - `bfield_lookup_duration_seconds`, a histogram of lookup latencies
+    ///
+    /// Use `Registry::new_custom` to prefix or label them, e.g. to tell
+    /// several `BField`s apart.
+    pub fn register(registry: &Registry) -> prometheus::Result<Self> {
+        let lookups = IntCounterVec::new(
+            Opts::new("bfield_lookups_total", "Number of lookups by outcome"),
+            &["outcome"],
+        )?;
+        let probes = IntCounterVec::new(
+            Opts::new(
+                "bfield_member_probes_total",
+                "Number of lookups that probed each array",
+            ),
+            &["member"],
+        )?;
+        // from 100ns (a lookup in cache) to about 25ms (a lookup waiting on
+        // page faults from a slow disk)
+        let latency = Histogram::with_opts(
+            HistogramOpts::new("bfield_lookup_duration_seconds", "Latency of lookups")
+                .buckets(exponential_buckets(1e-7, 4., 10)?),
+        )?;
+        registry.register(Box::new(lookups.clone()))?;
+        registry.register(Box::new(probes.clone()))?;
+        registry.register(Box::new(latency.clone()))?;
+        Ok(LookupMetrics {
+            lookups,
+            probes,
+            latency,
+        })
+    }
+
+    /// The metrics of a `BField` with `n_members` arrays, with the labelled
+    /// counters resolved once rather than on every lookup.
+    pub(crate) fn bind(&self, n_members: usize) -> BoundMetrics {
+        BoundMetrics {
+            hits: self.lookups.with_label_values(&["hit"]),
+            misses: self.lookups.with_label_values(&["miss"]),
+            indeterminate: self.lookups.with_label_values(&["indeterminate"]),
+            probes: (0..n_members)
+                .map(|ix| self.probes.with_label_values(&[&ix.to_string()]))
+                .collect(),
+            latency: self.latency.clone(),
+        }
+    }
+}
+
+/// The [`LookupMetrics`] of one `BField`.
+#[derive(Clone, Debug)]
+pub(crate) struct BoundMetrics {
+    hits: IntCounter,
+    misses: IntCounter,
+    indeterminate: IntCounter,
+    probes: Vec<IntCounter>,
+    latency: Histogram,
+}
+
+impl BoundMetrics {
+    /// Records a lookup that probed the first `n_probed` arrays.
+    pub fn record(&self, lookup: &BFieldLookup, n_probed: usize, elapsed: Duration) {
+        match lookup {
+            BFieldLookup::Some(_) => self.hits.inc(),
+            BFieldLookup::None => self.misses.inc(),
+            BFieldLookup::Indeterminate => self.indeterminate.inc(),
+        }
+        for counter in self.probes.iter().take(n_probed) {
+            counter.inc();
+        }
+        self.latency.observe(elapsed.as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bfield::BField;
+
+    #[test]
+    fn can_record_lookups() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .unwrap();
+        bfield
+            .build_from_iter((0..100u32).map(|i| (format!("key {}", i), i)), |_| {})
+            .unwrap();
+        let registry = Registry::new();
+        let metrics = LookupMetrics::register(&registry).unwrap();
+        bfield.monitor_lookups(Some(&metrics));
+
+        for i in 0..100 {
+            assert_eq!(bfield.get(format!("key {}", i).as_bytes()), Some(i));
+        }
+        for i in 0..50 {
+            assert_eq!(bfield.get(format!("missing {}", i).as_bytes()), None);
+        }
+        let count = |outcome| metrics.lookups.with_label_values(&[outcome]).get();
+        assert_eq!(count("hit") + count("miss") + count("indeterminate"), 150);
+        assert!(count("hit") >= 100);
+        assert_eq!(metrics.probes.with_label_values(&["0"]).get(), 150);
+        assert_eq!(metrics.latency.get_sample_count(), 150);
+        // every metric family is exported
+        assert_eq!(registry.gather().len(), 3);
+        // registering the same metrics twice fails
+        assert!(LookupMetrics::register(&registry).is_err());
+
+        bfield.monitor_lookups(None);
+        bfield.get(b"key 1");
+        assert_eq!(metrics.latency.get_sample_count(), 150);
+    }
+}
@@ -0,0 +1,63 @@
+//! Looking keys up on rayon's thread pool.
+use rayon::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bfield::{BField, DEFAULT_PIPELINE_DEPTH};
+use crate::bfield_member::BFieldValue;
+
+/// Number of keys each task of `par_get` looks up.
+const PAR_GET_CHUNK_SIZE: usize = 1024;
+
+impl<T, V> BField<T, V>
+where
+    T: Clone + DeserializeOwned + Serialize + Sync,
+    V: BFieldValue + Send + Sync,
+{
+    /// The values of each of `keys`, as returned by [`BField::get`], looked
+    /// up in parallel on rayon's global pool (in chunks of 1024 pipelined
+    /// lookups, see [`BField::get_many_pipelined`]). Lookups only read the
+    /// arrays and skip the cache, so the threads share nothing they write
+    /// to (apart from the lookup metrics, if enabled).
+    pub fn par_get<K: AsRef<[u8]> + Sync>(&self, keys: &[K]) -> Vec<Option<V>> {
+        keys.par_chunks(PAR_GET_CHUNK_SIZE)
+            .flat_map_iter(|chunk| self.get_many_uncached(chunk, DEFAULT_PIPELINE_DEPTH))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_get_in_parallel() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .unwrap();
+        bfield
+            .build_from_iter((0..2_000u32).map(|i| (i.to_be_bytes(), i)), |_| {})
+            .unwrap();
+        bfield.persist_to_disk().unwrap();
+        let bfield: BField<String> =
+            BField::load(tmp_dir.path().join("bfield.0.bfd"), true).unwrap();
+
+        let keys: Vec<[u8; 4]> = (0..5_000u32).map(u32::to_be_bytes).collect();
+        let values = bfield.par_get(&keys);
+        assert_eq!(values, bfield.get_many(&keys));
+        assert_eq!(values[1_234], Some(1_234));
+        assert!(bfield.par_get::<&[u8]>(&[]).is_empty());
+    }
+}
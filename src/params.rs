@@ -0,0 +1,294 @@
+//! The encoding of the params given to `create` (the `T` of a `BField`) in
+//! the member headers. Up to v0.3 they were bincode-encoded as an
+//! `Option<T>`, so adding a field to `T` made older files unreadable. They're
+//! now written as JSON, tagged with a version picked by the application (see
+//! `BField::set_params_version`): fields can be added to `T` (with
+//! `#[serde(default)]`) and unknown ones are ignored, and other changes can
+//! be handled on load with `LoadOptions::upgrade_params`.
+//!
+//! The params start with a tag: bincode's `None` (0) or `Some` (1) for
+//! headers written before v0.4, or `JSON_TAG` followed by the version (a
+//! `u32`), the length of the JSON (a `u64`) and the JSON itself.
+//! `PROVENANCE_TAG` is the same, followed by the [`Provenance`] of the
+//! `BField` as JSON (also after its length); it's only written with params,
+//! i.e. in primary arrays.
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::bfield_member::header_options;
+use crate::provenance::Provenance;
+
+const NONE_TAG: u8 = 0;
+const BINCODE_TAG: u8 = 1;
+const JSON_TAG: u8 = 2;
+const PROVENANCE_TAG: u8 = 3;
+
+/// Converts params written with an older version into the current one, see
+/// [`LoadOptions::upgrade_params`](crate::LoadOptions::upgrade_params).
+#[derive(Clone)]
+pub(crate) struct ParamsUpgrade {
+    /// The current version of the params
+    pub version: u32,
+    pub upgrade: Arc<dyn Fn(u32, Value) -> Result<Value, io::Error> + Send + Sync>,
+}
+
+impl fmt::Debug for ParamsUpgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ParamsUpgrade")
+            .field("version", &self.version)
+            .finish_non_exhaustive()
+    }
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Appends `other`, tagged with `version`, and the `provenance` of the
+/// `BField` (if there are params) to `header`.
+pub(crate) fn encode_other<T: Serialize>(
+    header: &mut Vec<u8>,
+    other: Option<&T>,
+    version: u32,
+    provenance: Option<&Provenance>,
+) {
+    let other = match other {
+        Some(other) => other,
+        None => return header.push(NONE_TAG),
+    };
+    header.push(match provenance {
+        Some(_) => PROVENANCE_TAG,
+        None => JSON_TAG,
+    });
+    header.extend(version.to_le_bytes());
+    push_json(header, other);
+    if let Some(provenance) = provenance {
+        push_json(header, provenance);
+    }
+}
+
+fn push_json<J: Serialize>(header: &mut Vec<u8>, value: &J) {
+    let json = serde_json::to_vec(value).expect("BField params can't be encoded as JSON");
+    header.extend((json.len() as u64).to_le_bytes());
+    header.extend(json);
+}
+
+/// Reads JSON written by `push_json`.
+fn read_json(cursor: &mut io::Cursor<&[u8]>) -> Result<Value, io::Error> {
+    let len: u64 = header_options()
+        .deserialize_from(&mut *cursor)
+        .map_err(invalid)?;
+    let start = cursor.position() as usize;
+    let json = usize::try_from(len)
+        .ok()
+        .and_then(|len| cursor.get_ref().get(start..start.checked_add(len)?))
+        .ok_or_else(|| invalid("BField params are truncated"))?;
+    cursor.set_position((start + json.len()) as u64);
+    serde_json::from_slice(json).map_err(invalid)
+}
+
+/// The params read from a header, see `decode_other`.
+pub(crate) struct DecodedParams<T> {
+    pub other: Option<T>,
+    /// 0 for the bincode-encoded params
+    pub version: u32,
+    pub provenance: Option<Provenance>,
+}
+
+/// Reads the params (with their version and the provenance of the
+/// `BField`) at the cursor, converting them with `upgrade` if they're older.
+pub(crate) fn decode_other<T: DeserializeOwned>(
+    cursor: &mut io::Cursor<&[u8]>,
+    upgrade: Option<&ParamsUpgrade>,
+) -> Result<DecodedParams<T>, io::Error> {
+    let tag: u8 = header_options()
+        .deserialize_from(&mut *cursor)
+        .map_err(invalid)?;
+    let other = match tag {
+        NONE_TAG => None,
+        BINCODE_TAG => Some(
+            header_options()
+                .deserialize_from(&mut *cursor)
+                .map_err(invalid)?,
+        ),
+        JSON_TAG | PROVENANCE_TAG => return decode_json(cursor, tag, upgrade),
+        tag => return Err(invalid(format!("Unknown BField params tag {}", tag))),
+    };
+    Ok(DecodedParams {
+        other,
+        version: 0,
+        provenance: None,
+    })
+}
+
+/// Reads the JSON params after `tag` (and the provenance, with
+/// `PROVENANCE_TAG`).
+fn decode_json<T: DeserializeOwned>(
+    cursor: &mut io::Cursor<&[u8]>,
+    tag: u8,
+    upgrade: Option<&ParamsUpgrade>,
+) -> Result<DecodedParams<T>, io::Error> {
+    let mut version: u32 = header_options()
+        .deserialize_from(&mut *cursor)
+        .map_err(invalid)?;
+    let mut value = read_json(cursor)?;
+    let provenance = match tag {
+        PROVENANCE_TAG => Some(serde_json::from_value(read_json(cursor)?).map_err(invalid)?),
+        _ => None,
+    };
+    if let Some(upgrade) = upgrade.filter(|u| version < u.version) {
+        value = (upgrade.upgrade)(version, value)?;
+        version = upgrade.version;
+    }
+    let other = serde_json::from_value(value).map_err(|e| {
+        invalid(format!(
+            "Can't decode the BField params (version {}): {}",
+            version, e
+        ))
+    })?;
+    Ok(DecodedParams {
+        other: Some(other),
+        version,
+        provenance,
+    })
+}
+
+/// The params of a `BField` opened by the C and Python bindings, whatever
+/// their type: their JSON, or the raw bytes of those written with bincode
+/// before v0.4 (e.g. strings). They're written back as they were read.
+#[cfg(any(feature = "ffi", feature = "python"))]
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum RawParams {
+    Json(Value),
+    Bytes(Vec<u8>),
+}
+
+#[cfg(any(feature = "ffi", feature = "python"))]
+impl RawParams {
+    /// The params as bytes: strings as their UTF-8 bytes, arrays of bytes
+    /// (e.g. a `Vec<u8>`) as they are and anything else as its JSON.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            RawParams::Json(Value::String(string)) => string.clone().into_bytes(),
+            RawParams::Json(Value::Array(items))
+                if items
+                    .iter()
+                    .all(|i| matches!(i.as_u64(), Some(byte) if byte < 256)) =>
+            {
+                items
+                    .iter()
+                    .filter_map(|i| i.as_u64())
+                    .map(|i| i as u8)
+                    .collect()
+            }
+            RawParams::Json(value) => value.to_string().into_bytes(),
+            RawParams::Bytes(bytes) => bytes.clone(),
+        }
+    }
+}
+
+#[cfg(any(feature = "ffi", feature = "python"))]
+impl Serialize for RawParams {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            RawParams::Json(value) => value.serialize(serializer),
+            RawParams::Bytes(bytes) => bytes.serialize(serializer),
+        }
+    }
+}
+
+#[cfg(any(feature = "ffi", feature = "python"))]
+impl<'de> serde::Deserialize<'de> for RawParams {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // only the JSON encoding is self-describing
+        if deserializer.is_human_readable() {
+            Value::deserialize(deserializer).map(RawParams::Json)
+        } else {
+            Vec::deserialize(deserializer).map(RawParams::Bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct ParamsV1 {
+        name: String,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct ParamsV2 {
+        name: String,
+        #[serde(default)]
+        k: u8,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct ParamsV3 {
+        names: Vec<String>,
+    }
+
+    fn decode<T: DeserializeOwned>(
+        header: &[u8],
+        upgrade: Option<&ParamsUpgrade>,
+    ) -> Result<(Option<T>, u32), io::Error> {
+        let mut cursor = io::Cursor::new(header);
+        let decoded = decode_other(&mut cursor, upgrade)?;
+        assert_eq!(cursor.position() as usize, header.len());
+        Ok((decoded.other, decoded.version))
+    }
+
+    #[test]
+    fn can_evolve_params() {
+        let mut header = Vec::new();
+        let v1 = ParamsV1 {
+            name: "db".to_string(),
+        };
+        encode_other(&mut header, Some(&v1), 1, None);
+        assert_eq!(decode(&header, None).unwrap(), (Some(v1), 1));
+        // new fields get their defaults...
+        let (v2, _) = decode::<ParamsV2>(&header, None).unwrap();
+        assert_eq!(v2.unwrap().k, 0);
+        // ...and others need an upgrade
+        assert!(decode::<ParamsV3>(&header, None).is_err());
+        let upgrade = ParamsUpgrade {
+            version: 3,
+            upgrade: Arc::new(|version, mut value| {
+                assert_eq!(version, 1);
+                let name = value["name"].take();
+                Ok(serde_json::json!({ "names": [name] }))
+            }),
+        };
+        let (v3, version) = decode::<ParamsV3>(&header, Some(&upgrade)).unwrap();
+        assert_eq!(v3.unwrap().names, ["db"]);
+        assert_eq!(version, 3);
+
+        // headers written before v0.4
+        let legacy = bincode::serialize(&Some(12usize)).unwrap();
+        assert_eq!(decode(&legacy, Some(&upgrade)).unwrap(), (Some(12usize), 0));
+        let mut none = Vec::new();
+        encode_other::<usize>(&mut none, None, 1, Some(&Provenance::default()));
+        assert_eq!(decode::<usize>(&none, None).unwrap(), (None, 0));
+
+        header.truncate(header.len() - 1);
+        assert!(decode::<ParamsV1>(&header, None).is_err());
+
+        // the provenance follows the params
+        let provenance = Provenance::new(0.1, 0.025);
+        let mut header = Vec::new();
+        encode_other(&mut header, Some(&12usize), 1, Some(&provenance));
+        let decoded = decode_other::<usize>(&mut io::Cursor::new(&header), None).unwrap();
+        assert_eq!(decoded.other, Some(12));
+        assert_eq!(decoded.provenance, Some(provenance));
+    }
+}
@@ -0,0 +1,146 @@
+//! Exporting the [`BField::diagnostics`] to Parquet, to analyze saturation
+//! across many databases with the usual dataframe and SQL tools.
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, UInt32Array, UInt64Array};
+use parquet::arrow::ArrowWriter;
+use parquet::errors::ParquetError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bfield::BField;
+use crate::bfield_member::BFieldValue;
+use crate::diagnostics::{Diagnostics, RegionDensity};
+use crate::stats::MemberStats;
+
+/// The `io::Error` behind `error` if there is one (e.g. from the writer).
+fn parquet_error(error: ParquetError) -> io::Error {
+    match error {
+        ParquetError::External(e) => match e.downcast::<io::Error>() {
+            Ok(e) => *e,
+            Err(e) => io::Error::other(e),
+        },
+        e => io::Error::other(e),
+    }
+}
+
+/// A member, its statistics, and one of its regions.
+type Row<'a> = (usize, &'a MemberStats, usize, &'a RegionDensity);
+
+/// One row per region of each array, with the same columns as the CSV
+/// export.
+fn diagnostics_batch(diagnostics: &Diagnostics) -> RecordBatch {
+    let rows: Vec<Row> = diagnostics
+        .members
+        .iter()
+        .enumerate()
+        .flat_map(|(ix, member)| {
+            member
+                .regions
+                .iter()
+                .enumerate()
+                .map(move |(region, r)| (ix, &member.stats, region, r))
+        })
+        .collect();
+    let u32s = |f: fn(&Row) -> usize| -> ArrayRef {
+        Arc::new(UInt32Array::from_iter_values(
+            rows.iter().map(|row| f(row) as u32),
+        ))
+    };
+    let u64s = |f: fn(&Row) -> u64| -> ArrayRef {
+        Arc::new(UInt64Array::from_iter_values(rows.iter().map(f)))
+    };
+    let f64s = |f: fn(&Row) -> f64| -> ArrayRef {
+        Arc::new(Float64Array::from_iter_values(rows.iter().map(f)))
+    };
+    RecordBatch::try_from_iter(vec![
+        ("member", u32s(|row| row.0)),
+        ("size", u64s(|row| row.1.size as u64)),
+        ("n_keys", u64s(|row| row.1.n_keys)),
+        ("n_set", u64s(|row| row.1.n_set as u64)),
+        ("density", f64s(|row| row.1.density)),
+        ("false_positive_rate", f64s(|row| row.1.false_positive_rate)),
+        ("indeterminacy_rate", f64s(|row| row.1.indeterminacy_rate)),
+        ("region", u32s(|row| row.2)),
+        ("region_start", u64s(|row| row.3.start as u64)),
+        ("region_end", u64s(|row| row.3.end as u64)),
+        ("region_n_set", u64s(|row| row.3.n_set as u64)),
+        ("region_density", f64s(|row| row.3.density)),
+    ])
+    .expect("the columns have the same length")
+}
+
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> BField<T, V> {
+    /// Writes the [`BField::diagnostics`] to `writer` as a Parquet file,
+    /// with one row per region of each array like the CSV export of
+    /// [`BField::export_diagnostics`].
+    pub fn export_diagnostics_parquet<W: Write + Send>(&self, writer: W) -> Result<(), io::Error> {
+        let batch = diagnostics_batch(&self.diagnostics());
+        let mut writer =
+            ArrowWriter::try_new(writer, batch.schema(), None).map_err(parquet_error)?;
+        writer.write(&batch).map_err(parquet_error)?;
+        writer.close().map_err(parquet_error)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Float64Type, UInt64Type};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use crate::diagnostics::DIAGNOSTICS_REGIONS;
+
+    #[test]
+    fn can_export_diagnostics_to_parquet() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            true,
+            String::new(),
+        )
+        .unwrap();
+        for i in 0..1_000u32 {
+            bfield.insert(&i.to_be_bytes(), i, 0);
+        }
+        let path = tmp_dir.path().join("diagnostics.parquet");
+        bfield
+            .export_diagnostics_parquet(File::create(&path).unwrap())
+            .unwrap();
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let batches: Vec<RecordBatch> = reader.map(Result::unwrap).collect();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.num_rows(), 2 * DIAGNOSTICS_REGIONS);
+        assert_eq!(batch.num_columns(), 12);
+        let n_keys = batch.column_by_name("n_keys").unwrap();
+        assert_eq!(n_keys.as_primitive::<UInt64Type>().value(0), 1_000);
+        let n_set: u64 = batch
+            .column_by_name("region_n_set")
+            .unwrap()
+            .as_primitive::<UInt64Type>()
+            .values()[..DIAGNOSTICS_REGIONS]
+            .iter()
+            .sum();
+        assert_eq!(n_set as usize, bfield.stats().members[0].n_set);
+        let density = batch.column_by_name("region_density").unwrap();
+        assert!(density.as_primitive::<Float64Type>().value(0) > 0.0);
+    }
+}
@@ -0,0 +1,261 @@
+//! Building a `BField` one pass at a time, feeding each pass only the keys
+//! the previous one left indeterminate, for builds whose keys are too
+//! expensive to produce again for every pass (as `build_from_iter` does).
+use std::io;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bfield::BField;
+use crate::bfield_member::{BFieldLookup, BFieldValue};
+
+/// A set of keys with their values, stored in a single buffer.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeySet<V> {
+    bytes: Vec<u8>,
+    /// Where each key ends in `bytes`
+    ends: Vec<usize>,
+    values: Vec<V>,
+}
+
+impl<V: Copy> KeySet<V> {
+    /// An empty set.
+    pub fn new() -> Self {
+        KeySet {
+            bytes: Vec::new(),
+            ends: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    /// Number of keys.
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    /// Whether there are no keys.
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+
+    /// Adds `key` with `value`.
+    pub fn push(&mut self, key: &[u8], value: V) {
+        self.bytes.extend_from_slice(key);
+        self.ends.push(self.bytes.len());
+        self.values.push(value);
+    }
+
+    /// The keys with their values, in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], V)> + Clone + '_ {
+        let starts = std::iter::once(0).chain(self.ends.iter().copied());
+        starts
+            .zip(&self.ends)
+            .zip(&self.values)
+            .map(move |((start, &end), &value)| (&self.bytes[start..end], value))
+    }
+
+    /// Keeps only the keys for which `keep` returns `true`.
+    fn retain<F: FnMut(&[u8]) -> bool>(&mut self, mut keep: F) {
+        let mut kept = KeySet::new();
+        for (key, value) in self.iter() {
+            if keep(key) {
+                kept.push(key, value);
+            }
+        }
+        *self = kept;
+    }
+}
+
+/// What a pass of [`BField::run_pass`] did.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PassReport<V> {
+    /// The member the keys were inserted into
+    pub pass: usize,
+    /// Number of keys inserted
+    pub n_inserted: u64,
+    /// Number of keys that weren't inserted as an earlier member already
+    /// determines them
+    pub n_skipped: u64,
+    /// The keys inserted that are indeterminate once the pass is done, to
+    /// feed the next one
+    pub indeterminate: KeySet<V>,
+}
+
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> BField<T, V> {
+    /// Inserts the `(key, value)` pairs of `pairs` into member `pass` (the
+    /// ones an earlier member determines are skipped), and returns the keys
+    /// that are left indeterminate in it once they're all inserted: these
+    /// are the only ones the next pass needs. Running every pass this way,
+    /// starting with all the keys at pass 0, builds the same `BField` as
+    /// `build_from_iter`, but only goes over all the keys once.
+    ///
+    /// Since any key can be made indeterminate by the ones inserted after
+    /// it, the keys inserted are kept in memory (in one buffer) until the
+    /// end of the pass, and all looked up again then.
+    pub fn run_pass<I, K>(&self, pairs: I, pass: usize) -> Result<PassReport<V>, io::Error>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+    {
+        if self.is_read_only() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Can't insert into read_only bfields",
+            ));
+        }
+        if pass >= self.members().len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Can't run pass {} of a BField with {} members",
+                    pass,
+                    self.members().len()
+                ),
+            ));
+        }
+        let mut report = PassReport {
+            pass,
+            n_inserted: 0,
+            n_skipped: 0,
+            indeterminate: KeySet::new(),
+        };
+        let batch_size = self.batch_size();
+        let mut batch = Vec::with_capacity(batch_size);
+        let mut pairs = pairs.into_iter();
+        loop {
+            batch.clear();
+            batch.extend(pairs.by_ref().take(batch_size));
+            if batch.is_empty() {
+                break;
+            }
+            let inserted: Vec<(&[u8], V)> = batch
+                .iter()
+                .map(|(key, value)| (key.as_ref(), *value))
+                .filter(|(key, _)| self.reaches(key, pass))
+                .collect();
+            let keys: Vec<&[u8]> = inserted.iter().map(|&(key, _)| key).collect();
+            let values: Vec<u64> = inserted.iter().map(|(_, v)| v.into_u64()).collect();
+            self.insert_keys(&keys, &values, pass)?;
+            for (key, value) in inserted {
+                report.indeterminate.push(key, value);
+            }
+            report.n_skipped += (batch.len() - keys.len()) as u64;
+            report.n_inserted += keys.len() as u64;
+        }
+
+        let member = &self.members()[pass];
+        report
+            .indeterminate
+            .retain(|key| member.get(key) == BFieldLookup::Indeterminate);
+        if pass + 1 == self.members().len() {
+            self.finish_build(report.indeterminate.iter())?;
+        }
+        Ok(report)
+    }
+
+    /// The keys of `keys` that are indeterminate in each of the first
+    /// `n_members` members, i.e. that a lookup would go on to look up in
+    /// the next one: the keys an incremental pipeline still has to insert
+    /// into the deeper members.
+    ///
+    /// Panics if there are fewer than `n_members` members.
+    pub fn screen_indeterminate<'a, 'k, I>(
+        &'a self,
+        keys: I,
+        n_members: usize,
+    ) -> impl Iterator<Item = &'k [u8]> + 'a
+    where
+        I: IntoIterator<Item = &'k [u8]>,
+        I::IntoIter: 'a,
+        'k: 'a,
+    {
+        assert!(
+            n_members <= self.members().len(),
+            "Can't screen keys through {} members of a BField with {}",
+            n_members,
+            self.members().len()
+        );
+        keys.into_iter()
+            .filter(move |key| self.reaches(key, n_members))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_set() {
+        let mut keys = KeySet::new();
+        assert!(keys.is_empty());
+        keys.push(b"first", 1u32);
+        keys.push(b"", 2);
+        keys.push(b"third", 3);
+        let all: Vec<(&[u8], u32)> = keys.iter().collect();
+        assert_eq!(all, [(&b"first"[..], 1), (&b""[..], 2), (&b"third"[..], 3)]);
+        keys.retain(|key| key.len() > 1);
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys.iter().nth(1), Some((&b"third"[..], 3)));
+    }
+
+    fn create(dir: &std::path::Path, name: &str) -> BField<String> {
+        // small enough for plenty of keys to need the secondary arrays
+        let mut bfield = BField::create(
+            dir,
+            name,
+            60_000,
+            10,
+            39,
+            4,
+            0.5,
+            0.1,
+            3,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        // so the digests don't depend on the creation time
+        bfield.set_deterministic(true).unwrap();
+        bfield
+    }
+
+    #[test]
+    fn can_run_passes() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let pairs = (0..3_000u32).map(|i| (i.to_be_bytes(), i));
+        let expected = create(tmp_dir.path(), "expected");
+        expected.build_from_iter(pairs.clone(), |_| {}).unwrap();
+
+        let bfield = create(tmp_dir.path(), "bfield");
+        let first = bfield.run_pass(pairs.clone(), 0).unwrap();
+        assert_eq!((first.n_inserted, first.n_skipped), (3_000, 0));
+        assert!(!first.indeterminate.is_empty());
+        let n_indeterminate = pairs
+            .clone()
+            .filter(|(key, _)| bfield.members()[0].get(key) == BFieldLookup::Indeterminate)
+            .count();
+        assert_eq!(first.indeterminate.len(), n_indeterminate);
+
+        // feeding all the keys again skips the determinate ones
+        let second = bfield.run_pass(pairs, 1).unwrap();
+        assert_eq!(second.n_inserted as usize, n_indeterminate);
+        assert_eq!(second.n_skipped as usize, 3_000 - n_indeterminate);
+        let third = bfield.run_pass(second.indeterminate.iter(), 2).unwrap();
+        assert_eq!(third.n_skipped, 0);
+        assert_eq!(bfield.digest(), expected.digest());
+
+        let screened: Vec<&[u8]> = bfield
+            .screen_indeterminate(first.indeterminate.iter().map(|(key, _)| key), 2)
+            .collect();
+        let expected: Vec<&[u8]> = second.indeterminate.iter().map(|(key, _)| key).collect();
+        assert_eq!(screened, expected);
+        // nothing to screen out before the primary array
+        assert_eq!(bfield.screen_indeterminate(Vec::new(), 0).count(), 0);
+        let keys: Vec<[u8; 4]> = (0..10u32).map(u32::to_be_bytes).collect();
+        let all = bfield.screen_indeterminate(keys.iter().map(|key| &key[..]), 0);
+        assert_eq!(all.count(), 10);
+
+        let err = bfield.run_pass(third.indeterminate.iter(), 3).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,245 @@
+//! Building a `BField` from keys produced on their own thread (e.g. parsed
+//! from FASTQ files) while a pool of workers hashes and inserts them, so
+//! producing keys overlaps with inserting them. See
+//! [`BField::build_from_channel`].
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crossbeam_channel::{bounded, Sender};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bfield::{BField, Progress};
+use crate::bfield_member::BFieldValue;
+
+impl<T, V> BField<T, V>
+where
+    T: Clone + DeserializeOwned + Serialize + Sync,
+    V: BFieldValue + Send + Sync,
+{
+    /// Inserts the `(key, value)` pairs that `produce` sends, going through
+    /// them once per member like [`BField::build_from_iter`] (which
+    /// `progress` is called like, from any thread). For each pass (and once
+    /// more at the end if the sidecar is enabled), `produce` runs on its own
+    /// thread and should send all the pairs over the channel it's given,
+    /// which holds up to `capacity` of them, while `n_workers` threads take
+    /// them off it and insert them.
+    ///
+    /// If a worker fails (e.g. on a value that can't be stored), the others
+    /// stop, sending fails and the build returns the worker's error, so
+    /// `produce` should stop at the first failed send. An error returned by
+    /// `produce` ends the build once the pairs already sent are inserted.
+    pub fn build_from_channel<K, P, F>(
+        &self,
+        n_workers: usize,
+        capacity: usize,
+        produce: P,
+        progress: F,
+    ) -> Result<(), io::Error>
+    where
+        K: AsRef<[u8]> + Send,
+        P: Fn(&Sender<(K, V)>) -> Result<(), io::Error> + Sync,
+        F: Fn(Progress) + Sync,
+    {
+        if self.is_read_only() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Can't insert into read_only bfields",
+            ));
+        }
+        if n_workers == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can't build with 0 workers",
+            ));
+        }
+        let n_members = self.members().len();
+        let keys_inserted = AtomicU64::new(0);
+        for pass in 0..n_members {
+            self.run_workers(n_workers, capacity, &produce, |batch| {
+                self.insert_batch(batch, pass)?;
+                let n = batch.len() as u64;
+                let before = keys_inserted.fetch_add(n, Ordering::Relaxed);
+                if (before + n) / 100_000 > before / 100_000 {
+                    progress(Progress {
+                        members_completed: pass,
+                        total_members: n_members,
+                        keys_inserted: before + n,
+                        ..Progress::default()
+                    });
+                }
+                Ok(())
+            })?;
+            progress(Progress {
+                members_completed: pass + 1,
+                total_members: n_members,
+                keys_inserted: keys_inserted.load(Ordering::Relaxed),
+                ..Progress::default()
+            });
+        }
+        if self.n_sidecar_keys().is_some() {
+            self.run_workers(n_workers, capacity, &produce, |batch| {
+                self.fill_sidecar(batch.iter().map(|(key, value)| (key, *value)));
+                Ok(())
+            })?;
+        }
+        self.write_build_files()
+    }
+
+    /// Runs `produce` on its own thread, with `n_workers` threads calling
+    /// `work` on batches of the pairs it sends until it's done or one of
+    /// them fails.
+    fn run_workers<K, P, W>(
+        &self,
+        n_workers: usize,
+        capacity: usize,
+        produce: &P,
+        work: W,
+    ) -> Result<(), io::Error>
+    where
+        K: AsRef<[u8]> + Send,
+        P: Fn(&Sender<(K, V)>) -> Result<(), io::Error> + Sync,
+        W: Fn(&[(K, V)]) -> Result<(), io::Error> + Sync,
+    {
+        let (sender, receiver) = bounded(capacity);
+        let batch_size = self.batch_size();
+        let failed = AtomicBool::new(false);
+        let failure = Mutex::new(None);
+        let produced = thread::scope(|scope| {
+            for _ in 0..n_workers {
+                let receiver = receiver.clone();
+                let (work, failed, failure) = (&work, &failed, &failure);
+                scope.spawn(move || {
+                    let mut batch = Vec::with_capacity(batch_size);
+                    // wait for a pair, then take the ones already queued
+                    while let Ok(pair) = receiver.recv() {
+                        if failed.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        batch.clear();
+                        batch.push(pair);
+                        batch.extend(receiver.try_iter().take(batch_size - 1));
+                        if let Err(e) = work(&batch) {
+                            failed.store(true, Ordering::Relaxed);
+                            failure.lock().unwrap().get_or_insert(e);
+                            break;
+                        }
+                    }
+                });
+            }
+            // only the workers hold receivers, so sends fail once they stop
+            drop(receiver);
+            let producer = scope.spawn(move || produce(&sender));
+            producer.join().expect("the producer panicked")
+        });
+        match failure.into_inner().unwrap() {
+            Some(e) => Err(e),
+            None => produced,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create(dir: &std::path::Path, name: &str) -> BField<String> {
+        let mut bfield = BField::create(
+            dir,
+            name,
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            3,
+            true,
+            String::new(),
+        )
+        .unwrap();
+        // so the digests don't depend on the creation time
+        bfield.set_deterministic(true).unwrap();
+        bfield
+    }
+
+    #[test]
+    fn can_build_from_channel() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let pairs = (0..5_000u32).map(|i| (i.to_be_bytes(), i));
+        let expected = create(tmp_dir.path(), "expected");
+        expected.build_from_iter(pairs.clone(), |_| {}).unwrap();
+
+        let mut bfield = create(tmp_dir.path(), "bfield");
+        bfield.enable_sidecar();
+        let passes = Mutex::new(Vec::new());
+        bfield
+            .build_from_channel(
+                4,
+                64,
+                |sender| {
+                    for pair in pairs.clone() {
+                        if sender.send(pair).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(())
+                },
+                |progress| passes.lock().unwrap().push(progress.members_completed),
+            )
+            .unwrap();
+        assert_eq!(bfield.digest(), expected.digest());
+        assert_eq!(passes.into_inner().unwrap(), [1, 2, 3]);
+        assert!(pairs
+            .clone()
+            .all(|(key, value)| bfield.get(&key) == Some(value)));
+
+        let err = bfield
+            .build_from_channel(0, 64, |_: &Sender<([u8; 4], u32)>| Ok(()), |_| {})
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn can_stop_channel_builds() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield = create(tmp_dir.path(), "bfield");
+        // the producer's error is returned
+        let err = bfield
+            .build_from_channel(
+                2,
+                8,
+                |sender| {
+                    sender.send((b"key".to_vec(), 1)).unwrap();
+                    Err(io::Error::other("parse error"))
+                },
+                |_| {},
+            )
+            .unwrap_err();
+        assert_eq!(err.to_string(), "parse error");
+
+        // and so are the workers', which stop the producer
+        let sent = AtomicU64::new(0);
+        let err = bfield
+            .build_from_channel(
+                2,
+                8,
+                |sender| {
+                    for i in 0u32.. {
+                        // too large for the markers
+                        if sender.send((i.to_be_bytes(), u32::MAX)).is_err() {
+                            break;
+                        }
+                        sent.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Ok(())
+                },
+                |_| {},
+            )
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(sent.into_inner() < 1_000);
+    }
+}
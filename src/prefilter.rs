@@ -0,0 +1,175 @@
+//! A Bloom filter over the keys of a `BField`, checked before its members so
+//! most absent keys are rejected with a couple of memory reads.
+use std::convert::TryInto;
+use std::f64::consts::LN_2;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::keyed::{hash_with, HashSecret};
+
+const PREFILTER_MAGIC: [u8; 4] = *b"BFBL";
+/// Seed for the filter's hash, so its positions aren't correlated with the
+/// members' (which hash with seed 0)
+const PREFILTER_SEED: u64 = 0x0042_4c4f_4f4d;
+
+/// A Bloom filter with atomic bit updates, so keys can be added from several
+/// threads.
+///
+/// It's stored as `{filename}.prefilter.bin` next to the `.bfd` files: the
+/// magic bytes, the number of hashes as a `u8`, the number of bits as a
+/// big-endian `u64` and the bits as big-endian `u64` words.
+#[derive(Debug)]
+pub(crate) struct Prefilter {
+    n_hashes: u8,
+    n_bits: u64,
+    words: Box<[AtomicU64]>,
+}
+
+/// The prefilter path for a `BField` whose primary array is at
+/// `main_db_path` (`{filename}.0.bfd` -> `{filename}.prefilter.bin`).
+pub(crate) fn prefilter_path(main_db_path: &Path) -> Option<PathBuf> {
+    let filename = main_db_path.file_name()?.to_str()?;
+    let base = filename.strip_suffix("0.bfd")?;
+    Some(main_db_path.with_file_name(format!("{base}prefilter.bin")))
+}
+
+impl Prefilter {
+    /// An empty filter sized for `n_keys` keys with a false positive rate
+    /// of `false_positive_rate`.
+    pub fn new(n_keys: usize, false_positive_rate: f64) -> Self {
+        let n_keys = n_keys.max(1) as f64;
+        let n_bits = (-n_keys * false_positive_rate.ln() / (LN_2 * LN_2)).ceil() as u64;
+        let n_hashes = (n_bits as f64 / n_keys * LN_2).round().clamp(1.0, 32.0) as u8;
+        Self::with_shape(n_hashes, n_bits.max(64))
+    }
+
+    fn with_shape(n_hashes: u8, n_bits: u64) -> Self {
+        let n_words = n_bits.div_ceil(64) as usize;
+        Prefilter {
+            n_hashes,
+            n_bits,
+            words: (0..n_words).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// An empty filter of the same size.
+    pub fn empty_copy(&self) -> Self {
+        Self::with_shape(self.n_hashes, self.n_bits)
+    }
+
+    /// A copy of this filter.
+    pub fn duplicate(&self) -> Self {
+        let copy = self.empty_copy();
+        copy.or_with(self);
+        copy
+    }
+
+    /// Whether `other` has the same size and number of hashes.
+    pub fn same_shape(&self, other: &Prefilter) -> bool {
+        self.n_hashes == other.n_hashes && self.n_bits == other.n_bits
+    }
+
+    /// Adds the bits of `other`, which must have the same shape.
+    pub fn or_with(&self, other: &Prefilter) {
+        debug_assert!(self.same_shape(other));
+        for (word, other) in self.words.iter().zip(other.words.iter()) {
+            word.fetch_or(other.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+
+    /// The bits of `key`, hashed with the members' `secret` if they have one.
+    fn positions(&self, key: &[u8], secret: Option<&HashSecret>) -> impl Iterator<Item = u64> {
+        let (h1, h2) = hash_with(secret, key, PREFILTER_SEED);
+        let n_bits = self.n_bits;
+        (0..u64::from(self.n_hashes)).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % n_bits)
+    }
+
+    pub fn insert(&self, key: &[u8], secret: Option<&HashSecret>) {
+        for pos in self.positions(key, secret) {
+            self.words[(pos / 64) as usize].fetch_or(1 << (pos % 64), Ordering::Relaxed);
+        }
+    }
+
+    /// Whether `key` may have been inserted; `false` means it definitely
+    /// wasn't.
+    pub fn contains(&self, key: &[u8], secret: Option<&HashSecret>) -> bool {
+        self.positions(key, secret).all(|pos| {
+            self.words[(pos / 64) as usize].load(Ordering::Relaxed) & (1 << (pos % 64)) != 0
+        })
+    }
+
+    /// Reads the filter at `path`, returning `None` if there isn't one.
+    pub fn read(path: &Path) -> Result<Option<Self>, io::Error> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} isn't a valid prefilter file", path),
+            )
+        };
+        if data.len() < 13 || data[..4] != PREFILTER_MAGIC {
+            return Err(invalid());
+        }
+        let n_hashes = data[4];
+        let n_bits = u64::from_be_bytes(data[5..13].try_into().unwrap());
+        let words = &data[13..];
+        if n_hashes == 0 || n_bits == 0 || words.len() as u64 != 8 * n_bits.div_ceil(64) {
+            return Err(invalid());
+        }
+        let words = words
+            .chunks_exact(8)
+            .map(|w| AtomicU64::new(u64::from_be_bytes(w.try_into().unwrap())))
+            .collect();
+        Ok(Some(Prefilter {
+            n_hashes,
+            n_bits,
+            words,
+        }))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), io::Error> {
+        let mut data = Vec::with_capacity(13 + 8 * self.words.len());
+        data.extend_from_slice(&PREFILTER_MAGIC);
+        data.push(self.n_hashes);
+        data.extend_from_slice(&self.n_bits.to_be_bytes());
+        for word in self.words.iter() {
+            data.extend_from_slice(&word.load(Ordering::Relaxed).to_be_bytes());
+        }
+        fs::write(path, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_filter_absent_keys() {
+        let prefilter = Prefilter::new(10_000, 0.01);
+        assert_eq!(prefilter.n_hashes, 7);
+        for i in 0..10_000u32 {
+            prefilter.insert(&i.to_be_bytes(), None);
+        }
+        assert!((0..10_000u32).all(|i| prefilter.contains(&i.to_be_bytes(), None)));
+        let n_false_positives = (10_000..110_000u32)
+            .filter(|i| prefilter.contains(&i.to_be_bytes(), None))
+            .count();
+        assert!(n_false_positives < 1_500, "{}", n_false_positives);
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("bfield.prefilter.bin");
+        assert!(Prefilter::read(&path).unwrap().is_none());
+        prefilter.write(&path).unwrap();
+        let read = Prefilter::read(&path).unwrap().unwrap();
+        assert!(read.same_shape(&prefilter));
+        assert!((0..10_000u32).all(|i| read.contains(&i.to_be_bytes(), None)));
+        fs::write(&path, b"BFBL").unwrap();
+        assert!(Prefilter::read(&path).is_err());
+    }
+}
@@ -0,0 +1,47 @@
+//! Where a `BField` comes from, recorded in the header of its primary array
+//! so databases can be told apart once they're copied around.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// How and when a `BField` was built, see [`BField::provenance`](crate::BField::provenance).
+///
+/// Stored as JSON next to the params given to `create` (see
+/// `BField::set_params_version`), so fields can be added later.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Provenance {
+    /// When the `BField` was created, in seconds since the Unix epoch
+    /// (`None` in deterministic builds, see
+    /// [`BField::set_deterministic`](crate::BField::set_deterministic))
+    pub created_at: Option<u64>,
+    /// The version of this crate it was created with
+    pub crate_version: String,
+    /// β, the ratio of the sizes of consecutive secondary arrays
+    pub secondary_scaledown: f64,
+    /// The smallest ratio of the size of a secondary array to the primary one
+    pub max_scaledown: f64,
+    /// The number of elements it was built for, see
+    /// [`BField::declare_n_elements`](crate::BField::declare_n_elements)
+    pub n_elements: Option<u64>,
+    /// A free-form label, e.g. the name and release of the database, see
+    /// [`BField::set_build_label`](crate::BField::set_build_label)
+    pub label: Option<String>,
+}
+
+impl Provenance {
+    /// The provenance of a `BField` created now with the given scaling.
+    pub(crate) fn new(secondary_scaledown: f64, max_scaledown: f64) -> Self {
+        Provenance {
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|elapsed| elapsed.as_secs()),
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            secondary_scaledown,
+            max_scaledown,
+            n_elements: None,
+            label: None,
+        }
+    }
+}
@@ -0,0 +1,272 @@
+//! Python bindings, built as the `bfield` extension module with
+//! `maturin build --features python` (see `pyproject.toml`).
+// the `PyResult` conversions generated by pyo3 0.22 trip this lint
+#![allow(clippy::useless_conversion)]
+use std::io;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::bfield::BField;
+use crate::params::RawParams;
+
+/// Values are exposed to Python as ints of up to 64 bits, and the `other`
+/// parameters as bytes (which read the `String` parameters of `BField`s
+/// built from Rust as their UTF-8 bytes, see `RawParams::to_bytes`).
+type Inner = BField<RawParams, u64>;
+
+/// A `BField` with `bytes` keys and `int` values.
+#[pyclass(name = "BField", module = "bfield")]
+struct PyBField {
+    // `None` only while `persist_to_disk` takes it by value
+    inner: Option<Inner>,
+}
+
+impl PyBField {
+    fn inner(&self) -> PyResult<&Inner> {
+        self.inner
+            .as_ref()
+            .ok_or_else(|| PyValueError::new_err("BField failed to persist"))
+    }
+}
+
+#[pymethods]
+impl PyBField {
+    /// Creates a `BField`, see `BField::create` in the Rust docs.
+    #[staticmethod]
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (
+        directory,
+        filename,
+        size,
+        n_hashes,
+        marker_width,
+        n_marker_bits,
+        secondary_scaledown = 0.1,
+        max_scaledown = 0.025,
+        n_secondaries = 4,
+        in_memory = false,
+        params = Vec::new(),
+    ))]
+    fn create(
+        directory: &str,
+        filename: &str,
+        size: usize,
+        n_hashes: u8,
+        marker_width: u8,
+        n_marker_bits: u8,
+        secondary_scaledown: f64,
+        max_scaledown: f64,
+        n_secondaries: u8,
+        in_memory: bool,
+        params: Vec<u8>,
+    ) -> PyResult<Self> {
+        let inner = BField::create(
+            directory,
+            filename,
+            size,
+            n_hashes,
+            marker_width,
+            n_marker_bits,
+            secondary_scaledown,
+            max_scaledown,
+            n_secondaries,
+            in_memory,
+            RawParams::Bytes(params),
+        )?;
+        Ok(PyBField { inner: Some(inner) })
+    }
+
+    /// Loads the `BField` whose primary array is at `path`.
+    #[staticmethod]
+    #[pyo3(signature = (path, read_only = true))]
+    fn load(py: Python<'_>, path: &str, read_only: bool) -> PyResult<Self> {
+        let inner = py.allow_threads(|| BField::load(path, read_only))?;
+        Ok(PyBField { inner: Some(inner) })
+    }
+
+    /// Inserts `key` with `value` for pass `pass_`, returning whether it was
+    /// inserted.
+    #[pyo3(signature = (key, value, pass_ = 0))]
+    fn insert(&self, key: &[u8], value: u64, pass_: usize) -> PyResult<bool> {
+        let inner = self.inner()?;
+        check_insert(inner, value, pass_)?;
+        Ok(inner.insert(key, value, pass_))
+    }
+
+    /// Inserts all the `(key, value)` pairs over every pass, without
+    /// holding the GIL.
+    fn build(&self, py: Python<'_>, pairs: Vec<(Vec<u8>, u64)>) -> PyResult<()> {
+        let inner = self.inner()?;
+        if let Some(&(_, value)) = pairs.iter().max_by_key(|(_, value)| *value) {
+            check_insert(inner, value, 0)?;
+        }
+        py.allow_threads(|| inner.build_from_iter(pairs.iter().map(|(k, v)| (k, *v)), |_| {}))?;
+        Ok(())
+    }
+
+    /// The value stored for `key`, or `None`.
+    fn get(&self, key: &[u8]) -> PyResult<Option<u64>> {
+        Ok(self.inner()?.get(key))
+    }
+
+    /// The values stored for each of `keys`, looked up without holding the
+    /// GIL.
+    fn get_many(&self, py: Python<'_>, keys: Vec<Vec<u8>>) -> PyResult<Vec<Option<u64>>> {
+        let inner = self.inner()?;
+        Ok(py.allow_threads(|| keys.iter().map(|key| inner.get(key)).collect()))
+    }
+
+    /// Writes an in-memory `BField` to disk.
+    fn persist_to_disk(&mut self, py: Python<'_>) -> PyResult<()> {
+        let inner = self
+            .inner
+            .take()
+            .ok_or_else(|| PyValueError::new_err("BField failed to persist"))?;
+        self.inner = Some(py.allow_threads(|| inner.persist_to_disk())?);
+        Ok(())
+    }
+
+    /// The largest value that can be stored.
+    fn max_value(&self) -> PyResult<u64> {
+        Ok(self.inner()?.max_value())
+    }
+
+    /// The `other` parameters given at creation.
+    fn params<'py>(&self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyBytes>>> {
+        let params = self.inner()?.params().as_ref();
+        Ok(params.map(|params| PyBytes::new_bound(py, &params.to_bytes())))
+    }
+
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.inner()?.members().len())
+    }
+
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!("{:?}", self.inner()?))
+    }
+}
+
+/// Raises a `ValueError` instead of panicking on inserts `BField::insert`
+/// would reject.
+fn check_insert(inner: &Inner, value: u64, pass: usize) -> PyResult<()> {
+    if value > inner.max_value() {
+        return Err(PyValueError::new_err(format!(
+            "value {} is larger than the max value {}",
+            value,
+            inner.max_value()
+        )));
+    }
+    if pass >= inner.members().len() {
+        return Err(PyValueError::new_err(format!(
+            "pass {} is out of range for {} members",
+            pass,
+            inner.members().len()
+        )));
+    }
+    if inner.is_read_only() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Can't insert into read_only bfields",
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// The `bfield` Python module.
+#[pymodule]
+fn bfield(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyBField>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::types::PyDict;
+
+    #[test]
+    fn can_use_bfield_from_python() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let module = PyModule::new_bound(py, "bfield").unwrap();
+            bfield(&module).unwrap();
+            let locals = PyDict::new_bound(py);
+            locals.set_item("bfield", module).unwrap();
+            locals
+                .set_item("directory", tmp_dir.path().to_str().unwrap())
+                .unwrap();
+            py.run_bound(
+                r#"
+b = bfield.BField.create(directory, "bfield", 1_000_000, 10, 39, 4, n_secondaries=2, in_memory=True, params=b"x")
+b.build([(i.to_bytes(4, "big"), i) for i in range(1000)])
+b.insert(b"extra", 80_000)
+b.persist_to_disk()
+# release the write lock before reopening the files
+del b
+b = bfield.BField.load(directory + "/bfield.0.bfd")
+assert b.get(b"\x00\x00\x00\x05") == 5
+assert b.get_many([b"\x00\x00\x00\x07", b"missing"]) == [7, None]
+assert b.get(b"extra") == 80_000
+assert b.params() == b"x"
+assert len(b) == 2
+try:
+    b.insert(b"more", 1)
+    raise AssertionError("read-only")
+except PermissionError:
+    pass
+"#,
+                None,
+                Some(&locals),
+            )
+            .unwrap();
+        });
+    }
+
+    #[test]
+    fn can_load_rust_bfield_from_python() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let db: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            1,
+            false,
+            "params".to_string(),
+        )
+        .unwrap();
+        db.insert(b"key", 7, 0);
+        drop(db);
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let module = PyModule::new_bound(py, "bfield").unwrap();
+            bfield(&module).unwrap();
+            let locals = PyDict::new_bound(py);
+            locals.set_item("bfield", module).unwrap();
+            locals
+                .set_item(
+                    "path",
+                    tmp_dir.path().join("bfield.0.bfd").to_str().unwrap(),
+                )
+                .unwrap();
+            py.run_bound(
+                r#"
+b = bfield.BField.load(path)
+assert b.get(b"key") == 7
+assert b.params() == b"params"
+"#,
+                None,
+                Some(&locals),
+            )
+            .unwrap();
+        });
+    }
+}
@@ -0,0 +1,153 @@
+//! Watching the fill rate of the members while a `BField` is built.
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bfield::BField;
+use crate::bfield_member::BFieldValue;
+use crate::storage::Storage;
+
+/// How many 64-bit words are read to estimate the density of a member
+const N_SAMPLES: usize = 4096;
+
+/// Reported by a [`SaturationMonitor`] when a member is fuller than its
+/// threshold.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SaturationWarning {
+    /// Index of the member (0 for the primary array)
+    pub member: usize,
+    /// Estimated fraction of the member's bits that are set
+    pub density: f64,
+    /// The threshold it went over
+    pub threshold: f64,
+}
+
+/// Periodically samples the density of the member being inserted into and
+/// calls a callback when it's over a threshold, see
+/// [`BField::monitor_saturation`].
+///
+/// A well-parametrized array ends up about half full (the density at which
+/// each bit carries the most information), so a threshold a bit over 0.5
+/// catches builds with more keys than planned.
+pub struct SaturationMonitor {
+    threshold: f64,
+    sample_every: u64,
+    callback: Box<dyn Fn(&SaturationWarning) + Send + Sync>,
+    n_inserts: AtomicU64,
+}
+
+impl SaturationMonitor {
+    /// Calls `callback` whenever a member's sampled density is over
+    /// `threshold` (a fraction between 0 and 1), checking every 100,000
+    /// inserts by default.
+    pub fn new<F>(threshold: f64, callback: F) -> Self
+    where
+        F: Fn(&SaturationWarning) + Send + Sync + 'static,
+    {
+        SaturationMonitor {
+            threshold,
+            sample_every: 100_000,
+            callback: Box::new(callback),
+            n_inserts: AtomicU64::new(0),
+        }
+    }
+
+    /// How many inserts to wait between samples.
+    pub fn sample_every(mut self, n_inserts: u64) -> Self {
+        self.sample_every = n_inserts.max(1);
+        self
+    }
+
+    /// Counts an insert into `member`, sampling its density if it's time to.
+    pub(crate) fn record_insert(&self, member: usize, storage: &Storage) {
+        let n = self.n_inserts.fetch_add(1, Ordering::Relaxed) + 1;
+        if n % self.sample_every != 0 {
+            return;
+        }
+        let density = storage.sampled_density(N_SAMPLES);
+        if density > self.threshold {
+            (self.callback)(&SaturationWarning {
+                member,
+                density,
+                threshold: self.threshold,
+            });
+        }
+    }
+}
+
+impl fmt::Debug for SaturationMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SaturationMonitor")
+            .field("threshold", &self.threshold)
+            .field("sample_every", &self.sample_every)
+            .finish()
+    }
+}
+
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> BField<T, V> {
+    /// Returns an `InvalidData` error if the sampled density of any member
+    /// is over `threshold`, e.g. to abort a build once it's done more harm
+    /// than good.
+    pub fn check_saturation(&self, threshold: f64) -> Result<(), io::Error> {
+        for (ix, member) in self.members().iter().enumerate() {
+            let density = member.storage().sampled_density(N_SAMPLES);
+            if density > threshold {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Member {} is {:.1}% full, over the {:.1}% threshold",
+                        ix,
+                        100.0 * density,
+                        100.0 * threshold
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[test]
+    fn can_monitor_saturation() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        // far too small for 10,000 keys
+        let mut bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            1,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&warnings);
+        bfield.monitor_saturation(Some(
+            SaturationMonitor::new(0.5, move |w| recorded.lock().unwrap().push(w.clone()))
+                .sample_every(1_000),
+        ));
+        bfield.check_saturation(0.5).unwrap();
+        for i in 0..10_000u32 {
+            bfield.insert(&i.to_be_bytes(), i, 0);
+        }
+        let warnings = warnings.lock().unwrap();
+        assert!(!warnings.is_empty());
+        assert!(warnings.iter().all(|w| w.member == 0 && w.density > 0.5));
+        let err = bfield.check_saturation(0.5).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
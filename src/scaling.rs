@@ -0,0 +1,238 @@
+//! Building a `BField` that grows secondary arrays as they're needed instead
+//! of having their number picked up front.
+use std::io;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bfield::{BField, Progress};
+use crate::bfield_member::{BFieldLookup, BFieldValue};
+
+/// Smallest size in bits of a secondary array sized from the keys left over
+const MIN_MEASURED_SIZE: usize = 4096;
+
+/// When and how [`BField::build_scalable`] adds secondary arrays.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScalingOptions {
+    max_indeterminacy: f64,
+    secondary_scaledown: f64,
+    max_scaledown: f64,
+    max_members: usize,
+    /// Safety factor to size secondaries from the keys left over, if set
+    measured_safety_factor: Option<f64>,
+}
+
+impl ScalingOptions {
+    /// Adds a secondary array whenever more than `max_indeterminacy` (a
+    /// fraction between 0 and 1) of the keys are still indeterminate after
+    /// a pass, scaled down from the previous one by 0.1 (but no smaller than
+    /// 0.025 times the primary array) and up to 16 arrays in total by
+    /// default.
+    pub fn new(max_indeterminacy: f64) -> Self {
+        ScalingOptions {
+            max_indeterminacy,
+            secondary_scaledown: 0.1,
+            max_scaledown: 0.025,
+            max_members: 16,
+            measured_safety_factor: None,
+        }
+    }
+
+    /// The scaling factor (β) from each array to the next one, see
+    /// [`BField::create`].
+    pub fn secondary_scaledown(mut self, secondary_scaledown: f64) -> Self {
+        self.secondary_scaledown = secondary_scaledown;
+        self
+    }
+
+    /// The smallest size of a secondary array, as a fraction of the primary
+    /// one, see [`BField::create`].
+    pub fn max_scaledown(mut self, max_scaledown: f64) -> Self {
+        self.max_scaledown = max_scaledown;
+        self
+    }
+
+    /// The most arrays (including the primary one) the `BField` can end up
+    /// with, after which the remaining indeterminate keys are left as is.
+    pub fn max_members(mut self, max_members: usize) -> Self {
+        self.max_members = max_members;
+        self
+    }
+
+    /// Sizes each new secondary array from the number of keys that were
+    /// still indeterminate after the previous pass, giving them as many
+    /// bits per key as the primary array has times `safety_factor`, instead
+    /// of scaling it down from the previous array. As far fewer keys than a
+    /// fixed β expects usually fall through, this can make the secondary
+    /// arrays much smaller.
+    pub fn size_from_indeterminate_keys(mut self, safety_factor: f64) -> Self {
+        self.measured_safety_factor = Some(safety_factor);
+        self
+    }
+
+    /// The size of the array to add after `sizes`, given that
+    /// `n_indeterminate` of the `n_keys` keys are still indeterminate.
+    fn next_size(&self, sizes: &[usize], n_keys: usize, n_indeterminate: usize) -> usize {
+        match self.measured_safety_factor {
+            Some(safety_factor) => {
+                let bits_per_key = sizes[0] as f64 / n_keys.max(1) as f64;
+                let size = (n_indeterminate as f64 * bits_per_key * safety_factor).ceil();
+                (size as usize).max(MIN_MEASURED_SIZE)
+            }
+            None => f64::max(
+                sizes[sizes.len() - 1] as f64 * self.secondary_scaledown,
+                sizes[0] as f64 * self.max_scaledown,
+            ) as usize,
+        }
+    }
+}
+
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> BField<T, V> {
+    /// Inserts all the `(key, value)` pairs of `pairs` like
+    /// [`BField::build_from_iter`], but once the last array is filled, adds
+    /// secondary arrays (in new `.bfd` files if the `BField` is stored in
+    /// some) for as long as too many keys are still indeterminate, as set
+    /// by `options`. The `BField` can be created with a single array.
+    ///
+    /// Returns the fraction of the keys still indeterminate in every member
+    /// at the end (before they're added to the sidecar, if it's enabled).
+    pub fn build_scalable<I, K, F>(
+        &mut self,
+        pairs: I,
+        options: &ScalingOptions,
+        progress: F,
+    ) -> Result<f64, io::Error>
+    where
+        I: IntoIterator<Item = (K, V)> + Clone,
+        K: AsRef<[u8]>,
+        F: Fn(Progress),
+    {
+        if self.is_read_only() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Can't insert into read_only bfields",
+            ));
+        }
+        let mut report = Progress {
+            total_members: self.members().len(),
+            ..Progress::default()
+        };
+        let mut pass = 0;
+        loop {
+            for (key, value) in pairs.clone() {
+                self.insert(key.as_ref(), value, pass);
+                report.keys_inserted += 1;
+                if report.keys_inserted % 100_000 == 0 {
+                    progress(report);
+                }
+            }
+            report.members_completed += 1;
+            progress(report);
+            pass += 1;
+            if pass < self.members().len() {
+                continue;
+            }
+
+            let (mut n_keys, mut n_indeterminate) = (0usize, 0usize);
+            for (key, _) in pairs.clone() {
+                n_keys += 1;
+                if self.lookup(key.as_ref()) == BFieldLookup::Indeterminate {
+                    n_indeterminate += 1;
+                }
+            }
+            let indeterminacy = n_indeterminate as f64 / n_keys.max(1) as f64;
+            if indeterminacy <= options.max_indeterminacy
+                || self.members().len() >= options.max_members
+            {
+                self.finish_build(pairs)?;
+                return Ok(indeterminacy);
+            }
+            let sizes: Vec<usize> = self.members().iter().map(|m| m.storage().size()).collect();
+            let size = options.next_size(&sizes, n_keys, n_indeterminate);
+            self.add_secondary(size.max(1))?;
+            report.total_members += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_add_secondaries_on_demand() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            400_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            1,
+            false,
+            String::new(),
+        )
+        .expect("to build");
+        let pairs: Vec<(u32, u32)> = (0..10_000u32).map(|i| (i, i)).collect();
+        let pairs = pairs.iter().map(|&(k, v)| (k.to_be_bytes(), v));
+        let options = ScalingOptions::new(0.001).secondary_scaledown(0.5);
+        let indeterminacy = bfield.build_scalable(pairs, &options, |_| {}).unwrap();
+        assert!(indeterminacy <= 0.001, "{}", indeterminacy);
+        let sizes = bfield.build_parameters().sizes;
+        assert!(sizes.len() > 1);
+        assert_eq!(sizes[1], 200_000);
+        drop(bfield);
+
+        let bfield: BField<String> =
+            BField::load(tmp_dir.path().join("bfield.0.bfd"), true).unwrap();
+        assert_eq!(bfield.build_parameters().sizes, sizes);
+        let n_found = (0..10_000u32)
+            .filter(|&i| bfield.get(&i.to_be_bytes()) == Some(i))
+            .count();
+        assert!(n_found >= 9_990);
+    }
+
+    #[test]
+    fn can_size_secondaries_from_indeterminate_keys() {
+        let options = ScalingOptions::new(0.0).size_from_indeterminate_keys(2.0);
+        assert_eq!(options.next_size(&[1_000_000], 10_000, 500), 100_000);
+        assert_eq!(
+            options.next_size(&[1_000_000], 10_000, 1),
+            MIN_MEASURED_SIZE
+        );
+        let options = ScalingOptions::new(0.0);
+        assert_eq!(
+            options.next_size(&[1_000_000, 100_000], 10_000, 500),
+            25_000
+        );
+
+        let mut bfield: BField<String> = BField::create(
+            "",
+            "bfield",
+            1_000_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            1,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        let pairs = (0..10_000u32).map(|i| (i.to_be_bytes(), i));
+        let options = ScalingOptions::new(0.0).size_from_indeterminate_keys(2.0);
+        let indeterminacy = bfield.build_scalable(pairs, &options, |_| {}).unwrap();
+        assert_eq!(indeterminacy, 0.0);
+        let sizes = bfield.build_parameters().sizes;
+        assert!(sizes.len() > 1);
+        // far smaller than the 100,000 bits a β of 0.1 would give
+        assert!(sizes[1] < 50_000, "{:?}", sizes);
+        for i in 0..10_000u32 {
+            assert_eq!(bfield.get(&i.to_be_bytes()), Some(i));
+        }
+    }
+}
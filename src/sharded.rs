@@ -0,0 +1,326 @@
+//! A `BField` split into independent shards by key hash, so each shard can
+//! be built (or queried) by its own thread.
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use murmurhash3::murmurhash3_x64_128;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::bfield::{BField, LoadOptions};
+use crate::bfield_member::{BFieldVal, BFieldValue};
+
+const SHARDS_VERSION: u32 = 1;
+/// Seed for the shard hash, so the shard a key goes to isn't correlated with
+/// its positions in the shard's members (which hash with seed 0)
+const SHARD_SEED: u64 = 0x5348_4152_4453;
+
+/// The file listing the shards, written next to them as `{filename}.shards.json`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct ShardsFile {
+    version: u32,
+    /// The primary array file of each shard, relative to the shards file
+    shards: Vec<String>,
+}
+
+/// A `BField` partitioned into `n_shards` independent `BField`s by a hash of
+/// the key.
+///
+/// Each key only ever touches one shard, so shards can be built in parallel
+/// without any coordination, e.g. by giving each thread the keys for which
+/// [`ShardedBField::shard_index`] returns its shard.
+pub struct ShardedBField<T, V = BFieldVal> {
+    shards: Vec<BField<T, V>>,
+    /// The shards file, `None` for in-memory `ShardedBField`s not persisted yet
+    path: Option<PathBuf>,
+}
+
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> ShardedBField<T, V> {
+    /// Creates `n_shards` `BField`s in `directory`, stored as
+    /// `{filename}.shard{i}.{n}.bfd` and listed in `{filename}.shards.json`.
+    ///
+    /// `size` is the total primary size, split evenly between the shards; the
+    /// other parameters are the same as for [`BField::create`].
+    #[allow(clippy::too_many_arguments)]
+    pub fn create<P>(
+        directory: P,
+        filename: &str,
+        n_shards: usize,
+        size: usize,
+        n_hashes: u8,             // k
+        marker_width: u8,         // nu
+        n_marker_bits: u8,        // kappa
+        secondary_scaledown: f64, // beta
+        max_scaledown: f64,
+        n_secondaries: u8,
+        in_memory: bool,
+        other_params: T,
+    ) -> Result<Self, io::Error>
+    where
+        P: AsRef<Path>,
+    {
+        if n_shards == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A ShardedBField needs at least one shard",
+            ));
+        }
+        let mut shards = Vec::with_capacity(n_shards);
+        for i in 0..n_shards {
+            shards.push(BField::create(
+                directory.as_ref(),
+                &format!("{filename}.shard{i}"),
+                size.div_ceil(n_shards),
+                n_hashes,
+                marker_width,
+                n_marker_bits,
+                secondary_scaledown,
+                max_scaledown,
+                n_secondaries,
+                in_memory,
+                other_params.clone(),
+            )?);
+        }
+        let path = if in_memory {
+            None
+        } else {
+            Some(directory.as_ref().join(format!("{filename}.shards.json")))
+        };
+        let sharded = ShardedBField { shards, path };
+        sharded.write_shards_file()?;
+        Ok(sharded)
+    }
+
+    /// Loads the `ShardedBField` given the path to its `.shards.json` file.
+    pub fn load<P: AsRef<Path>>(path: P, read_only: bool) -> Result<Self, io::Error> {
+        Self::load_with(path, &LoadOptions::new().read_only(read_only))
+    }
+
+    /// Same as [`ShardedBField::load`], loading each shard with `options`.
+    pub fn load_with<P: AsRef<Path>>(path: P, options: &LoadOptions) -> Result<Self, io::Error> {
+        let path = path.as_ref();
+        let data = fs::read(path)?;
+        let shards_file: ShardsFile = serde_json::from_slice(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if shards_file.version > SHARDS_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{:?} has version {}, only up to {} is supported",
+                    path, shards_file.version, SHARDS_VERSION
+                ),
+            ));
+        }
+        if shards_file.shards.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} doesn't list any shards", path),
+            ));
+        }
+        let parent = path.parent().unwrap_or_else(|| Path::new(""));
+        let shards = shards_file
+            .shards
+            .iter()
+            .map(|file| BField::load_with(parent.join(file), options))
+            .collect::<Result<_, _>>()?;
+        Ok(ShardedBField {
+            shards,
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    /// Writes every shard to disk, see [`BField::persist_to_disk`].
+    pub fn persist_to_disk(self) -> Result<Self, io::Error> {
+        let mut shards = Vec::with_capacity(self.shards.len());
+        for shard in self.shards {
+            shards.push(shard.persist_to_disk()?);
+        }
+        let path = self
+            .path
+            .or_else(|| shards_path(&shards[0].members()[0].filename));
+        let sharded = ShardedBField { shards, path };
+        sharded.write_shards_file()?;
+        Ok(sharded)
+    }
+
+    fn write_shards_file(&self) -> Result<(), io::Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let shards = self
+            .shards
+            .iter()
+            .map(|shard| {
+                shard.members()[0]
+                    .filename
+                    .file_name()
+                    .map(|f| f.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            })
+            .collect();
+        let shards_file = ShardsFile {
+            version: SHARDS_VERSION,
+            shards,
+        };
+        let data = serde_json::to_vec_pretty(&shards_file)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, data)
+    }
+
+    /// The index of the shard `key` is stored in.
+    #[inline]
+    pub fn shard_index(&self, key: &[u8]) -> usize {
+        let (hash, _) = murmurhash3_x64_128(key, SHARD_SEED);
+        // maps the hash onto 0..n_shards without a division
+        ((u128::from(hash) * self.shards.len() as u128) >> 64) as usize
+    }
+
+    /// The shards, e.g. to build or query each of them on its own thread.
+    pub fn shards(&self) -> &[BField<T, V>] {
+        &self.shards
+    }
+
+    /// Inserts `key` in its shard, see [`BField::insert`].
+    pub fn insert(&self, key: &[u8], value: V, pass: usize) -> bool {
+        self.shards[self.shard_index(key)].insert(key, value, pass)
+    }
+
+    /// Inserts `key` in its shard, see [`BField::force_insert`].
+    #[deprecated(since = "0.4.0", note = "use `mask_or_insert` instead")]
+    #[allow(deprecated)]
+    pub fn force_insert(&self, key: &[u8], value: V) {
+        self.shards[self.shard_index(key)].force_insert(key, value)
+    }
+
+    /// Inserts `key` in its shard, see [`BField::mask_or_insert`].
+    pub fn mask_or_insert(&mut self, key: &[u8], value: V) -> Result<(), io::Error> {
+        let ix = self.shard_index(key);
+        self.shards[ix].mask_or_insert(key, value)
+    }
+
+    /// Looks `key` up in its shard, see [`BField::get`].
+    pub fn get(&self, key: &[u8]) -> Option<V> {
+        self.shards[self.shard_index(key)].get(key)
+    }
+
+    /// Returns the largest value that can be stored, see [`BField::max_value`].
+    pub fn max_value(&self) -> V {
+        self.shards[0].max_value()
+    }
+
+    /// Returns the params given at build time to the shards.
+    pub fn params(&self) -> &Option<T> {
+        self.shards[0].params()
+    }
+}
+
+impl<T: fmt::Debug, V> fmt::Debug for ShardedBField<T, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShardedBField")
+            .field("path", &self.path)
+            .field("shards", &self.shards)
+            .finish()
+    }
+}
+
+/// `{filename}.shards.json` for a shard whose primary array is at
+/// `{filename}.shard{i}.0.bfd`.
+fn shards_path(main_db_path: &Path) -> Option<PathBuf> {
+    let filename = main_db_path.file_name()?.to_str()?;
+    let base = filename.strip_suffix(".0.bfd")?;
+    let (base, _) = base.rsplit_once(".shard")?;
+    Some(main_db_path.with_file_name(format!("{base}.shards.json")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(dir: &Path, in_memory: bool) -> ShardedBField<String> {
+        ShardedBField::create(
+            dir,
+            "bfield",
+            4,
+            1_000_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            2,
+            in_memory,
+            String::new(),
+        )
+        .expect("to build")
+    }
+
+    #[test]
+    fn test_shards_path() {
+        assert_eq!(
+            shards_path(Path::new("/tmp/bfield.shard3.0.bfd")),
+            Some(PathBuf::from("/tmp/bfield.shards.json"))
+        );
+        assert_eq!(shards_path(Path::new("/tmp/bfield.0.bfd")), None);
+    }
+
+    #[test]
+    fn can_build_shards_in_parallel() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let sharded = build(tmp_dir.path(), true);
+        let max_value = 10_000u32;
+        for p in 0..2 {
+            std::thread::scope(|s| {
+                for (i, shard) in sharded.shards().iter().enumerate() {
+                    let sharded = &sharded;
+                    s.spawn(move || {
+                        for key in 0..max_value {
+                            if sharded.shard_index(&key.to_be_bytes()) == i {
+                                shard.insert(&key.to_be_bytes(), key, p);
+                            }
+                        }
+                    });
+                }
+            });
+        }
+        for key in 0..max_value {
+            assert_eq!(sharded.get(&key.to_be_bytes()), Some(key));
+        }
+        // keys are spread over all the shards
+        let mut counts = [0; 4];
+        for key in 0..max_value {
+            counts[sharded.shard_index(&key.to_be_bytes())] += 1;
+        }
+        assert!(counts.iter().all(|&c| c > 2_000), "{:?}", counts);
+
+        let sharded = sharded.persist_to_disk().unwrap();
+        drop(sharded);
+        let path = tmp_dir.path().join("bfield.shards.json");
+        let sharded = ShardedBField::<String>::load(&path, true).unwrap();
+        assert_eq!(sharded.shards().len(), 4);
+        for key in 0..max_value {
+            assert_eq!(sharded.get(&key.to_be_bytes()), Some(key));
+        }
+    }
+
+    #[test]
+    fn can_load_on_disk_shards() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let sharded = build(tmp_dir.path(), false);
+        for p in 0..2 {
+            for key in 0..1_000u32 {
+                sharded.insert(&key.to_be_bytes(), key, p);
+            }
+        }
+        drop(sharded);
+        let path = tmp_dir.path().join("bfield.shards.json");
+        let sharded = ShardedBField::<String>::load(&path, true).unwrap();
+        for key in 0..1_000u32 {
+            assert_eq!(sharded.get(&key.to_be_bytes()), Some(key));
+        }
+        fs::remove_file(tmp_dir.path().join("bfield.shard2.1.bfd")).unwrap();
+        assert!(ShardedBField::<String>::load(&path, true).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+//! An exact map for the keys a `BField` build left indeterminate in every
+//! member, so they aren't lost.
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::keyed::{hash_with, HashSecret};
+
+const SIDECAR_MAGIC: [u8; 4] = *b"BFSC";
+/// Size of an entry on disk: a 128-bit key hash and a 64-bit value
+const ENTRY_LEN: usize = 24;
+
+/// Values of keys indeterminate in every member, by 128-bit key hash.
+///
+/// It's stored as `{filename}.sidecar.bin` next to the `.bfd` files: the
+/// magic bytes, the number of entries as a big-endian `u64`, and then the
+/// entries sorted by hash, each as a big-endian `u128` hash and `u64` value.
+#[derive(Debug, Default)]
+pub(crate) struct Sidecar {
+    entries: RwLock<HashMap<u128, u64>>,
+}
+
+/// The sidecar path for a `BField` whose primary array is at
+/// `main_db_path` (`{filename}.0.bfd` -> `{filename}.sidecar.bin`).
+pub(crate) fn sidecar_path(main_db_path: &Path) -> Option<PathBuf> {
+    let filename = main_db_path.file_name()?.to_str()?;
+    let base = filename.strip_suffix("0.bfd")?;
+    Some(main_db_path.with_file_name(format!("{base}sidecar.bin")))
+}
+
+/// The hash `key` is stored under, keyed with the members' `secret` if
+/// they have one.
+fn key_hash(key: &[u8], secret: Option<&HashSecret>) -> u128 {
+    let (high, low) = hash_with(secret, key, 0);
+    (u128::from(high) << 64) | u128::from(low)
+}
+
+impl Sidecar {
+    pub fn insert(&self, key: &[u8], secret: Option<&HashSecret>, value: u64) {
+        self.write_entries().insert(key_hash(key, secret), value);
+    }
+
+    pub fn remove(&self, key: &[u8], secret: Option<&HashSecret>) {
+        self.write_entries().remove(&key_hash(key, secret));
+    }
+
+    pub fn get(&self, key: &[u8], secret: Option<&HashSecret>) -> Option<u64> {
+        self.read_entries().get(&key_hash(key, secret)).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.read_entries().len()
+    }
+
+    /// A copy of this sidecar.
+    pub fn duplicate(&self) -> Self {
+        Sidecar {
+            entries: RwLock::new(self.read_entries().clone()),
+        }
+    }
+
+    fn read_entries(&self) -> RwLockReadGuard<'_, HashMap<u128, u64>> {
+        self.entries.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write_entries(&self) -> RwLockWriteGuard<'_, HashMap<u128, u64>> {
+        self.entries.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Reads the sidecar at `path`, returning `None` if there isn't one.
+    pub fn read(path: &Path) -> Result<Option<Self>, io::Error> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} isn't a valid sidecar file", path),
+            )
+        };
+        if data.len() < 12 || data[..4] != SIDECAR_MAGIC {
+            return Err(invalid());
+        }
+        let n_entries = u64::from_be_bytes(data[4..12].try_into().unwrap()) as usize;
+        let records = &data[12..];
+        if records.len() != n_entries.checked_mul(ENTRY_LEN).ok_or_else(invalid)? {
+            return Err(invalid());
+        }
+        let entries = records
+            .chunks_exact(ENTRY_LEN)
+            .map(|entry| {
+                (
+                    u128::from_be_bytes(entry[..16].try_into().unwrap()),
+                    u64::from_be_bytes(entry[16..].try_into().unwrap()),
+                )
+            })
+            .collect();
+        Ok(Some(Sidecar {
+            entries: RwLock::new(entries),
+        }))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), io::Error> {
+        let entries = self.read_entries();
+        let mut sorted: Vec<_> = entries.iter().collect();
+        sorted.sort_unstable();
+        let mut data = Vec::with_capacity(12 + ENTRY_LEN * sorted.len());
+        data.extend_from_slice(&SIDECAR_MAGIC);
+        data.extend_from_slice(&(sorted.len() as u64).to_be_bytes());
+        for (hash, value) in sorted {
+            data.extend_from_slice(&hash.to_be_bytes());
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        fs::write(path, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_write_and_read_sidecar() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("bfield.sidecar.bin");
+        assert!(Sidecar::read(&path).unwrap().is_none());
+
+        let sidecar = Sidecar::default();
+        sidecar.insert(b"a", None, 1);
+        sidecar.insert(b"b", None, u64::MAX);
+        sidecar.insert(b"c", None, 3);
+        sidecar.remove(b"c", None);
+        // keys are hashed with the secret of keyed `BField`s
+        let secret = HashSecret::new(*b"0123456789abcdef");
+        sidecar.insert(b"d", Some(&secret), 4);
+        assert_eq!(sidecar.get(b"d", Some(&secret)), Some(4));
+        assert_eq!(sidecar.get(b"d", None), None);
+        sidecar.remove(b"d", Some(&secret));
+        sidecar.write(&path).unwrap();
+        let read = Sidecar::read(&path).unwrap().unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read.get(b"a", None), Some(1));
+        assert_eq!(read.get(b"b", None), Some(u64::MAX));
+        assert_eq!(read.get(b"c", None), None);
+
+        fs::write(&path, b"BFSC\0\0\0\0\0\0\0\x01").unwrap();
+        assert_eq!(
+            Sidecar::read(&path).err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            sidecar_path(Path::new("/tmp/bfield.0.bfd")),
+            Some(PathBuf::from("/tmp/bfield.sidecar.bin"))
+        );
+    }
+}
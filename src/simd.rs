@@ -0,0 +1,108 @@
+//! Reading all the markers of a lookup at once with SIMD instructions, see
+//! `BitStorage::and_ranges`.
+use std::convert::TryInto;
+
+/// The widest markers that can be read: a marker starting at any bit of a
+/// byte has to fit in the 8 bytes loaded for it.
+pub(crate) const MAX_WIDTH: usize = 57;
+
+/// The AND of the `width`-bit ranges of `bytes` (laid out as for
+/// `get_range`) starting at `positions`, each of which must be followed by
+/// at least 64 bits.
+pub(crate) fn and_ranges(bytes: &[u8], positions: &[usize], width: usize) -> u64 {
+    assert!(width > 0 && width <= MAX_WIDTH, "Range too large");
+    assert!(
+        positions.iter().all(|&pos| (pos >> 3) + 8 <= bytes.len()),
+        "Range ends outside of the bit array"
+    );
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // Safety: AVX2 is available and the loads are in bounds
+            return unsafe { and_ranges_avx2(bytes, positions, width) };
+        }
+    }
+    and_ranges_words(bytes, positions, width)
+}
+
+/// The 64 bits starting at `pos`.
+#[inline]
+fn load(bytes: &[u8], pos: usize) -> u64 {
+    let i = pos >> 3;
+    u64::from_be_bytes(bytes[i..i + 8].try_into().unwrap()) << (pos & 7)
+}
+
+/// Same as `and_ranges`, loading a 64-bit word per range.
+fn and_ranges_words(bytes: &[u8], positions: &[usize], width: usize) -> u64 {
+    let merged = positions
+        .iter()
+        .fold(u64::MAX, |merged, &pos| merged & load(bytes, pos));
+    merged >> (64 - width)
+}
+
+/// Same as `and_ranges`, gathering the words of 4 ranges at a time.
+///
+/// # Safety
+///
+/// The CPU must support AVX2, and the 8 bytes starting at `pos / 8` must be
+/// in bounds for every position.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn and_ranges_avx2(bytes: &[u8], positions: &[usize], width: usize) -> u64 {
+    use std::arch::x86_64::*;
+
+    // reverses the bytes of each 64-bit lane, as the bits are big-endian
+    let bswap = _mm256_setr_epi8(
+        7, 6, 5, 4, 3, 2, 1, 0, 15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 15, 14, 13,
+        12, 11, 10, 9, 8,
+    );
+    let bit_mask = _mm256_set1_epi64x(7);
+    let mut merged = _mm256_set1_epi64x(-1);
+    let mut chunks = positions.chunks_exact(4);
+    for chunk in &mut chunks {
+        let pos = _mm256_loadu_si256(chunk.as_ptr() as *const __m256i);
+        let words =
+            _mm256_i64gather_epi64::<1>(bytes.as_ptr() as *const i64, _mm256_srli_epi64::<3>(pos));
+        let words = _mm256_sllv_epi64(
+            _mm256_shuffle_epi8(words, bswap),
+            _mm256_and_si256(pos, bit_mask),
+        );
+        merged = _mm256_and_si256(merged, words);
+    }
+    let mut lanes = [0u64; 4];
+    _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, merged);
+    let merged = chunks
+        .remainder()
+        .iter()
+        .fold(u64::MAX, |merged, &pos| merged & load(bytes, pos));
+    lanes.iter().fold(merged, |merged, &lane| merged & lane) >> (64 - width)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::get_range;
+
+    #[test]
+    fn test_and_ranges() {
+        let bytes: Vec<u8> = (0..512u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8 | 0x81)
+            .collect();
+        let max_pos = 8 * bytes.len() - 64;
+        for width in [1, 7, 39, 57] {
+            for n in [1, 3, 4, 10, 16] {
+                let positions: Vec<usize> = (0..n).map(|i| (i * 977 + width) % max_pos).collect();
+                let expected = positions
+                    .iter()
+                    .fold(u128::MAX >> (128 - width), |merged, &pos| {
+                        merged & get_range(&bytes, pos..pos + width)
+                    });
+                assert_eq!(u128::from(and_ranges(&bytes, &positions, width)), expected);
+                assert_eq!(
+                    u128::from(and_ranges_words(&bytes, &positions, width)),
+                    expected
+                );
+            }
+        }
+    }
+}
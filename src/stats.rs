@@ -0,0 +1,504 @@
+//! Fill rates of the members, the error rates they imply and the ones
+//! actually observed, to check the parameters a `BField` was built with held
+//! up.
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::bfield::{BField, BuildParams};
+use crate::bfield_member::{BFieldLookup, BFieldValue};
+
+/// Statistics about one of the arrays of a `BField`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MemberStats {
+    /// Size in bits
+    pub size: usize,
+    /// Number of keys inserted (or masked) into this array
+    pub n_keys: u64,
+    /// Number of bits set
+    pub n_set: usize,
+    /// Fraction of the bits set
+    pub density: f64,
+    /// Estimated probability that a key that wasn't inserted has at least κ
+    /// bits set in this array, i.e. isn't rejected by it (α for this array
+    /// alone, see the README)
+    pub false_positive_rate: f64,
+    /// Estimated probability that a key inserted in this array is
+    /// indeterminate in it (β for this array alone)
+    pub indeterminacy_rate: f64,
+}
+
+/// Statistics about a whole `BField`, see [`BField::stats`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Stats {
+    /// The statistics of each array, starting with the primary one
+    pub members: Vec<MemberStats>,
+    /// Total size in bits
+    pub size: usize,
+    /// Total number of bits set
+    pub n_set: usize,
+    /// Estimated probability that `get` returns a value for a key that
+    /// wasn't inserted
+    pub false_positive_rate: f64,
+    /// Estimated probability that `get` can't determine the value of an
+    /// inserted key (i.e. it's indeterminate in every array)
+    pub indeterminacy_rate: f64,
+}
+
+/// Error rates observed by looking up keys that weren't inserted, see
+/// [`BField::estimate_indeterminacy`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProbeStats {
+    /// Number of keys looked up
+    pub n_samples: usize,
+    /// What happened to the probes in each array, starting with the primary one
+    pub members: Vec<MemberProbeStats>,
+    /// Fraction of the probes for which `get` returned a value
+    pub false_positive_rate: f64,
+    /// Fraction of the probes that were indeterminate in every array
+    pub indeterminacy_rate: f64,
+}
+
+/// Outcome of the probes that were looked up in one of the arrays.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MemberProbeStats {
+    /// Number of probes that got to this array, i.e. were indeterminate in
+    /// all the previous ones
+    pub n_probes: usize,
+    /// Fraction of those decoding to a value in this array
+    pub false_positive_rate: f64,
+    /// Fraction of those that were indeterminate in this array
+    pub indeterminacy_rate: f64,
+}
+
+/// How the lookups of keys with known values went, see [`BField::validate`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Validation<V> {
+    /// Lookups returning the expected value
+    pub n_correct: u64,
+    /// Lookups returning another value
+    pub n_wrong: u64,
+    /// Lookups that were indeterminate in every array
+    pub n_indeterminate: u64,
+    /// Lookups that didn't find the key at all
+    pub n_missing: u64,
+    /// The first few lookups that went wrong
+    pub errors: Vec<ValidationError<V>>,
+}
+
+impl<V> Validation<V> {
+    /// Whether every lookup returned the expected value.
+    pub fn is_ok(&self) -> bool {
+        self.n_wrong == 0 && self.n_indeterminate == 0 && self.n_missing == 0
+    }
+}
+
+/// A lookup that didn't return the expected value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValidationError<V> {
+    /// `key` decoded to `found` (which may not even fit in `V`)
+    WrongValue {
+        /// The key looked up
+        key: Vec<u8>,
+        /// The value it was inserted with
+        expected: V,
+        /// The value it decoded to
+        found: u64,
+    },
+    /// `key` was indeterminate in every array
+    Indeterminate {
+        /// The key looked up
+        key: Vec<u8>,
+        /// The value it was inserted with
+        expected: V,
+    },
+    /// `key` wasn't found
+    Missing {
+        /// The key looked up
+        key: Vec<u8>,
+        /// The value it was inserted with
+        expected: V,
+    },
+}
+
+/// SplitMix64, to generate the probe keys without pulling in an RNG.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Fraction of `n` out of `total`, 0 if there's nothing to count.
+fn fraction(n: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        n as f64 / total as f64
+    }
+}
+
+/// `n` choose `i` as a float (exact enough for `n` up to 255).
+fn choose_f64(n: u32, i: u32) -> f64 {
+    (0..i).fold(1.0, |c, j| c * f64::from(n - j) / f64::from(j + 1))
+}
+
+/// Probability of exactly `i` successes out of `n` trials of probability `p`.
+fn binomial_pmf(n: u32, i: u32, p: f64) -> f64 {
+    choose_f64(n, i) * p.powi(i as i32) * (1.0 - p).powi((n - i) as i32)
+}
+
+/// The estimated probability that a key inserted in an array with the given
+/// density is indeterminate in it, see `BField::stats`.
+pub(crate) fn indeterminacy_rate_at(params: &BuildParams, density: f64) -> f64 {
+    let p = density.powi(i32::from(params.n_hashes));
+    1.0 - (1.0 - p).powi(i32::from(params.marker_width - params.n_marker_bits))
+}
+
+impl<T: Clone + DeserializeOwned + Serialize, V: BFieldValue> BField<T, V> {
+    /// Counts the bits set in each array and estimates the resulting error
+    /// rates (see the README for the formulas). This reads every array in
+    /// full, so it can take a while for large `BField`s.
+    ///
+    /// The estimates use the measured density `d` of each array: a bit of a
+    /// looked up marker is wrongly set with probability `p = d^k`.
+    pub fn stats(&self) -> Stats {
+        let params = self.build_parameters();
+        let nu = u32::from(params.marker_width);
+        let kappa = u32::from(params.n_marker_bits);
+        let mut members = Vec::with_capacity(params.sizes.len());
+        // probability a key that wasn't inserted is indeterminate in all the
+        // arrays so far, i.e. gets looked up in the next one
+        let mut reaches = 1.0;
+        let mut false_positive_rate = 0.0;
+        let mut indeterminacy_rate = 1.0;
+        for member in self.members() {
+            let size = member.storage().size();
+            let n_set = member.storage().count_ones();
+            let density = if size == 0 {
+                0.0
+            } else {
+                n_set as f64 / size as f64
+            };
+            let p = density.powi(i32::from(params.n_hashes));
+            // CumBinom(ν, κ, p), see 5(b) in the README
+            let member_false_positives: f64 = (kappa..=nu).map(|i| binomial_pmf(nu, i, p)).sum();
+            let member_indeterminacy = indeterminacy_rate_at(&params, density);
+
+            false_positive_rate += reaches * binomial_pmf(nu, kappa, p);
+            reaches *= member_false_positives - binomial_pmf(nu, kappa, p);
+            indeterminacy_rate *= member_indeterminacy;
+            members.push(MemberStats {
+                size,
+                n_keys: member.n_keys(),
+                n_set,
+                density,
+                false_positive_rate: member_false_positives,
+                indeterminacy_rate: member_indeterminacy,
+            });
+        }
+        Stats {
+            size: members.iter().map(|m| m.size).sum(),
+            n_set: members.iter().map(|m| m.n_set).sum(),
+            members,
+            false_positive_rate,
+            indeterminacy_rate,
+        }
+    }
+
+    /// Looks up `n_samples` random keys (24 random bytes each, so almost
+    /// certainly not inserted) and reports how often each array returned a
+    /// value or was indeterminate for them: an empirical cross-check of the
+    /// rates estimated by [`BField::stats`]. The keys are the same on every
+    /// call.
+    pub fn estimate_indeterminacy(&self, n_samples: usize) -> ProbeStats {
+        let n_members = self.members().len();
+        let mut n_probes = vec![0; n_members];
+        let mut n_values = vec![0; n_members];
+        let mut n_indeterminate = vec![0; n_members];
+        let mut state = 0x6266_6965_6C64;
+        for _ in 0..n_samples {
+            let mut key = [0; 24];
+            for chunk in key.chunks_mut(8) {
+                chunk.copy_from_slice(&splitmix64(&mut state).to_le_bytes());
+            }
+            for (ix, member) in self.members().iter().enumerate() {
+                n_probes[ix] += 1;
+                match member.get(&key) {
+                    BFieldLookup::Indeterminate => n_indeterminate[ix] += 1,
+                    BFieldLookup::Some(_) => {
+                        n_values[ix] += 1;
+                        break;
+                    }
+                    BFieldLookup::None => break,
+                }
+            }
+        }
+        let members = (0..n_members)
+            .map(|ix| MemberProbeStats {
+                n_probes: n_probes[ix],
+                false_positive_rate: fraction(n_values[ix], n_probes[ix]),
+                indeterminacy_rate: fraction(n_indeterminate[ix], n_probes[ix]),
+            })
+            .collect();
+        ProbeStats {
+            n_samples,
+            members,
+            false_positive_rate: fraction(n_values.iter().sum(), n_samples),
+            indeterminacy_rate: fraction(n_indeterminate[n_members - 1], n_samples),
+        }
+    }
+}
+
+impl<T, V> BField<T, V>
+where
+    T: Clone + DeserializeOwned + Serialize,
+    V: BFieldValue + PartialEq,
+{
+    /// Looks up every key of `pairs` and counts how many returned their
+    /// expected value, another value, or were indeterminate or missing,
+    /// keeping the first `max_errors` failed lookups, e.g. to check the
+    /// quality of a database after building it.
+    pub fn validate<I, K>(&self, pairs: I, max_errors: usize) -> Validation<V>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+    {
+        let mut validation = Validation {
+            n_correct: 0,
+            n_wrong: 0,
+            n_indeterminate: 0,
+            n_missing: 0,
+            errors: Vec::new(),
+        };
+        for (key, expected) in pairs {
+            let key = key.as_ref();
+            let error = match self.lookup(key) {
+                BFieldLookup::Some(found) if V::from_u64(found) == Some(expected) => {
+                    validation.n_correct += 1;
+                    continue;
+                }
+                BFieldLookup::Some(found) => {
+                    validation.n_wrong += 1;
+                    ValidationError::WrongValue {
+                        key: key.to_vec(),
+                        expected,
+                        found,
+                    }
+                }
+                BFieldLookup::Indeterminate => {
+                    validation.n_indeterminate += 1;
+                    ValidationError::Indeterminate {
+                        key: key.to_vec(),
+                        expected,
+                    }
+                }
+                BFieldLookup::None => {
+                    validation.n_missing += 1;
+                    ValidationError::Missing {
+                        key: key.to_vec(),
+                        expected,
+                    }
+                }
+            };
+            if validation.errors.len() < max_errors {
+                validation.errors.push(error);
+            }
+        }
+        validation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binomial_pmf() {
+        assert_eq!(choose_f64(39, 4), 82_251.0);
+        let total: f64 = (0..=39).map(|i| binomial_pmf(39, i, 0.3)).sum();
+        assert!((total - 1.0).abs() < 1e-12);
+        assert_eq!(binomial_pmf(10, 0, 0.0), 1.0);
+    }
+
+    #[test]
+    fn can_compute_stats() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            1_000_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            3,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        let empty = bfield.stats();
+        assert_eq!(empty.n_set, 0);
+        assert_eq!(empty.false_positive_rate, 0.0);
+        assert_eq!(empty.indeterminacy_rate, 0.0);
+
+        for p in 0..3 {
+            for i in 0..10_000u32 {
+                bfield.insert(&i.to_be_bytes(), i, p);
+            }
+        }
+        let stats = bfield.stats();
+        assert_eq!(stats.members.len(), 3);
+        assert_eq!(stats.size, 1_000_000 + 100_000 + 25_000);
+        assert_eq!(
+            stats.n_set,
+            stats.members.iter().map(|m| m.n_set).sum::<usize>()
+        );
+        // every insert sets at most k * κ bits of the primary array
+        let primary = &stats.members[0];
+        assert!(primary.n_set > 0 && primary.n_set <= 10_000 * 10 * 4);
+        assert!(primary.density > 0.0 && primary.density < 0.5);
+        assert!(stats.false_positive_rate > 0.0);
+        assert!(stats.false_positive_rate < primary.false_positive_rate);
+        assert!(stats.indeterminacy_rate < primary.indeterminacy_rate);
+    }
+
+    #[test]
+    fn can_estimate_indeterminacy() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        // small enough for the primary array to give plenty of errors
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            100_000,
+            3,
+            39,
+            4,
+            0.5,
+            0.1,
+            3,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        for p in 0..3 {
+            for i in 0..5_000u32 {
+                bfield.insert(&i.to_be_bytes(), i, p);
+            }
+        }
+        let probes = bfield.estimate_indeterminacy(20_000);
+        assert_eq!(probes, bfield.estimate_indeterminacy(20_000));
+        assert_eq!(probes.members[0].n_probes, 20_000);
+        let primary = &probes.members[0];
+        assert!(primary.indeterminacy_rate > 0.0);
+        assert_eq!(
+            probes.members[1].n_probes,
+            (20_000.0 * primary.indeterminacy_rate).round() as usize
+        );
+
+        // roughly matches the analytic estimate (which counts indeterminate
+        // markers as false positives too)
+        let stats = bfield.stats();
+        let observed = primary.false_positive_rate + primary.indeterminacy_rate;
+        let expected = stats.members[0].false_positive_rate;
+        assert!(
+            (observed - expected).abs() < 0.2 * expected + 0.005,
+            "{} vs {}",
+            observed,
+            expected
+        );
+        let ratio = probes.false_positive_rate / stats.false_positive_rate;
+        assert!(ratio > 0.5 && ratio < 1.5, "{:?} vs {:?}", probes, stats);
+    }
+
+    #[test]
+    fn can_count_keys() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        for in_memory in [true, false] {
+            let bfield: BField<String> = BField::create(
+                tmp_dir.path(),
+                "bfield",
+                1_000_000,
+                10,
+                39,
+                4,
+                0.1,
+                0.025,
+                2,
+                in_memory,
+                String::new(),
+            )
+            .expect("to build");
+            for p in 0..2 {
+                for i in 0..1_000u32 {
+                    bfield.insert(&i.to_be_bytes(), i, p);
+                }
+            }
+            let n_keys: Vec<u64> = bfield.stats().members.iter().map(|m| m.n_keys).collect();
+            assert_eq!(n_keys[0], 1_000);
+            assert!(n_keys[1] < 100);
+            if in_memory {
+                drop(bfield.persist_to_disk().unwrap());
+            } else {
+                bfield.update_checksums().unwrap();
+                drop(bfield);
+            }
+            let bfield =
+                BField::<String>::load(tmp_dir.path().join("bfield.0.bfd"), false).unwrap();
+            let loaded: Vec<u64> = bfield.stats().members.iter().map(|m| m.n_keys).collect();
+            assert_eq!(loaded, n_keys);
+            bfield.verify_checksums().unwrap();
+            // counting resumes from the recorded numbers
+            bfield.insert(&5_000u32.to_be_bytes(), 5_000, 0);
+            assert_eq!(bfield.stats().members[0].n_keys, 1_001);
+        }
+    }
+
+    #[test]
+    fn can_validate() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bfield: BField<String> = BField::create(
+            tmp_dir.path(),
+            "bfield",
+            1_000_000,
+            10,
+            39,
+            4,
+            0.1,
+            0.025,
+            3,
+            true,
+            String::new(),
+        )
+        .expect("to build");
+        let pairs: Vec<([u8; 4], u32)> = (0..1_000u32).map(|i| (i.to_be_bytes(), i)).collect();
+        bfield
+            .build_from_iter(pairs.iter().copied(), |_| {})
+            .unwrap();
+        let validation = bfield.validate(pairs.iter().copied(), 10);
+        assert!(validation.is_ok());
+        assert_eq!(validation.n_correct, 1_000);
+
+        // wrong expectations and keys that were never inserted
+        let validation = bfield.validate(
+            vec![
+                (1u32.to_be_bytes(), 2),
+                (5_000u32.to_be_bytes(), 1),
+                (6_000u32.to_be_bytes(), 1),
+            ],
+            2,
+        );
+        assert!(!validation.is_ok());
+        assert_eq!((validation.n_wrong, validation.n_missing), (1, 2));
+        assert_eq!(validation.errors.len(), 2);
+        assert_eq!(
+            validation.errors[0],
+            ValidationError::WrongValue {
+                key: 1u32.to_be_bytes().to_vec(),
+                expected: 2,
+                found: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,1052 @@
+//! The bit arrays backing the members and their on-disk layout.
+//!
+//! A member file (or member image in a container) is laid out the same way on
+//! every platform:
+//! - the magic bytes `BF_MAGIC`
+//! - the header length as a big-endian `u16`, then the header (the bincode
+//!   encoded `BFieldParams`, which are little-endian, see `bfield_member`,
+//!   with the user params as JSON, see `params`)
+//! - the size of the bit array in bits as a big-endian `u64`
+//! - the bit array, where bit `i` is bit `7 - i % 8` of byte `i / 8`
+//!   (i.e. bits are numbered from the most significant bit of each byte)
+//!
+//! All reads and writes go through byte-wise big-endian conversions, so
+//! files can be shared between machines of any endianness or word size.
+#[cfg(feature = "mmap")]
+use std::fs::{File, OpenOptions, TryLockError};
+#[cfg(feature = "prefetching")]
+use std::intrinsics;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering as AtomicOrdering};
+use std::sync::Arc;
+
+#[cfg(feature = "mmap")]
+use memmap2::{Mmap, MmapMut};
+#[cfg(feature = "mmap")]
+use mmap_bitvec::{BitVector, MmapBitVec, MmapKind};
+
+pub(crate) use crate::lookup::{byte_len, get_range, MemberImage, BF_MAGIC};
+#[cfg(feature = "simd")]
+use crate::simd;
+
+/// The expected access pattern for the bit arrays, see [`BField::advise`](crate::BField::advise).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessPattern {
+    /// No particular pattern (the default)
+    Normal,
+    /// Random lookups; disables read-ahead
+    Random,
+    /// Mostly sequential access; enables aggressive read-ahead
+    Sequential,
+    /// The arrays will be needed soon and should be read into memory
+    WillNeed,
+    /// The arrays won't be needed soon and can be evicted from the page cache
+    /// (ignored for arrays that aren't backed by a file, as this would lose
+    /// their contents)
+    DontNeed,
+}
+
+/// Where the bit arrays of a new `BField` are stored, see
+/// [`BField::create`](crate::BField::create). `false` and `true` convert to
+/// `File` and `Memory`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backing {
+    /// `.bfd` files, mapped into memory
+    File,
+    /// Anonymous memory maps (or owned buffers without the `mmap` feature)
+    Memory,
+    /// Heap-allocated 64-bit words, read and written a word at a time
+    /// instead of a byte at a time, and without the page faults of a map.
+    /// The fastest choice for `BField`s that fit in RAM.
+    Words,
+}
+
+impl From<bool> for Backing {
+    fn from(in_memory: bool) -> Self {
+        if in_memory {
+            Backing::Memory
+        } else {
+            Backing::File
+        }
+    }
+}
+
+/// The bit array backing a `BFieldMember`.
+///
+/// Without the `mmap` feature (e.g. on wasm32), only the `Region` and `Heap`
+/// variants exist: in-memory bit arrays are owned buffers and `.bfd` files
+/// are read into memory.
+pub(crate) enum Storage {
+    /// A `.bfd` file managed by `mmap-bitvec`
+    #[cfg(feature = "mmap")]
+    Mmap(MmapBitVec),
+    /// An anonymous in-memory map managed by `mmap-bitvec`
+    #[cfg(feature = "mmap")]
+    Memory(MmapBitVec),
+    /// A member's bit array inside a larger mapping, e.g. a container file
+    Region(Region),
+    /// A bit array in an owned buffer, read from a file (see
+    /// `BField::load_in_memory`) or, without the `mmap` feature, created in
+    /// memory
+    Heap {
+        // atomics, as other threads can write through a shared reference
+        bytes: Box<[AtomicU8]>,
+        /// Size in bits
+        size: usize,
+        /// Whether it was created in memory and never written to a file
+        anonymous: bool,
+    },
+    /// A bit array created in memory as 64-bit words, see `Backing::Words`
+    Words {
+        // each word is stored big-endian, so the bytes are laid out like
+        // those of the other variants
+        words: Box<[AtomicU64]>,
+        /// Size in bits
+        size: usize,
+    },
+}
+
+/// The operations on a bit array the members' marker encoding is built on,
+/// so inserts and lookups don't depend on where the bits are stored.
+/// [`Storage`] covers every kind of `BField`; other backends only need to
+/// implement these.
+pub(crate) trait BitStorage {
+    /// Size in bits
+    fn size(&self) -> usize;
+
+    /// Reads the (at most 128) bits in `r`, with bit `i` of the array at bit
+    /// `r.end - 1 - i` of the result.
+    fn get_range(&self, r: Range<usize>) -> u128;
+
+    /// ORs `x` into the bits in `r`. Several threads may insert at once, so
+    /// this has to be atomic with respect to other calls.
+    fn set_range(&self, r: Range<usize>, x: u128);
+
+    /// Makes sure all the changes are durably stored (a no-op for backends
+    /// that aren't backed by a file).
+    fn persist(&self) -> Result<(), io::Error>;
+
+    /// Hints that the bit at `pos` is about to be read.
+    #[inline]
+    fn prefetch(&self, _pos: usize) {}
+
+    /// The AND of the `width`-bit ranges starting at `positions`, for
+    /// backends that can read them faster all at once than one at a time
+    /// with `get_range` (`None` otherwise).
+    #[inline]
+    fn and_ranges(&self, _positions: &[usize], _width: usize) -> Option<u64> {
+        None
+    }
+}
+
+impl BitStorage for Storage {
+    #[inline]
+    fn size(&self) -> usize {
+        Storage::size(self)
+    }
+
+    #[inline]
+    fn get_range(&self, r: Range<usize>) -> u128 {
+        Storage::get_range(self, r)
+    }
+
+    #[inline]
+    fn set_range(&self, r: Range<usize>, x: u128) {
+        Storage::set_range(self, r, x)
+    }
+
+    fn persist(&self) -> Result<(), io::Error> {
+        match self {
+            // `MmapBitVec` flushes when it's dropped
+            #[cfg(feature = "mmap")]
+            Storage::Mmap(_) | Storage::Memory(_) => Ok(()),
+            Storage::Heap { .. } | Storage::Words { .. } => Ok(()),
+            Storage::Region(r) => r.map.flush(),
+        }
+    }
+
+    #[inline]
+    fn prefetch(&self, pos: usize) {
+        debug_assert!(pos < Storage::size(self));
+        // Safety: `pos` is in bounds
+        prefetch_read(unsafe { self.as_ptr().add(pos >> 3) });
+    }
+
+    #[cfg(feature = "simd")]
+    #[inline]
+    fn and_ranges(&self, positions: &[usize], width: usize) -> Option<u64> {
+        let size = Storage::size(self);
+        // writable arrays are only read with atomics, see `get_range`
+        if width > simd::MAX_WIDTH
+            || self.is_writable()
+            || positions.iter().any(|&pos| pos + 64 > size)
+        {
+            return None;
+        }
+        Some(simd::and_ranges(self.as_bytes(), positions, width))
+    }
+}
+
+// Empty function on some archs
+#[allow(unused_variables)]
+#[inline]
+fn prefetch_read(pointer: *const u8) {
+    #[cfg(all(target_arch = "x86_64", target_feature = "sse"))]
+    {
+        use std::arch::x86_64 as arch_impl;
+
+        unsafe {
+            arch_impl::_mm_prefetch::<{ arch_impl::_MM_HINT_NTA }>(pointer as *const i8);
+        }
+    }
+}
+
+/// An advisory lock on a `.bfd` or container file, held for as long as the
+/// file is mapped: exclusive for writable `BField`s and shared for read-only
+/// ones, so two processes (or two `BField`s in one process) can't write to
+/// the same file at once, or read it while it's being written.
+///
+/// Locks are mandatory on Windows, and would stop the other handles the
+/// file is read and written with, so the lock is held on a companion
+/// `{filename}.lock` file there instead (which is left in place).
+#[cfg(feature = "mmap")]
+pub(crate) struct FileLock {
+    file: File,
+    #[cfg(windows)]
+    _lock_file: Option<File>,
+}
+
+#[cfg(feature = "mmap")]
+impl FileLock {
+    /// Opens the file at `path` (creating it if `exclusive`) and locks it,
+    /// failing with `ErrorKind::WouldBlock` if another handle holds a
+    /// conflicting lock. Files on filesystems without locks (e.g. some
+    /// network filesystems) are opened without one.
+    pub fn acquire(path: &Path, exclusive: bool) -> Result<Self, io::Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(exclusive)
+            .create(exclusive)
+            .truncate(false)
+            .open(path)?;
+        #[cfg(not(windows))]
+        let locked = lock(&file, exclusive);
+        #[cfg(windows)]
+        let lock_file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(lock_path(path))
+        {
+            Ok(lock_file) => Some(lock_file),
+            // e.g. a read-only directory, where nothing can write the file
+            Err(e) if !exclusive && e.kind() == io::ErrorKind::PermissionDenied => None,
+            Err(e) => return Err(e),
+        };
+        #[cfg(windows)]
+        let locked = match &lock_file {
+            Some(lock_file) => lock(lock_file, exclusive),
+            None => Ok(()),
+        };
+        let acquired = FileLock {
+            file,
+            #[cfg(windows)]
+            _lock_file: lock_file,
+        };
+        match locked {
+            Ok(()) => Ok(acquired),
+            Err(TryLockError::WouldBlock) if exclusive => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!(
+                    "{:?} is already open in another process (or BField), so it can't be opened for writing",
+                    path
+                ),
+            )),
+            Err(TryLockError::WouldBlock) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!(
+                    "{:?} is being written by another process (or BField), so it can't be opened",
+                    path
+                ),
+            )),
+            Err(TryLockError::Error(e)) if e.kind() == io::ErrorKind::Unsupported => Ok(acquired),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    /// The locked file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+#[cfg(feature = "mmap")]
+fn lock(file: &File, exclusive: bool) -> Result<(), TryLockError> {
+    if exclusive {
+        file.try_lock()
+    } else {
+        file.try_lock_shared()
+    }
+}
+
+/// The companion file a `.bfd` or container file is locked through on
+/// Windows, see `FileLock`.
+#[cfg(any(windows, test))]
+pub(crate) fn lock_path(path: &Path) -> std::path::PathBuf {
+    let mut filename = path.file_name().unwrap_or_default().to_os_string();
+    filename.push(".lock");
+    path.with_file_name(filename)
+}
+
+/// Renames `from` over `to`. Windows refuses to replace a file that's open
+/// without sharing deletes (as virus scanners and indexers briefly do) or
+/// that's mapped, so the rename is retried for a moment there before
+/// failing with an error saying so.
+pub(crate) fn replace_file(from: &Path, to: &Path) -> Result<(), io::Error> {
+    let mut attempts = 0;
+    loop {
+        match std::fs::rename(from, to) {
+            Err(e) if cfg!(windows) && e.kind() == io::ErrorKind::PermissionDenied => {
+                attempts += 1;
+                if attempts == 10 {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!(
+                            "Can't replace {:?} with {:?}, it's still open or mapped: {}",
+                            to, from, e
+                        ),
+                    ));
+                }
+                std::thread::sleep(std::time::Duration::from_millis(50 * attempts));
+            }
+            result => return result,
+        }
+    }
+}
+
+/// A mapping shared by all the members stored in it. Mapped files are kept
+/// locked until the mapping is dropped.
+pub(crate) enum Mapping {
+    #[cfg(feature = "mmap")]
+    ReadOnly(Mmap, #[allow(dead_code)] FileLock),
+    #[cfg(feature = "mmap")]
+    ReadWrite(MmapMut, #[allow(dead_code)] FileLock),
+    /// Bytes managed by the caller, see `BField::from_bytes`
+    Borrowed(&'static [u8]),
+}
+
+impl Mapping {
+    /// Maps the file locked by `lock`, which stays locked as long as the
+    /// mapping is alive.
+    #[cfg(feature = "mmap")]
+    pub fn open(lock: FileLock, read_only: bool) -> Result<Self, io::Error> {
+        Ok(if read_only {
+            Mapping::ReadOnly(unsafe { Mmap::map(lock.file())? }, lock)
+        } else {
+            Mapping::ReadWrite(unsafe { MmapMut::map_mut(lock.file())? }, lock)
+        })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            #[cfg(feature = "mmap")]
+            Mapping::ReadOnly(m, _) => m,
+            #[cfg(feature = "mmap")]
+            Mapping::ReadWrite(m, _) => m,
+            Mapping::Borrowed(bytes) => bytes,
+        }
+    }
+
+    pub fn flush(&self) -> Result<(), io::Error> {
+        match self {
+            #[cfg(feature = "mmap")]
+            Mapping::ReadOnly(..) => Ok(()),
+            #[cfg(feature = "mmap")]
+            Mapping::ReadWrite(m, _) => m.flush(),
+            Mapping::Borrowed(_) => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Region {
+    // keeps `ptr` alive
+    map: Arc<Mapping>,
+    ptr: *mut u8,
+    /// Size in bits
+    size: usize,
+}
+
+// `ptr` points into the mapping kept alive by `map`, which is only written
+// to with atomics (see `Storage::set_range`)
+unsafe impl Send for Region {}
+unsafe impl Sync for Region {}
+
+impl Region {
+    /// The bit array of `size` bits starting `offset` bytes into `map`.
+    pub fn new(map: Arc<Mapping>, offset: usize, size: usize) -> Self {
+        let ptr = map.as_slice()[offset..].as_ptr() as *mut u8;
+        debug_assert!(map.as_slice().len() >= offset + byte_len(size));
+        Region { map, ptr, size }
+    }
+}
+
+impl Storage {
+    /// A zeroed bit array of `size` bits in an owned buffer.
+    pub fn heap(size: usize) -> Self {
+        Storage::Heap {
+            bytes: (0..byte_len(size)).map(|_| AtomicU8::new(0)).collect(),
+            size,
+            anonymous: false,
+        }
+    }
+
+    /// A zeroed bit array of `size` bits in heap-allocated words.
+    pub fn words(size: usize) -> Self {
+        Storage::Words {
+            words: (0..size.div_ceil(64)).map(|_| AtomicU64::new(0)).collect(),
+            size,
+        }
+    }
+
+    /// A zeroed bit array of `size` bits stored as `backing` asks, with
+    /// `header` if it's a file at `path`.
+    pub fn create(
+        backing: Backing,
+        path: &Path,
+        size: usize,
+        header: &[u8],
+    ) -> Result<Self, io::Error> {
+        match backing {
+            Backing::File => Storage::create_file(path, size, header),
+            Backing::Memory => Storage::anonymous(size),
+            Backing::Words => Ok(Storage::words(size)),
+        }
+    }
+
+    /// How new bit arrays should be stored to match this one.
+    pub fn backing(&self) -> Backing {
+        match self {
+            Storage::Words { .. } => Backing::Words,
+            storage if storage.is_file() => Backing::File,
+            _ => Backing::Memory,
+        }
+    }
+
+    /// A zeroed in-memory bit array of `size` bits: an anonymous map, or an
+    /// owned buffer without the `mmap` feature.
+    pub fn anonymous(size: usize) -> Result<Self, io::Error> {
+        #[cfg(feature = "mmap")]
+        {
+            Ok(Storage::Memory(MmapBitVec::from_memory(size)?))
+        }
+        #[cfg(not(feature = "mmap"))]
+        {
+            let mut storage = Storage::heap(size);
+            storage.set_persisted(false);
+            Ok(storage)
+        }
+    }
+
+    /// Creates a `.bfd` file at `path` with `header` for a zeroed bit array
+    /// of `size` bits and maps it. Not supported without the `mmap` feature.
+    pub fn create_file(path: &Path, size: usize, header: &[u8]) -> Result<Self, io::Error> {
+        #[cfg(feature = "mmap")]
+        {
+            Ok(Storage::Mmap(MmapBitVec::create(
+                path,
+                size,
+                Some(BF_MAGIC),
+                header,
+            )?))
+        }
+        #[cfg(not(feature = "mmap"))]
+        {
+            let _ = (size, header);
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "Can't create {:?}: file-backed BFields need the `mmap` feature",
+                    path
+                ),
+            ))
+        }
+    }
+
+    /// Whether the bit array is a mapped `.bfd` file.
+    pub fn is_file(&self) -> bool {
+        match self {
+            #[cfg(feature = "mmap")]
+            Storage::Mmap(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the bit array was created in memory and never written to a
+    /// file.
+    pub fn is_anonymous(&self) -> bool {
+        match self {
+            #[cfg(feature = "mmap")]
+            Storage::Memory(_) => true,
+            Storage::Heap { anonymous, .. } => *anonymous,
+            Storage::Words { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Records whether an owned buffer was written to a file.
+    pub fn set_persisted(&mut self, persisted: bool) {
+        if let Storage::Heap { anonymous, .. } = self {
+            *anonymous = !persisted;
+        }
+    }
+
+    #[inline]
+    pub fn size(&self) -> usize {
+        match self {
+            #[cfg(feature = "mmap")]
+            Storage::Mmap(bv) | Storage::Memory(bv) => bv.size(),
+            Storage::Region(r) => r.size,
+            Storage::Heap { size, .. } | Storage::Words { size, .. } => *size,
+        }
+    }
+
+    /// Short description of where the bit array is stored.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "mmap")]
+            Storage::Mmap(bv) if matches!(bv.mmap, MmapKind::Mmap(_)) => "read-only file",
+            #[cfg(feature = "mmap")]
+            Storage::Mmap(_) => "file",
+            #[cfg(feature = "mmap")]
+            Storage::Memory(_) => "anonymous map",
+            Storage::Region(_) => "shared mapping",
+            Storage::Heap { .. } => "heap",
+            Storage::Words { .. } => "words",
+        }
+    }
+
+    /// Pointer to the start of the bit array.
+    #[inline]
+    pub fn as_ptr(&self) -> *const u8 {
+        match self {
+            #[cfg(feature = "mmap")]
+            Storage::Mmap(bv) | Storage::Memory(bv) => bv.mmap.as_ptr(),
+            Storage::Region(r) => r.ptr,
+            Storage::Heap { bytes, .. } => bytes.as_ptr() as *const u8,
+            Storage::Words { words, .. } => words.as_ptr() as *const u8,
+        }
+    }
+
+    /// The raw bytes of the bit array. These aren't read atomically, so the
+    /// bit array shouldn't be inserted into while they're in use.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.as_ptr(), byte_len(self.size())) }
+    }
+
+    /// Whether the bit array can be written to.
+    pub fn is_writable(&self) -> bool {
+        match self {
+            #[cfg(feature = "mmap")]
+            Storage::Mmap(bv) => matches!(bv.mmap, MmapKind::MmapMut(_)),
+            #[cfg(feature = "mmap")]
+            Storage::Memory(_) => true,
+            Storage::Heap { .. } | Storage::Words { .. } => true,
+            #[cfg(feature = "mmap")]
+            Storage::Region(r) => matches!(*r.map, Mapping::ReadWrite(..)),
+            #[cfg(not(feature = "mmap"))]
+            Storage::Region(_) => false,
+        }
+    }
+
+    /// ORs the bits of `other`, which must be the same size, into this bit
+    /// array.
+    pub fn or_with(&self, other: &Storage) {
+        assert_eq!(self.size(), other.size(), "Bit arrays of different sizes");
+        assert!(self.is_writable(), "Can't write to a read-only bit array");
+        let ptr = self.as_ptr();
+        for (i, &byte) in other.as_bytes().iter().enumerate() {
+            if byte != 0 {
+                // Safety: `i` is in bounds and the memory is writable
+                unsafe {
+                    (*(ptr.add(i) as *const AtomicU8)).fetch_or(byte, AtomicOrdering::Relaxed)
+                };
+            }
+        }
+    }
+
+    /// The number of bits set in this bit array or `other` (which must be
+    /// the same size), and the number of those that are only set in `other`.
+    pub fn count_union(&self, other: &Storage) -> (usize, usize) {
+        assert_eq!(self.size(), other.size(), "Bit arrays of different sizes");
+        self.as_bytes().iter().zip(other.as_bytes()).fold(
+            (0, 0),
+            |(n_set, n_new), (&ours, &theirs)| {
+                (
+                    n_set + (ours | theirs).count_ones() as usize,
+                    n_new + (theirs & !ours).count_ones() as usize,
+                )
+            },
+        )
+    }
+
+    /// Reads the (at most 128) bits in `r`.
+    ///
+    /// Writable bit arrays are read with atomic loads so lookups can run
+    /// while other threads insert.
+    #[inline]
+    pub fn get_range(&self, r: Range<usize>) -> u128 {
+        assert!(r.end <= self.size(), "Range ends outside of the bit array");
+        if let Storage::Words { words, .. } = self {
+            return words_get_range(words, r);
+        }
+        if self.is_writable() {
+            // Safety: the range is in bounds
+            unsafe { atomic_get_range(self.as_ptr(), r) }
+        } else {
+            get_range(self.as_bytes(), r)
+        }
+    }
+
+    /// ORs `x` into the bits in `r`.
+    ///
+    /// This uses atomic byte-wise ORs, so several threads can insert into
+    /// the same bit array concurrently without losing bits.
+    #[inline]
+    pub fn set_range(&self, r: Range<usize>, x: u128) {
+        assert!(r.end <= self.size(), "Range ends outside of the bit array");
+        assert!(self.is_writable(), "Can't write to a read-only bit array");
+        if let Storage::Words { words, .. } = self {
+            return words_set_range(words, r, x);
+        }
+        // Safety: the range is in bounds and the memory is writable
+        unsafe { atomic_set_range(self.as_ptr() as *mut u8, r, x) }
+    }
+
+    /// Number of bits set in the whole bit array.
+    pub fn count_ones(&self) -> usize {
+        match self {
+            #[cfg(feature = "mmap")]
+            Storage::Mmap(bv) | Storage::Memory(bv) => bv.rank(0..bv.size()),
+            Storage::Words { words, .. } => words
+                .iter()
+                .map(|w| w.load(AtomicOrdering::Relaxed).count_ones() as usize)
+                .sum(),
+            // the padding bits of the last byte are never set
+            Storage::Region(_) | Storage::Heap { .. } => self
+                .as_bytes()
+                .iter()
+                .map(|b| b.count_ones() as usize)
+                .sum(),
+        }
+    }
+
+    /// Estimates the fraction of bits set from `n_samples` 64-bit words
+    /// spread evenly over the bit array (or from all of it if it's smaller).
+    pub fn sampled_density(&self, n_samples: usize) -> f64 {
+        let size = self.size();
+        if size == 0 {
+            return 0.0;
+        }
+        let n_words = n_samples.min(size / 64);
+        if n_words == 0 {
+            return self.get_range(0..size).count_ones() as f64 / size as f64;
+        }
+        let stride = size / n_words;
+        let n_set: u32 = (0..n_words)
+            .map(|i| self.get_range(i * stride..i * stride + 64).count_ones())
+            .sum();
+        f64::from(n_set) / (64 * n_words) as f64
+    }
+
+    /// Number of bits set in `r`.
+    #[cfg(test)]
+    pub fn rank(&self, r: Range<usize>) -> usize {
+        match self {
+            #[cfg(feature = "mmap")]
+            Storage::Mmap(bv) | Storage::Memory(bv) => bv.rank(r),
+            Storage::Region(_) | Storage::Heap { .. } | Storage::Words { .. } => {
+                self.as_bytes()[..]
+                    .iter()
+                    .enumerate()
+                    .map(|(i, b)| {
+                        (0..8)
+                            .filter(|j| r.contains(&(i * 8 + j)) && b & (0x80 >> j) != 0)
+                            .count()
+                    })
+                    .sum()
+            }
+        }
+    }
+
+    /// Fills the (zeroed) bit array with the bytes read from `reader`.
+    pub fn read_from<R: Read + ?Sized>(&mut self, reader: &mut R) -> Result<(), io::Error> {
+        if let Storage::Heap { bytes, .. } = self {
+            // Safety: `AtomicU8` has the same layout as `u8` and `bytes` is
+            // borrowed mutably
+            let bytes = unsafe {
+                std::slice::from_raw_parts_mut(bytes.as_mut_ptr() as *mut u8, bytes.len())
+            };
+            return reader.read_exact(bytes);
+        }
+        let size = self.size();
+        let mut buf = vec![0; (1 << 16).min(byte_len(size))];
+        let mut pos = 0;
+        while pos < size {
+            let n = buf.len().min(byte_len(size - pos));
+            reader.read_exact(&mut buf[..n])?;
+            for chunk in buf[..n].chunks(16) {
+                let n_bits = (8 * chunk.len()).min(size - pos);
+                let mut word = [0; 16];
+                word[..chunk.len()].copy_from_slice(chunk);
+                let value = u128::from_be_bytes(word) >> (128 - n_bits);
+                // most of a typical bit array is zeroes
+                if value != 0 {
+                    self.set_range(pos..pos + n_bits, value);
+                }
+                pos += n_bits;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells the OS how the bit array will be accessed, with `madvise`. This
+    /// is a no-op on non-Unix platforms.
+    pub fn advise(&self, pattern: AccessPattern) -> Result<(), io::Error> {
+        let file_backed = match self {
+            Storage::Region(r) => !matches!(*r.map, Mapping::Borrowed(_)),
+            storage => storage.is_file(),
+        };
+        if pattern == AccessPattern::DontNeed && !file_backed {
+            return Ok(());
+        }
+        #[cfg(all(unix, feature = "mmap"))]
+        {
+            let advice = match pattern {
+                AccessPattern::Normal => libc::MADV_NORMAL,
+                AccessPattern::Random => libc::MADV_RANDOM,
+                AccessPattern::Sequential => libc::MADV_SEQUENTIAL,
+                AccessPattern::WillNeed => libc::MADV_WILLNEED,
+                AccessPattern::DontNeed => libc::MADV_DONTNEED,
+            };
+            self.madvise(advice)?;
+        }
+        Ok(())
+    }
+
+    /// Asks the kernel to back the bit array with transparent huge pages
+    /// (`MADV_HUGEPAGE`). Only supported on Linux with the `mmap` feature.
+    pub fn advise_huge_pages(&self) -> Result<(), io::Error> {
+        #[cfg(all(target_os = "linux", feature = "mmap"))]
+        {
+            self.madvise(libc::MADV_HUGEPAGE)
+        }
+        #[cfg(not(all(target_os = "linux", feature = "mmap")))]
+        {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Huge pages are only supported on Linux",
+            ))
+        }
+    }
+
+    #[cfg(all(unix, feature = "mmap"))]
+    fn madvise(&self, advice: libc::c_int) -> Result<(), io::Error> {
+        // `madvise` wants a page-aligned address
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let start = self.as_ptr() as usize;
+        let aligned_start = start - start % page_size;
+        let len = start - aligned_start + byte_len(self.size());
+        let ret = unsafe { libc::madvise(aligned_start as *mut libc::c_void, len, advice) };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Faults in every page of the bit array by reading a byte from each
+    /// (after asking the OS to read them ahead), so the first lookups don't
+    /// have to wait on the disk.
+    pub fn warm_up(&self) -> Result<(), io::Error> {
+        self.advise(AccessPattern::WillNeed)?;
+        let mut checksum = 0u8;
+        for byte in self.as_bytes().iter().step_by(4096) {
+            checksum ^= unsafe { std::ptr::read_volatile(byte) };
+        }
+        std::hint::black_box(checksum);
+        Ok(())
+    }
+}
+
+/// Same as `get_range`, reading the bytes at `ptr` with atomic loads.
+///
+/// # Safety
+///
+/// `ptr` must be valid for reads of the bytes holding the bits in `r`.
+#[inline]
+unsafe fn atomic_get_range(ptr: *const u8, r: Range<usize>) -> u128 {
+    debug_assert!(r.end - r.start <= 128, "Range too large (>128)");
+    let first = r.start >> 3;
+    let len = byte_len(r.end).saturating_sub(first);
+    let mut buf = [0; 17];
+    for (i, byte) in buf[..len].iter_mut().enumerate() {
+        *byte = (*(ptr.add(first + i) as *const AtomicU8)).load(AtomicOrdering::Relaxed);
+    }
+    get_range(&buf[..len], r.start - 8 * first..r.end - 8 * first)
+}
+
+/// ORs the low `r.end - r.start` bits of `x` into `r` of the bytes at `ptr`
+/// with atomic operations, with the same layout as `get_range`.
+///
+/// # Safety
+///
+/// `ptr` must be valid for writes of the bytes holding the bits in `r`.
+#[inline]
+unsafe fn atomic_set_range(ptr: *mut u8, r: Range<usize>, x: u128) {
+    debug_assert!(r.end - r.start <= 128, "Range too large (>128)");
+    let mut pos = r.start;
+    while pos < r.end {
+        let bit_offset = pos & 7;
+        let n = (8 - bit_offset).min(r.end - pos);
+        let chunk = ((x >> (r.end - pos - n)) & ((1 << n) - 1)) as u8;
+        if chunk != 0 {
+            (*(ptr.add(pos >> 3) as *const AtomicU8))
+                .fetch_or(chunk << (8 - bit_offset - n), AtomicOrdering::Relaxed);
+        }
+        pos += n;
+    }
+}
+
+/// Same as `get_range`, for bits stored in big-endian 64-bit words.
+#[inline]
+fn words_get_range(words: &[AtomicU64], r: Range<usize>) -> u128 {
+    debug_assert!(r.end - r.start <= 128, "Range too large (>128)");
+    let mut value = 0;
+    let mut pos = r.start;
+    while pos < r.end {
+        let bit_offset = pos & 63;
+        let n = (64 - bit_offset).min(r.end - pos);
+        let word = u64::from_be(words[pos >> 6].load(AtomicOrdering::Relaxed));
+        value = (value << n) | u128::from((word << bit_offset) >> (64 - n));
+        pos += n;
+    }
+    value
+}
+
+/// Same as `atomic_set_range`, for bits stored in big-endian 64-bit words.
+#[inline]
+fn words_set_range(words: &[AtomicU64], r: Range<usize>, x: u128) {
+    debug_assert!(r.end - r.start <= 128, "Range too large (>128)");
+    let mut pos = r.start;
+    while pos < r.end {
+        let bit_offset = pos & 63;
+        let n = (64 - bit_offset).min(r.end - pos);
+        let chunk = (x >> (r.end - pos - n)) as u64 & (u64::MAX >> (64 - n));
+        if chunk != 0 {
+            words[pos >> 6].fetch_or(
+                (chunk << (64 - bit_offset - n)).to_be(),
+                AtomicOrdering::Relaxed,
+            );
+        }
+        pos += n;
+    }
+}
+
+/// Encodes the prefix `mmap-bitvec` writes in front of a bit array:
+/// magic bytes, the header length and header, and the size in bits.
+pub(crate) fn encode_member_prefix(header: &[u8], size: usize) -> Vec<u8> {
+    assert!(header.len() <= usize::from(u16::MAX), "Header too long");
+    let mut prefix = Vec::with_capacity(2 + 2 + header.len() + 8);
+    prefix.extend_from_slice(&BF_MAGIC);
+    prefix.extend_from_slice(&(header.len() as u16).to_be_bytes());
+    prefix.extend_from_slice(header);
+    prefix.extend_from_slice(&(size as u64).to_be_bytes());
+    prefix
+}
+
+/// Parses the prefix written by `encode_member_prefix`, without checking
+/// the bit array that follows.
+pub(crate) fn parse_member_prefix(bytes: &[u8]) -> Result<MemberImage<'_>, io::Error> {
+    Ok(crate::lookup::parse_member_prefix(bytes)?)
+}
+
+/// Parses the prefix written by `encode_member_prefix`, checking the
+/// bit array is fully contained in `bytes`.
+pub(crate) fn parse_member(bytes: &[u8]) -> Result<MemberImage<'_>, io::Error> {
+    Ok(crate::lookup::parse_member(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    fn test_ranges_match_mmap_bitvec() {
+        let mut bv = MmapBitVec::from_memory(1024).unwrap();
+        let storage = Storage::heap(1024);
+        let ranges = [
+            (0, 7),
+            (3, 39),
+            (61, 128),
+            (130, 258),
+            (893, 1000),
+            (1000, 1024),
+        ];
+        for (i, (start, end)) in ranges.iter().enumerate() {
+            let value =
+                (0x5A5A_F00D_1234_5678_9ABC_DEF0_0FED_CBA9u128 >> i) >> (128 - (end - start));
+            bv.set_range(*start..*end, value);
+            storage.set_range(*start..*end, value);
+        }
+        for (start, end) in ranges.iter() {
+            assert_eq!(storage.get_range(*start..*end), bv.get_range(*start..*end));
+            assert_eq!(
+                get_range(storage.as_bytes(), *start..*end),
+                bv.get_range(*start..*end)
+            );
+        }
+        let n_set: u32 = storage.as_bytes().iter().map(|b| b.count_ones()).sum();
+        assert_eq!(n_set as usize, bv.rank(0..1024));
+        assert_eq!(storage.as_bytes(), Storage::Memory(bv).as_bytes());
+    }
+
+    #[test]
+    fn test_words_storage() {
+        let heap = Storage::heap(1000);
+        let words = Storage::create(Backing::Words, Path::new(""), 1000, b"").unwrap();
+        assert_eq!(words.backing(), Backing::Words);
+        assert!(words.is_anonymous());
+        // ranges within a word, across two words and across three
+        let ranges = [
+            (0, 7),
+            (3, 67),
+            (60, 128),
+            (61, 189),
+            (130, 258),
+            (936, 1000),
+        ];
+        for (i, (start, end)) in ranges.iter().enumerate() {
+            let value =
+                (0x5A5A_F00D_1234_5678_9ABC_DEF0_0FED_CBA9u128 >> i) >> (128 - (end - start));
+            heap.set_range(*start..*end, value);
+            words.set_range(*start..*end, value);
+        }
+        for (start, end) in ranges.iter() {
+            assert_eq!(words.get_range(*start..*end), heap.get_range(*start..*end));
+        }
+        assert_eq!(words.as_bytes(), heap.as_bytes());
+        assert_eq!(words.count_ones(), heap.count_ones());
+        assert_eq!(words.rank(100..900), heap.rank(100..900));
+    }
+
+    #[test]
+    #[cfg(feature = "simd")]
+    fn test_and_ranges() {
+        let heap = Storage::heap(4096);
+        for i in 0..200 {
+            heap.set_range(i * 19..i * 19 + 39, 0x55_5555_5555 >> (i % 5));
+        }
+        let positions = [0, 57, 190, 1000, 2003, 3000, 4050];
+        for pos in positions {
+            heap.set_range(pos..pos + 39, 0x40_0000_1234);
+        }
+        // writable arrays are read one range at a time
+        assert_eq!(heap.and_ranges(&positions, 39), None);
+
+        let bytes: &'static [u8] = Box::leak(heap.as_bytes().to_vec().into_boxed_slice());
+        let region = Storage::Region(Region::new(Arc::new(Mapping::Borrowed(bytes)), 0, 4096));
+        let expected = positions[..6].iter().fold(u128::MAX, |merged, &pos| {
+            merged & heap.get_range(pos..pos + 39)
+        });
+        assert_ne!(expected, 0);
+        assert_eq!(
+            region.and_ranges(&positions[..6], 39).map(u128::from),
+            Some(expected)
+        );
+        // too close to the end of the array, or too wide
+        assert_eq!(region.and_ranges(&positions, 39), None);
+        assert_eq!(region.and_ranges(&positions[..6], 64), None);
+    }
+
+    #[test]
+    fn test_parse_member() {
+        let mut image = encode_member_prefix(b"params", 20);
+        image.extend_from_slice(&[0xFF, 0x00, 0xF0]);
+        let member = parse_member(&image).unwrap();
+        assert_eq!(member.header, b"params");
+        assert_eq!(member.size, 20);
+        assert_eq!(get_range(&image[member.data_offset..], 4..12), 0xF0);
+
+        assert!(parse_member(&image[..image.len() - 1]).is_err());
+        assert!(parse_member(b"\x00\x00\x00\x00").is_err());
+
+        // a header claiming more bits than the image holds (or than fit in
+        // memory) doesn't overflow
+        let mut huge = encode_member_prefix(b"params", 0);
+        let size_offset = huge.len() - 8;
+        huge[size_offset..].copy_from_slice(&u64::MAX.to_be_bytes());
+        huge.extend_from_slice(&[0xFF; 64]);
+        let err = parse_member(&huge).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(byte_len(usize::MAX), usize::MAX / 8 + 1);
+    }
+
+    #[test]
+    fn test_advise() {
+        let storage = Storage::anonymous(1 << 16).unwrap();
+        storage.set_range(3..10, 0x7F);
+        for pattern in [
+            AccessPattern::Random,
+            AccessPattern::Sequential,
+            AccessPattern::WillNeed,
+            AccessPattern::DontNeed,
+            AccessPattern::Normal,
+        ] {
+            storage.advise(pattern).unwrap();
+        }
+        // the anonymous map wasn't discarded
+        assert_eq!(storage.get_range(3..10), 0x7F);
+    }
+
+    #[test]
+    fn test_replace_file() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let (from, to) = (
+            tmp_dir.path().join("new.bfd"),
+            tmp_dir.path().join("old.bfd"),
+        );
+        std::fs::write(&from, b"new").unwrap();
+        std::fs::write(&to, b"old").unwrap();
+        replace_file(&from, &to).unwrap();
+        assert_eq!(std::fs::read(&to).unwrap(), b"new");
+        assert!(!from.exists());
+        assert_eq!(
+            replace_file(&from, &to).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            lock_path(Path::new("dir/bfield.0.bfd")),
+            Path::new("dir/bfield.0.bfd.lock")
+        );
+    }
+
+    #[test]
+    fn test_anonymous_storage() {
+        let mut storage = Storage::anonymous(100).unwrap();
+        assert!(storage.is_anonymous());
+        assert!(!storage.is_file());
+        assert!(storage.is_writable());
+        storage.set_range(90..100, 0x3FF);
+        assert_eq!(storage.count_ones(), 10);
+        if let Storage::Heap { .. } = storage {
+            storage.set_persisted(true);
+            assert!(!storage.is_anonymous());
+        }
+        assert!(!Storage::heap(100).is_anonymous());
+    }
+}
@@ -0,0 +1,171 @@
+//! Predicting the error rates of a `BField`'s parameters before spending
+//! hours building it, by building a scaled-down model in memory.
+//!
+//! The model keeps the number of bits per key of every array, so its arrays
+//! fill up to the same densities as the full-size ones and the rates
+//! measured on it carry over.
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::bfield::{BField, BuildParams};
+use crate::stats::Stats;
+use crate::storage::Backing;
+
+/// The most keys the model is built with: enough to measure rates down to
+/// about 1e-5, in a second or so.
+pub const MAX_MODEL_KEYS: u64 = 1 << 18;
+
+/// The smallest size in bits of the model's arrays.
+const MIN_MODEL_SIZE: usize = 64;
+
+/// What [`simulate`] predicts for a build.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SimulationReport {
+    /// Number of keys the full build would insert
+    pub n_keys: u64,
+    /// Number of keys the model was built with
+    pub n_model_keys: u64,
+    /// Size of the model relative to the full build
+    pub scale: f64,
+    /// The size in bits of each array of the model, starting with the
+    /// primary one
+    pub model_sizes: Vec<usize>,
+    /// Number of keys that weren't inserted looked up in the model
+    pub n_queries: usize,
+    /// Fraction of those for which `get` returned a value
+    pub false_positive_rate: f64,
+    /// Fraction of the inserted keys for which `get` returned `None`
+    pub indeterminacy_rate: f64,
+    /// The rates estimated from the densities of the model's arrays, see
+    /// [`BField::stats`]
+    pub stats: Stats,
+}
+
+/// Builds a model of a `BField` with the given parameters holding `n_keys`
+/// keys (scaled down to at most [`MAX_MODEL_KEYS`] keys, along with the
+/// arrays) with values spread over all the values the markers can store,
+/// then measures its error rates by looking up every inserted key and
+/// `n_queries` keys that weren't inserted.
+pub fn simulate(
+    params: &BuildParams,
+    n_keys: u64,
+    n_queries: usize,
+) -> Result<SimulationReport, io::Error> {
+    if params.sizes.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Can't simulate a BField without arrays",
+        ));
+    }
+    let n_model_keys = n_keys.min(MAX_MODEL_KEYS);
+    let scale = if n_keys == 0 {
+        1.0
+    } else {
+        n_model_keys as f64 / n_keys as f64
+    };
+    let model_sizes: Vec<usize> = params
+        .sizes
+        .iter()
+        .map(|&size| ((size as f64 * scale).ceil() as usize).max(MIN_MODEL_SIZE))
+        .collect();
+
+    // in-memory arrays don't write anything to the directory
+    let mut model: BField<()> = BField::create(
+        std::env::temp_dir(),
+        "simulation",
+        model_sizes[0],
+        params.n_hashes,
+        params.marker_width,
+        params.n_marker_bits,
+        0.0,
+        0.0,
+        1,
+        Backing::Words,
+        (),
+    )?;
+    for &size in &model_sizes[1..] {
+        model.add_secondary(size)?;
+    }
+    let n_values = u64::from(model.max_value()) + 1;
+    let pairs = (0..n_model_keys).map(|i| (i.to_be_bytes(), (i % n_values) as u32));
+    model.build_from_iter(pairs.clone(), |_| {})?;
+
+    let n_indeterminate = pairs.filter(|(key, _)| model.get(key).is_none()).count();
+    // the inserted keys count up from 0, so these count down from the top
+    let n_false_positives = (0..n_queries as u64)
+        .filter(|i| model.get(u64::MAX - i).is_some())
+        .count();
+    Ok(SimulationReport {
+        n_keys,
+        n_model_keys,
+        scale,
+        model_sizes,
+        n_queries,
+        false_positive_rate: rate(n_false_positives as u64, n_queries as u64),
+        indeterminacy_rate: rate(n_indeterminate as u64, n_model_keys),
+        stats: model.stats(),
+    })
+}
+
+fn rate(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_simulate_builds() {
+        let params = BuildParams {
+            n_hashes: 10,
+            marker_width: 39,
+            n_marker_bits: 4,
+            sizes: vec![3_000_000, 300_000, 75_000],
+        };
+        let small = simulate(&params, 50_000, 10_000).unwrap();
+        assert_eq!(small.n_model_keys, 50_000);
+        assert_eq!(small.scale, 1.0);
+        assert_eq!(small.model_sizes, params.sizes);
+        assert_eq!(small.stats.members[0].n_keys, 50_000);
+
+        // ten times the keys and the arrays give about the same rates
+        let large = BuildParams {
+            sizes: params.sizes.iter().map(|size| size * 10).collect(),
+            ..params.clone()
+        };
+        let report = simulate(&large, 500_000, 10_000).unwrap();
+        assert_eq!(report.n_model_keys, MAX_MODEL_KEYS);
+        assert!((report.scale - MAX_MODEL_KEYS as f64 / 500_000.).abs() < 1e-12);
+        assert_eq!(report.model_sizes[0], 15_728_640);
+        let density = |report: &SimulationReport| report.stats.members[0].density;
+        assert!((density(&report) - density(&small)).abs() < 0.01);
+        assert!(report.false_positive_rate < 0.001);
+        assert!(report.indeterminacy_rate < 0.001);
+
+        // overfilling the arrays shows up in the rates
+        let tiny = BuildParams {
+            sizes: vec![100_000],
+            ..params
+        };
+        let report = simulate(&tiny, 50_000, 10_000).unwrap();
+        assert!(report.indeterminacy_rate > 0.5);
+        assert!((report.indeterminacy_rate - report.stats.indeterminacy_rate).abs() < 0.1);
+
+        let err = simulate(
+            &BuildParams {
+                sizes: vec![],
+                ..tiny
+            },
+            10,
+            10,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}